@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+
+use crate::errors::{fail, ErrorCode};
+use crate::options::CLI_OPTIONS;
+use crate::progress::{progress, progress_stage};
+use crate::MetadataResult;
+
+/// Name of the metadata file inside the archive.
+const METADATA_FILE: &str = "metadata.json";
+
+/// Write the files (relative to image_dir) and metadata into a zip archive at path.
+/// Frames and videos are already compressed, so only the metadata is deflated.
+fn write_zip(path: &Path, image_dir: &Path, files: &[String], metadata: &str) {
+    let file = File::create(path).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Io,
+            &format!("Could not create {:?}: {:?}", path, e),
+        )
+    });
+    let mut zip = zip::ZipWriter::new(BufWriter::new(file));
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    for name in files {
+        zip.start_file(name.as_str(), stored)
+            .expect("Could not add file to archive");
+        let mut input = File::open(image_dir.join(name))
+            .unwrap_or_else(|e| fail(ErrorCode::Io, &format!("Could not read {}: {:?}", name, e)));
+        std::io::copy(&mut input, &mut zip).expect("Could not write file to archive");
+    }
+    zip.start_file(METADATA_FILE, FileOptions::default())
+        .expect("Could not add metadata to archive");
+    zip.write_all(metadata.as_bytes())
+        .expect("Could not write metadata to archive");
+    zip.finish().expect("Could not finish archive");
+}
+
+/// Write the files (relative to image_dir) and metadata into a tar archive at path.
+fn write_tar(path: &Path, image_dir: &Path, files: &[String], metadata: &str) {
+    let file = File::create(path).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Io,
+            &format!("Could not create {:?}: {:?}", path, e),
+        )
+    });
+    let mut tar = tar::Builder::new(BufWriter::new(file));
+    for name in files {
+        tar.append_path_with_name(image_dir.join(name), name)
+            .unwrap_or_else(|e| {
+                fail(
+                    ErrorCode::Io,
+                    &format!("Could not add {} to archive: {:?}", name, e),
+                )
+            });
+    }
+    let mut header = tar::Header::new_gnu();
+    header.set_size(metadata.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    tar.append_data(&mut header, METADATA_FILE, metadata.as_bytes())
+        .expect("Could not add metadata to archive");
+    tar.into_inner()
+        .and_then(|mut writer| writer.flush())
+        .expect("Could not finish archive");
+}
+
+/// Package the files (relative to image_dir, e.g. the kept frames and the final video) and the
+/// metadata as JSON into the archive given by the archive option (.zip or .tar), if it is set.
+/// Files that don't exist, like frames that failed to download, are left out.
+pub fn write_archive(image_dir: &Path, files: &[String], metadata_result: &MetadataResult) {
+    let path = match &CLI_OPTIONS.archive {
+        Some(path) => path,
+        None => return,
+    };
+    progress_stage(&format!("Writing archive {:?}", path));
+    let files = files
+        .iter()
+        .filter(|name| image_dir.join(name).is_file())
+        .cloned()
+        .collect::<Vec<_>>();
+    let metadata = serde_json::to_string(metadata_result).expect("Serialization failed");
+    let is_tar = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("tar"));
+    if is_tar {
+        write_tar(path, image_dir, &files, &metadata);
+    } else {
+        write_zip(path, image_dir, &files, &metadata);
+    }
+    progress(&format!(
+        "Archived {} files into {:?}",
+        files.len() + 1,
+        path
+    ));
+}
@@ -0,0 +1,227 @@
+use std::path::Path;
+use std::process::Command;
+
+use crate::errors::{fail, ErrorCode};
+use crate::ffmpeg::{ffmpeg_path, fps};
+use crate::options::CLI_OPTIONS;
+use crate::progress::{progress, warning};
+use crate::SerializablePointBearing;
+
+/// Sample rate the audio is decoded at for beat detection.
+const SAMPLE_RATE: usize = 11025;
+/// Samples per step of the onset envelope, about 23 ms.
+const HOP: usize = 256;
+/// Only the start of long tracks is analyzed; the tempo of music rarely drifts.
+const ANALYZED_SECONDS: &str = "120";
+/// Tempo range considered by detection, and the tempo preferred between octave candidates.
+const MIN_BPM: f64 = 60.0;
+const MAX_BPM: f64 = 180.0;
+const PREFERRED_BPM: f64 = 120.0;
+/// Turns sharper than this many degrees between points count as scene changes.
+const SCENE_CHANGE_DEGREES: f64 = 45.0;
+
+/// Tempo of the music: a beat every period seconds, starting first_beat seconds into it.
+#[derive(Debug, Clone, Copy)]
+pub struct BeatGrid {
+    pub period: f64,
+    pub first_beat: f64,
+}
+
+/// The beat grid to synchronize frames to, if the beat_sync option is set, found once per run.
+fn beat_grid() -> Option<BeatGrid> {
+    *crate::context::current()
+        .beat_grid
+        .get_or_init(find_beat_grid)
+}
+
+/// Decode the audio file at path into mono samples with ffmpeg.
+fn decode_audio(path: &Path) -> Vec<f64> {
+    let output = Command::new(ffmpeg_path())
+        .args(["-v", "error", "-i"])
+        .arg(path)
+        .args(["-t", ANALYZED_SECONDS, "-ac", "1", "-ar"])
+        .arg(SAMPLE_RATE.to_string())
+        .args(["-f", "s16le", "-"])
+        .output()
+        .unwrap_or_else(|e| {
+            fail(
+                ErrorCode::FfmpegMissing,
+                &format!("Could not run ffmpeg to decode {:?}: {:?}", path, e),
+            )
+        });
+    output
+        .stdout
+        .chunks_exact(2)
+        .map(|pair| i16::from_le_bytes([pair[0], pair[1]]) as f64 / 32768.0)
+        .collect()
+}
+
+/// Onset strength per hop: how much louder each hop is than the previous one.
+fn onset_envelope(samples: &[f64]) -> Vec<f64> {
+    let energies = samples
+        .chunks(HOP)
+        .map(|hop| (1.0 + hop.iter().map(|s| s * s).sum::<f64>()).ln())
+        .collect::<Vec<_>>();
+    energies
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).max(0.0))
+        .collect()
+}
+
+/// Estimate the beat period (in hops) by autocorrelating the onset envelope, weighting lags
+/// toward PREFERRED_BPM to avoid picking half or double the tempo.
+fn estimate_period(onsets: &[f64]) -> Option<f64> {
+    let hop_seconds = HOP as f64 / SAMPLE_RATE as f64;
+    let min_lag = (60.0 / MAX_BPM / hop_seconds).floor() as usize;
+    let max_lag = (60.0 / MIN_BPM / hop_seconds).ceil() as usize;
+    if onsets.len() <= 2 * max_lag {
+        return None;
+    }
+    let score = |lag: usize| {
+        let correlation = onsets[lag..]
+            .iter()
+            .zip(onsets)
+            .map(|(a, b)| a * b)
+            .sum::<f64>()
+            / (onsets.len() - lag) as f64;
+        let bpm = 60.0 / (lag as f64 * hop_seconds);
+        let octaves = (bpm / PREFERRED_BPM).log2();
+        correlation * (-0.5 * octaves * octaves).exp()
+    };
+    let scores = (min_lag - 1..=max_lag + 1).map(score).collect::<Vec<_>>();
+    let best = (1..scores.len() - 1).max_by(|&a, &b| scores[a].partial_cmp(&scores[b]).unwrap())?;
+    // Refine the lag between hops with a parabola through the peak and its neighbors.
+    let (left, peak, right) = (scores[best - 1], scores[best], scores[best + 1]);
+    let curvature = left - 2.0 * peak + right;
+    let shift = if curvature < 0.0 {
+        0.5 * (left - right) / curvature
+    } else {
+        0.0
+    };
+    Some((min_lag - 1 + best) as f64 + shift)
+}
+
+/// Find the offset (in hops) of the first beat: the one where beats a period apart have the
+/// strongest onsets.
+fn estimate_phase(onsets: &[f64], period: f64) -> f64 {
+    (0..period.ceil() as usize)
+        .map(|offset| {
+            let strength = (0..)
+                .map(|beat| (offset as f64 + beat as f64 * period).round() as usize)
+                .take_while(|&index| index < onsets.len())
+                .map(|index| onsets[index])
+                .sum::<f64>();
+            (offset, strength)
+        })
+        .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+        .map_or(0.0, |(offset, _)| offset as f64)
+}
+
+/// Beat grid from the bpm option, or detected from the audio option's file.
+fn find_beat_grid() -> Option<BeatGrid> {
+    if !CLI_OPTIONS.beat_sync {
+        return None;
+    }
+    if let Some(bpm) = CLI_OPTIONS.bpm {
+        return Some(BeatGrid {
+            period: 60.0 / bpm,
+            first_beat: 0.0,
+        });
+    }
+    let audio = CLI_OPTIONS
+        .audio
+        .as_ref()
+        .expect("--beat-sync without --audio or --bpm is rejected by the options");
+    let onsets = onset_envelope(&decode_audio(audio));
+    let hop_seconds = HOP as f64 / SAMPLE_RATE as f64;
+    match estimate_period(&onsets) {
+        Some(period) => {
+            let grid = BeatGrid {
+                period: period * hop_seconds,
+                first_beat: estimate_phase(&onsets, period) * hop_seconds,
+            };
+            progress(&format!(
+                "Detected {:.1} BPM, first beat at {:.2}s",
+                60.0 / grid.period,
+                grid.first_beat
+            ));
+            Some(grid)
+        }
+        None => {
+            warning("Audio is too short to detect beats, not synchronizing to the beat");
+            None
+        }
+    }
+}
+
+/// Number of frames per beat, the closest whole number to the fps option's.
+pub fn frames_per_beat() -> Option<usize> {
+    let grid = beat_grid()?;
+    Some(((fps() as f64 * grid.period).round() as usize).max(1))
+}
+
+/// Frame rate that puts a whole number of frames in every beat, if synchronizing to the beat.
+pub fn beat_framerate() -> Option<f64> {
+    Some(frames_per_beat()? as f64 / beat_grid()?.period)
+}
+
+/// Seconds to skip into the audio so its beats line up with the frames of a route starting
+/// route_start seconds into the video.
+pub fn audio_start(route_start: f64) -> f64 {
+    beat_grid().map_or(0.0, |grid| {
+        (grid.first_beat - route_start).rem_euclid(grid.period)
+    })
+}
+
+/// Smallest angle in degrees between two bearings.
+fn bearing_change(a: f64, b: f64) -> f64 {
+    let change = (a - b).rem_euclid(360.0);
+    change.min(360.0 - change)
+}
+
+/// Drop frames before each scene change (a sharp turn) so that it starts on a beat,
+/// i.e. at a multiple of frames_per_beat. At most frames_per_beat - 1 frames are dropped
+/// per scene change, spread evenly over the frames since the previous one.
+pub fn align_scene_changes(
+    points: Vec<SerializablePointBearing>,
+    frames_per_beat: usize,
+) -> Vec<SerializablePointBearing> {
+    let changes = (1..points.len())
+        .filter(|&i| {
+            bearing_change(points[i - 1].bearing, points[i].bearing) > SCENE_CHANGE_DEGREES
+        })
+        .collect::<Vec<_>>();
+    let mut aligned: Vec<SerializablePointBearing> = Vec::with_capacity(points.len());
+    let mut segment_start = 0;
+    for &change in changes.iter().chain(std::iter::once(&points.len())) {
+        let segment = &points[segment_start..change];
+        let excess = if change < points.len() {
+            (aligned.len() + segment.len()) % frames_per_beat
+        } else {
+            0
+        };
+        if excess >= segment.len() {
+            aligned.extend_from_slice(segment);
+        } else {
+            // Evenly spaced indices within the segment to drop.
+            let step = segment.len() as f64 / (excess + 1) as f64;
+            let dropped = (1..=excess)
+                .map(|i| (i as f64 * step).floor() as usize)
+                .collect::<Vec<_>>();
+            aligned.extend(
+                segment
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| !dropped.contains(i))
+                    .map(|(_, point)| *point),
+            );
+        }
+        segment_start = change;
+    }
+    progress(&format!(
+        "Aligned {} scene changes to the beat, dropping {} frames",
+        changes.len(),
+        points.len() - aligned.len()
+    ));
+    aligned
+}
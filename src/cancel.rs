@@ -0,0 +1,108 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde_json::json;
+
+use crate::errors::CANCELLED_EXIT_CODE;
+use crate::options::CLI_OPTIONS;
+use crate::progress::{cancelled, current_stage};
+
+/// File written to the output directory when the run is cancelled.
+pub const CHECKPOINT_FILE: &str = "streetwarp-checkpoint.json";
+
+lazy_static! {
+    /// Ids of the child processes that are running, to stop them on cancel.
+    static ref CHILD_PIDS: Mutex<Vec<u32>> = Mutex::new(vec![]);
+}
+
+/// Registers a child process for the lifetime of the guard.
+pub struct ChildGuard(u32);
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        CHILD_PIDS.lock().unwrap().retain(|&pid| pid != self.0);
+    }
+}
+
+/// Stop the child process with id pid if the run is cancelled before the guard is dropped.
+pub fn track_child(pid: u32) -> ChildGuard {
+    CHILD_PIDS.lock().unwrap().push(pid);
+    ChildGuard(pid)
+}
+
+/// Stop every tracked child process.
+fn kill_children() {
+    for pid in CHILD_PIDS.lock().unwrap().drain(..) {
+        let status = if cfg!(windows) {
+            std::process::Command::new("taskkill")
+                .args(["/F", "/PID", &pid.to_string()])
+                .status()
+        } else {
+            std::process::Command::new("kill")
+                .arg(pid.to_string())
+                .status()
+        };
+        if let Err(e) = status {
+            warn!("Could not stop child process {}: {:?}", pid, e);
+        }
+    }
+}
+
+/// Quote arg for a POSIX shell, so that the resume command can be pasted back as is.
+fn shell_quote(arg: &str) -> String {
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Write a checkpoint into output_dir recording where the run stopped and how to resume it.
+fn write_checkpoint(output_dir: &Path, fetched_metadata: &Path) -> PathBuf {
+    let fetched = fetched_metadata.is_file();
+    let resume = if fetched {
+        format!(
+            "streetwarp encode {}",
+            shell_quote(&output_dir.to_string_lossy())
+        )
+    } else {
+        std::env::args()
+            .map(|arg| shell_quote(&arg))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let checkpoint = json!({
+        "stage": current_stage(),
+        "args": std::env::args().collect::<Vec<_>>(),
+        "fetched": fetched,
+        "resume": resume,
+    });
+    let path = output_dir.join(CHECKPOINT_FILE);
+    if let Err(e) = std::fs::write(&path, checkpoint.to_string()) {
+        warn!("Could not write checkpoint {:?}: {:?}", path, e);
+    }
+    path
+}
+
+/// On Ctrl-C, stop the child processes, write a checkpoint to output_dir (or delete it if it
+/// is_temp and the cancel_cleanup option is set), report the cancellation and exit.
+/// fetched_metadata is the file written once every image is fetched, which encode resumes from.
+pub fn handle_ctrl_c(output_dir: PathBuf, is_temp: bool, fetched_metadata: PathBuf) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        kill_children();
+        let checkpoint = if is_temp && CLI_OPTIONS.cancel_cleanup {
+            if let Err(e) = std::fs::remove_dir_all(&output_dir) {
+                warn!("Could not remove {:?}: {:?}", output_dir, e);
+            }
+            None
+        } else {
+            Some(write_checkpoint(&output_dir, &fetched_metadata))
+        };
+        cancelled(checkpoint.as_deref());
+        std::process::exit(CANCELLED_EXIT_CODE);
+    });
+}
@@ -0,0 +1,63 @@
+use serde_json::{json, Value};
+
+use crate::errors::{ErrorCode, CANCELLED_EXIT_CODE};
+use crate::options::{CODECS, FORMATS, GEOCODERS, MINTERP_MODES, PACES};
+
+/// Version of the metadata result format read by use_metadata and printed by dry_run, written
+/// into its schemaVersion field. Within a version, fields are only added, and always with a
+/// default, so every file of a version reads the same. Removing, renaming or changing the
+/// meaning of a field starts a new version, with a migration in schema.rs from the previous one,
+/// so files written by any older version still read. Files of newer versions are refused.
+/// Version 2 added schemaVersion itself; files without it are version 1.
+pub const METADATA_SCHEMA_VERSION: u32 = 2;
+/// Version of the JSON progress, stage and warning messages printed with the progress option.
+/// Version 2 added schemaVersion to every event and the counted fields of PROGRESS events.
+pub const PROGRESS_SCHEMA_VERSION: u32 = 2;
+
+/// Features this binary was compiled with.
+fn compiled_features() -> Vec<&'static str> {
+    let mut features = vec![];
+    if cfg!(feature = "scripting") {
+        features.push("scripting");
+    }
+    if cfg!(feature = "native-encode") {
+        features.push("native-encode");
+    }
+    features
+}
+
+/// Exit code of each failure class, by the code of its ERROR events.
+fn exit_codes() -> Value {
+    let mut exit_codes = serde_json::Map::new();
+    for code in ErrorCode::all() {
+        exit_codes.insert(code.name().to_string(), json!(code.exit_code()));
+    }
+    exit_codes.insert("CANCELLED".to_string(), json!(CANCELLED_EXIT_CODE));
+    Value::Object(exit_codes)
+}
+
+/// What this binary supports, for orchestrators that adapt to the version deployed.
+pub fn capabilities() -> Value {
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "inputFormats": ["gpx", "json", "takeout"],
+        "remoteRoutes": ["strava", "komoot", "ridewithgps", "garmin"],
+        "sensorFormats": ["gpx", "fit"],
+        "subcommands": ["metadata", "fetch", "encode", "run", "jobs", "watch", "merge", "completions"],
+        "providers": {
+            "imagery": ["google-streetview", "mock"],
+            "geocoders": GEOCODERS,
+        },
+        "minterpModes": MINTERP_MODES,
+        "codecs": CODECS,
+        "outputFormats": FORMATS,
+        "paces": PACES,
+        "profiles": ["preview", "archival", "social"],
+        "features": compiled_features(),
+        "exitCodes": exit_codes(),
+        "schemaVersions": {
+            "metadata": METADATA_SCHEMA_VERSION,
+            "progress": PROGRESS_SCHEMA_VERSION,
+        },
+    })
+}
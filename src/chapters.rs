@@ -0,0 +1,106 @@
+use std::path::Path;
+
+use geo::{prelude::*, Point};
+
+use crate::errors::{fail, ErrorCode};
+use crate::ffmpeg::input_framerate;
+use crate::{GPXWaypoint, SerializablePointBearing};
+
+/// Waypoints farther than this many meters from every frame are not on the route.
+const MAX_WAYPOINT_DISTANCE: f64 = 200.0;
+
+/// A chapter starting start seconds into the video.
+struct Chapter {
+    title: String,
+    start: f64,
+}
+
+/// Escape the characters with special meaning in ffmetadata files.
+fn escape_metadata(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        if "=;#\\\n".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Pair each waypoint near the route with the index of its closest point.
+pub fn nearest_points<'a>(
+    waypoints: &'a [GPXWaypoint],
+    points: &[SerializablePointBearing],
+) -> Vec<(usize, &'a GPXWaypoint)> {
+    waypoints
+        .iter()
+        .filter_map(|waypoint| {
+            let location = Point::new(waypoint.lng, waypoint.lat);
+            let (index, distance) = points
+                .iter()
+                .map(|p| location.geodesic_distance(&Point::new(p.lng, p.lat)))
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())?;
+            Some((index, waypoint)).filter(|_| distance <= MAX_WAYPOINT_DISTANCE)
+        })
+        .collect()
+}
+
+/// Find the chapters at each waypoint near the route, starting at the time the closest frame
+/// appears in the video. The route starts offset seconds into the video.
+fn waypoint_chapters(
+    waypoints: &[GPXWaypoint],
+    points: &[SerializablePointBearing],
+    offset: f64,
+) -> Vec<Chapter> {
+    let framerate = input_framerate(points.len());
+    let mut chapters = nearest_points(waypoints, points)
+        .into_iter()
+        .map(|(index, waypoint)| Chapter {
+            title: waypoint.name.clone(),
+            start: offset + index as f64 / framerate,
+        })
+        .collect::<Vec<_>>();
+    chapters.sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap());
+    chapters
+}
+
+/// Write an ffmetadata file with a chapter per waypoint on the route (plus a "Start" chapter
+/// before the first one) to path. The route starts offset seconds into a video lasting duration
+/// seconds. Return the number of waypoint chapters.
+pub fn write_chapters(
+    path: &Path,
+    waypoints: &[GPXWaypoint],
+    points: &[SerializablePointBearing],
+    offset: f64,
+    duration: f64,
+) -> usize {
+    let mut chapters = waypoint_chapters(waypoints, points, offset);
+    let waypoint_count = chapters.len();
+    if chapters.first().is_none_or(|c| c.start > 0.0) {
+        chapters.insert(
+            0,
+            Chapter {
+                title: "Start".to_string(),
+                start: 0.0,
+            },
+        );
+    }
+    let mut contents = ";FFMETADATA1\n".to_string();
+    for (i, chapter) in chapters.iter().enumerate() {
+        let end = chapters.get(i + 1).map_or(duration, |next| next.start);
+        contents += &format!(
+            "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+            (chapter.start * 1000.0).round() as u64,
+            (end * 1000.0).round() as u64,
+            escape_metadata(&chapter.title)
+        );
+    }
+    std::fs::write(path, contents).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Io,
+            &format!("Could not write {:?}: {:?}", path, e),
+        )
+    });
+    waypoint_count
+}
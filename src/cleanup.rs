@@ -0,0 +1,110 @@
+use std::path::Path;
+
+use crate::options::CLI_OPTIONS;
+use crate::overlay::OVERLAY_FILES;
+use crate::pacing::PACED_PATTERN;
+use crate::progress::{progress, warning};
+
+/// Suffixes of the intermediate videos and encoder logs written next to the output.
+const INTERMEDIATE_SUFFIXES: &[&str] = &["-original.mp4", "-stabilized.mp4", ".trf"];
+/// Parts of the names of other intermediate videos, e.g. streetwarp-lapse.mp4-body.mp4 or the
+/// video streetwarp-lapse.gif.mp4 converted to a GIF.
+const INTERMEDIATE_INFIXES: &[&str] = &[
+    "-body.",
+    "-remuxed.",
+    "-passlog",
+    ".gif.",
+    "-chunk",
+    "-interpolated.",
+];
+
+/// Whether name is a frame of one of the n_points points of the route as fetched or kept by the
+/// optimizer, e.g. 12.jpg or 12.opt.jpg, or a paced frame, e.g. 40.paced.jpg.
+fn is_frame(name: &str, n_points: usize) -> bool {
+    let (digits, extension) = match name.split_once('.') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let index = match digits.parse::<usize>() {
+        // Only plain indices, so that e.g. +1.jpg or 01.jpg are left alone.
+        Ok(index) if index.to_string() == digits => index,
+        _ => return false,
+    };
+    match extension {
+        "jpg" | "opt.jpg" => index < n_points,
+        // Pacing repeats frames, so there can be more paced frames than points.
+        _ => PACED_PATTERN.strip_prefix("%d.") == Some(extension),
+    }
+}
+
+/// Whether the file named name in the output directory of a route of n_points points is only
+/// needed while encoding: a frame, an overlay file or an intermediate video.
+fn is_intermediate(name: &str, n_points: usize) -> bool {
+    is_frame(name, n_points)
+        || name == crate::FETCHED_METADATA_FILE
+        || OVERLAY_FILES.contains(&name)
+        || INTERMEDIATE_SUFFIXES
+            .iter()
+            .any(|suffix| name.ends_with(suffix))
+        || INTERMEDIATE_INFIXES
+            .iter()
+            .any(|infix| name.contains(infix))
+}
+
+/// Number of points whose frames were fetched into output_dir, read from the fetched metadata
+/// written next to them, or 0 if there is none.
+fn fetched_points(output_dir: &Path) -> usize {
+    std::fs::read(output_dir.join(crate::FETCHED_METADATA_FILE))
+        .ok()
+        .and_then(|contents| serde_json::from_slice::<serde_json::Value>(&contents).ok())
+        .and_then(|metadata| Some(metadata["gpsPoints"].as_array()?.len()))
+        .unwrap_or(0)
+}
+
+/// After a successful encode of a video of n_points frames to output, delete the frames and
+/// intermediate files in output_dir if the cleanup option is set: all of output_dir if it is_temp
+/// and the output was written elsewhere, otherwise only the intermediate files. Without cleanup
+/// or keep_frames, warn about what is left behind in a temporary directory.
+pub fn clean_output_dir(output_dir: &Path, is_temp: bool, output: &Path, n_points: usize) {
+    if CLI_OPTIONS.keep_frames {
+        return;
+    }
+    if !CLI_OPTIONS.cleanup {
+        if is_temp {
+            let size = fs_extra::dir::get_size(output_dir).unwrap_or(0);
+            warning(&format!(
+                "Left {:.2} MB of frames in {}, pass --cleanup to delete them or --keep-frames to keep them",
+                size as f64 / 1000000.0,
+                output_dir.to_string_lossy()
+            ));
+        }
+        return;
+    }
+    if is_temp && !output.starts_with(output_dir) {
+        std::fs::remove_dir_all(output_dir)
+            .unwrap_or_else(|e| warning(&format!("Could not delete {:?}: {:?}", output_dir, e)));
+        progress(&format!("Deleted {}", output_dir.to_string_lossy()));
+        return;
+    }
+    let entries = match std::fs::read_dir(output_dir) {
+        Ok(entries) => entries,
+        Err(e) => return warning(&format!("Could not read {:?}: {:?}", output_dir, e)),
+    };
+    // Frames that were fetched but left out of the video, e.g. by --dedup, are deleted too.
+    let n_points = n_points.max(fetched_points(output_dir));
+    let mut deleted = 0;
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.path().is_file() && entry.path() != output && is_intermediate(&name, n_points) {
+            match std::fs::remove_file(entry.path()) {
+                Ok(()) => deleted += 1,
+                Err(e) => warning(&format!("Could not delete {:?}: {:?}", entry.path(), e)),
+            }
+        }
+    }
+    progress(&format!(
+        "Deleted {} frames and intermediate files from {}",
+        deleted,
+        output_dir.to_string_lossy()
+    ));
+}
@@ -0,0 +1,146 @@
+use std::collections::VecDeque;
+use std::path::Path;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::RgbImage;
+use rayon::prelude::*;
+
+use crate::options::CLI_OPTIONS;
+use crate::progress::progress;
+
+/// Per-channel (R, G, B) counts of each pixel value.
+type Histogram = [[u64; 256]; 3];
+/// Per-channel mapping from input to output pixel value.
+type Lut = [[u8; 256]; 3];
+
+fn histogram(img: &RgbImage) -> Histogram {
+    let mut hist = [[0u64; 256]; 3];
+    for pixel in img.pixels() {
+        for channel in 0..3 {
+            hist[channel][pixel[channel] as usize] += 1;
+        }
+    }
+    hist
+}
+
+/// Cumulative distribution of one channel, normalized to end at 1.
+fn cdf(counts: &[u64; 256]) -> [f64; 256] {
+    let total = counts.iter().sum::<u64>().max(1) as f64;
+    let mut cdf = [0.0; 256];
+    let mut sum = 0;
+    for (value, &count) in counts.iter().enumerate() {
+        sum += count;
+        cdf[value] = sum as f64 / total;
+    }
+    cdf
+}
+
+/// Map each value of source to the value with the same cumulative share in reference,
+/// moved only strength (0 to 1) of the way from the original value.
+fn matching_lut(source: &Histogram, reference: &Histogram, strength: f64) -> Lut {
+    let mut lut = [[0u8; 256]; 3];
+    for channel in 0..3 {
+        let source_cdf = cdf(&source[channel]);
+        let reference_cdf = cdf(&reference[channel]);
+        let mut matched = 0;
+        for value in 0..256 {
+            while matched < 255 && reference_cdf[matched] < source_cdf[value] {
+                matched += 1;
+            }
+            let target = value as f64 + strength * (matched as f64 - value as f64);
+            lut[channel][value] = target.round() as u8;
+        }
+    }
+    lut
+}
+
+/// Histogram of a frame with the given histogram after applying lut, without decoding it again.
+fn apply_to_histogram(hist: &Histogram, lut: &Lut) -> Histogram {
+    let mut mapped = [[0u64; 256]; 3];
+    for channel in 0..3 {
+        for value in 0..256 {
+            mapped[channel][lut[channel][value] as usize] += hist[channel][value];
+        }
+    }
+    mapped
+}
+
+fn load_frame(path: &Path) -> Option<RgbImage> {
+    image::open(path).ok().map(|img| img.to_rgb8())
+}
+
+/// Histogram-match each frame {index}.jpg in image_dir to the combined histogram of the previous
+/// color_match_window (default: 5) already matched frames, reducing color and exposure jumps
+/// between panoramas captured at different times. Frames are rewritten in place.
+pub fn match_colors<P: AsRef<Path>>(image_dir: &P, n_frames: usize) {
+    let image_dir = image_dir.as_ref();
+    let window = CLI_OPTIONS.color_match_window.unwrap_or(5).max(1);
+    let strength = CLI_OPTIONS
+        .color_match_strength
+        .unwrap_or(0.7)
+        .clamp(0.0, 1.0);
+    let frame_path = |index: usize| image_dir.join(format!("{}.jpg", index));
+    let histograms = (0..n_frames)
+        .into_par_iter()
+        .map(|index| load_frame(&frame_path(index)).map(|img| histogram(&img)))
+        .collect::<Vec<_>>();
+
+    // Each lut depends on the matched frames before it, but only through their histograms,
+    // so the luts are found in order and then applied to the images in parallel.
+    let mut recent: VecDeque<Histogram> = VecDeque::with_capacity(window);
+    let mut luts = vec![];
+    for hist in &histograms {
+        let hist = match hist {
+            Some(hist) => hist,
+            None => {
+                luts.push(None);
+                continue;
+            }
+        };
+        let lut = if recent.is_empty() {
+            None
+        } else {
+            let mut reference = [[0u64; 256]; 3];
+            for previous in &recent {
+                for channel in 0..3 {
+                    for value in 0..256 {
+                        reference[channel][value] += previous[channel][value];
+                    }
+                }
+            }
+            Some(matching_lut(hist, &reference, strength))
+        };
+        let matched = match &lut {
+            Some(lut) => apply_to_histogram(hist, lut),
+            None => *hist,
+        };
+        if recent.len() == window {
+            recent.pop_front();
+        }
+        recent.push_back(matched);
+        luts.push(lut);
+    }
+
+    let matched_frames = luts
+        .into_par_iter()
+        .enumerate()
+        .filter_map(|(index, lut)| {
+            let lut = lut?;
+            let path = frame_path(index);
+            let mut img = load_frame(&path)?;
+            for pixel in img.pixels_mut() {
+                for channel in 0..3 {
+                    pixel[channel] = lut[channel][pixel[channel] as usize];
+                }
+            }
+            // Frames may be hard links into the frame store, so unlink before writing.
+            let _ = std::fs::remove_file(&path);
+            let file = std::fs::File::create(&path).ok()?;
+            let mut writer = std::io::BufWriter::new(file);
+            JpegEncoder::new_with_quality(&mut writer, 95)
+                .encode_image(&img)
+                .ok()
+        })
+        .count();
+    progress(&format!("Matched colors of {} frames", matched_frames));
+}
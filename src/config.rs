@@ -0,0 +1,157 @@
+use std::path::PathBuf;
+
+use serde_json::{json, Map, Value};
+use structopt::clap::ArgMatches;
+
+use crate::errors::{fail, ErrorCode};
+use crate::options::Cli;
+
+/// Config file looked up in the user's config directory when the config option is not given.
+const DEFAULT_CONFIG_PATH: &str = "streetwarp/config.toml";
+/// Keys of the config file that are not options.
+const PROFILES_KEY: &str = "profiles";
+const PROFILE_KEY: &str = "profile";
+
+/// Options of the profiles available without a config file. A profile of the same name in the
+/// config file adds to or overrides these.
+fn builtin_profile(name: &str) -> Option<Value> {
+    match name {
+        "preview" => Some(json!({
+            "preview": true,
+            "minterp": "skip",
+            "frames_per_mile": 50,
+        })),
+        "archival" => Some(json!({
+            "frames_per_mile": 200,
+            "minterp": "good",
+            "codec": "hevc",
+            "crf": 20,
+        })),
+        "social" => Some(json!({
+            "aspect": "9:16",
+            "codec": "h264",
+            "target_duration": 30,
+            "thumbnail": true,
+        })),
+        _ => None,
+    }
+}
+
+/// The config file in $XDG_CONFIG_HOME (or ~/.config), if there is one.
+fn default_config_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join(DEFAULT_CONFIG_PATH)).filter(|path| path.is_file())
+}
+
+/// Read the config file at the config option's path, or at the default path if it exists.
+fn read_config(cli: &Cli) -> Map<String, Value> {
+    let path = match cli.config.clone().or_else(default_config_path) {
+        Some(path) => path,
+        None => return Map::new(),
+    };
+    let text = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!("Could not read config file {:?}: {:?}", path, e),
+        )
+    });
+    match toml::from_str(&text) {
+        Ok(Value::Object(table)) => table,
+        Ok(_) => fail(
+            ErrorCode::InputInvalid,
+            &format!("Config file {:?} is not a table of options", path),
+        ),
+        Err(e) => fail(
+            ErrorCode::InputInvalid,
+            &format!("Could not parse config file {:?}: {}", path, e),
+        ),
+    }
+}
+
+/// Options of a table in the config file, with keys in the field names of Cli
+/// (frames-per-mile and frames_per_mile are both accepted).
+fn option_entries(table: Map<String, Value>) -> impl Iterator<Item = (String, Value)> {
+    table
+        .into_iter()
+        .map(|(key, value)| (key.replace('-', "_"), value))
+}
+
+/// Whether the option with the given field name of Cli was given on the command line parsed
+/// into matches, whatever its value.
+fn is_given(matches: &ArgMatches, key: &str) -> bool {
+    matches.occurrences_of(key.replace('_', "-")) > 0
+}
+
+/// Fill in the options of cli not given on the command line parsed into matches from the config
+/// file, then from the profile chosen by the profile option (or the config file's profile key).
+pub fn apply_config(cli: Cli, matches: &ArgMatches) -> Cli {
+    let mut config = read_config(&cli);
+    let mut profiles = match config.remove(PROFILES_KEY) {
+        Some(Value::Object(profiles)) => profiles,
+        Some(_) => fail(
+            ErrorCode::InputInvalid,
+            "profiles in the config file must be a table of profiles",
+        ),
+        None => Map::new(),
+    };
+    let profile_name = cli.profile.clone().or_else(|| {
+        config
+            .remove(PROFILE_KEY)
+            .and_then(|name| name.as_str().map(String::from))
+    });
+
+    let mut defaults = Map::new();
+    defaults.extend(option_entries(config));
+    if let Some(name) = &profile_name {
+        let builtin = builtin_profile(name);
+        let custom = profiles.remove(name);
+        if builtin.is_none() && custom.is_none() {
+            fail(
+                ErrorCode::InputInvalid,
+                &format!(
+                    "Unknown profile {}, expected preview, archival, social or a profile in the config file",
+                    name
+                ),
+            );
+        }
+        for profile in builtin.into_iter().chain(custom) {
+            match profile {
+                Value::Object(options) => defaults.extend(option_entries(options)),
+                _ => fail(
+                    ErrorCode::InputInvalid,
+                    &format!("Profile {} must be a table of options", name),
+                ),
+            }
+        }
+    }
+    if defaults.is_empty() {
+        return cli;
+    }
+
+    let mut options = match serde_json::to_value(&cli).expect("Could not serialize options") {
+        Value::Object(options) => options,
+        _ => unreachable!(),
+    };
+    for (key, value) in defaults {
+        match options.get_mut(&key) {
+            Some(_) if key == "input_path" || key == "config" => fail(
+                ErrorCode::InputInvalid,
+                &format!("{} can not be set in the config file", key),
+            ),
+            Some(option) if !is_given(matches, &key) => *option = value,
+            Some(_) => {}
+            None => fail(
+                ErrorCode::InputInvalid,
+                &format!("Unknown option {} in the config file", key),
+            ),
+        }
+    }
+    serde_json::from_value(Value::Object(options)).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!("Invalid option in the config file: {}", e),
+        )
+    })
+}
@@ -0,0 +1,104 @@
+use std::path::Path;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{self, FilterType};
+use image::{GenericImageView, Rgb, RgbImage};
+use rayon::prelude::*;
+
+use crate::errors::{fail, ErrorCode};
+use crate::progress::progress;
+
+/// Width in pixels of each frame in the contact sheet.
+const CELL_WIDTH: u32 = 320;
+/// Gap in pixels between and around the frames.
+const GAP: u32 = 4;
+const BACKGROUND_COLOR: [u8; 3] = [24, 24, 24];
+/// Color of the cells of frames that could not be read, so gaps in coverage stand out.
+const MISSING_COLOR: [u8; 3] = [160, 30, 30];
+
+/// Parse a grid size like 4x3 into (columns, rows).
+fn parse_grid(grid: &str) -> (u32, u32) {
+    let parts = grid
+        .split('x')
+        .map(|part| part.trim().parse::<u32>().ok().filter(|&n| n > 0))
+        .collect::<Vec<_>>();
+    match parts.as_slice() {
+        [Some(columns), Some(rows)] => (*columns, *rows),
+        _ => fail(
+            ErrorCode::InputInvalid,
+            &format!("Invalid contact sheet grid {}, expected NxM like 4x3", grid),
+        ),
+    }
+}
+
+/// Indices of count frames evenly spaced from the first to the last of n_frames.
+fn evenly_spaced(n_frames: usize, count: usize) -> Vec<usize> {
+    if count <= 1 || n_frames <= 1 {
+        return vec![0; count.min(n_frames)];
+    }
+    let step = (n_frames - 1) as f64 / (count - 1) as f64;
+    (0..count.min(n_frames))
+        .map(|i| (i as f64 * step).round() as usize)
+        .collect()
+}
+
+/// Tile evenly spaced frames matching pattern (e.g. %d.jpg) in image_dir into a grid image
+/// (e.g. 4x3 for 4 columns and 3 rows) at out_path, in route order from left to right.
+pub fn write_contact_sheet(
+    image_dir: &Path,
+    pattern: &str,
+    n_frames: usize,
+    grid: &str,
+    out_path: &Path,
+) {
+    let (columns, rows) = parse_grid(grid);
+    let indices = evenly_spaced(n_frames, (columns * rows) as usize);
+    let cells = indices
+        .par_iter()
+        .map(|&index| {
+            let path = image_dir.join(pattern.replace("%d", &index.to_string()));
+            image::open(path).ok()
+        })
+        .collect::<Vec<_>>();
+    // Every cell gets the aspect ratio of the first readable frame.
+    let (frame_width, frame_height) = cells
+        .iter()
+        .flatten()
+        .next()
+        .map_or((4, 3), |img| img.dimensions());
+    let cell_height = (CELL_WIDTH as f64 * frame_height as f64 / frame_width as f64).round() as u32;
+    let mut sheet = RgbImage::from_pixel(
+        columns * (CELL_WIDTH + GAP) + GAP,
+        rows * (cell_height + GAP) + GAP,
+        Rgb(BACKGROUND_COLOR),
+    );
+    for (i, cell) in cells.iter().enumerate() {
+        let (column, row) = (i as u32 % columns, i as u32 / columns);
+        let x = GAP + column * (CELL_WIDTH + GAP);
+        let y = GAP + row * (cell_height + GAP);
+        let tile = match cell {
+            Some(img) => img
+                .resize_to_fill(CELL_WIDTH, cell_height, FilterType::Triangle)
+                .to_rgb8(),
+            None => RgbImage::from_pixel(CELL_WIDTH, cell_height, Rgb(MISSING_COLOR)),
+        };
+        imageops::overlay(&mut sheet, &tile, x, y);
+    }
+    let file = std::fs::File::create(out_path).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Io,
+            &format!("Could not create {:?}: {:?}", out_path, e),
+        )
+    });
+    let mut writer = std::io::BufWriter::new(file);
+    JpegEncoder::new_with_quality(&mut writer, 90)
+        .encode_image(&sheet)
+        .expect("Could not write contact sheet");
+    let missing = cells.iter().filter(|cell| cell.is_none()).count();
+    progress(&format!(
+        "Wrote contact sheet of {} frames ({} missing) to {:?}",
+        cells.len(),
+        missing,
+        out_path
+    ));
+}
@@ -0,0 +1,131 @@
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Mutex, OnceLock};
+use std::task::Poll;
+
+use serde_json::Value;
+
+use crate::beats::BeatGrid;
+use crate::errors::ErrorCode;
+use crate::options::Cli;
+use crate::progress;
+use crate::throttle;
+
+/// The options and state of one run of the pipeline. A plain run has one for the whole process,
+/// while the jobs subcommand gives each job its own so that jobs running at once don't mix.
+pub struct Context {
+    pub options: Cli,
+    /// Command line of the run, including the program name.
+    pub args: Vec<String>,
+    /// Id of the job in the jobs subcommand, copied into every progress event of the run.
+    pub job_id: Option<Value>,
+    pub progress: progress::State,
+    pub network: throttle::Totals,
+    pub limits: throttle::Limits,
+    /// Code of the first failure of the run, which the failures that follow from it (e.g.
+    /// joining the task that panicked) neither report again nor replace.
+    pub failure: Mutex<Option<ErrorCode>>,
+    /// The minterp option after accounting for filters missing from ffmpeg.
+    pub minterp_mode: OnceLock<String>,
+    /// The beat grid to synchronize frames to, if the beat_sync option is set.
+    pub beat_grid: OnceLock<Option<BeatGrid>>,
+}
+
+/// Context of the plain run, used by every thread that has no context of its own.
+static GLOBAL: OnceLock<Context> = OnceLock::new();
+
+thread_local! {
+    /// Context of the job being run on this thread.
+    static CURRENT: Cell<Option<&'static Context>> = const { Cell::new(None) };
+}
+
+impl Context {
+    fn new(options: Cli, args: Vec<String>, job_id: Option<Value>) -> Context {
+        Context {
+            options,
+            args,
+            job_id,
+            progress: progress::State::default(),
+            network: throttle::Totals::default(),
+            limits: throttle::Limits::default(),
+            failure: Mutex::new(None),
+            minterp_mode: OnceLock::new(),
+            beat_grid: OnceLock::new(),
+        }
+    }
+
+    /// Context of a job of the jobs subcommand. It is leaked so that its options can be read
+    /// like those of a plain run, which costs the few kilobytes of the options per job.
+    pub fn for_job(options: Cli, args: Vec<String>, job_id: Value) -> &'static Context {
+        Box::leak(Box::new(Context::new(options, args, Some(job_id))))
+    }
+
+    /// Run f with this as the context of the current thread.
+    pub fn enter<R>(&'static self, f: impl FnOnce() -> R) -> R {
+        struct Restore(Option<&'static Context>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                CURRENT.with(|current| current.set(self.0));
+            }
+        }
+        let _restore = Restore(CURRENT.with(|current| current.replace(Some(self))));
+        f()
+    }
+
+    /// Run future with this as the context of whichever thread polls it.
+    pub fn scope<F>(&'static self, future: F) -> Scoped<F::Output>
+    where
+        F: Future + Send + 'static,
+    {
+        Scoped {
+            context: self,
+            future: Box::pin(future),
+        }
+    }
+}
+
+/// A future that runs in a context, made by Context::scope.
+pub struct Scoped<T> {
+    context: &'static Context,
+    future: Pin<Box<dyn Future<Output = T> + Send>>,
+}
+
+impl<T> Future for Scoped<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context) -> Poll<T> {
+        let context = self.context;
+        context.enter(|| self.future.as_mut().poll(cx))
+    }
+}
+
+/// Set the options of the plain run, with its command line from the environment.
+pub fn init(options: Cli) {
+    let context = Context::new(options, std::env::args().collect(), None);
+    if GLOBAL.set(context).is_err() {
+        panic!("The options were already set");
+    }
+}
+
+/// Context of the job run by this thread, or else of the plain run, if there is one.
+pub fn try_current() -> Option<&'static Context> {
+    CURRENT
+        .with(|current| current.get())
+        .or_else(|| GLOBAL.get())
+}
+
+/// Context of the job run by this thread, or else of the plain run.
+pub fn current() -> &'static Context {
+    try_current().expect("Options were read outside of a run")
+}
+
+/// Spawn future on the runtime in the current context, so it reports its progress and
+/// failures as part of the run.
+pub fn spawn<F>(future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(current().scope(future))
+}
@@ -0,0 +1,79 @@
+use std::path::Path;
+
+use image::imageops::FilterType;
+use rayon::prelude::*;
+
+use crate::options::CLI_OPTIONS;
+use crate::progress::progress;
+
+/// Compute the 64-bit difference hash of an image: shrink to 9x8 grayscale
+/// and record whether each pixel is brighter than its right neighbor.
+pub fn dhash(path: &Path) -> Option<u64> {
+    let img = image::open(path).ok()?;
+    let small = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    Some(hash)
+}
+
+/// Drop frames {index}.jpg in image_dir whose hash is within dedup_threshold bits (default: 4)
+/// of the previous kept frame, then renumber the kept frames so the sequence is contiguous.
+/// Return the original indices of the kept frames.
+pub fn dedup_frames<P: AsRef<Path>>(image_dir: &P, n_frames: usize) -> Vec<usize> {
+    let image_dir = image_dir.as_ref();
+    let threshold = CLI_OPTIONS.dedup_threshold.unwrap_or(4);
+    let hashes = (0..n_frames)
+        .into_par_iter()
+        .map(|index| dhash(&image_dir.join(format!("{}.jpg", index))))
+        .collect::<Vec<_>>();
+
+    let mut kept = vec![];
+    let mut last_hash: Option<u64> = None;
+    for (index, hash) in hashes.into_iter().enumerate() {
+        let is_repeat = match (last_hash, hash) {
+            (Some(last), Some(hash)) => (last ^ hash).count_ones() <= threshold,
+            _ => false,
+        };
+        if !is_repeat {
+            kept.push(index);
+            last_hash = hash;
+        }
+    }
+    progress(&format!(
+        "Removed {} duplicate frames",
+        n_frames - kept.len()
+    ));
+    keep_frames(image_dir, n_frames, &kept);
+    kept
+}
+
+/// Delete the frames {index}.jpg in image_dir whose index is not in kept, which must be sorted,
+/// then renumber the kept frames so the sequence is contiguous.
+pub fn keep_frames(image_dir: &Path, n_frames: usize, kept: &[usize]) {
+    // Delete dropped frames first so each rename below targets a free filename.
+    let mut kept_iter = kept.iter().peekable();
+    for index in 0..n_frames {
+        if kept_iter.peek() == Some(&&index) {
+            kept_iter.next();
+        } else {
+            std::fs::remove_file(image_dir.join(format!("{}.jpg", index)))
+                .expect("Could not remove dropped frame");
+        }
+    }
+    for (to, &from) in kept.iter().enumerate() {
+        if to != from {
+            std::fs::rename(
+                image_dir.join(format!("{}.jpg", from)),
+                image_dir.join(format!("{}.jpg", to)),
+            )
+            .expect("Could not renumber kept frames");
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use futures::stream::BoxStream;
+use futures::{Future, Stream, StreamExt};
+use sha2::{Digest, Sha256};
+
+use crate::options::CLI_OPTIONS;
+
+/// Whether the deterministic option asked for the same input to always give the same output.
+pub fn is_enabled() -> bool {
+    CLI_OPTIONS.deterministic
+}
+
+/// Run the futures of a stream up to n at once like buffer_unordered, except that in
+/// deterministic mode their outputs come in the order of the stream instead of as they complete.
+pub trait BufferCompletions<'a>: Stream + Send + Sized + 'a
+where
+    Self::Item: Future + Send + 'a,
+    <Self::Item as Future>::Output: Send,
+{
+    fn buffer_completions(self, n: usize) -> BoxStream<'a, <Self::Item as Future>::Output> {
+        if is_enabled() {
+            self.buffered(n).boxed()
+        } else {
+            self.buffer_unordered(n).boxed()
+        }
+    }
+}
+
+impl<'a, S> BufferCompletions<'a> for S
+where
+    S: Stream + Send + 'a,
+    S::Item: Future + Send + 'a,
+    <S::Item as Future>::Output: Send,
+{
+}
+
+/// Name of the temporary output directory: from the current time, or in deterministic mode
+/// from the command line, so rerunning it reuses the same directory.
+pub fn temp_dir_name(now_secs: u64) -> String {
+    if !is_enabled() {
+        return format!("streetwarp-tmp-{}", now_secs);
+    }
+    let args = crate::context::current().args.join("\0");
+    let hash = hex::encode(Sha256::digest(args.as_bytes()));
+    format!("streetwarp-tmp-{}", &hash[..16])
+}
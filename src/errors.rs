@@ -0,0 +1,140 @@
+use std::any::Any;
+use std::cell::Cell;
+
+use crate::context;
+use crate::progress::error;
+
+/// Exit code of a run that was cancelled, the conventional one of a process interrupted by SIGINT.
+pub const CANCELLED_EXIT_CODE: i32 = 130;
+
+/// Class of a failure, reported as the code of ERROR progress events and the exit code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    /// The input route, metadata or options could not be read.
+    InputInvalid,
+    /// The API key is missing or was rejected.
+    ApiAuth,
+    /// The API key is over its query limit.
+    QuotaExceeded,
+    /// ffmpeg could not run or lacks a feature the options need.
+    FfmpegMissing,
+    /// ffmpeg or the optimizer failed to produce the video.
+    EncodeFailed,
+    /// A request could not reach its server or got an error response.
+    Network,
+    /// Frames are still missing or corrupt after refetching them.
+    FramesMissing,
+    /// A file could not be read or written.
+    Io,
+    /// Any other failure.
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorCode::InputInvalid => "INPUT_INVALID",
+            ErrorCode::ApiAuth => "API_AUTH",
+            ErrorCode::QuotaExceeded => "QUOTA_EXCEEDED",
+            ErrorCode::FfmpegMissing => "FFMPEG_MISSING",
+            ErrorCode::EncodeFailed => "ENCODE_FAILED",
+            ErrorCode::Network => "NETWORK",
+            ErrorCode::FramesMissing => "FRAMES_MISSING",
+            ErrorCode::Io => "IO",
+            ErrorCode::Internal => "INTERNAL",
+        }
+    }
+
+    /// Exit code of a run that failed with this code.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorCode::Internal => 1,
+            ErrorCode::InputInvalid => 2,
+            ErrorCode::ApiAuth => 3,
+            ErrorCode::QuotaExceeded => 4,
+            ErrorCode::FfmpegMissing => 5,
+            ErrorCode::EncodeFailed => 6,
+            ErrorCode::Network => 7,
+            ErrorCode::FramesMissing => 8,
+            ErrorCode::Io => 9,
+        }
+    }
+
+    /// Every code, for the capabilities listing.
+    pub fn all() -> Vec<ErrorCode> {
+        vec![
+            ErrorCode::Internal,
+            ErrorCode::InputInvalid,
+            ErrorCode::ApiAuth,
+            ErrorCode::QuotaExceeded,
+            ErrorCode::FfmpegMissing,
+            ErrorCode::EncodeFailed,
+            ErrorCode::Network,
+            ErrorCode::FramesMissing,
+            ErrorCode::Io,
+        ]
+    }
+}
+
+thread_local! {
+    /// Code of the failure this thread is panicking with, set by fail.
+    static FAILING_WITH: Cell<Option<ErrorCode>> = const { Cell::new(None) };
+}
+
+/// Panic with msg, reported as an ERROR event with code.
+#[track_caller]
+pub fn fail(code: ErrorCode, msg: &str) -> ! {
+    FAILING_WITH.with(|failing_with| failing_with.set(Some(code)));
+    panic!("{}", msg)
+}
+
+/// Message of a panic payload, which is a &str or a String for panics with a message.
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|msg| msg.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Unknown error".to_string())
+}
+
+/// Report the first panic of the run as a non-recoverable ERROR event, with the code passed to
+/// fail or INTERNAL for any other panic, before printing it as usual. Panics outside of a run
+/// (while reading the options of a job) keep the code on this thread for failure_code.
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if let Some(context) = context::try_current() {
+            let code = FAILING_WITH
+                .with(|failing_with| failing_with.take())
+                .unwrap_or(ErrorCode::Internal);
+            let first = {
+                let mut failure = context.failure.lock().unwrap();
+                let first = failure.is_none();
+                if first {
+                    *failure = Some(code);
+                }
+                first
+            };
+            if first {
+                error(code, &panic_message(info.payload()), false);
+            }
+        }
+        default_hook(info);
+    }));
+}
+
+/// Code of the failure of a caught panic: the first failure of the current run, or else the code
+/// of the panic raised on this thread outside of a run.
+pub fn failure_code() -> ErrorCode {
+    context::try_current()
+        .and_then(|context| *context.failure.lock().unwrap())
+        .or_else(|| FAILING_WITH.with(|failing_with| failing_with.take()))
+        .unwrap_or(ErrorCode::Internal)
+}
+
+/// Exit with the exit code of the failure if result is a caught panic, or return its value.
+/// Panics before the hook was installed (while reading the options) still have the code they
+/// were raised with on this thread.
+pub fn exit_on_panic<T>(result: std::thread::Result<T>) -> T {
+    result.unwrap_or_else(|_| std::process::exit(failure_code().exit_code()))
+}
@@ -0,0 +1,301 @@
+use std::path::Path;
+
+use geo::prelude::*;
+use geo::Point;
+use serde_json::{json, Value};
+
+use crate::errors::{fail, ErrorCode};
+use crate::options::CLI_OPTIONS;
+use crate::progress::progress;
+use crate::{cumulative_distances, MetadataResult, SerializablePointBearing};
+
+/// Consecutive frames further apart than this many times the median spacing between frames
+/// are reported as a gap in coverage.
+const GAP_SPACING_RATIO: f64 = 5.0;
+/// Frames closer than this many meters are never a gap, however dense the rest of the route.
+const MIN_GAP_METERS: f64 = 50.0;
+
+/// Page of the HTML map export. Leaflet draws the GeoJSON export substituted for __GEOJSON__: the
+/// track in blue, frames colored from green to red by their distance from the route up to
+/// HTML_MAX_ERROR_METERS, and gaps in coverage as dashed red lines.
+const HTML_TEMPLATE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>__TITLE__</title>
+<link rel="stylesheet" href="https://unpkg.com/leaflet@1.9.4/dist/leaflet.css">
+<script src="https://unpkg.com/leaflet@1.9.4/dist/leaflet.js"></script>
+<style>html, body, #map { height: 100%; margin: 0; }</style>
+</head>
+<body>
+<div id="map"></div>
+<script>
+const data = __GEOJSON__;
+const map = L.map("map");
+L.tileLayer("https://{s}.tile.openstreetmap.org/{z}/{x}/{y}.png", {
+  attribution: "&copy; OpenStreetMap contributors",
+  maxZoom: 19,
+}).addTo(map);
+function errorColor(error) {
+  const t = Math.min((error || 0) / __MAX_ERROR__, 1);
+  return `hsl(${Math.round(120 * (1 - t))}, 90%, 45%)`;
+}
+const layer = L.geoJSON(data, {
+  style: (feature) => feature.properties.kind === "gap"
+    ? { color: "#d00", weight: 4, dashArray: "6 6" }
+    : { color: "#36c", weight: 3, opacity: 0.6 },
+  pointToLayer: (feature, latlng) => L.circleMarker(latlng, {
+    radius: 4,
+    color: errorColor(feature.properties.error),
+    fillOpacity: 0.9,
+  }),
+  onEachFeature: (feature, layer) => {
+    const rows = Object.entries(feature.properties)
+      .filter(([, value]) => value !== null)
+      .map(([key, value]) => `<b>${key}</b>: ${typeof value === "number" ? +value.toFixed(6) : value}`);
+    layer.bindPopup(rows.join("<br>"));
+  },
+}).addTo(map);
+map.fitBounds(layer.getBounds());
+</script>
+</body>
+</html>
+"##;
+/// Distance in meters from the route at which frames are drawn fully red on the HTML map.
+const HTML_MAX_ERROR_METERS: f64 = 30.0;
+
+/// Location of the panorama of point, or of the route point if its metadata has none.
+fn pano_location(point: &SerializablePointBearing) -> (f64, f64) {
+    (
+        point.panoLat.unwrap_or(point.lat),
+        point.panoLng.unwrap_or(point.lng),
+    )
+}
+
+/// Distance in meters between the panoramas of each pair of consecutive frames.
+fn frame_spacings(points: &[SerializablePointBearing]) -> Vec<f64> {
+    points
+        .windows(2)
+        .map(|pair| {
+            let (lat1, lng1) = pano_location(&pair[0]);
+            let (lat2, lng2) = pano_location(&pair[1]);
+            Point::new(lng1, lat1).geodesic_distance(&Point::new(lng2, lat2))
+        })
+        .collect()
+}
+
+/// Index of the frame before each gap in coverage, with the length of the gap in meters.
+pub fn find_gaps(points: &[SerializablePointBearing]) -> Vec<(usize, f64)> {
+    let spacings = frame_spacings(points);
+    // Paced frames repeat panoramas, which says nothing about how dense the coverage is.
+    let mut sorted = spacings
+        .iter()
+        .copied()
+        .filter(|meters| *meters > 0.0)
+        .collect::<Vec<_>>();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let median = match sorted.get(sorted.len() / 2) {
+        Some(median) => *median,
+        None => return vec![],
+    };
+    let threshold = (median * GAP_SPACING_RATIO).max(MIN_GAP_METERS);
+    spacings
+        .into_iter()
+        .enumerate()
+        .filter(|(_, meters)| *meters > threshold)
+        .collect()
+}
+
+/// GeoJSON FeatureCollection of the original track, the panorama of each frame and the gaps
+/// in coverage between frames.
+fn geojson(metadata_result: &MetadataResult) -> Value {
+    let points = &metadata_result.gpsPoints;
+    let mut features = vec![json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "LineString",
+            "coordinates": metadata_result
+                .originalPoints
+                .iter()
+                .map(|point| json!([point.lng, point.lat]))
+                .collect::<Vec<_>>(),
+        },
+        "properties": {
+            "kind": "track",
+            "name": metadata_result.name,
+        },
+    })];
+    features.extend(points.iter().enumerate().map(|(index, point)| {
+        let (lat, lng) = pano_location(point);
+        json!({
+            "type": "Feature",
+            "geometry": { "type": "Point", "coordinates": [lng, lat] },
+            "properties": {
+                "kind": "frame",
+                "index": index,
+                "bearing": point.bearing,
+                "panoId": point.panoId,
+                "panoDate": point.panoDate,
+                "error": point.error,
+            },
+        })
+    }));
+    features.extend(find_gaps(points).into_iter().map(|(index, meters)| {
+        let (lat1, lng1) = pano_location(&points[index]);
+        let (lat2, lng2) = pano_location(&points[index + 1]);
+        json!({
+            "type": "Feature",
+            "geometry": { "type": "LineString", "coordinates": [[lng1, lat1], [lng2, lat2]] },
+            "properties": {
+                "kind": "gap",
+                "fromFrame": index,
+                "toFrame": index + 1,
+                "meters": meters,
+            },
+        })
+    }));
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+}
+
+/// Escape the characters of text that are special in XML.
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// GPX track through the panorama of each frame, in order, with the bearing of the frame as the
+/// course of its point in the Garmin TrackPointExtension, so mapping tools understand it.
+fn gpx(metadata_result: &MetadataResult) -> String {
+    let mut gpx = String::from(concat!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+        "\n",
+        r#"<gpx version="1.1" creator="streetwarp" xmlns="http://www.topografix.com/GPX/1/1" "#,
+        r#"xmlns:gpxtpx="http://www.garmin.com/xmlschemas/TrackPointExtension/v2">"#,
+        "\n",
+    ));
+    gpx.push_str(&format!(
+        "  <trk>\n    <name>{}</name>\n    <trkseg>\n",
+        escape_xml(&metadata_result.name)
+    ));
+    for point in &metadata_result.gpsPoints {
+        let (lat, lng) = pano_location(point);
+        gpx.push_str(&format!(
+            concat!(
+                "      <trkpt lat=\"{}\" lon=\"{}\">\n",
+                "        <extensions><gpxtpx:TrackPointExtension>",
+                "<gpxtpx:course>{}</gpxtpx:course>",
+                "</gpxtpx:TrackPointExtension></extensions>\n",
+                "      </trkpt>\n",
+            ),
+            lat,
+            lng,
+            point.bearing.rem_euclid(360.0)
+        ));
+    }
+    gpx.push_str("    </trkseg>\n  </trk>\n</gpx>\n");
+    gpx
+}
+
+/// Quote field for CSV if it contains a separator, quote or line break.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// CSV table with a row per frame of the video: its location, bearing, panorama, distance in meters from the
+/// route and distance in meters along the route.
+fn csv(metadata_result: &MetadataResult) -> String {
+    let points = &metadata_result.gpsPoints;
+    let mut csv =
+        String::from("index,lat,lng,bearing,pano_id,pano_date,error_meters,distance_meters\n");
+    for (index, (point, distance)) in points.iter().zip(cumulative_distances(points)).enumerate() {
+        let optional = |value: Option<&str>| value.map_or(String::new(), csv_field);
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{:.1}\n",
+            index,
+            point.lat,
+            point.lng,
+            point.bearing,
+            optional(point.panoId.as_deref()),
+            optional(point.panoDate.as_deref()),
+            point
+                .error
+                .map_or(String::new(), |error| format!("{:.1}", error)),
+            distance
+        ));
+    }
+    csv
+}
+
+/// HTML page with a map of the GeoJSON export.
+fn html(metadata_result: &MetadataResult) -> String {
+    // Escape "</" so no string in the data can close the script element.
+    let geojson = geojson(metadata_result).to_string().replace("</", "<\\/");
+    HTML_TEMPLATE
+        .replace("__TITLE__", &escape_xml(&metadata_result.name))
+        .replace("__MAX_ERROR__", &HTML_MAX_ERROR_METERS.to_string())
+        .replace("__GEOJSON__", &geojson)
+}
+
+fn write_export(path: &Path, contents: &str) {
+    std::fs::write(path, contents).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Io,
+            &format!("Could not write export {:?}: {:?}", path, e),
+        )
+    });
+    progress(&format!("Exported {}", path.to_string_lossy()));
+}
+
+/// Write {index}.json next to the {index}.jpg of each of points in output_dir with the
+/// panorama, capture date, copyright, location and heading of the frame, if the frame_sidecars
+/// option is set.
+pub fn write_frame_sidecars(points: &[SerializablePointBearing], output_dir: &Path) {
+    if !CLI_OPTIONS.frame_sidecars {
+        return;
+    }
+    for (index, point) in points.iter().enumerate() {
+        let (lat, lng) = pano_location(point);
+        let sidecar = json!({
+            "index": index,
+            "panoId": point.panoId,
+            "panoDate": point.panoDate,
+            "copyright": point.panoCopyright,
+            "lat": lat,
+            "lng": lng,
+            "heading": point.bearing,
+        });
+        let path = output_dir.join(format!("{}.json", index));
+        std::fs::write(&path, sidecar.to_string()).unwrap_or_else(|e| {
+            fail(
+                ErrorCode::Io,
+                &format!("Could not write frame sidecar {:?}: {:?}", path, e),
+            )
+        });
+    }
+}
+
+/// Write each export of metadata_result that an export option asks for.
+pub fn write_exports(metadata_result: &MetadataResult) {
+    if let Some(path) = &CLI_OPTIONS.export_geojson {
+        write_export(path, &geojson(metadata_result).to_string());
+    }
+    if let Some(path) = &CLI_OPTIONS.export_gpx {
+        write_export(path, &gpx(metadata_result));
+    }
+    if let Some(path) = &CLI_OPTIONS.export_csv {
+        write_export(path, &csv(metadata_result));
+    }
+    if let Some(path) = &CLI_OPTIONS.export_html {
+        write_export(path, &html(metadata_result));
+    }
+}
@@ -1,77 +1,989 @@
+use futures::{Stream, StreamExt};
+use std::collections::HashMap;
 use std::path::Path;
 use std::process::Stdio;
-use tokio::io::AsyncBufReadExt;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
 use tokio::process::Command;
 
+use crate::context;
+use crate::errors::{fail, ErrorCode};
 use crate::options::CLI_OPTIONS;
-use crate::progress::progress;
+use crate::progress::{progress, warning, ProgressReporter};
 
-type GetProgress = dyn Fn(usize) -> f64;
-pub async fn ffmpeg<P: AsRef<Path>>(working_dir: P, get_progress: &GetProgress, args: &[&str]) {
-    let mut command = Command::new("ffmpeg");
-    let command = command
+/// What the ffmpeg binary at ffmpeg_path supports, probed once per binary.
+pub struct Capabilities {
+    pub version: String,
+    pub filters: Vec<String>,
+    pub encoders: Vec<String>,
+}
+
+lazy_static! {
+    /// Capabilities of each ffmpeg binary by its path, shared by the jobs using it.
+    static ref FFMPEG_CAPABILITIES: Mutex<HashMap<String, Arc<Capabilities>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Capabilities of the ffmpeg binary at ffmpeg_path, probed the first time it is used.
+pub fn ffmpeg_capabilities() -> Arc<Capabilities> {
+    if let Some(capabilities) = FFMPEG_CAPABILITIES.lock().unwrap().get(ffmpeg_path()) {
+        return capabilities.clone();
+    }
+    // Probed without holding the lock, so a failed probe can't poison it for other jobs.
+    let capabilities = Arc::new(Capabilities::probe());
+    FFMPEG_CAPABILITIES
+        .lock()
+        .unwrap()
+        .insert(ffmpeg_path().to_string(), capabilities.clone());
+    capabilities
+}
+
+/// The minterp option after accounting for filters missing from ffmpeg, resolved once per run.
+pub fn minterp_mode() -> &'static str {
+    context::current()
+        .minterp_mode
+        .get_or_init(resolve_minterp_mode)
+}
+
+/// Whether the timelapse is encoded in-process instead of by the ffmpeg executable.
+#[cfg(feature = "native-encode")]
+pub fn uses_native_encoder() -> bool {
+    CLI_OPTIONS.native_encode
+}
+
+#[cfg(not(feature = "native-encode"))]
+pub fn uses_native_encoder() -> bool {
+    false
+}
+
+pub fn ffmpeg_path() -> &'static str {
+    CLI_OPTIONS.ffmpeg_path.as_deref().unwrap_or("ffmpeg")
+}
+
+/// Run ffmpeg with the given arguments and return its stdout, or None if it could not run.
+fn ffmpeg_output(args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new(ffmpeg_path())
         .args(args)
+        .output()
+        .ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Take the name column (second whitespace-separated field) of an ffmpeg -filters or -encoders listing.
+fn listing_names(listing: &str) -> Vec<String> {
+    listing
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+impl Capabilities {
+    fn probe() -> Capabilities {
+        let version = ffmpeg_output(&["-hide_banner", "-version"]).unwrap_or_else(|| {
+            fail(
+                ErrorCode::FfmpegMissing,
+                &format!(
+                    "Could not run ffmpeg at {}, install ffmpeg or pass --ffmpeg-path",
+                    ffmpeg_path()
+                ),
+            )
+        });
+        Capabilities {
+            version: version.lines().next().unwrap_or("").to_string(),
+            filters: listing_names(
+                &ffmpeg_output(&["-hide_banner", "-filters"]).unwrap_or_default(),
+            ),
+            encoders: listing_names(
+                &ffmpeg_output(&["-hide_banner", "-encoders"]).unwrap_or_default(),
+            ),
+        }
+    }
+
+    pub fn has_filter(&self, name: &str) -> bool {
+        self.filters.iter().any(|f| f == name)
+    }
+
+    pub fn has_encoder(&self, name: &str) -> bool {
+        self.encoders.iter().any(|e| e == name)
+    }
+}
+
+/// Probe ffmpeg and fail early if it can't produce the output video.
+pub fn check_ffmpeg() {
+    let capabilities = ffmpeg_capabilities();
+    info!("Using {}", capabilities.version);
+    let encoder = Codec::from_options().encoder();
+    if !capabilities.has_encoder(encoder) {
+        fail(
+            ErrorCode::FfmpegMissing,
+            &format!(
+                "{} was built without {}, needed by the chosen --codec",
+                ffmpeg_path(),
+                encoder
+            ),
+        );
+    }
+    if (CLI_OPTIONS.attribution
+        || CLI_OPTIONS.hud
+        || CLI_OPTIONS.speed_overlay
+        || CLI_OPTIONS.data_overlay)
+        && !capabilities.has_filter("drawtext")
+    {
+        fail(
+            ErrorCode::FfmpegMissing,
+            &format!(
+                "{} was built without libfreetype (drawtext filter), needed by text overlays",
+                ffmpeg_path()
+            ),
+        );
+    }
+    if CLI_OPTIONS.title.is_some() && !capabilities.has_filter("drawtext") {
+        fail(
+            ErrorCode::FfmpegMissing,
+            &format!(
+                "{} was built without libfreetype (drawtext filter), needed by --title",
+                ffmpeg_path()
+            ),
+        );
+    }
+    if (CLI_OPTIONS.street_names
+        || CLI_OPTIONS.entering_labels
+        || CLI_OPTIONS.waypoint_dwell_labels)
+        && !capabilities.has_filter("subtitles")
+    {
+        fail(
+            ErrorCode::FfmpegMissing,
+            &format!(
+                "{} was built without libass (subtitles filter), needed by --street-names, --entering-labels and --waypoint-dwell-labels",
+                ffmpeg_path()
+            ),
+        );
+    }
+    if CLI_OPTIONS.deflicker && !capabilities.has_filter("deflicker") {
+        fail(
+            ErrorCode::FfmpegMissing,
+            &format!(
+                "{} has no deflicker filter, needed by --deflicker",
+                ffmpeg_path()
+            ),
+        );
+    }
+    if CLI_OPTIONS.stabilize && !capabilities.has_filter("vidstabdetect") {
+        fail(
+            ErrorCode::FfmpegMissing,
+            &format!(
+                "{} was built without libvidstab, needed by --stabilize",
+                ffmpeg_path()
+            ),
+        );
+    }
+    // The video encoder is checked above with the codec, which defaults to vp9 for WebM.
+    if OutputFormat::from_options() == OutputFormat::Webm && !capabilities.has_encoder("libopus") {
+        fail(
+            ErrorCode::FfmpegMissing,
+            &format!(
+                "{} was built without libopus, needed by --format webm",
+                ffmpeg_path()
+            ),
+        );
+    }
+    if CLI_OPTIONS.compare_minterp {
+        for needed in &["minterpolate", "tblend"] {
+            if !capabilities.has_filter(needed) {
+                fail(
+                    ErrorCode::FfmpegMissing,
+                    &format!(
+                        "{} has no {} filter, needed by --compare-minterp",
+                        ffmpeg_path(),
+                        needed
+                    ),
+                );
+            }
+        }
+    }
+    if CLI_OPTIONS.loudnorm && !capabilities.has_filter("loudnorm") {
+        fail(
+            ErrorCode::FfmpegMissing,
+            &format!(
+                "{} has no loudnorm filter, needed by --loudnorm",
+                ffmpeg_path()
+            ),
+        );
+    }
+    if OutputFormat::from_options() == OutputFormat::Frames
+        && CLI_OPTIONS.frame_format.as_deref() == Some("webp")
+        && !capabilities.has_encoder("libwebp")
+    {
+        fail(
+            ErrorCode::FfmpegMissing,
+            &format!(
+                "{} was built without libwebp, needed by --frame-format webp",
+                ffmpeg_path()
+            ),
+        );
+    }
+    minterp_mode();
+}
+
+/// Both blur modes need minterpolate, and fast mode also needs tblend.
+/// The native encoder has no filters, so it always skips.
+fn resolve_minterp_mode() -> String {
+    if CLI_OPTIONS.preview {
+        return "skip".to_string();
+    }
+    let requested = CLI_OPTIONS.minterp.clone().unwrap_or("good".to_string());
+    if requested == "skip" {
+        return requested;
+    }
+    if uses_native_encoder() {
+        warning("The native encoder does not support motion interpolation, using --minterp skip");
+        return "skip".to_string();
+    }
+    if requested == "external" {
+        return requested;
+    }
+    let capabilities = ffmpeg_capabilities();
+    if !capabilities.has_filter("minterpolate") {
+        warning("ffmpeg has no minterpolate filter, using --minterp skip");
+        return "skip".to_string();
+    }
+    if requested == "fast" && !capabilities.has_filter("tblend") {
+        warning("ffmpeg has no tblend filter, using --minterp good");
+        return "good".to_string();
+    }
+    requested
+}
+
+/// Kind of file produced, chosen by the format option.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// A video in the codec's preferred container.
+    Video,
+    /// An animated GIF converted from the video.
+    Gif,
+    /// A WebM video with a silent opus audio track.
+    Webm,
+    /// The final frames and a manifest, without a video.
+    Frames,
+}
+
+impl OutputFormat {
+    pub fn from_options() -> OutputFormat {
+        match CLI_OPTIONS.format.as_deref().unwrap_or("video") {
+            "video" => OutputFormat::Video,
+            "gif" => OutputFormat::Gif,
+            "webm" => OutputFormat::Webm,
+            "frames" => OutputFormat::Frames,
+            other => fail(
+                ErrorCode::InputInvalid,
+                &format!(
+                    "Unknown format {}, available: video, gif, webm, frames",
+                    other
+                ),
+            ),
+        }
+    }
+}
+
+/// Video codec of the output, chosen by the codec option.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    H264,
+    Hevc,
+    Vp9,
+    Av1,
+}
+
+impl Codec {
+    pub fn from_options() -> Codec {
+        let webm = OutputFormat::from_options() == OutputFormat::Webm;
+        let default = if webm { "vp9" } else { "h264" };
+        let codec = match CLI_OPTIONS.codec.as_deref().unwrap_or(default) {
+            "h264" => Codec::H264,
+            "hevc" => Codec::Hevc,
+            "vp9" => Codec::Vp9,
+            "av1" => Codec::Av1,
+            other => fail(
+                ErrorCode::InputInvalid,
+                &format!("Unknown codec {}, available: h264, hevc, vp9, av1", other),
+            ),
+        };
+        if webm && (codec == Codec::H264 || codec == Codec::Hevc) {
+            fail(
+                ErrorCode::InputInvalid,
+                "WebM output needs the vp9 or av1 codec",
+            );
+        }
+        codec
+    }
+
+    /// Name of the ffmpeg encoder for this codec.
+    pub fn encoder(&self) -> &'static str {
+        match self {
+            Codec::H264 => "libx264",
+            Codec::Hevc => "libx265",
+            Codec::Vp9 => "libvpx-vp9",
+            Codec::Av1 => "libaom-av1",
+        }
+    }
+
+    /// Preferred container file extension for this codec, or webm for WebM output.
+    pub fn extension(&self) -> &'static str {
+        match (self, OutputFormat::from_options()) {
+            (_, OutputFormat::Webm) | (Codec::Vp9, _) => "webm",
+            _ => "mp4",
+        }
+    }
+
+    /// Default crf, giving roughly the quality of x264 at crf 22.
+    pub fn default_crf(&self) -> u32 {
+        match self {
+            Codec::H264 => 22,
+            Codec::Hevc => 26,
+            Codec::Vp9 => 32,
+            Codec::Av1 => 30,
+        }
+    }
+
+    fn args(&self) -> Vec<&'static str> {
+        match self {
+            Codec::H264 => vec!["-preset", "faster"],
+            // The hvc1 tag lets Apple players recognize the stream.
+            Codec::Hevc => vec!["-preset", "faster", "-tag:v", "hvc1"],
+            Codec::Vp9 => vec!["-deadline", "good", "-cpu-used", "4", "-row-mt", "1"],
+            Codec::Av1 => vec!["-cpu-used", "6", "-row-mt", "1"],
+        }
+    }
+}
+
+/// Frame rate of the timelapse before motion interpolation, default: 24.
+pub fn fps() -> u32 {
+    CLI_OPTIONS.fps.unwrap_or(24)
+}
+
+/// Output video size for the aspect option (format: W:H), with the shorter side 480 pixels.
+fn aspect_video_size(aspect: &str) -> (u32, u32) {
+    let mut parts = aspect.split(':').map(|p| p.parse::<f64>());
+    let ratio = match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(width)), Some(Ok(height)), None) if width > 0.0 && height > 0.0 => width / height,
+        _ => fail(
+            ErrorCode::InputInvalid,
+            &format!("Could not parse aspect ratio {}, expected W:H", aspect),
+        ),
+    };
+    // Encoders need even dimensions for yuv420p.
+    let even = |side: f64| (side / 2.0).round() as u32 * 2;
+    if ratio >= 1.0 {
+        (even(480.0 * ratio), 480)
+    } else {
+        (480, even(480.0 / ratio))
+    }
+}
+
+/// Output video (width, height), parsed from the video_size option (format: WxH)
+/// or derived from the aspect option, default: 640x480. Previews are half that size.
+pub fn video_size() -> (u32, u32) {
+    let (width, height) = match &CLI_OPTIONS.aspect {
+        Some(aspect) => aspect_video_size(aspect),
+        None => {
+            let size = CLI_OPTIONS
+                .video_size
+                .clone()
+                .unwrap_or("640x480".to_string());
+            let mut parts = size.split('x').map(|p| p.parse::<u32>());
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(Ok(width)), Some(Ok(height)), None) if width > 0 && height > 0 => {
+                    (width, height)
+                }
+                _ => fail(
+                    ErrorCode::InputInvalid,
+                    &format!("Could not parse video size {}, expected WxH", size),
+                ),
+            }
+        }
+    };
+    if CLI_OPTIONS.preview {
+        // Encoders need even dimensions for yuv420p.
+        ((width / 2).max(2) & !1, (height / 2).max(2) & !1)
+    } else {
+        (width, height)
+    }
+}
+
+/// Whether frames are padded rather than cropped to fit the video aspect ratio (aspect_fit option).
+pub fn pads_to_fit() -> bool {
+    match CLI_OPTIONS.aspect_fit.as_deref() {
+        None | Some("crop") => false,
+        Some("pad") => true,
+        Some(fit) => fail(
+            ErrorCode::InputInvalid,
+            &format!("Unknown aspect fit {}, expected crop or pad", fit),
+        ),
+    }
+}
+
+/// Height of the Google logo and copyright line at the bottom of Street View images.
+const WATERMARK_HEIGHT: u32 = 24;
+
+/// Pixels to crop from the bottom of each frame, parsed from the crop_bottom option.
+pub fn crop_bottom() -> u32 {
+    match CLI_OPTIONS.crop_bottom.as_deref() {
+        None => 0,
+        Some("auto") => WATERMARK_HEIGHT,
+        Some(pixels) => pixels.parse().unwrap_or_else(|_| {
+            fail(
+                ErrorCode::InputInvalid,
+                &format!(
+                    "Could not parse crop bottom {}, expected pixels or auto",
+                    pixels
+                ),
+            )
+        }),
+    }
+}
+
+/// Video filter to scale frames to the video size, cropping or padding to keep their aspect ratio.
+fn fit_filter() -> String {
+    let (width, height) = video_size();
+    let crop = match crop_bottom() {
+        0 => String::new(),
+        pixels => format!("crop=iw:ih-{}:0:0,", pixels),
+    };
+    if pads_to_fit() {
+        format!(
+            "{crop}scale={w}:{h}:force_original_aspect_ratio=decrease,pad={w}:{h}:(ow-iw)/2:(oh-ih)/2",
+            crop = crop,
+            w = width,
+            h = height
+        )
+    } else {
+        format!(
+            "{crop}scale={w}:{h}:force_original_aspect_ratio=increase,crop={w}:{h}",
+            crop = crop,
+            w = width,
+            h = height
+        )
+    }
+}
+
+/// Video filter chain applied to frames as they are encoded into the raw timelapse.
+fn frame_filters() -> String {
+    let mut filters = vec![fit_filter()];
+    if CLI_OPTIONS.deflicker {
+        // pm (power mean) is less sensitive than the default arithmetic mean to single outliers.
+        filters.push(format!(
+            "deflicker=size={}:mode=pm",
+            CLI_OPTIONS.deflicker_size.unwrap_or(5)
+        ));
+    }
+    filters.extend(crate::overlay::hud_filter());
+    filters.extend(crate::overlay::speed_filter());
+    filters.extend(crate::overlay::sensor_filter());
+    filters.extend(crate::overlay::street_names_filter());
+    filters.extend(crate::overlay::entering_labels_filter());
+    filters.extend(crate::overlay::dwell_labels_filter());
+    filters.extend(crate::overlay::attribution_filter());
+    filters.join(",")
+}
+
+/// Split a whitespace-separated list of extra ffmpeg arguments from the options.
+fn extra_args(args: &Option<String>) -> Vec<String> {
+    args.as_ref()
+        .map(|a| a.split_whitespace().map(|a| a.to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// Arguments to start every ffmpeg command with, applying to the first input.
+fn input_args() -> Vec<String> {
+    extra_args(&CLI_OPTIONS.ffmpeg_input_args)
+}
+
+/// How the encoder chooses the bitrate of the output.
+enum RateControl {
+    /// Constant quality given by the crf option.
+    Crf,
+    /// One pass of a two-pass encode targeting kbps.
+    TwoPass {
+        pass: u32,
+        kbps: u64,
+        log_file: String,
+    },
+}
+
+/// Length in seconds of the output video made from num_images frames.
+pub fn output_duration(num_images: usize) -> f64 {
+    if let Some(framerate) = crate::beats::beat_framerate() {
+        return num_images as f64 / framerate;
+    }
+    CLI_OPTIONS
+        .target_duration
+        .unwrap_or(num_images as f64 / fps() as f64)
+}
+
+/// Number of frames in the output video made from num_images frames with the minterp mode.
+fn output_frames(num_images: usize) -> f64 {
+    match minterp_mode() {
+        "skip" => num_images as f64,
+        "fast" => output_duration(num_images) * fps() as f64,
+        _ => output_duration(num_images) * (fps() * 3) as f64,
+    }
+}
+
+/// Rate at which source frames are shown, so num_images frames last output_duration seconds.
+pub fn input_framerate(num_images: usize) -> f64 {
+    num_images as f64 / output_duration(num_images)
+}
+
+/// Target video bitrate in kbps from the bitrate or target_size_mb options, if either is set.
+pub fn target_bitrate_kbps(duration_secs: f64) -> Option<u64> {
+    if let Some(kbps) = CLI_OPTIONS.bitrate {
+        return Some(kbps);
+    }
+    CLI_OPTIONS.target_size_mb.map(|size_mb| {
+        // Leave about 3% of the size for container overhead.
+        (size_mb * 8000.0 * 0.97 / duration_secs.max(1.0)) as u64
+    })
+}
+
+/// Whether the final output is encoded in two passes to a target bitrate.
+pub fn uses_target_bitrate() -> bool {
+    CLI_OPTIONS.bitrate.is_some() || CLI_OPTIONS.target_size_mb.is_some()
+}
+
+/// Output path for ffmpeg runs that only analyze the video and discard the result.
+fn null_output() -> &'static str {
+    if cfg!(windows) {
+        "NUL"
+    } else {
+        "/dev/null"
+    }
+}
+
+/// Arguments to encode the output video into out_filename, ending the argument list.
+fn output_args(out_filename: &str, rate: &RateControl) -> Vec<String> {
+    let codec = Codec::from_options();
+    let mut args = to_args(&["-c:v", codec.encoder()]);
+    match rate {
+        RateControl::Crf => {
+            let crf = CLI_OPTIONS.crf.unwrap_or_else(|| codec.default_crf());
+            args.extend(to_args(&["-crf", &crf.to_string()]));
+            // Constant quality mode needs -b:v 0 for vpx and aom.
+            if codec == Codec::Vp9 || codec == Codec::Av1 {
+                args.extend(to_args(&["-b:v", "0"]));
+            }
+        }
+        RateControl::TwoPass {
+            pass,
+            kbps,
+            log_file,
+        } => {
+            args.extend(to_args(&["-b:v", &format!("{}k", kbps)]));
+            // The x265 wrapper ignores -pass, so its passes are set through x265-params.
+            if codec == Codec::Hevc {
+                let params = format!("pass={}:stats={}", pass, log_file);
+                args.extend(to_args(&["-x265-params", &params]));
+            } else {
+                args.extend(to_args(&[
+                    "-pass",
+                    &pass.to_string(),
+                    "-passlogfile",
+                    log_file,
+                ]));
+            }
+        }
+    }
+    args.extend(to_args(&codec.args()));
+    args.extend(to_args(&["-pix_fmt", "yuv420p"]));
+    args.extend(extra_args(&CLI_OPTIONS.ffmpeg_output_args));
+    if let RateControl::TwoPass { pass: 1, .. } = rate {
+        // The first pass only collects statistics, so discard its output.
+        args.extend(to_args(&[
+            "-an",
+            "-f",
+            "null",
+            "-progress",
+            "pipe:1",
+            "-y",
+            null_output(),
+        ]));
+        return args;
+    }
+    // faststart moves the index to the front of mp4 files so playback can begin immediately.
+    if out_filename.ends_with(".mp4") || out_filename.ends_with(".mov") {
+        args.extend(to_args(&["-movflags", "faststart"]));
+    }
+    args.extend(to_args(&["-progress", "pipe:1", "-y", out_filename]));
+    args
+}
+
+fn to_args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| a.to_string()).collect()
+}
+
+type GetProgress = dyn Fn(usize) -> f64 + Sync;
+pub async fn ffmpeg<P: AsRef<Path>, S: AsRef<str>>(
+    working_dir: P,
+    get_progress: &GetProgress,
+    args: &[S],
+) {
+    let args = args.iter().map(|a| a.as_ref()).collect::<Vec<_>>();
+    let mut command = Command::new(ffmpeg_path());
+    let command = command
+        .args(&args)
         .current_dir(working_dir)
         .stdout(Stdio::piped());
-    // Print arguments list to stderr
-    eprintln!("ffmpeg {}", args.join(" "));
-    let mut child = command.spawn().expect("ffmpeg spawn failure");
+    debug!("ffmpeg {}", args.join(" "));
+    let mut child = command.spawn().unwrap_or_else(|e| {
+        fail(
+            ErrorCode::FfmpegMissing,
+            &format!("Could not run {}: {:?}", ffmpeg_path(), e),
+        )
+    });
+    let _guard = crate::cancel::track_child(child.id());
     let stdout = child.stdout.take().expect("ffmpeg stdout failure");
     let mut reader = tokio::io::BufReader::new(stdout).lines();
+    let mut reporter = ProgressReporter::frames();
     // Ensure the child process is spawned in the runtime so it can
     // make progress on its own while we await for any output.
-    let thread = tokio::spawn(async {
-        child.await.expect("child process encountered an error");
+    let thread = context::spawn(async {
+        let status = child.await.expect("child process encountered an error");
+        if !status.success() {
+            fail(
+                ErrorCode::EncodeFailed,
+                &format!("ffmpeg failed with exit code {:?}", status.code()),
+            );
+        }
     });
 
     while let Some(line) = reader.next_line().await.expect("ffmpeg readline failure") {
         if line.contains("frame=") {
             let frame =
                 str::parse::<usize>(&line["frame=".len()..]).expect("Could not parse frame");
-            progress(&format!("{:.1}% rendered", get_progress(frame)));
+            reporter.set_percent(frame, get_progress(frame));
         }
     }
     thread.await.expect("Failed to join ffmpeg thread");
 }
 
-pub async fn create_timelapse<P: AsRef<Path>>(image_dir: P, num_images: usize, out_filename: &str) {
-    // ffmpeg -framerate 30 -pattern_type glob -i "folder-with-photos/*.JPG" -s:v 1440x1080 -c:v libx264 -crf 25 -pix_fmt yuv420p my-timelapse.mp4
-    let pattern = if CLI_OPTIONS.optimizer.is_some() {
-        "%d.opt.jpg"
-    } else {
-        "%d.jpg"
+/// Run ffmpeg like ffmpeg(), writing each item of input to its stdin as it arrives.
+async fn ffmpeg_with_input<P: AsRef<Path>, S: AsRef<str>>(
+    working_dir: P,
+    get_progress: &GetProgress,
+    args: &[S],
+    input: impl Stream<Item = Vec<u8>>,
+) {
+    let args = args.iter().map(|a| a.as_ref()).collect::<Vec<_>>();
+    let mut command = Command::new(ffmpeg_path());
+    let command = command
+        .args(&args)
+        .current_dir(working_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped());
+    debug!("ffmpeg {}", args.join(" "));
+    let mut child = command.spawn().unwrap_or_else(|e| {
+        fail(
+            ErrorCode::FfmpegMissing,
+            &format!("Could not run {}: {:?}", ffmpeg_path(), e),
+        )
+    });
+    let _guard = crate::cancel::track_child(child.id());
+    let mut stdin = child.stdin.take().expect("ffmpeg stdin failure");
+    let stdout = child.stdout.take().expect("ffmpeg stdout failure");
+    let mut reader = tokio::io::BufReader::new(stdout).lines();
+    let mut reporter = ProgressReporter::frames();
+    let thread = context::spawn(async {
+        let status = child.await.expect("child process encountered an error");
+        if !status.success() {
+            fail(
+                ErrorCode::EncodeFailed,
+                &format!("ffmpeg failed with exit code {:?}", status.code()),
+            );
+        }
+    });
+    let write_input = async move {
+        futures::pin_mut!(input);
+        while let Some(bytes) = input.next().await {
+            stdin
+                .write_all(&bytes)
+                .await
+                .expect("Could not write to ffmpeg stdin");
+        }
+        // Dropping stdin closes it, which tells ffmpeg the input is over.
+    };
+    let read_progress = async {
+        while let Some(line) = reader.next_line().await.expect("ffmpeg readline failure") {
+            if line.contains("frame=") {
+                let frame =
+                    str::parse::<usize>(&line["frame=".len()..]).expect("Could not parse frame");
+                reporter.set_percent(frame, get_progress(frame));
+            }
+        }
+    };
+    futures::join!(write_input, read_progress);
+    thread.await.expect("Failed to join ffmpeg thread");
+}
+
+/// Run ffmpeg with args followed by the arguments to encode the final output video into
+/// out_filename. If a target bitrate is set, encode in two passes to hit it closely, using
+/// duration_secs (the length of the output video) to convert a target size into a bitrate.
+async fn encode_output<P: AsRef<Path>>(
+    working_dir: P,
+    get_progress: &GetProgress,
+    args: Vec<String>,
+    duration_secs: f64,
+    out_filename: &str,
+) {
+    let kbps = match target_bitrate_kbps(duration_secs) {
+        Some(kbps) => kbps,
+        None => {
+            let mut args = args;
+            args.extend(output_args(out_filename, &RateControl::Crf));
+            ffmpeg(working_dir, get_progress, &args).await;
+            return;
+        }
     };
+    let log_file = format!("{}-passlog", out_filename);
+    for pass in 1..=2 {
+        progress(&format!("Encoding pass {}/2 at {} kbps", pass, kbps));
+        let mut pass_args = args.clone();
+        pass_args.extend(output_args(
+            out_filename,
+            &RateControl::TwoPass {
+                pass,
+                kbps,
+                log_file: log_file.clone(),
+            },
+        ));
+        ffmpeg(working_dir.as_ref(), get_progress, &pass_args).await;
+    }
+}
+
+pub async fn create_timelapse<P: AsRef<Path>>(
+    image_dir: P,
+    num_images: usize,
+    pattern: &str,
+    out_filename: &str,
+) {
+    if uses_native_encoder() {
+        #[cfg(feature = "native-encode")]
+        crate::native_encode::encode_timelapse(
+            image_dir.as_ref(),
+            num_images,
+            pattern,
+            out_filename,
+        );
+        return;
+    }
+    if let Some(chunk_frames) = CLI_OPTIONS.encode_chunk_frames {
+        let n_chunks = num_images.div_ceil(chunk_frames.max(1));
+        if n_chunks > 1 && !CLI_OPTIONS.deflicker {
+            create_timelapse_chunked(image_dir, num_images, pattern, out_filename, n_chunks).await;
+            return;
+        }
+        if n_chunks > 1 {
+            warning("Encoding in one chunk, since deflicker averages over neighboring frames");
+        }
+    }
+    // ffmpeg -framerate 30 -pattern_type glob -i "folder-with-photos/*.JPG" -s:v 1440x1080 -c:v libx264 -crf 25 -pix_fmt yuv420p my-timelapse.mp4
+    // The minterp stages convert this rate to fps() (or a multiple) when resampling.
+    let framerate = format!("{:.4}", input_framerate(num_images));
+    let filter = frame_filters();
+    let mut args = input_args();
+    args.extend(to_args(&[
+        "-framerate",
+        &framerate,
+        "-pattern_type",
+        "sequence",
+        "-i",
+        pattern,
+        "-filter:v",
+        &filter,
+    ]));
+    args.extend(output_args(out_filename, &RateControl::Crf));
     ffmpeg(
         image_dir,
         &(move |frame| 100.0 * (frame as f64) / (num_images as f64)),
-        &[
-            "-framerate",
-            "24",
-            "-pattern_type",
-            "sequence",
-            "-i",
-            pattern,
-            "-s:v",
-            "640x480",
-            "-c:v",
-            "libx264",
-            "-crf",
-            "22",
-            "-pix_fmt",
-            "yuv420p",
-            "-preset",
-            "faster",
-            "-movflags",
-            "faststart",
-            "-progress",
-            "pipe:1",
-            "-y",
-            out_filename,
-        ],
+        &args,
     )
     .await;
 }
 
+/// Like create_timelapse, but split the frames into n_chunks runs of consecutive frames, encode
+/// them in parallel ffmpeg processes, up to one per core, and concatenate the chunks into
+/// out_filename without reencoding.
+async fn create_timelapse_chunked<P: AsRef<Path>>(
+    image_dir: P,
+    num_images: usize,
+    pattern: &str,
+    out_filename: &str,
+    n_chunks: usize,
+) {
+    let image_dir = image_dir.as_ref();
+    let framerate = format!("{:.4}", input_framerate(num_images));
+    let extension = Path::new(out_filename)
+        .extension()
+        .map_or(String::new(), |ext| format!(".{}", ext.to_string_lossy()));
+    let chunk_len = num_images.div_ceil(n_chunks);
+    let chunks = (0..n_chunks)
+        .map(|chunk| {
+            let start = chunk * chunk_len;
+            let len = chunk_len.min(num_images - start);
+            let chunk_filename = format!("{}-chunk{}{}", out_filename, chunk, extension);
+            (start, len, chunk_filename)
+        })
+        .collect::<Vec<_>>();
+    let jobs = rayon::current_num_threads().min(n_chunks);
+    progress(&format!(
+        "Encoding {} frames in {} chunks, {} at a time",
+        num_images, n_chunks, jobs
+    ));
+    // Frames done in each chunk, so progress covers all of them.
+    let done = std::sync::Arc::new(
+        (0..n_chunks)
+            .map(|_| std::sync::atomic::AtomicUsize::new(0))
+            .collect::<Vec<_>>(),
+    );
+    futures::stream::iter(chunks.clone().into_iter().enumerate())
+        .map(|(chunk, (start, len, chunk_filename))| {
+            let done = done.clone();
+            // Shift the timestamps of the chunk to its place in the whole video, so sendcmd
+            // overlays change text on the same frames, then start the chunk at zero.
+            let filter = format!(
+                "setpts=PTS+{}/(FRAME_RATE*TB),{},setpts=PTS-STARTPTS",
+                start,
+                frame_filters()
+            );
+            let mut args = input_args();
+            args.extend(to_args(&[
+                "-framerate",
+                &framerate,
+                "-pattern_type",
+                "sequence",
+                "-start_number",
+                &start.to_string(),
+                "-i",
+                pattern,
+                "-frames:v",
+                &len.to_string(),
+                "-filter:v",
+                &filter,
+            ]));
+            args.extend(output_args(&chunk_filename, &RateControl::Crf));
+            async move {
+                let get_progress = move |frame: usize| {
+                    done[chunk].store(frame, std::sync::atomic::Ordering::Relaxed);
+                    let total = done
+                        .iter()
+                        .map(|frames| frames.load(std::sync::atomic::Ordering::Relaxed))
+                        .sum::<usize>();
+                    100.0 * total as f64 / num_images as f64
+                };
+                ffmpeg(image_dir, &get_progress, &args).await;
+            }
+        })
+        .buffer_unordered(jobs)
+        .collect::<Vec<_>>()
+        .await;
+
+    // Paths in a concat list are relative to the list, which is in the same directory.
+    let list_filename = format!("{}-chunks.txt", out_filename);
+    let list = chunks
+        .iter()
+        .map(|(_, _, chunk_filename)| {
+            let name = Path::new(chunk_filename)
+                .file_name()
+                .map_or(String::new(), |name| name.to_string_lossy().to_string());
+            format!("file '{}'\n", name.replace('\'', "'\\''"))
+        })
+        .collect::<String>();
+    let list_path = image_dir.join(&list_filename);
+    std::fs::write(&list_path, list).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Io,
+            &format!("Could not write chunk list {:?}: {:?}", list_path, e),
+        )
+    });
+    let mut args = to_args(&[
+        "-f",
+        "concat",
+        "-safe",
+        "0",
+        "-i",
+        &list_filename,
+        "-c",
+        "copy",
+    ]);
+    if out_filename.ends_with(".mp4") || out_filename.ends_with(".mov") {
+        args.extend(to_args(&["-movflags", "faststart"]));
+    }
+    args.extend(to_args(&["-progress", "pipe:1", "-y", out_filename]));
+    ffmpeg(
+        image_dir,
+        &(move |frame| 100.0 * (frame as f64) / (num_images as f64)),
+        &args,
+    )
+    .await;
+    let _ = std::fs::remove_file(&list_path);
+    for (_, _, chunk_filename) in &chunks {
+        let _ = std::fs::remove_file(image_dir.join(chunk_filename));
+    }
+}
+
+/// Like create_timelapse, but encode the num_images JPEG frames of frames in order as they
+/// arrive, piped to ffmpeg's stdin, instead of reading the files of a finished sequence.
+pub async fn create_timelapse_from_stream<P: AsRef<Path>>(
+    image_dir: P,
+    num_images: usize,
+    frames: impl Stream<Item = Vec<u8>>,
+    out_filename: &str,
+) {
+    let framerate = format!("{:.4}", input_framerate(num_images));
+    let filter = frame_filters();
+    let mut args = input_args();
+    args.extend(to_args(&[
+        "-framerate",
+        &framerate,
+        "-f",
+        "image2pipe",
+        "-c:v",
+        "mjpeg",
+        "-i",
+        "-",
+        "-filter:v",
+        &filter,
+    ]));
+    args.extend(output_args(out_filename, &RateControl::Crf));
+    ffmpeg_with_input(
+        image_dir,
+        &(move |frame| 100.0 * (frame as f64) / (num_images as f64)),
+        &args,
+        frames,
+    )
+    .await;
+}
+
+/// Like create_timelapse, but read the frames from the files frames in order and pipe them to
+/// ffmpeg's stdin instead of having ffmpeg read a numbered file sequence.
+pub async fn create_timelapse_piped<P: AsRef<Path>>(
+    image_dir: P,
+    frames: &[std::path::PathBuf],
+    out_filename: &str,
+) {
+    let files = futures::stream::iter(frames.to_vec())
+        .map(|path| async move {
+            tokio::fs::read(&path).await.unwrap_or_else(|e| {
+                fail(
+                    ErrorCode::Io,
+                    &format!("Could not read frame {:?}: {:?}", path, e),
+                )
+            })
+        })
+        // Read a few frames ahead so ffmpeg doesn't wait on the disk.
+        .buffered(8);
+    create_timelapse_from_stream(image_dir, frames.len(), files, out_filename).await;
+}
+
 pub async fn blend_timelapse<P: AsRef<Path>>(
     image_dir: P,
     num_images: usize,
@@ -79,26 +991,357 @@ pub async fn blend_timelapse<P: AsRef<Path>>(
     out_filename: &str,
 ) {
     // ffmpeg -i streetwarp.mp4-original.mp4 -filter_complex "[0:v]minterpolate=fps=48.0,tblend=all_mode=average,framestep=2[out]" -map "[out]" -c:v libx264 -crf 17 -pix_fmt yuv420p -y -preset ultrafast -progress streetwarp-lapse24_blur.mp4
-    ffmpeg(
+    let filter = format!(
+        "[0:v]minterpolate=fps={},tblend=all_mode=average,framestep=2[out]",
+        fps() * 2
+    );
+    let mut args = input_args();
+    args.extend(to_args(&[
+        "-i",
+        original_filename,
+        "-filter_complex",
+        &filter,
+        "-map",
+        "[out]",
+    ]));
+    let total_frames = output_frames(num_images);
+    encode_output(
+        image_dir,
+        &(move |frame| 100.0 * (frame as f64) / total_frames),
+        args,
+        output_duration(num_images),
+        out_filename,
+    )
+    .await;
+}
+
+pub async fn minterp_timelapse<P: AsRef<Path>>(
+    image_dir: P,
+    num_images: usize,
+    original_filename: &str,
+    out_filename: &str,
+) {
+    // ffmpeg -i streetwarp-lapse24.mp4 -filter:v "minterpolate='mi_mode=mci:mc_mode=aobmc:vsbmc=1:fps=50'" -c:v libx264 -crf 17 -pix_fmt yuv420p -y -preset ultrafast streetwarp-lapse24_flow.mp4
+    let filter = format!(
+        "minterpolate='mi_mode=mci:mc_mode=aobmc:vsbmc=1:fps={}'",
+        fps() * 3
+    );
+    let mut args = input_args();
+    args.extend(to_args(&["-i", original_filename, "-filter:v", &filter]));
+    let total_frames = output_frames(num_images);
+    encode_output(
+        image_dir,
+        &(move |frame| 100.0 * (frame as f64) / total_frames),
+        args,
+        output_duration(num_images),
+        out_filename,
+    )
+    .await;
+}
+
+/// Interpolate the timelapse at original_filename with the minterp_cmd option's command, then
+/// encode its output into out_filename at the frame rate of the good minterp mode. The command
+/// gets the paths of the input video and of the video to write, and that frame rate.
+pub async fn external_minterp_timelapse<P: AsRef<Path>>(
+    image_dir: P,
+    num_images: usize,
+    original_filename: &str,
+    out_filename: &str,
+) {
+    let image_dir = image_dir.as_ref();
+    let minterp_cmd = CLI_OPTIONS
+        .minterp_cmd
+        .as_deref()
+        .expect("--minterp external without --minterp-cmd");
+    let interpolated_filename = format!("{}-interpolated.mp4", out_filename);
+    let target_fps = fps() * 3;
+    let child = crate::hooks::shell_command(minterp_cmd)
+        .arg(original_filename)
+        .arg(&interpolated_filename)
+        .arg(target_fps.to_string())
+        .current_dir(image_dir)
+        .spawn()
+        .unwrap_or_else(|e| {
+            fail(
+                ErrorCode::EncodeFailed,
+                &format!("Could not run minterp command {}: {:?}", minterp_cmd, e),
+            )
+        });
+    let _guard = crate::cancel::track_child(child.id());
+    let status = child.await.unwrap_or_else(|e| {
+        fail(
+            ErrorCode::EncodeFailed,
+            &format!("Could not run minterp command {}: {:?}", minterp_cmd, e),
+        )
+    });
+    if !status.success() || !image_dir.join(&interpolated_filename).is_file() {
+        fail(
+            ErrorCode::EncodeFailed,
+            &format!(
+                "Minterp command {} failed with exit code {:?} or wrote no {}",
+                minterp_cmd,
+                status.code(),
+                interpolated_filename
+            ),
+        );
+    }
+    // Tools interpolate by different factors, so resample to the rate of the other modes.
+    let filter = format!("fps={}", target_fps);
+    let mut args = input_args();
+    args.extend(to_args(&[
+        "-i",
+        &interpolated_filename,
+        "-filter:v",
+        &filter,
+    ]));
+    let total_frames = output_frames(num_images);
+    encode_output(
+        image_dir,
+        &(move |frame| 100.0 * (frame as f64) / total_frames),
+        args,
+        output_duration(num_images),
+        out_filename,
+    )
+    .await;
+}
+
+pub async fn stack_timelapses<P: AsRef<Path>>(
+    working_dir: P,
+    num_images: usize,
+    input_filenames: &[&str],
+    vertical: bool,
+    out_filename: &str,
+) {
+    // ffmpeg -i 2010.mp4 -i 2020.mp4 -filter_complex "[0:v][1:v]hstack=inputs=2[out]" -map "[out]" -c:v libx264 -crf 22 -pix_fmt yuv420p comparison.mp4
+    let filter = format!(
+        "{}{}=inputs={}:shortest=1[out]",
+        (0..input_filenames.len())
+            .map(|i| format!("[{}:v]", i))
+            .collect::<String>(),
+        if vertical { "vstack" } else { "hstack" },
+        input_filenames.len()
+    );
+    let mut args = vec![];
+    for filename in input_filenames {
+        args.push("-i");
+        args.push(filename);
+    }
+    args.extend_from_slice(&["-filter_complex", &filter, "-map", "[out]"]);
+    let args = input_args()
+        .into_iter()
+        .chain(to_args(&args))
+        .collect::<Vec<_>>();
+    let total_frames = output_frames(num_images);
+    encode_output(
+        working_dir,
+        &(move |frame| 100.0 * (frame as f64) / total_frames),
+        args,
+        output_duration(num_images),
+        out_filename,
+    )
+    .await;
+}
+
+/// Put the videos of labeled_filenames (label, filename) side by side, each at fps() and with
+/// its label in the top left corner if ffmpeg has the drawtext filter.
+pub async fn stack_labeled_timelapses<P: AsRef<Path>>(
+    working_dir: P,
+    num_images: usize,
+    labeled_filenames: &[(&str, &str)],
+    out_filename: &str,
+) {
+    // ffmpeg -i skip.mp4 -i fast.mp4 -filter_complex "[0:v]fps=24,drawtext=text='skip':...[v0];[1:v]fps=24,drawtext=text='fast':...[v1];[v0][v1]hstack=inputs=2:shortest=1[out]" -map "[out]" out.mp4
+    let labels = ffmpeg_capabilities().has_filter("drawtext");
+    let mut filter = String::new();
+    let mut args = vec![];
+    for (i, (label, filename)) in labeled_filenames.iter().enumerate() {
+        args.push("-i");
+        args.push(*filename);
+        filter.push_str(&format!("[{}:v]fps={}", i, fps()));
+        if labels {
+            filter.push_str(&format!(
+                ",drawtext=text='{}':x=10:y=10:fontsize=h/16:fontcolor=white:box=1:boxcolor=black@0.5:boxborderw=6",
+                label
+            ));
+        }
+        filter.push_str(&format!("[v{}];", i));
+    }
+    filter.push_str(&format!(
+        "{}hstack=inputs={}:shortest=1[out]",
+        (0..labeled_filenames.len())
+            .map(|i| format!("[v{}]", i))
+            .collect::<String>(),
+        labeled_filenames.len()
+    ));
+    args.extend_from_slice(&["-filter_complex", &filter, "-map", "[out]"]);
+    let args = input_args()
+        .into_iter()
+        .chain(to_args(&args))
+        .collect::<Vec<_>>();
+    let duration = output_duration(num_images);
+    let total_frames = duration * fps() as f64;
+    encode_output(
+        working_dir,
+        &(move |frame| 100.0 * (frame as f64) / total_frames),
+        args,
+        duration,
+        out_filename,
+    )
+    .await;
+}
+
+/// Re-encode original_filename into out_filename unchanged except for the output settings.
+/// Used to apply a target bitrate when minterp is skipped.
+pub async fn reencode_timelapse<P: AsRef<Path>>(
+    image_dir: P,
+    num_images: usize,
+    original_filename: &str,
+    out_filename: &str,
+) {
+    let mut args = input_args();
+    args.extend(to_args(&["-i", original_filename]));
+    encode_output(
         image_dir,
         &(move |frame| 100.0 * (frame as f64) / (num_images as f64)),
+        args,
+        output_duration(num_images),
+        out_filename,
+    )
+    .await;
+}
+
+/// Stabilize original_filename into out_filename with vid.stab: the first pass detects camera
+/// motion between frames into a transforms file, and the second pass smooths it out.
+pub async fn stabilize_timelapse<P: AsRef<Path>>(
+    image_dir: P,
+    num_images: usize,
+    original_filename: &str,
+    out_filename: &str,
+) {
+    // ffmpeg -i streetwarp-lapse.mp4-original.mp4 -vf vidstabdetect=result=transforms.trf -f null /dev/null
+    // ffmpeg -i streetwarp-lapse.mp4-original.mp4 -vf vidstabtransform=input=transforms.trf:smoothing=10,unsharp=5:5:0.8:3:3:0.4 stabilized.mp4
+    let transforms_file = format!("{}.trf", out_filename);
+    let detect_filter = format!("vidstabdetect=shakiness=8:result={}", transforms_file);
+    let mut args = input_args();
+    args.extend(to_args(&[
+        "-i",
+        original_filename,
+        "-filter:v",
+        &detect_filter,
+        "-f",
+        "null",
+        "-progress",
+        "pipe:1",
+        "-y",
+        null_output(),
+    ]));
+    ffmpeg(
+        image_dir.as_ref(),
+        &(move |frame| 50.0 * (frame as f64) / (num_images as f64)),
+        &args,
+    )
+    .await;
+    // Stabilizing zooms and shifts frames, so sharpen them again afterwards.
+    let transform_filter = format!(
+        "vidstabtransform=input={}:smoothing={},unsharp=5:5:0.8:3:3:0.4",
+        transforms_file,
+        CLI_OPTIONS.stabilize_smoothing.unwrap_or(10)
+    );
+    let mut args = input_args();
+    args.extend(to_args(&[
+        "-i",
+        original_filename,
+        "-filter:v",
+        &transform_filter,
+    ]));
+    args.extend(output_args(out_filename, &RateControl::Crf));
+    ffmpeg(
+        image_dir.as_ref(),
+        &(move |frame| 50.0 + 50.0 * (frame as f64) / (num_images as f64)),
+        &args,
+    )
+    .await;
+}
+
+/// Put a title card before and an end card after the video body_filename (of num_images frames,
+/// with the given (width, height)) and encode the result into out_filename.
+/// The card texts must already be written into image_dir.
+pub async fn add_title_cards<P: AsRef<Path>>(
+    image_dir: P,
+    num_images: usize,
+    size: (u32, u32),
+    body_filename: &str,
+    out_filename: &str,
+) {
+    // ffmpeg -i body.mp4 -f lavfi -i color=c=black:s=640x480:r=72:d=3 -f lavfi -i color=... -filter_complex "[1:v]drawtext=...[intro];[0:v]fps=72[body];[2:v]drawtext=...[outro];[intro][body][outro]concat=n=3:v=1:a=0[out]" -map "[out]" out.mp4
+    let card_duration = CLI_OPTIONS.card_duration.unwrap_or(3.0);
+    // Match the frame rate of the body so the cards don't change the output frame rate.
+    let frame_rate = output_frames(num_images) / output_duration(num_images);
+    let card_source = format!(
+        "color=c=black:s={}x{}:r={:.4}:d={}",
+        size.0, size.1, frame_rate, card_duration
+    );
+    let filter = format!(
+        "[1:v]{},setsar=1[intro];[0:v]fps={:.4},setsar=1[body];[2:v]{},setsar=1[outro];\
+         [intro][body][outro]concat=n=3:v=1:a=0[out]",
+        crate::overlay::card_filter(crate::overlay::TITLE_CARD_FILE),
+        frame_rate,
+        crate::overlay::card_filter(crate::overlay::END_CARD_FILE)
+    );
+    let mut args = input_args();
+    args.extend(to_args(&[
+        "-i",
+        body_filename,
+        "-f",
+        "lavfi",
+        "-i",
+        &card_source,
+        "-f",
+        "lavfi",
+        "-i",
+        &card_source,
+        "-filter_complex",
+        &filter,
+        "-map",
+        "[out]",
+    ]));
+    let total_frames = output_frames(num_images) + 2.0 * card_duration * frame_rate;
+    encode_output(
+        image_dir,
+        &(move |frame| 100.0 * (frame as f64) / total_frames),
+        args,
+        output_duration(num_images) + 2.0 * card_duration,
+        out_filename,
+    )
+    .await;
+}
+
+/// Convert the video at video_filename (lasting duration_secs) into a looping animated GIF at
+/// out_filename, with a palette generated from the video itself.
+pub async fn create_gif<P: AsRef<Path>>(
+    working_dir: P,
+    video_filename: &str,
+    duration_secs: f64,
+    out_filename: &str,
+) {
+    // ffmpeg -i out.gif.mp4 -filter_complex "fps=10,scale=480:-2:flags=lanczos,split[a][b];[a]palettegen[p];[b][p]paletteuse" -loop 0 out.gif
+    let fps = CLI_OPTIONS.gif_fps.unwrap_or(10);
+    let width = CLI_OPTIONS.gif_width.unwrap_or(480);
+    let filter = format!(
+        "fps={},scale={}:-2:flags=lanczos,split[a][b];[a]palettegen[p];[b][p]paletteuse",
+        fps, width
+    );
+    let total_frames = (duration_secs * fps as f64).max(1.0);
+    ffmpeg(
+        working_dir,
+        &(move |frame| 100.0 * (frame as f64) / total_frames),
         &[
             "-i",
-            original_filename,
+            video_filename,
             "-filter_complex",
-            "[0:v]minterpolate=fps=48,tblend=all_mode=average,framestep=2[out]",
-            "-map",
-            "[out]",
-            "-c:v",
-            "libx264",
-            "-crf",
-            "22",
-            "-pix_fmt",
-            "yuv420p",
-            "-preset",
-            "faster",
-            "-movflags",
-            "faststart",
+            &filter,
+            "-loop",
+            "0",
             "-progress",
             "pipe:1",
             "-y",
@@ -108,31 +1351,26 @@ pub async fn blend_timelapse<P: AsRef<Path>>(
     .await;
 }
 
-pub async fn minterp_timelapse<P: AsRef<Path>>(
-    image_dir: P,
-    num_images: usize,
-    original_filename: &str,
+/// Save the frame of the video at video_filename shown time_secs into it as the image out_filename.
+pub async fn extract_frame<P: AsRef<Path>>(
+    working_dir: P,
+    video_filename: &str,
+    time_secs: f64,
     out_filename: &str,
 ) {
-    // ffmpeg -i streetwarp-lapse24.mp4 -filter:v "minterpolate='mi_mode=mci:mc_mode=aobmc:vsbmc=1:fps=50'" -c:v libx264 -crf 17 -pix_fmt yuv420p -y -preset ultrafast streetwarp-lapse24_flow.mp4
+    // ffmpeg -ss 12.5 -i out.mp4 -frames:v 1 -q:v 2 out.jpg
     ffmpeg(
-        image_dir,
-        &(move |frame| 33.3 * (frame as f64) / (num_images as f64)),
+        working_dir,
+        &(|_| 100.0),
         &[
+            "-ss",
+            &format!("{:.3}", time_secs),
             "-i",
-            original_filename,
-            "-filter:v",
-            "minterpolate='mi_mode=mci:mc_mode=aobmc:vsbmc=1:fps=72'",
-            "-c:v",
-            "libx264",
-            "-crf",
-            "22",
-            "-pix_fmt",
-            "yuv420p",
-            "-preset",
-            "faster",
-            "-movflags",
-            "faststart",
+            video_filename,
+            "-frames:v",
+            "1",
+            "-q:v",
+            "2",
             "-progress",
             "pipe:1",
             "-y",
@@ -141,3 +1379,195 @@ pub async fn minterp_timelapse<P: AsRef<Path>>(
     )
     .await;
 }
+
+/// Rewrite video_filename in place with extra input arguments (e.g. -i subs.srt) and output
+/// arguments (e.g. -map options), copying the existing streams without re-encoding.
+async fn remux<P: AsRef<Path>>(
+    working_dir: P,
+    video_filename: &str,
+    input_args: &[&str],
+    output_args: &[&str],
+) {
+    let extension = Path::new(video_filename)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("mp4");
+    let muxed_filename = format!("{}-remuxed.{}", video_filename, extension);
+    let mut args = to_args(&["-i", video_filename]);
+    args.extend(to_args(input_args));
+    args.extend(to_args(&["-map", "0", "-c", "copy"]));
+    args.extend(to_args(output_args));
+    args.extend(to_args(&["-progress", "pipe:1", "-y", &muxed_filename]));
+    ffmpeg(working_dir.as_ref(), &(|_| 100.0), &args).await;
+    std::fs::rename(
+        working_dir.as_ref().join(&muxed_filename),
+        working_dir.as_ref().join(video_filename),
+    )
+    .expect("Could not replace video with remuxed video");
+}
+
+/// Subtitle codec the container of video_filename supports
+/// (mov_text for mp4/mov, webvtt for webm, otherwise srt).
+fn subtitle_codec(video_filename: &str) -> &'static str {
+    if video_filename.ends_with(".mp4") || video_filename.ends_with(".mov") {
+        "mov_text"
+    } else if video_filename.ends_with(".webm") {
+        "webvtt"
+    } else {
+        "srt"
+    }
+}
+
+/// Mux the subtitles in srt_filename into the video at video_filename as a soft subtitle track.
+pub async fn embed_subtitles<P: AsRef<Path>>(
+    working_dir: P,
+    video_filename: &str,
+    srt_filename: &str,
+) {
+    // ffmpeg -i out.mp4 -i out.mp4.srt -map 0 -c copy -map 1 -c:s mov_text out.mp4-remuxed.mp4
+    remux(
+        working_dir,
+        video_filename,
+        &["-i", srt_filename],
+        &["-map", "1", "-c:s", subtitle_codec(video_filename)],
+    )
+    .await;
+}
+
+/// Mux the telemetry in srt_filename into the video at video_filename as a subtitle track
+/// named GPS, after subtitle_streams existing subtitle tracks.
+pub async fn embed_gps_subtitles<P: AsRef<Path>>(
+    working_dir: P,
+    video_filename: &str,
+    srt_filename: &str,
+    subtitle_streams: usize,
+) {
+    let stream = format!("s:s:{}", subtitle_streams);
+    remux(
+        working_dir,
+        video_filename,
+        &["-i", srt_filename],
+        &[
+            "-map",
+            "1",
+            "-c:s",
+            subtitle_codec(video_filename),
+            &format!("-metadata:{}", stream),
+            "handler_name=GPS",
+            &format!("-metadata:{}", stream),
+            "title=GPS telemetry",
+            &format!("-disposition:{}", stream),
+            "0",
+        ],
+    )
+    .await;
+}
+
+/// Geotag the video at video_filename with an ISO 6709 location such as +48.8583+002.2945/,
+/// which the mp4 and mov muxers write as a ©xyz atom.
+pub async fn add_location<P: AsRef<Path>>(working_dir: P, video_filename: &str, location: &str) {
+    remux(
+        working_dir,
+        video_filename,
+        &[],
+        &[
+            "-metadata",
+            &format!("location={}", location),
+            "-metadata",
+            &format!("location-eng={}", location),
+        ],
+    )
+    .await;
+}
+
+/// Audio codec for the container of video_filename.
+fn audio_codec(video_filename: &str) -> &'static str {
+    if video_filename.ends_with(".webm") {
+        "libopus"
+    } else {
+        "aac"
+    }
+}
+
+/// Add the audio file at audio_path, starting start_secs into it, to the video at video_filename
+/// (lasting duration_secs) as its soundtrack, looping or trimming it to the video length and
+/// fading out over fade_secs. With a loudness target (LUFS), the audio is normalized to it first.
+pub async fn add_audio<P: AsRef<Path>>(
+    working_dir: P,
+    video_filename: &str,
+    audio_path: &Path,
+    start_secs: f64,
+    duration_secs: f64,
+    fade_secs: f64,
+    loudness: Option<f64>,
+) {
+    // ffmpeg -i out.mp4 -ss 0.2 -stream_loop -1 -i music.mp3 -map 0 -c copy -map 1:a:0 -c:a aac -af afade=t=out:st=57:d=3 -t 60 out.mp4-remuxed.mp4
+    let audio_path = audio_path.to_string_lossy();
+    let fade_start = (duration_secs - fade_secs).max(0.0);
+    let mut filter = format!("afade=t=out:st={:.3}:d={:.3}", fade_start, fade_secs);
+    if let Some(lufs) = loudness {
+        // loudnorm upsamples to 192 kHz, so the output rate is set back to 48 kHz below.
+        filter = format!("loudnorm=I={}:TP=-1.5:LRA=11,{}", lufs, filter);
+    }
+    remux(
+        working_dir,
+        video_filename,
+        &[
+            "-ss",
+            &format!("{:.3}", start_secs),
+            "-stream_loop",
+            "-1",
+            "-i",
+            &audio_path,
+        ],
+        &[
+            "-map",
+            "1:a:0",
+            "-c:a",
+            audio_codec(video_filename),
+            "-b:a",
+            "192k",
+            "-af",
+            &filter,
+            "-ar",
+            "48000",
+            "-t",
+            &format!("{:.3}", duration_secs),
+        ],
+    )
+    .await;
+}
+
+/// Add a silent stereo opus audio track to the video at video_filename, for sites that expect
+/// WebM videos to have audio.
+pub async fn add_silent_audio<P: AsRef<Path>>(working_dir: P, video_filename: &str) {
+    // ffmpeg -i out.webm -f lavfi -i anullsrc=... -map 0 -c copy -map 1 -c:a libopus -shortest out.webm-remuxed.webm
+    remux(
+        working_dir,
+        video_filename,
+        &[
+            "-f",
+            "lavfi",
+            "-i",
+            "anullsrc=channel_layout=stereo:sample_rate=48000",
+        ],
+        &["-map", "1", "-c:a", "libopus", "-b:a", "32k", "-shortest"],
+    )
+    .await;
+}
+
+/// Add the chapters of an ffmetadata file to the video at video_filename.
+pub async fn add_chapters<P: AsRef<Path>>(
+    working_dir: P,
+    video_filename: &str,
+    metadata_filename: &str,
+) {
+    // ffmpeg -i out.mp4 -i chapters.txt -map 0 -c copy -map_chapters 1 out.mp4-remuxed.mp4
+    remux(
+        working_dir,
+        video_filename,
+        &["-i", metadata_filename],
+        &["-map_chapters", "1"],
+    )
+    .await;
+}
@@ -0,0 +1,181 @@
+use std::convert::TryInto;
+
+use crate::GPXPoint;
+
+/// A field of a FIT definition message: (field number, size in bytes).
+type FitField = (u8, usize);
+
+/// Layout of the data messages of one local message type.
+#[derive(Clone, Default)]
+struct FitDefinition {
+    global_number: u16,
+    big_endian: bool,
+    fields: Vec<FitField>,
+    developer_size: usize,
+}
+
+/// Global message number of FIT record messages, the per-second activity samples.
+const FIT_RECORD_MESSAGE: u16 = 20;
+/// Seconds from the Unix epoch to the FIT epoch, 1989-12-31T00:00:00Z.
+const FIT_EPOCH_SECS: f64 = 631065600.0;
+/// Degrees per semicircle, the unit of FIT positions.
+const DEGREES_PER_SEMICIRCLE: f64 = 180.0 / 2147483648.0;
+
+/// Field numbers of record messages.
+const POSITION_LAT: u8 = 0;
+const POSITION_LONG: u8 = 1;
+const ALTITUDE: u8 = 2;
+const ENHANCED_ALTITUDE: u8 = 78;
+const TIMESTAMP: u8 = 253;
+
+/// Decode an unsigned little or big endian integer, or None if it is the FIT invalid value
+/// (all bits set).
+fn fit_uint(bytes: &[u8], big_endian: bool) -> Option<u64> {
+    let push_byte = |value: u64, &byte: &u8| value << 8 | byte as u64;
+    let value = if big_endian {
+        bytes.iter().fold(0, push_byte)
+    } else {
+        bytes.iter().rev().fold(0, push_byte)
+    };
+    let invalid = if bytes.len() >= 8 {
+        u64::MAX
+    } else {
+        (1u64 << (bytes.len() * 8)) - 1
+    };
+    Some(value).filter(|&v| v != invalid)
+}
+
+/// A record message of a FIT file, with the bytes of each of its fields.
+pub struct FitRecord<'a> {
+    big_endian: bool,
+    fields: Vec<(u8, &'a [u8])>,
+}
+
+impl<'a> FitRecord<'a> {
+    fn bytes(&self, number: u8) -> Option<&'a [u8]> {
+        self.fields
+            .iter()
+            .find(|(field, _)| *field == number)
+            .map(|(_, bytes)| *bytes)
+    }
+
+    /// Value of the unsigned integer field number, or None if it is missing or invalid.
+    pub fn uint(&self, number: u8) -> Option<u64> {
+        fit_uint(self.bytes(number)?, self.big_endian)
+    }
+
+    /// Value of the 32 bit signed integer field number, or None if it is missing or invalid.
+    fn sint32(&self, number: u8) -> Option<i32> {
+        let bytes: [u8; 4] = self.bytes(number)?.try_into().ok()?;
+        let value = if self.big_endian {
+            i32::from_be_bytes(bytes)
+        } else {
+            i32::from_le_bytes(bytes)
+        };
+        Some(value).filter(|&v| v != i32::MAX)
+    }
+}
+
+/// The record messages of the FIT file data, or None if it is not a FIT file.
+/// Only the parts of the format needed for records are decoded; other messages are skipped.
+pub fn read_records(data: &[u8]) -> Option<Vec<FitRecord<'_>>> {
+    let header_size = *data.first()? as usize;
+    if data.len() < 12 || &data[8..12] != b".FIT" {
+        return None;
+    }
+    let data_size = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+    let end = (header_size + data_size).min(data.len());
+    let mut definitions = vec![FitDefinition::default(); 16];
+    let mut records = vec![];
+    let mut pos = header_size;
+    while pos < end {
+        let header = data[pos];
+        pos += 1;
+        let (local_type, is_definition) = if header & 0x80 != 0 {
+            // Compressed timestamp header: always a data message.
+            ((header >> 5 & 0x03) as usize, false)
+        } else {
+            ((header & 0x0f) as usize, header & 0x40 != 0)
+        };
+        if is_definition {
+            if pos + 5 > end {
+                break;
+            }
+            let big_endian = data[pos + 1] == 1;
+            let number_bytes = [data[pos + 2], data[pos + 3]];
+            let global_number = if big_endian {
+                u16::from_be_bytes(number_bytes)
+            } else {
+                u16::from_le_bytes(number_bytes)
+            };
+            let field_count = data[pos + 4] as usize;
+            pos += 5;
+            let fields = (0..field_count)
+                .filter_map(|i| data.get(pos + 3 * i..pos + 3 * i + 2))
+                .map(|field| (field[0], field[1] as usize))
+                .collect::<Vec<_>>();
+            pos += 3 * field_count;
+            let mut developer_size = 0;
+            if header & 0x20 != 0 && pos < end {
+                let developer_count = data[pos] as usize;
+                developer_size = (0..developer_count)
+                    .filter_map(|i| data.get(pos + 1 + 3 * i + 1))
+                    .map(|&size| size as usize)
+                    .sum();
+                pos += 1 + 3 * developer_count;
+            }
+            definitions[local_type] = FitDefinition {
+                global_number,
+                big_endian,
+                fields,
+                developer_size,
+            };
+            continue;
+        }
+        let definition = &definitions[local_type];
+        let mut record = FitRecord {
+            big_endian: definition.big_endian,
+            fields: vec![],
+        };
+        for &(number, size) in &definition.fields {
+            let bytes = match data.get(pos..pos + size) {
+                Some(bytes) => bytes,
+                None => break,
+            };
+            pos += size;
+            record.fields.push((number, bytes));
+        }
+        pos += definition.developer_size;
+        if definition.global_number == FIT_RECORD_MESSAGE {
+            records.push(record);
+        }
+    }
+    Some(records)
+}
+
+/// The track of the FIT file data: the location, elevation and time of each record with a
+/// position, or None if it is not a FIT file.
+pub fn read_track(data: &[u8]) -> Option<Vec<GPXPoint>> {
+    let points = read_records(data)?
+        .iter()
+        .filter_map(|record| {
+            let lat = record.sint32(POSITION_LAT)? as f64 * DEGREES_PER_SEMICIRCLE;
+            let lng = record.sint32(POSITION_LONG)? as f64 * DEGREES_PER_SEMICIRCLE;
+            // Altitudes are stored in units of 0.2 meters from 500 meters below sea level.
+            let ele = record
+                .uint(ENHANCED_ALTITUDE)
+                .or_else(|| record.uint(ALTITUDE))
+                .map(|altitude| altitude as f64 / 5.0 - 500.0);
+            let time = record
+                .uint(TIMESTAMP)
+                .map(|timestamp| timestamp as f64 + FIT_EPOCH_SECS);
+            Some(GPXPoint {
+                lat,
+                lng,
+                ele,
+                time,
+            })
+        })
+        .collect();
+    Some(points)
+}
@@ -0,0 +1,87 @@
+use std::path::PathBuf;
+
+use reqwest::{RequestBuilder, StatusCode};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::errors::{fail, ErrorCode};
+use crate::options::CLI_OPTIONS;
+
+/// Query parameters that identify the caller rather than the request, left out of fixture keys
+/// so responses recorded with one API key replay with any other, or none.
+const CALLER_PARAMS: &[&str] = &["key", "signature"];
+
+/// Method and URL of request without the CALLER_PARAMS, which identify its response.
+fn describe(request: RequestBuilder) -> String {
+    let request = request
+        .build()
+        .unwrap_or_else(|e| panic!("Could not build request: {:?}", e));
+    let mut url = request.url().clone();
+    let query = url
+        .query_pairs()
+        .filter(|(name, _)| !CALLER_PARAMS.contains(&name.as_ref()))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+    if query.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(query);
+    }
+    format!("{} {}", request.method(), url)
+}
+
+/// Paths of the status and body files of the response to the request described by description
+/// in dir.
+fn fixture_paths(dir: &str, description: &str) -> (PathBuf, PathBuf) {
+    let key = hex::encode(Sha256::digest(description.as_bytes()));
+    let dir = PathBuf::from(dir);
+    (
+        dir.join(format!("{}.json", key)),
+        dir.join(format!("{}.body", key)),
+    )
+}
+
+/// The recorded response to request if the replay option is set, None otherwise.
+/// Fails if the replay directory has no response for it.
+pub fn replay(request: RequestBuilder) -> Option<(StatusCode, Vec<u8>)> {
+    let dir = CLI_OPTIONS.replay.as_deref()?;
+    let description = describe(request);
+    let (status_path, body_path) = fixture_paths(dir, &description);
+    let recorded = std::fs::read(&status_path)
+        .ok()
+        .and_then(|status| serde_json::from_slice::<serde_json::Value>(&status).ok())
+        .and_then(|status| status["status"].as_u64())
+        .and_then(|status| StatusCode::from_u16(status as u16).ok())
+        .zip(std::fs::read(&body_path).ok());
+    match recorded {
+        Some(response) => Some(response),
+        None => fail(
+            ErrorCode::InputInvalid,
+            &format!("No recorded response in {} for {}", dir, description),
+        ),
+    }
+}
+
+/// Save the response with status and body to request into the record option's directory,
+/// if it is set.
+pub fn record(request: RequestBuilder, status: StatusCode, body: &[u8]) {
+    let dir = match CLI_OPTIONS.record.as_deref() {
+        Some(dir) => dir,
+        None => return,
+    };
+    let description = describe(request);
+    let (status_path, body_path) = fixture_paths(dir, &description);
+    let status = json!({
+        "request": description,
+        "status": status.as_u16(),
+    });
+    std::fs::create_dir_all(dir)
+        .and_then(|_| std::fs::write(&body_path, body))
+        .and_then(|_| std::fs::write(&status_path, status.to_string()))
+        .unwrap_or_else(|e| {
+            fail(
+                ErrorCode::Io,
+                &format!("Could not record response into {}: {:?}", dir, e),
+            )
+        });
+}
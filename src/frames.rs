@@ -0,0 +1,158 @@
+use std::path::Path;
+
+use rayon::prelude::*;
+use serde_json::json;
+
+use crate::errors::{fail, ErrorCode};
+use crate::ffmpeg::{ffmpeg, input_framerate};
+use crate::options::CLI_OPTIONS;
+use crate::progress::progress;
+use crate::SerializablePointBearing;
+
+/// Subdirectory of the output directory that frames are exported into.
+pub const FRAMES_DIR: &str = "frames";
+const MANIFEST_FILE: &str = "manifest.json";
+/// Directory in the output directory holding the frames renumbered densely for ffmpeg to convert.
+const WEBP_WORK_DIR: &str = "frames-webp";
+
+/// Image format of exported frames, given by the frame_format option (jpg|png|webp, default: jpg).
+fn frame_format() -> &'static str {
+    match CLI_OPTIONS.frame_format.as_deref().unwrap_or("jpg") {
+        "jpg" => "jpg",
+        "png" => "png",
+        "webp" => "webp",
+        other => fail(
+            ErrorCode::InputInvalid,
+            &format!("Unknown frame format {}, expected jpg, png or webp", other),
+        ),
+    }
+}
+
+/// Write the frames matching pattern (e.g. %d.opt.jpg) in image_dir into the frames directory
+/// as 0.jpg, 1.jpg, ... (or png/webp), converting losslessly for png and webp.
+/// Return the pattern of the exported frames, relative to the frames directory.
+async fn write_frames(image_dir: &Path, pattern: &str, n_points: usize) -> String {
+    let format = frame_format();
+    let frames_pattern = format!("%d.{}", format);
+    let frames_dir = image_dir.join(FRAMES_DIR);
+    let source = |index: usize| image_dir.join(pattern.replace("%d", &index.to_string()));
+    let target = |index: usize| frames_dir.join(frames_pattern.replace("%d", &index.to_string()));
+    match format {
+        "webp" => {
+            // The image sequence demuxer stops at the first missing frame, so link the frames
+            // that exist into a dense sequence and rename the converted frames back afterwards.
+            let existing = (0..n_points)
+                .filter(|&index| source(index).exists())
+                .collect::<Vec<_>>();
+            let work_dir = image_dir.join(WEBP_WORK_DIR);
+            let _ = std::fs::remove_dir_all(&work_dir);
+            std::fs::create_dir_all(&work_dir).expect("Could not create webp work directory");
+            for (to, &from) in existing.iter().enumerate() {
+                let link = work_dir.join(format!("{}.jpg", to));
+                if std::fs::hard_link(source(from), &link).is_err() {
+                    std::fs::copy(source(from), &link).unwrap_or_else(|e| {
+                        fail(
+                            ErrorCode::Io,
+                            &format!("Could not copy frame {}: {:?}", from, e),
+                        )
+                    });
+                }
+            }
+            let n_existing = existing.len();
+            // ffmpeg -start_number 0 -i frames-webp/%d.jpg -c:v libwebp -lossless 1 -start_number 0 frames-webp/%d.webp
+            let input = format!("{}/%d.jpg", WEBP_WORK_DIR);
+            let output = format!("{}/%d.webp", WEBP_WORK_DIR);
+            ffmpeg(
+                image_dir,
+                &(move |frame| 100.0 * (frame as f64) / (n_existing.max(1) as f64)),
+                &[
+                    "-start_number",
+                    "0",
+                    "-i",
+                    &input,
+                    "-c:v",
+                    "libwebp",
+                    "-lossless",
+                    "1",
+                    "-start_number",
+                    "0",
+                    "-progress",
+                    "pipe:1",
+                    "-y",
+                    &output,
+                ],
+            )
+            .await;
+            for (from, &to) in existing.iter().enumerate() {
+                let _ = std::fs::rename(work_dir.join(format!("{}.webp", from)), target(to));
+            }
+            let _ = std::fs::remove_dir_all(&work_dir);
+        }
+        "png" => {
+            (0..n_points).into_par_iter().for_each(|index| {
+                if let Ok(img) = image::open(source(index)) {
+                    img.save(target(index)).unwrap_or_else(|e| {
+                        fail(
+                            ErrorCode::Io,
+                            &format!("Could not write frame {}: {:?}", index, e),
+                        )
+                    });
+                }
+            });
+        }
+        _ => {
+            for index in 0..n_points {
+                // Frames that failed to download are left out, as in the video.
+                let _ = std::fs::copy(source(index), target(index));
+            }
+        }
+    }
+    frames_pattern
+}
+
+/// Export the final frames of the points into the frames directory of image_dir instead of
+/// encoding a video, along with a manifest.json listing each frame's file and point.
+/// Return the paths of the exported files relative to image_dir.
+pub async fn export_frames(
+    image_dir: &Path,
+    pattern: &str,
+    points: &[SerializablePointBearing],
+) -> Vec<String> {
+    let frames_dir = image_dir.join(FRAMES_DIR);
+    std::fs::create_dir_all(&frames_dir).expect("Could not create frames directory");
+    let frames_pattern = write_frames(image_dir, pattern, points.len()).await;
+    let names = (0..points.len())
+        .map(|index| frames_pattern.replace("%d", &index.to_string()))
+        .collect::<Vec<_>>();
+    #[cfg(feature = "scripting")]
+    let names = match crate::scripting::Script::load() {
+        Some(script) => script.rename_frames(&frames_dir, &frames_pattern, points),
+        None => names,
+    };
+    let frames = names
+        .iter()
+        .zip(points)
+        .filter(|(name, _)| frames_dir.join(name).exists())
+        .map(|(name, point)| json!({ "file": name, "point": point }))
+        .collect::<Vec<_>>();
+    let manifest = json!({
+        "framerate": input_framerate(points.len()),
+        "frames": frames,
+    });
+    std::fs::write(
+        frames_dir.join(MANIFEST_FILE),
+        serde_json::to_string_pretty(&manifest).unwrap(),
+    )
+    .expect("Could not write frame manifest");
+    progress(&format!(
+        "Exported {} frames to {:?}",
+        frames.len(),
+        frames_dir
+    ));
+    names
+        .iter()
+        .map(|name| name.as_str())
+        .chain(std::iter::once(MANIFEST_FILE))
+        .map(|name| format!("{}/{}", FRAMES_DIR, name))
+        .collect()
+}
@@ -0,0 +1,173 @@
+use futures::{stream, StreamExt};
+use reqwest::Client;
+use serde_json::Value;
+
+use crate::deterministic::BufferCompletions;
+use crate::errors::{fail, ErrorCode};
+use crate::http;
+use crate::options::CLI_OPTIONS;
+use crate::progress::{error, warning, ProgressReporter, Unit};
+use crate::throttle;
+use crate::SerializablePointBearing;
+
+/// What a reverse geocoder knows about a location.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Place {
+    pub street: Option<String>,
+    pub locality: Option<String>,
+}
+
+/// Take the long name of the first address component with the given type from a Google
+/// geocoding result.
+fn google_component(result: &Value, kind: &str) -> Option<String> {
+    result["address_components"]
+        .as_array()?
+        .iter()
+        .find(|c| {
+            c["types"]
+                .as_array()
+                .is_some_and(|types| types.iter().any(|t| t == kind))
+        })
+        .and_then(|c| c["long_name"].as_str())
+        .map(|name| name.to_string())
+}
+
+/// Parse a response of the Google geocoding API, preferring the most specific result.
+fn parse_google_response(body: &Value) -> Place {
+    let results = body["results"].as_array().cloned().unwrap_or_default();
+    let find = |kinds: &[&str]| {
+        kinds
+            .iter()
+            .find_map(|kind| results.iter().find_map(|r| google_component(r, kind)))
+    };
+    Place {
+        street: find(&["route"]),
+        locality: find(&["locality", "postal_town", "administrative_area_level_3"]),
+    }
+}
+
+/// Parse a response of the Nominatim reverse API.
+fn parse_nominatim_response(body: &Value) -> Place {
+    let address = &body["address"];
+    let first = |keys: &[&str]| {
+        keys.iter()
+            .find_map(|key| address[*key].as_str())
+            .map(|name| name.to_string())
+    };
+    Place {
+        street: first(&["road", "pedestrian", "path", "cycleway"]),
+        locality: first(&["city", "town", "village", "hamlet", "municipality"]),
+    }
+}
+
+/// Reverse geocode one location with the geocoder option (google|nominatim, default: google).
+async fn reverse_geocode(client: &Client, lat: f64, lng: f64) -> Place {
+    let geocoder = CLI_OPTIONS.geocoder.as_deref().unwrap_or("google");
+    let request = || match geocoder {
+        "google" => client.get(&format!(
+            "https://maps.googleapis.com/maps/api/geocode/json?latlng={},{}&key={}",
+            lat,
+            lng,
+            CLI_OPTIONS.api_key()
+        )),
+        // Nominatim's usage policy asks for an identifying user agent.
+        "nominatim" => client
+            .get(&format!(
+                "https://nominatim.openstreetmap.org/reverse?format=jsonv2&lat={}&lon={}&zoom=17",
+                lat, lng
+            ))
+            .header("User-Agent", "streetwarp-cli"),
+        other => fail(
+            ErrorCode::InputInvalid,
+            &format!("Unknown geocoder {}, expected google or nominatim", other),
+        ),
+    };
+    let body = match throttle::send(request).await {
+        Ok((status, bytes)) if status.is_success() => serde_json::from_slice::<Value>(&bytes).ok(),
+        Ok((status, _)) => {
+            error(
+                ErrorCode::Network,
+                &format!("Error code in geocoding response: {:?}", status),
+                true,
+            );
+            None
+        }
+        Err(e) => {
+            error(
+                ErrorCode::Network,
+                &format!("Error in geocoding response: {:?}", e),
+                true,
+            );
+            None
+        }
+    };
+    match (geocoder, body) {
+        ("google", Some(body)) => parse_google_response(&body),
+        (_, Some(body)) => parse_nominatim_response(&body),
+        (_, None) => Place::default(),
+    }
+}
+
+/// Indices of the points to geocode: the first point, then the first point at least
+/// geocode_interval meters (default: 250) further along the route than the previous one.
+fn sample_indices(points: &[SerializablePointBearing]) -> Vec<usize> {
+    let interval = CLI_OPTIONS.geocode_interval.unwrap_or(250.0);
+    let distances = crate::cumulative_distances(points);
+    let mut indices = vec![];
+    let mut next_distance = 0.0;
+    for (index, &distance) in distances.iter().enumerate() {
+        if distance >= next_distance {
+            indices.push(index);
+            next_distance = distance + interval;
+        }
+    }
+    indices
+}
+
+/// Reverse geocode a sample of the points and return the place of every point,
+/// taken from the closest sampled point before it.
+/// Nominatim allows one request per second, so its requests are made one at a time.
+#[tracing::instrument(skip_all)]
+pub async fn geocode_points(points: &[SerializablePointBearing]) -> Vec<Place> {
+    let indices = sample_indices(points);
+    let nominatim = CLI_OPTIONS.geocoder.as_deref() == Some("nominatim");
+    let concurrency = if nominatim {
+        1
+    } else {
+        CLI_OPTIONS.network_concurrency.unwrap_or(40)
+    };
+    let client = http::client();
+    let mut reporter = ProgressReporter::new(Unit::Requests, indices.len());
+    let mut sampled = stream::iter(indices.into_iter())
+        .map(|index| {
+            let client = &client;
+            let point = &points[index];
+            async move {
+                let place = reverse_geocode(client, point.lat, point.lng).await;
+                if nominatim {
+                    tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+                }
+                (index, place)
+            }
+        })
+        .buffer_completions(concurrency)
+        .map(|result| {
+            reporter.advance();
+            result
+        })
+        .collect::<Vec<_>>()
+        .await;
+    sampled.sort_unstable_by_key(|(index, _)| *index);
+    if sampled.iter().all(|(_, place)| place == &Place::default()) {
+        warning("Reverse geocoding found no places, check that the geocoding API is enabled");
+    }
+
+    let mut places = vec![Place::default(); points.len()];
+    for (i, (start, place)) in sampled.iter().enumerate() {
+        let end = sampled.get(i + 1).map_or(points.len(), |(next, _)| *next);
+        for slot in &mut places[*start..end] {
+            *slot = place.clone();
+        }
+    }
+    places
+}
@@ -0,0 +1,191 @@
+use std::io::BufRead;
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use crate::errors::{fail, ErrorCode};
+use crate::options::CLI_OPTIONS;
+use crate::progress::progress;
+use crate::{get_distance, interp_points_lazy, GPXPoint, GPXWaypoint, ReadResult};
+
+/// Report reading progress every this many track points.
+const PROGRESS_POINTS: usize = 100000;
+
+/// Whether to read the GPX file as a stream and interpolate its points lazily.
+pub fn is_enabled() -> bool {
+    CLI_OPTIONS.low_memory
+}
+
+/// Fail on the error e in the XML of the GPX file.
+fn invalid(e: impl std::fmt::Debug) -> ! {
+    fail(
+        ErrorCode::InputInvalid,
+        &format!("Could not read gpx: {:?}", e),
+    )
+}
+
+/// Latitude and longitude in the attributes of a trkpt or wpt element.
+fn lat_lng(element: &BytesStart) -> Option<(f64, f64)> {
+    let coordinate = |name: &str| -> Option<f64> {
+        let attribute = element
+            .try_get_attribute(name)
+            .unwrap_or_else(|e| invalid(e))?;
+        attribute
+            .unescape_value()
+            .unwrap_or_else(|e| invalid(e))
+            .trim()
+            .parse()
+            .ok()
+    };
+    Some((coordinate("lat")?, coordinate("lon")?))
+}
+
+/// Read the track points, named waypoints and name of a GPX file like read_gpx, but one XML
+/// event at a time instead of loading the whole document, so memory only grows with the points
+/// kept.
+pub fn read_gpx_streaming<R: BufRead>(reader: R) -> ReadResult {
+    let mut reader = Reader::from_reader(reader);
+    let mut points = vec![];
+    let mut waypoints = vec![];
+    let mut name = None;
+    // Local names of the open elements, to know what the text of the innermost one belongs to.
+    let mut open: Vec<Vec<u8>> = vec![];
+    let mut point: Option<GPXPoint> = None;
+    let mut waypoint: Option<GPXWaypoint> = None;
+    let mut text = String::new();
+    let mut buf = vec![];
+    loop {
+        let event = reader
+            .read_event_into(&mut buf)
+            .unwrap_or_else(|e| invalid(e));
+        let self_closing = matches!(event, Event::Empty(_));
+        match event {
+            Event::Start(element) | Event::Empty(element) => {
+                let element_name = element.local_name().as_ref().to_vec();
+                match element_name.as_slice() {
+                    b"trkpt" => {
+                        point = lat_lng(&element).map(|(lat, lng)| GPXPoint {
+                            lat,
+                            lng,
+                            ele: None,
+                            time: None,
+                        })
+                    }
+                    b"wpt" => {
+                        waypoint = lat_lng(&element).map(|(lat, lng)| GPXWaypoint {
+                            name: String::new(),
+                            lat,
+                            lng,
+                        })
+                    }
+                    _ => (),
+                }
+                text.clear();
+                if self_closing {
+                    // A self-closing point has no children, so it is complete already.
+                    if element_name == b"trkpt" {
+                        points.extend(point.take());
+                    }
+                } else {
+                    open.push(element_name);
+                }
+            }
+            Event::Text(content) => {
+                text.push_str(&content.unescape().unwrap_or_else(|e| invalid(e)))
+            }
+            Event::CData(content) => text.push_str(&String::from_utf8_lossy(&content)),
+            Event::End(element) => {
+                let parent = open.get(open.len().saturating_sub(2)).map(Vec::as_slice);
+                match (element.local_name().as_ref(), parent) {
+                    (b"ele", _) => {
+                        if let Some(point) = point.as_mut() {
+                            point.ele = text.trim().parse().ok();
+                        }
+                    }
+                    (b"time", _) => {
+                        if let Some(point) = point.as_mut() {
+                            point.time = chrono::DateTime::parse_from_rfc3339(text.trim())
+                                .ok()
+                                .map(|time| time.timestamp_millis() as f64 / 1000.0);
+                        }
+                    }
+                    (b"name", Some(b"wpt")) => {
+                        if let Some(waypoint) = waypoint.as_mut() {
+                            waypoint.name = text.trim().to_string();
+                        }
+                    }
+                    // GPX 1.1 names the file in its metadata, GPX 1.0 directly in the root.
+                    (b"name", Some(b"metadata")) | (b"name", Some(b"gpx")) => {
+                        name = Some(text.trim().to_string());
+                    }
+                    (b"trkpt", _) => {
+                        points.extend(point.take());
+                        if points.len() % PROGRESS_POINTS == 0 {
+                            progress(&format!("Read {} track points", points.len()));
+                        }
+                    }
+                    (b"wpt", _) => {
+                        waypoints.extend(waypoint.take().filter(|w| !w.name.is_empty()));
+                    }
+                    _ => (),
+                }
+                text.clear();
+                open.pop();
+            }
+            Event::Eof => break,
+            // The declaration, comments and processing instructions have no points.
+            _ => (),
+        }
+        buf.clear();
+    }
+    if points.is_empty() {
+        fail(
+            ErrorCode::InputInvalid,
+            "Could not read gpx: no track points found",
+        );
+    }
+    let size = (points.len() * 32) as u64;
+    ReadResult {
+        points,
+        waypoints,
+        name,
+        size,
+    }
+}
+
+/// Like sample_points_by_distance on the points of interp_points_lazy(points, factor), without
+/// keeping the interpolated points: one pass measures the route and a second one samples it.
+/// Return the n sampled points, the interpolated distance and the number of interpolated points.
+pub fn sample_interpolated(
+    points: &[GPXPoint],
+    factor: usize,
+    n: usize,
+) -> (Vec<GPXPoint>, f64, usize) {
+    let mut total_dist = 0.0;
+    let mut count = 0;
+    let mut last: Option<GPXPoint> = None;
+    for point in interp_points_lazy(points, factor) {
+        if let Some(last) = &last {
+            total_dist += get_distance(last, &point);
+        }
+        last = Some(point);
+        count += 1;
+    }
+    let step = total_dist / (n as f64 - 0.99);
+    let mut current = 0.0;
+    let mut sample = Vec::with_capacity(n);
+    let mut interpolated = interp_points_lazy(points, factor).peekable();
+    while sample.len() < n {
+        let point = match interpolated.next() {
+            Some(point) => point,
+            None => break,
+        };
+        if current >= step * sample.len() as f64 {
+            sample.push(point);
+        }
+        if let Some(next) = interpolated.peek() {
+            current += get_distance(&point, next);
+        }
+    }
+    (sample, total_dist, count)
+}
@@ -0,0 +1,165 @@
+use futures::{stream, StreamExt};
+use reqwest::Client;
+use serde_json::Value;
+
+use crate::deterministic::BufferCompletions;
+use crate::errors::ErrorCode;
+use crate::http;
+use crate::interned::Interned;
+use crate::options::CLI_OPTIONS;
+use crate::progress::{error, ProgressReporter, Unit};
+use crate::throttle;
+use crate::SerializablePointBearing;
+
+/// One capture of a location in the street view "time machine".
+#[derive(Debug, Clone)]
+struct HistoricalPano {
+    pano_id: Interned,
+    year: i32,
+    month: i32,
+}
+
+/// Parse a metadata date string (format: YYYY-MM) into (year, month).
+pub fn parse_pano_date(date: &str) -> Option<(i32, i32)> {
+    let mut parts = date.split('-');
+    let year = parts.next()?.parse::<i32>().ok()?;
+    let month = parts.next().and_then(|m| m.parse::<i32>().ok()).unwrap_or(1);
+    Some((year, month))
+}
+
+/// Whether an option asks for older panoramas than the static API returns. They are found
+/// through the photo service behind the maps javascript client, which is not a documented API.
+pub fn is_enabled() -> bool {
+    CLI_OPTIONS.pano_date_target.is_some() || !CLI_OPTIONS.compare_years.is_empty()
+}
+
+/// Extract the list of dated panoramas from a photo service response, each panorama once.
+/// The response is a JSONP callback wrapping deeply nested arrays: the panoramas are listed at
+/// [1][5][0][3][0] and the dates at [1][5][0][8] as [index into panoramas, [year, month]].
+fn parse_history_response(body: &str) -> Vec<HistoricalPano> {
+    let (start, end) = match (body.find('('), body.rfind(')')) {
+        (Some(start), Some(end)) if start < end => (start, end),
+        _ => return vec![],
+    };
+    let data: Value = match serde_json::from_str(&body[start + 1..end]) {
+        Ok(data) => data,
+        Err(_) => return vec![],
+    };
+    let subset = &data[1][5][0];
+    let panos = match subset[3][0].as_array() {
+        Some(panos) => panos,
+        None => return vec![],
+    };
+    let dates = match subset[8].as_array() {
+        Some(dates) => dates,
+        None => return vec![],
+    };
+    let mut history: Vec<HistoricalPano> = vec![];
+    let entries = dates.iter().filter_map(|entry| {
+        let index = entry[0].as_u64()? as usize;
+        Some(HistoricalPano {
+            pano_id: Interned::new(panos.get(index)?[0][1].as_str()?),
+            year: entry[1][0].as_i64()? as i32,
+            month: entry[1][1].as_i64()? as i32,
+        })
+    });
+    // The same capture can be listed more than once.
+    for pano in entries {
+        if !history.iter().any(|p| p.pano_id == pano.pano_id) {
+            history.push(pano);
+        }
+    }
+    history
+}
+
+/// Request every panorama captured within 50 meters of the given location, including the older
+/// captures that the static API hides. Uses the photo service behind the maps javascript client.
+async fn get_pano_history(client: &Client, lat: f64, lng: f64) -> Vec<HistoricalPano> {
+    let url = format!(
+"https://maps.googleapis.com/maps/api/js/GeoPhotoService.SingleImageSearch?pb=!1m5!1sapiv3!5sUS!11m2!1m1!1b0!2m4!1m2!3d{}!4d{}!2d50!3m10!2m2!1sen!2sGB!9m1!1e2!11m4!1m3!1e2!2b1!3e2!4m10!1e1!1e2!1e3!1e4!1e8!1e6!5m1!1e2!6m1!1e2&callback=_xdc_._streetwarp", lat, lng);
+    match throttle::send(|| client.get(&url)).await {
+        Ok((status, body)) if status.is_success() => {
+            parse_history_response(&String::from_utf8_lossy(&body))
+        }
+        Ok((status, _)) => {
+            error(
+                ErrorCode::Network,
+                &format!("Error code in pano history response: {:?}", status),
+                true,
+            );
+            vec![]
+        }
+        Err(e) => {
+            error(
+                ErrorCode::Network,
+                &format!("Error in pano history response: {:?}", e),
+                true,
+            );
+            vec![]
+        }
+    }
+}
+
+/// Replace the panorama of each point with the capture closest to the middle of target_year.
+/// Points with no known history keep their current panorama.
+#[tracing::instrument(skip_all)]
+pub async fn select_historical_panos(
+    points: &[SerializablePointBearing],
+    target_year: i32,
+) -> Vec<SerializablePointBearing> {
+    let client = http::client();
+    let mut reporter = ProgressReporter::new(Unit::Requests, points.len());
+    let target_month = target_year * 12 + 6;
+    let histories = stream::iter(points.iter().enumerate())
+        .map(|(index, point)| {
+            let client = &client;
+            async move { (index, get_pano_history(client, point.lat, point.lng).await) }
+        })
+        .buffer_completions(CLI_OPTIONS.network_concurrency.unwrap_or(40));
+
+    let mut indexed_histories = histories
+        .map(|(index, history)| {
+            reporter.advance();
+            (index, history)
+        })
+        .collect::<Vec<_>>()
+        .await;
+    indexed_histories.sort_unstable_by_key(|&(index, _)| index);
+
+    points
+        .iter()
+        .zip(indexed_histories.into_iter().map(|(_, history)| history))
+        .map(|(point, mut history)| {
+            // The current panorama is also a candidate, using the date from its metadata.
+            if let (Some(pano_id), Some((year, month))) = (
+                point.panoId.as_ref(),
+                point.panoDate.as_deref().and_then(parse_pano_date),
+            ) {
+                history.push(HistoricalPano {
+                    pano_id: *pano_id,
+                    year,
+                    month,
+                });
+            }
+            let closest = history
+                .into_iter()
+                .min_by_key(|p| (p.year * 12 + p.month - 1 - target_month).abs());
+            let mut point = *point;
+            if let Some(closest) = closest {
+                point.panoId = Some(closest.pano_id);
+                point.panoDate = Some(Interned::new(&format!(
+                    "{}-{:02}",
+                    closest.year, closest.month
+                )));
+            }
+            point
+        })
+        .collect()
+}
+
+/// Drop each point whose panorama is the same as the one before it, as happens when nearby
+/// points pick the same historical capture.
+pub fn dedup_panos(mut points: Vec<SerializablePointBearing>) -> Vec<SerializablePointBearing> {
+    points.dedup_by(|point, previous| point.panoId.is_some() && point.panoId == previous.panoId);
+    points
+}
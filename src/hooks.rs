@@ -0,0 +1,116 @@
+use futures::{stream, StreamExt};
+use std::path::Path;
+use tokio::process::Command;
+
+use crate::deterministic::BufferCompletions;
+use crate::errors::{fail, ErrorCode};
+use crate::options::CLI_OPTIONS;
+use crate::progress::{progress_stage, warning, ProgressReporter, Unit};
+use crate::{MetadataResult, SerializablePointBearing};
+
+/// Run the frame hook executable once per frame {index}.jpg in image_dir, passing the frame path
+/// and the JSON metadata of its point as arguments. The hook may modify the frame in place.
+/// Runs frame_hook_concurrency hooks at once (default: number of cpus).
+#[tracing::instrument(skip_all)]
+pub async fn run_frame_hooks<P: AsRef<Path>>(image_dir: &P, points: &[SerializablePointBearing]) {
+    let hook_cmd = match &CLI_OPTIONS.frame_hook {
+        Some(hook_cmd) => hook_cmd,
+        None => return,
+    };
+    let image_dir = image_dir.as_ref();
+    let mut reporter = ProgressReporter::new(Unit::Frames, points.len());
+    let results = stream::iter(points.iter().enumerate())
+        .map(|(index, point)| async move {
+            let path = image_dir.join(format!("{}.jpg", index));
+            let metadata = serde_json::to_string(point).expect("Serialization failed");
+            let output = Command::new(hook_cmd)
+                .arg(&path)
+                .arg(&metadata)
+                .output()
+                .await;
+            (index, output)
+        })
+        .buffer_completions(
+            CLI_OPTIONS
+                .frame_hook_concurrency
+                .unwrap_or_else(rayon::current_num_threads),
+        );
+
+    results
+        .for_each(|(index, output)| {
+            reporter.advance();
+            match output {
+                Ok(output) if !output.status.success() => warning(&format!(
+                    "Frame hook failed on frame {} with exit code {:?}: {}",
+                    index,
+                    output.status.code(),
+                    String::from_utf8_lossy(&output.stderr)
+                )),
+                Err(e) => warning(&format!(
+                    "Frame hook could not run on frame {}: {:?}",
+                    index, e
+                )),
+                _ => (),
+            }
+            futures::future::ready(())
+        })
+        .await;
+}
+
+/// Command running cmd through the shell, passing it the arguments added to the command.
+pub fn shell_command(cmd: &str) -> Command {
+    if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(cmd);
+        command
+    } else {
+        // sh passes the arguments after the script name on as "$@".
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("{} \"$@\"", cmd))
+            .arg("streetwarp");
+        command
+    }
+}
+
+/// Run the post_cmd option's command through the shell after a successful encode, with the path
+/// of the output and of a JSON file holding metadata_result as its two arguments.
+#[tracing::instrument(skip_all)]
+pub async fn run_post_cmd(output_dir: &Path, metadata_result: &MetadataResult) {
+    let post_cmd = match &CLI_OPTIONS.post_cmd {
+        Some(post_cmd) => post_cmd,
+        None => return,
+    };
+    let output_path = output_dir.join(crate::output_filename());
+    let metadata_path = output_path.with_extension("metadata.json");
+    let metadata = serde_json::to_string(metadata_result).expect("Serialization failed");
+    std::fs::write(&metadata_path, metadata).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Io,
+            &format!("Could not write {:?}: {:?}", metadata_path, e),
+        )
+    });
+    progress_stage("Running post command");
+    let status = shell_command(post_cmd)
+        .arg(&output_path)
+        .arg(&metadata_path)
+        .status()
+        .await
+        .unwrap_or_else(|e| {
+            fail(
+                ErrorCode::EncodeFailed,
+                &format!("Could not run post command {}: {:?}", post_cmd, e),
+            )
+        });
+    if !status.success() {
+        fail(
+            ErrorCode::EncodeFailed,
+            &format!(
+                "Post command {} failed with exit code {:?}",
+                post_cmd,
+                status.code()
+            ),
+        );
+    }
+}
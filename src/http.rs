@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use reqwest::{Client, Proxy};
+
+use crate::errors::{fail, ErrorCode};
+use crate::options::CLI_OPTIONS;
+
+/// Proxy for every request: the proxy option, or else ALL_PROXY if neither HTTP_PROXY nor
+/// HTTPS_PROXY is set, since reqwest already reads those two itself.
+fn proxy_url() -> Option<String> {
+    let env = |name: &str| {
+        std::env::var(name)
+            .or_else(|_| std::env::var(name.to_lowercase()))
+            .ok()
+            .filter(|value| !value.is_empty())
+    };
+    CLI_OPTIONS.proxy.clone().or_else(|| {
+        if env("HTTP_PROXY").is_none() && env("HTTPS_PROXY").is_none() {
+            env("ALL_PROXY")
+        } else {
+            None
+        }
+    })
+}
+
+/// Timeout of each request with the request_timeout option, if set.
+pub fn request_timeout() -> Option<Duration> {
+    CLI_OPTIONS.request_timeout.map(Duration::from_secs)
+}
+
+/// Proxy and timeout of a client.
+type ClientKey = (Option<String>, Option<Duration>);
+
+lazy_static! {
+    /// Clients by their proxy and timeout, so the jobs of the jobs subcommand with the same ones
+    /// share a client and its connection pool.
+    static ref CLIENTS: Mutex<HashMap<ClientKey, Client>> =
+        Mutex::new(HashMap::new());
+}
+
+/// The client for requests to web APIs, sent through the configured proxy if any and timing out
+/// after request_timeout if set.
+pub fn client() -> Client {
+    let key = (proxy_url(), request_timeout());
+    if let Some(client) = CLIENTS.lock().unwrap().get(&key) {
+        return client.clone();
+    }
+    let client = build_client(key.0.as_deref(), key.1);
+    CLIENTS.lock().unwrap().insert(key, client.clone());
+    client
+}
+
+fn build_client(proxy_url: Option<&str>, timeout: Option<Duration>) -> Client {
+    let mut builder = Client::builder();
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(url) = proxy_url {
+        let proxy = Proxy::all(url).unwrap_or_else(|e| {
+            fail(
+                ErrorCode::InputInvalid,
+                &format!("Invalid proxy URL {}: {:?}", url, e),
+            )
+        });
+        builder = builder.proxy(proxy);
+    }
+    builder.build().expect("Could not create HTTP client")
+}
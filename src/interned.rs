@@ -0,0 +1,68 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+lazy_static! {
+    static ref STRINGS: Mutex<HashSet<&'static str>> = Mutex::new(HashSet::new());
+}
+
+/// A string kept for the rest of the run, like a panorama id, so the points that hold it stay
+/// Copy. Each distinct string is stored once.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Interned(&'static str);
+
+impl Interned {
+    pub fn new(text: &str) -> Interned {
+        let mut strings = STRINGS.lock().unwrap();
+        match strings.get(text) {
+            Some(stored) => Interned(stored),
+            None => {
+                let stored: &'static str = Box::leak(text.to_string().into_boxed_str());
+                strings.insert(stored);
+                Interned(stored)
+            }
+        }
+    }
+
+    /// The string, or None if it is empty, for optional fields of API responses.
+    pub fn non_empty(text: &str) -> Option<Interned> {
+        Some(text)
+            .filter(|text| !text.is_empty())
+            .map(Interned::new)
+    }
+}
+
+impl Deref for Interned {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.0
+    }
+}
+
+impl fmt::Debug for Interned {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.0, f)
+    }
+}
+
+impl fmt::Display for Interned {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl Serialize for Interned {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Interned {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Interned, D::Error> {
+        String::deserialize(deserializer).map(|text| Interned::new(&text))
+    }
+}
@@ -0,0 +1,117 @@
+use futures::{FutureExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::context::Context;
+use crate::errors::{failure_code, panic_message};
+use crate::options::{parse_job, CLI_OPTIONS};
+use crate::throttle;
+
+/// One line of input to the jobs subcommand.
+#[derive(Deserialize, Debug)]
+struct Job {
+    /// Copied into every event of the job.
+    id: Value,
+    /// Command line of the job without the program name, as for a separate streetwarp process,
+    /// e.g. ["fetch", "route.gpx", "--api-key", "KEY"].
+    args: Vec<String>,
+}
+
+/// Print an event line of the jobs protocol.
+fn emit(event: Value) {
+    println!(
+        "{}",
+        serde_json::to_string(&event).expect("Could not print job event")
+    );
+}
+
+/// The JOB_RESULT event of the job with id, which failed with the panic if any.
+fn job_result(id: &Value, panic: Option<Box<dyn std::any::Any + Send>>) -> Value {
+    match panic {
+        None => json!({
+            "type": "JOB_RESULT",
+            "id": id,
+            "success": true,
+            "exitCode": 0,
+        }),
+        Some(panic) => json!({
+            "type": "JOB_RESULT",
+            "id": id,
+            "success": false,
+            "exitCode": failure_code().exit_code(),
+            "error": panic_message(&*panic),
+        }),
+    }
+}
+
+/// Run the job in its own context, as a separate streetwarp process would with progress
+/// messages on, and return its result event. Its progress events are tagged with the job id.
+async fn run_job(job: Job) -> Value {
+    let Job { id, args } = job;
+    let options = match std::panic::catch_unwind(|| parse_job(&args)) {
+        Ok(options) => options,
+        Err(panic) => return job_result(&id, Some(panic)),
+    };
+    let args = std::iter::once("streetwarp".to_string())
+        .chain(args)
+        .collect();
+    let context = Context::for_job(options, args, id.clone());
+    let result = context
+        .scope(async {
+            std::panic::catch_unwind(crate::progress::open_sink)?;
+            let stage = CLI_OPTIONS.stage();
+            let (output_dir, is_temp) = crate::output_dir(stage);
+            std::panic::catch_unwind(|| crate::prepare_output_dir(stage, &output_dir))?;
+            let result = std::panic::AssertUnwindSafe(crate::run_and_deliver(
+                stage,
+                output_dir.clone(),
+                is_temp,
+            ))
+            .catch_unwind()
+            .await;
+            crate::notify::notify(&output_dir, crate::run_outcome(&result)).await;
+            result.map(|_| ())
+        })
+        .await;
+    context.enter(|| job_result(&id, result.err()))
+}
+
+/// Read newline-delimited JSON jobs from stdin and run up to max_jobs of them at once as tasks of
+/// this process, printing their progress and results as newline-delimited JSON events on
+/// stdout. The jobs share one pool of network_concurrency requests at once, and one token
+/// bucket of max_qps requests per second if set.
+pub async fn run_jobs(max_jobs: usize, network_concurrency: usize, max_qps: Option<f64>) {
+    throttle::share_budget(network_concurrency, max_qps);
+    BufReader::new(tokio::io::stdin())
+        .lines()
+        .for_each_concurrent(max_jobs, |line| async move {
+            let line = line.expect("Could not read job from stdin");
+            if line.trim().is_empty() {
+                return;
+            }
+            let job = match serde_json::from_str::<Job>(&line) {
+                Ok(job) => job,
+                Err(e) => {
+                    return emit(json!({
+                        "type": "JOB_RESULT",
+                        "id": Value::Null,
+                        "success": false,
+                        "error": format!("Invalid job {}: {}", line, e),
+                    }))
+                }
+            };
+            let id = job.id.clone();
+            emit(json!({ "type": "JOB_START", "id": id }));
+            // Each job is its own task, so jobs run on all threads of the runtime.
+            let result = tokio::spawn(run_job(job)).await.unwrap_or_else(|e| {
+                json!({
+                    "type": "JOB_RESULT",
+                    "id": id,
+                    "success": false,
+                    "error": format!("Job stopped: {:?}", e),
+                })
+            });
+            emit(result);
+        })
+        .await;
+}
@@ -0,0 +1,74 @@
+use std::path::Path;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{self, FilterType};
+use image::GenericImageView;
+use rayon::prelude::*;
+
+use crate::errors::{fail, ErrorCode};
+use crate::options::CLI_OPTIONS;
+use crate::pacing::Hold;
+use crate::progress::progress;
+
+/// Crop the center of img by scale (above 1 zooms in) and resize it back to its own size.
+fn zoom(img: &image::RgbImage, scale: f64) -> image::RgbImage {
+    let (width, height) = img.dimensions();
+    let crop_width = ((width as f64 / scale).round() as u32).max(1).min(width);
+    let crop_height = ((height as f64 / scale).round() as u32).max(1).min(height);
+    let view = img.view(
+        (width - crop_width) / 2,
+        (height - crop_height) / 2,
+        crop_width,
+        crop_height,
+    );
+    imageops::resize(&view, width, height, FilterType::Triangle)
+}
+
+/// Replace the repeated frames of each hold (matching pattern in image_dir) with a slow zoom into
+/// (or out of) the held image, up to the ken_burns_zoom option (default: 1.1), so holds don't look
+/// frozen.
+pub fn zoom_holds(image_dir: &Path, pattern: &str, holds: &[Hold]) {
+    let max_zoom = CLI_OPTIONS.ken_burns_zoom.unwrap_or(1.1).max(1.0);
+    let zoom_out = match CLI_OPTIONS.ken_burns_direction.as_deref().unwrap_or("in") {
+        "in" => false,
+        "out" => true,
+        other => fail(
+            ErrorCode::InputInvalid,
+            &format!("Unknown Ken Burns direction {}, expected in or out", other),
+        ),
+    };
+    let frame_path = |index: usize| image_dir.join(pattern.replace("%d", &index.to_string()));
+    let zoomed = holds
+        .par_iter()
+        .filter(|hold| {
+            let held = match image::open(frame_path(hold.start)) {
+                Ok(img) => img.to_rgb8(),
+                Err(_) => return false,
+            };
+            (0..hold.frames).into_par_iter().for_each(|offset| {
+                let progress = offset as f64 / (hold.frames - 1).max(1) as f64;
+                let progress = if zoom_out { 1.0 - progress } else { progress };
+                let frame = zoom(&held, 1.0 + (max_zoom - 1.0) * progress);
+                let path = frame_path(hold.start + offset);
+                // Repeated frames may be hard links to the same file, so unlink before writing.
+                let _ = std::fs::remove_file(&path);
+                let file = std::fs::File::create(&path).unwrap_or_else(|e| {
+                    fail(
+                        ErrorCode::Io,
+                        &format!("Could not write {:?}: {:?}", path, e),
+                    )
+                });
+                JpegEncoder::new_with_quality(&mut std::io::BufWriter::new(file), 95)
+                    .encode_image(&frame)
+                    .unwrap_or_else(|e| {
+                        fail(
+                            ErrorCode::Io,
+                            &format!("Could not encode {:?}: {:?}", path, e),
+                        )
+                    });
+            });
+            true
+        })
+        .count();
+    progress(&format!("Added Ken Burns zoom to {} holds", zoomed));
+}
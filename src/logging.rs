@@ -0,0 +1,32 @@
+use std::io::IsTerminal;
+
+use tracing::Level;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
+
+use crate::errors::{fail, ErrorCode};
+
+/// Log to stderr as text or JSON lines by log_format (the log_format option), at info level or
+/// more detailed with each verbose flag. Libraries only log their warnings, so -vv stays
+/// readable.
+pub fn init(verbose: u8, log_format: Option<&str>) {
+    let level = match verbose {
+        0 => Level::INFO,
+        1 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    let filter = Targets::new()
+        .with_default(Level::WARN)
+        .with_target(env!("CARGO_PKG_NAME"), level);
+    let format = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    match log_format.unwrap_or("text") {
+        "text" => format.finish().with(filter).init(),
+        "json" => format.json().finish().with(filter).init(),
+        other => fail(
+            ErrorCode::InputInvalid,
+            &format!("Unknown log format {}, expected text or json", other),
+        ),
+    }
+}
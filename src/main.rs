@@ -3,13 +3,65 @@ extern crate lazy_static;
 
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate tracing;
+mod archive;
+mod beats;
+mod cancel;
+mod capabilities;
+mod chapters;
+mod cleanup;
+mod colormatch;
+mod config;
+mod contactsheet;
+mod context;
+mod dedup;
+mod deterministic;
+mod errors;
+mod export;
 mod ffmpeg;
+mod fit;
+mod fixtures;
+mod frames;
+mod geocode;
+mod gpxstream;
+mod history;
+mod hooks;
+mod http;
+mod interned;
+mod jobs;
+mod kenburns;
+mod logging;
+mod minimap;
+mod mock;
+#[cfg(feature = "native-encode")]
+mod native_encode;
+mod notify;
 mod optim;
 mod options;
+mod overlay;
+mod pacing;
+mod pipeline;
+mod policy;
 mod progress;
+mod schema;
+#[cfg(feature = "scripting")]
+mod scripting;
+mod sensors;
+mod shard;
+mod sources;
+mod store;
+mod summary;
+mod takeout;
+mod telemetry;
+mod throttle;
+mod thumbnail;
+mod upload;
+mod validate;
+mod watch;
 
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, BufWriter};
 use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::{env, fs};
@@ -19,12 +71,15 @@ use gpx::{read, Gpx};
 use geo::{prelude::*, Point};
 
 use fs_extra::dir::{get_dir_content, get_size};
-use futures::{stream, StreamExt};
+use futures::{stream, FutureExt, StreamExt};
 use rayon::prelude::*;
-use reqwest::Client;
 
+use deterministic::BufferCompletions;
+use errors::{fail, ErrorCode};
 use ffmpeg::*;
-use options::CLI_OPTIONS;
+use interned::Interned;
+use options::{Stage, CLI_OPTIONS};
+use policy::{ErrorPolicy, MissingPolicy};
 use progress::*;
 
 #[derive(Deserialize, Serialize, Debug, Copy, Clone, Default, PartialEq)]
@@ -38,10 +93,22 @@ struct GPXPoint {
     lat: f64,
     lng: f64,
     ele: Option<f64>,
+    /// Seconds since the unix epoch, if the track point has a time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    time: Option<f64>,
+}
+
+/// A named waypoint of the input GPX, e.g. a summit or cafe stop.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+struct GPXWaypoint {
+    name: String,
+    lat: f64,
+    lng: f64,
 }
 
 struct ReadResult {
     points: Vec<GPXPoint>,
+    waypoints: Vec<GPXWaypoint>,
     name: Option<String>,
     size: u64,
 }
@@ -52,9 +119,29 @@ struct SerializablePointBearing {
     lng: f64,
     bearing: f64,
     ele: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    panoId: Option<Interned>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    panoDate: Option<Interned>,
+    /// Time of the GPX point this frame was sampled from, in seconds since the unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    time: Option<f64>,
+    /// Location of the panorama, which can be some meters from the route point at lat, lng.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    panoLat: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    panoLng: Option<f64>,
+    /// Distance in meters from the route point to its panorama.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    panoCopyright: Option<Interned>,
+    /// Output frames showing this point, chosen by pacing before the images are fetched.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    frameCount: Option<u32>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, Default)]
 struct GSVMetadata {
     #[serde(default)]
     date: String,
@@ -65,6 +152,9 @@ struct GSVMetadata {
     #[serde(default)]
     pano_id: String,
 
+    #[serde(default)]
+    copyright: String,
+
     #[serde(default)]
     status: String,
 }
@@ -77,6 +167,9 @@ struct PointBearing {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct MetadataResult {
+    /// Version of this format, see METADATA_SCHEMA_VERSION. Read through schema.rs, which
+    /// upgrades older versions.
+    schemaVersion: u32,
     distance: f64,
     frames: usize,
     gpsPoints: Vec<SerializablePointBearing>,
@@ -84,15 +177,41 @@ struct MetadataResult {
     averageError: f64,
     name: String,
     fileSizeBytes: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    waypoints: Vec<GPXWaypoint>,
+    /// Error statistics of each kilometer of the route, to find where panoramas are far off it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    segmentErrors: Vec<SegmentError>,
+}
+
+/// Distances in meters from the route to the panoramas of the frames within one kilometer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct SegmentError {
+    /// Kilometers along the route at the start of the segment.
+    km: usize,
+    /// Index of the first frame in the segment.
+    firstFrame: usize,
+    frames: usize,
+    averageError: f64,
+    maxError: f64,
 }
 
 impl SerializablePointBearing {
-    fn from_geo(pb: &PointBearing) -> SerializablePointBearing {
+    fn from_geo(pb: &PointBearing, meta: &GSVMetadata, error: f64) -> SerializablePointBearing {
         SerializablePointBearing {
             bearing: pb.bearing,
             lat: pb.point.lat,
             lng: pb.point.lng,
             ele: pb.point.ele,
+            panoId: Interned::non_empty(&meta.pano_id),
+            panoDate: Interned::non_empty(&meta.date),
+            time: pb.point.time,
+            // Points kept without imagery have no panorama to locate.
+            panoLat: Some(meta.location.lat).filter(|_| !meta.pano_id.is_empty()),
+            panoLng: Some(meta.location.lng).filter(|_| !meta.pano_id.is_empty()),
+            error: Some(error).filter(|_| !meta.pano_id.is_empty()),
+            panoCopyright: Interned::non_empty(&meta.copyright),
+            frameCount: None,
         }
     }
 }
@@ -103,123 +222,358 @@ impl GPXPoint {
     }
 }
 
+/// Size of requested streetview images: the largest size within the API limit of 640x640
+/// with the same aspect ratio as the output video.
+fn image_size() -> (u32, u32) {
+    let (width, height) = video_size();
+    let scale = (640.0 / width as f64).min(640.0 / height as f64);
+    (
+        (width as f64 * scale).round() as u32,
+        (height as f64 * scale).round() as u32,
+    )
+}
+
+/// URL of the streetview image of point_bearing in Google's static API.
+/// When older panoramas were selected, points request their exact panorama instead of the
+/// nearest one.
+fn image_url(point_bearing: &SerializablePointBearing) -> String {
+    let (image_width, image_height) = image_size();
+    let location = match &point_bearing.panoId {
+        Some(pano_id) if history::is_enabled() => format!("pano={}", pano_id),
+        _ => format!("location={},{}", point_bearing.lat, point_bearing.lng),
+    };
+    format!(
+"https://maps.googleapis.com/maps/api/streetview?size={}x{}&{}&fov=100&source=outdoor&heading={}&pitch=0&key={}", image_width, image_height, location, point_bearing.bearing, CLI_OPTIONS.api_key())
+}
+
 /// For each input point_bearing, request the streetview image from Google's static API.
 /// Save each image as {index}.jpg within out_dir.
+#[tracing::instrument(skip_all)]
 async fn get_images<P: AsRef<Path>>(point_bearings: &[SerializablePointBearing], out_dir: &P) {
-    let url = |point_bearing: &SerializablePointBearing| {
-        format!(
-"https://maps.googleapis.com/maps/api/streetview?size=640x480&location={},{}&fov=100&source=outdoor&heading={}&pitch=0&key={}", point_bearing.lat, point_bearing.lng, point_bearing.bearing, CLI_OPTIONS.api_key)
-    };
-    let total_requests = point_bearings.len();
-    let mut requests_completed = 0;
-    let client = Client::new();
-    let bodies = stream::iter(point_bearings.iter().map(url).enumerate())
+    let indices = (0..point_bearings.len()).collect::<Vec<_>>();
+    get_images_by_index(point_bearings, &indices, out_dir).await;
+}
+
+/// Like get_images, but only request the images of point_bearings at the given indices.
+async fn get_images_by_index<P: AsRef<Path>>(
+    point_bearings: &[SerializablePointBearing],
+    indices: &[usize],
+    out_dir: &P,
+) {
+    let mut reporter = ProgressReporter::new(Unit::Requests, indices.len());
+    if mock::is_enabled() {
+        for &index in indices {
+            let filename = out_dir.as_ref().join(format!("{}.jpg", &index));
+            let frame = mock::frame(index, &point_bearings[index]);
+            if let Err(e) = tokio::fs::write(&filename, frame).await {
+                error(
+                    ErrorCode::Io,
+                    &format!("Could not write {:?}: {:?}", &filename, e),
+                    true,
+                );
+            }
+            reporter.advance();
+        }
+        return;
+    }
+    let (stored, indices): (Vec<usize>, Vec<usize>) = indices.iter().partition(|&&index| {
+        let filename = out_dir.as_ref().join(format!("{}.jpg", &index));
+        store::link(&point_bearings[index], &filename)
+    });
+    if !stored.is_empty() {
+        progress(&format!(
+            "Reused {} frames from the frame store",
+            stored.len()
+        ));
+        reporter.set(stored.len());
+    }
+    let client = http::client();
+    let urls = indices
+        .iter()
+        .map(|&index| (index, image_url(&point_bearings[index])));
+    let bodies = stream::iter(urls)
         .map(|(index, url)| {
             let client = &client;
             async move {
-                let resp = client.get(&url).send().await;
-                (index, resp.unwrap().bytes().await)
+                let bytes = throttle::send(|| client.get(&url))
+                    .await
+                    .map(|(_, bytes)| bytes);
+                (index, bytes)
             }
         })
-        .buffer_unordered(CLI_OPTIONS.network_concurrency.unwrap_or(40));
+        .buffer_completions(CLI_OPTIONS.network_concurrency.unwrap_or(40));
 
     bodies
         .map(|(index, bytes)| {
-            requests_completed += 1;
-            progress(&format!(
-                "Progress: {:.1}% ({}/{})",
-                (requests_completed as f64 / total_requests as f64) * 100.0,
-                requests_completed,
-                total_requests
-            ));
+            reporter.advance();
             (index, bytes)
         })
         .for_each(|(index, bytes)| async move {
             let filename = out_dir.as_ref().join(format!("{}.jpg", &index));
-            tokio::fs::write(filename, bytes.unwrap()).await.unwrap();
+            // Failures are left for validate_images to detect and refetch.
+            match bytes {
+                Ok(bytes) => match tokio::fs::write(&filename, bytes).await {
+                    Ok(()) => store::save(&point_bearings[index], &filename),
+                    Err(e) => error(
+                        ErrorCode::Io,
+                        &format!("Could not write {:?}: {:?}", &filename, e),
+                        true,
+                    ),
+                },
+                Err(e) => error(
+                    ErrorCode::Network,
+                    &format!("Error in streetview image response {}: {:?}", index, e),
+                    true,
+                ),
+            }
         })
         .await;
-    // TODO: check that the images are all in fact jpg, and not an error message (which is png)
-    // TODO: if we see a png image, then convert it to jpg
+    store::prune();
+}
+
+/// Meters per degree of latitude, to size the metadata grid cells.
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/// Group point_bearings into square grid cells of the metadata_cell_meters option (default: 10),
+/// since nearby points get the same panorama. Return the index of the first point of each cell,
+/// and for each point the position of its cell in that list.
+fn metadata_cells(point_bearings: &[PointBearing]) -> (Vec<usize>, Vec<usize>) {
+    let cell_meters = CLI_OPTIONS.metadata_cell_meters.unwrap_or(10.0);
+    if cell_meters <= 0.0 {
+        let indices = (0..point_bearings.len()).collect::<Vec<_>>();
+        return (indices.clone(), indices);
+    }
+    let mut cells = std::collections::HashMap::new();
+    let mut representatives = vec![];
+    let cell_of_point = point_bearings
+        .iter()
+        .enumerate()
+        .map(|(index, pb)| {
+            let lat_cell = (pb.point.lat * METERS_PER_DEGREE / cell_meters).floor() as i64;
+            // Cells span more degrees of longitude away from the equator.
+            let lng_meters = METERS_PER_DEGREE * pb.point.lat.to_radians().cos().max(0.01);
+            let lng_cell = (pb.point.lng * lng_meters / cell_meters).floor() as i64;
+            *cells.entry((lat_cell, lng_cell)).or_insert_with(|| {
+                representatives.push(index);
+                representatives.len() - 1
+            })
+        })
+        .collect::<Vec<_>>();
+    (representatives, cell_of_point)
 }
 
 /// For each input point_bearing, request its streetview metadata from Google's static API.
+/// Points in the same metadata cell share one request (see metadata_cells).
 /// Sends requests in parallel determined by network_concurrency option.
 /// Return array of metadata, one item per input point.
+#[tracing::instrument(skip_all)]
 async fn get_metadata(point_bearings: &[PointBearing]) -> Vec<GSVMetadata> {
+    if mock::is_enabled() {
+        return point_bearings.iter().map(mock::metadata).collect();
+    }
     // use metadata requests to skip errors https://developers.google.com/maps/documentation/streetview/metadata
     // and to correct points lat/lng
     // and to skip images that are a copy of the previous one
     let url = |point_bearing: &PointBearing| {
         format!(
-"https://maps.googleapis.com/maps/api/streetview/metadata?location={},{}&source=outdoor&key={}", point_bearing.point.lat, point_bearing.point.lng, CLI_OPTIONS.api_key)
+"https://maps.googleapis.com/maps/api/streetview/metadata?location={},{}&source=outdoor&key={}", point_bearing.point.lat, point_bearing.point.lng, CLI_OPTIONS.api_key())
     };
-    let client = Client::new();
-    let total_request_count = point_bearings.len();
-    let mut requests_completed = 0;
-    let bodies = stream::iter(point_bearings.iter().map(url).enumerate())
-        .map(|(index, url)| {
-            let client = &client;
-            async move {
-                let resp = client.get(&url).send().await;
-                let resp = resp.expect("Error in streetview metadata response");
-                if !resp.status().is_success() {
-                    panic!(
-                        "Error code in streetview metadata response: {:?}",
-                        resp.status()
-                    );
+    let (representatives, cell_of_point) = metadata_cells(point_bearings);
+    if representatives.len() < point_bearings.len() {
+        progress(&format!(
+            "Sharing metadata between nearby points: {} requests for {} points",
+            representatives.len(),
+            point_bearings.len()
+        ));
+    }
+    let client = http::client();
+    let mut reporter = ProgressReporter::new(Unit::Requests, representatives.len());
+    let bodies = stream::iter(
+        representatives
+            .iter()
+            .map(|&index| url(&point_bearings[index]))
+            .enumerate(),
+    )
+    .map(|(index, url)| {
+        let client = &client;
+        async move {
+            let retries = CLI_OPTIONS.fetch_retries.unwrap_or(3);
+            let mut attempt = 0;
+            loop {
+                let failure = match throttle::send(|| client.get(&url)).await {
+                    Ok((status, bytes)) if status.is_success() => return (index, Some(bytes)),
+                    Ok((status, _)) => {
+                        format!("Error code in streetview metadata response: {:?}", status)
+                    }
+                    Err(e) => format!("Error in streetview metadata response: {:?}", e),
+                };
+                match ErrorPolicy::from_options() {
+                    ErrorPolicy::Retry if attempt < retries => {
+                        error(ErrorCode::Network, &failure, true);
+                        attempt += 1;
+                        throttle::retry_backoff(attempt).await;
+                    }
+                    ErrorPolicy::Skip => {
+                        error(ErrorCode::Network, &failure, true);
+                        return (index, None);
+                    }
+                    _ => fail(ErrorCode::Network, &failure),
                 }
-                (index, resp.bytes().await)
             }
-        })
-        .buffer_unordered(CLI_OPTIONS.network_concurrency.unwrap_or(40));
+        }
+    })
+    .buffer_completions(CLI_OPTIONS.network_concurrency.unwrap_or(40));
 
     let mut indexed_metadata = bodies
         .map(|(index, bytes)| {
-            requests_completed += 1;
-            // Print progress message with requests completed / total requests as percentage
-            let percent = (requests_completed as f64 / total_request_count as f64) * 100.0;
-            progress(&format!(
-                "Progress: {:.1}% ({}/{})",
-                percent, requests_completed, total_request_count
-            ));
-            let parsed = serde_json::from_slice::<GSVMetadata>(&bytes.unwrap())
+            reporter.advance();
+            let bytes = match bytes {
+                Some(bytes) => bytes,
+                None => {
+                    let skipped = GSVMetadata {
+                        status: policy::REQUEST_FAILED_STATUS.to_string(),
+                        ..Default::default()
+                    };
+                    return (index, skipped);
+                }
+            };
+            let parsed = serde_json::from_slice::<GSVMetadata>(&bytes)
                 .expect("Could not parse GSV metadata");
+            // Throttled requests are retried, so these only remain once retrying gave up.
+            match parsed.status.as_str() {
+                "OVER_QUERY_LIMIT" => fail(
+                    ErrorCode::QuotaExceeded,
+                    "The API key is over its query limit",
+                ),
+                "REQUEST_DENIED" => fail(ErrorCode::ApiAuth, "The API key was rejected"),
+                _ => (),
+            }
             (index, parsed)
         })
         .collect::<Vec<_>>()
         .await;
     indexed_metadata.sort_unstable_by_key(|&(index, _)| index);
-    indexed_metadata
+    cell_of_point
         .into_iter()
-        .map(|(_, data)| data)
+        .map(|cell| indexed_metadata[cell].1.clone())
         .collect::<Vec<_>>()
 }
 
+/// Location of the preflight metadata request, which has had streetview coverage for years.
+const PREFLIGHT_LOCATION: (f64, f64) = (48.8584, 2.2945);
+
+/// Send a single streetview metadata request to check that the api key is valid and has the
+/// Street View Static API enabled, panicking with the API's explanation if not.
+/// Metadata requests are free, so this costs nothing compared to failing partway through.
+#[tracing::instrument(skip_all)]
+async fn check_api_key() {
+    progress_stage("Checking API key");
+    let url = format!(
+        "https://maps.googleapis.com/maps/api/streetview/metadata?location={},{}&key={}",
+        PREFLIGHT_LOCATION.0,
+        PREFLIGHT_LOCATION.1,
+        CLI_OPTIONS.api_key()
+    );
+    let client = http::client();
+    let (http_status, body) = throttle::send(|| client.get(&url))
+        .await
+        .unwrap_or_else(|e| {
+            fail(
+                ErrorCode::Network,
+                &format!("Could not reach the Street View API: {:?}", e),
+            )
+        });
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap_or_else(|_| {
+        fail(
+            ErrorCode::Network,
+            &format!(
+                "Invalid response from the Street View API ({})",
+                http_status
+            ),
+        )
+    });
+    let status = body["status"].as_str().unwrap_or_default();
+    match status {
+        // Any of these means the key was accepted.
+        "OK" | "ZERO_RESULTS" | "NOT_FOUND" => (),
+        "REQUEST_DENIED" => fail(
+            ErrorCode::ApiAuth,
+            &format!(
+                "The API key was rejected: {}\n\
+                 Check that the key is correct and that the Street View Static API is enabled for its project.",
+                body["error_message"].as_str().unwrap_or("no reason given")
+            ),
+        ),
+        "OVER_QUERY_LIMIT" => fail(
+            ErrorCode::QuotaExceeded,
+            &format!(
+                "The API key is over its query limit: {}",
+                body["error_message"].as_str().unwrap_or("no reason given")
+            ),
+        ),
+        _ => fail(
+            ErrorCode::Network,
+            &format!(
+                "Unexpected Street View API status {} ({}): {}",
+                status,
+                http_status,
+                body["error_message"].as_str().unwrap_or("no reason given")
+            ),
+        ),
+    }
+}
+
 /// Given list of point_bearings and their metadata (expect arrays of same length),
-/// Filter out any points whose metadata is not ok and
+/// Filter out any points whose metadata is not ok, or keep them without a panorama according to
+/// the on_missing option, and
 /// Group together all points that share the same panorama location.
-/// Return point_bearings and metadata by selecting the closest point per panorama id.
+/// Return point_bearings, metadata, and errors by selecting the closest point per panorama id,
+/// without panoramas further from the route than the max_error_meters option.
 fn group_by_location(
     point_bearings: Vec<PointBearing>,
     metadata: Vec<GSVMetadata>,
-) -> (Vec<PointBearing>, Vec<f64>) {
+) -> (Vec<PointBearing>, Vec<GSVMetadata>, Vec<f64>) {
+    let missing_policy = MissingPolicy::from_options();
     let mut grouped_points = vec![vec![]];
     let mut last_pano = None;
-    for (point_bearing, meta) in
-        point_bearings
-            .into_iter()
-            .zip(metadata.into_iter())
-            .filter(|(_, metadata)| {
-                let is_ok = metadata.status == "OK";
-                if !is_ok {
-                    eprintln!("Metadata not ok! {:?}", &metadata);
+    for (point_bearing, meta) in point_bearings
+        .into_iter()
+        .zip(metadata.into_iter())
+        .filter_map(|(point_bearing, metadata)| {
+            if metadata.status == "OK" {
+                return Some((point_bearing, metadata));
+            }
+            debug!("Metadata not ok! {:?}", &metadata);
+            if metadata.status == policy::REQUEST_FAILED_STATUS {
+                return None;
+            }
+            match missing_policy {
+                MissingPolicy::Skip => None,
+                MissingPolicy::Fail => fail(
+                    ErrorCode::FramesMissing,
+                    &format!(
+                        "No streetview imagery at {},{}: {}",
+                        point_bearing.point.lat, point_bearing.point.lng, metadata.status
+                    ),
+                ),
+                // Without a pano id the frame is requested by location, which gives the
+                // placeholder, and the point is never grouped with its neighbors.
+                MissingPolicy::Placeholder => {
+                    let placeholder = GSVMetadata {
+                        location: GSVPoint {
+                            lat: point_bearing.point.lat,
+                            lng: point_bearing.point.lng,
+                        },
+                        status: metadata.status,
+                        ..Default::default()
+                    };
+                    Some((point_bearing, placeholder))
                 }
-                is_ok
-            })
+            }
+        })
     {
         if let Some(last_pano) = last_pano {
-            if last_pano != meta.pano_id {
+            if last_pano != meta.pano_id || meta.pano_id.is_empty() {
                 grouped_points.push(vec![]);
             }
         }
@@ -240,9 +594,66 @@ fn group_by_location(
                 .expect("Could not group streetview points")
         })
         .collect::<Vec<_>>();
+    let best_groups = match CLI_OPTIONS.max_error_meters {
+        Some(max_error) => {
+            let n_groups = best_groups.len();
+            let kept = best_groups
+                .into_iter()
+                .filter(|(_, _, err)| *err <= max_error)
+                .collect::<Vec<_>>();
+            if kept.len() < n_groups {
+                warning(&format!(
+                    "Dropped {} of {} panoramas further than {} meters from the route",
+                    n_groups - kept.len(),
+                    n_groups,
+                    max_error
+                ));
+            }
+            kept
+        }
+        None => best_groups,
+    };
     let errs = best_groups.iter().map(|(_, _, e)| *e).collect::<Vec<_>>();
+    let metadata = best_groups
+        .iter()
+        .map(|(_, m, _)| m.clone())
+        .collect::<Vec<_>>();
     let point_bearings = best_groups.into_iter().map(|(p, _, _)| p).collect::<Vec<_>>();
-    (point_bearings, errs)
+    (point_bearings, metadata, errs)
+}
+
+/// The points of interp_points, generated one at a time instead of collected.
+fn interp_points_lazy(
+    points: &[GPXPoint],
+    factor: usize,
+) -> Box<dyn Iterator<Item = GPXPoint> + '_> {
+    if factor < 2 {
+        return Box::new(points.iter().copied());
+    }
+    Box::new(points.windows(2).flat_map(move |pair| {
+        let (p1, p2) = (pair[0], pair[1]);
+        let p1geo = p1.to_geo_point();
+        let p2geo = p2.to_geo_point();
+        p1geo
+            .haversine_intermediate_fill(
+                &p2geo,
+                p1geo.haversine_distance(&p2geo) / (factor as f64),
+                /* include ends */ false,
+            )
+            .into_iter()
+            .enumerate()
+            .map(move |(i, p)| GPXPoint {
+                lat: p.lat(),
+                lng: p.lng(),
+                ele: p1
+                    .ele
+                    .and_then(|e1| p2.ele.map(|e2| e1 + (e2 - e1) * (i as f64 / factor as f64))),
+                time: p1.time.and_then(|t1| {
+                    p2.time
+                        .map(|t2| t1 + (t2 - t1) * (i as f64 / factor as f64))
+                }),
+            })
+    }))
 }
 
 /// Fill *factor* points between each pair of points in input array.
@@ -251,30 +662,7 @@ fn interp_points(points: Vec<GPXPoint>, factor: usize) -> Vec<GPXPoint> {
     if factor < 2 {
         points
     } else {
-        points
-            .iter()
-            .zip(points.iter().skip(1))
-            .flat_map(move |(p1, p2)| {
-                let p1geo = p1.to_geo_point();
-                let p2geo = p2.to_geo_point();
-                p1geo
-                    .haversine_intermediate_fill(
-                        &p2geo,
-                        p1geo.haversine_distance(&p2geo) / (factor as f64),
-                        /* include ends */ false,
-                    )
-                    .into_iter()
-                    .enumerate()
-                    .map(move |(i, p)| GPXPoint {
-                        lat: p.lat(),
-                        lng: p.lng(),
-                        // Also interp the elevation if given at both endpoints
-                        ele: p1.ele.and_then(|e1| {
-                            p2.ele.map(|e2| e1 + (e2 - e1) * (i as f64 / factor as f64))
-                        }),
-                    })
-            })
-            .collect::<Vec<_>>()
+        interp_points_lazy(&points, factor).collect()
     }
 }
 
@@ -319,6 +707,69 @@ fn get_distance(point1: &GPXPoint, point2: &GPXPoint) -> f64 {
     p1.geodesic_distance(&p2)
 }
 
+/// Error statistics of points, split into the kilometers of the route they fall in.
+/// Kilometers without frames, or whose frames have no error, are left out.
+fn segment_errors(points: &[SerializablePointBearing]) -> Vec<SegmentError> {
+    let mut segments: Vec<SegmentError> = vec![];
+    for ((index, point), distance) in points.iter().enumerate().zip(cumulative_distances(points)) {
+        let error = match point.error {
+            Some(error) => error,
+            None => continue,
+        };
+        let km = (distance / 1000.0) as usize;
+        match segments.last_mut() {
+            Some(segment) if segment.km == km => {
+                segment.averageError +=
+                    (error - segment.averageError) / (segment.frames + 1) as f64;
+                segment.maxError = segment.maxError.max(error);
+                segment.frames += 1;
+            }
+            _ => segments.push(SegmentError {
+                km,
+                firstFrame: index,
+                frames: 1,
+                averageError: error,
+                maxError: error,
+            }),
+        }
+    }
+    segments
+}
+
+/// Average distance in meters from the route to the panoramas of the points which have one.
+fn average_error(points: &[SerializablePointBearing]) -> f64 {
+    let errors = points
+        .iter()
+        .filter_map(|point| point.error)
+        .collect::<Vec<_>>();
+    errors.iter().sum::<f64>() / errors.len().max(1) as f64
+}
+
+/// Recompute the frame count and error statistics of metadata_result after its points changed.
+/// Metadata written before points recorded their error keeps its average error.
+fn update_statistics(metadata_result: &mut MetadataResult) {
+    let points = &metadata_result.gpsPoints;
+    metadata_result.frames = points.len();
+    if points.iter().any(|point| point.error.is_some()) {
+        metadata_result.averageError = average_error(points);
+    }
+    metadata_result.segmentErrors = segment_errors(points);
+}
+
+/// Distance in meters along the points from the first point to each point.
+fn cumulative_distances(points: &[SerializablePointBearing]) -> Vec<f64> {
+    let mut total = 0.0;
+    let mut distances = vec![0.0];
+    for pair in points.windows(2) {
+        let p1 = Point::new(pair[0].lng, pair[0].lat);
+        let p2 = Point::new(pair[1].lng, pair[1].lat);
+        total += p1.geodesic_distance(&p2);
+        distances.push(total);
+    }
+    distances.truncate(points.len());
+    distances
+}
+
 fn find_bearings(points: &[GPXPoint]) -> Vec<PointBearing> {
     let mut results = points
         .par_iter()
@@ -339,7 +790,12 @@ fn find_bearings(points: &[GPXPoint]) -> Vec<PointBearing> {
 }
 
 fn read_gpx<R: std::io::Read>(reader: R) -> ReadResult {
-    let gpx: Gpx = read(reader).expect("Could not read gpx");
+    let gpx: Gpx = read(reader).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!("Could not read gpx: {}", e),
+        )
+    });
     let points = gpx
         .tracks
         .into_iter()
@@ -350,18 +806,712 @@ fn read_gpx<R: std::io::Read>(reader: R) -> ReadResult {
             lat: p.point().lat(),
             lng: p.point().lng(),
             ele: p.elevation,
+            time: p.time.map(|t| t.timestamp_millis() as f64 / 1000.0),
+        })
+        .collect::<Vec<_>>();
+    let waypoints = gpx
+        .waypoints
+        .iter()
+        .filter_map(|w| {
+            Some(GPXWaypoint {
+                name: w.name.clone()?,
+                lat: w.point().lat(),
+                lng: w.point().lng(),
+            })
         })
         .collect::<Vec<_>>();
     // Estimate each point is about 32 bytes
     let size = (points.len() * 32) as u64;
     ReadResult {
         points: points,
+        waypoints,
         name: gpx.metadata.and_then(|m| m.name),
         size: size,
     }
 }
 
-async fn create_video(output_dir: PathBuf, mut metadata_result: MetadataResult) {
+/// Whether the output is an animated GIF converted from the encoded video.
+fn gif_output() -> bool {
+    OutputFormat::from_options() == OutputFormat::Gif
+}
+
+/// Output filename for the final video, defaulting to streetwarp-lapse with the extension of
+/// the codec (or gif for gif output).
+fn output_filename() -> String {
+    let extension = if gif_output() {
+        "gif"
+    } else {
+        Codec::from_options().extension()
+    };
+    CLI_OPTIONS
+        .output
+        .clone()
+        .unwrap_or(format!("streetwarp-lapse.{}", extension))
+}
+
+/// Filename of the encoded video, which for gif output is converted into out_filename at the end.
+fn video_filename(out_filename: &str) -> String {
+    if gif_output() {
+        format!("{}.{}", out_filename, Codec::from_options().extension())
+    } else {
+        out_filename.to_string()
+    }
+}
+
+/// Whether title and end cards are added to the video.
+fn title_cards_enabled() -> bool {
+    CLI_OPTIONS.title.is_some() && !uses_native_encoder()
+}
+
+/// Seconds into the video that the route starts, after the title card if there is one.
+fn route_start_time() -> f64 {
+    if title_cards_enabled() {
+        CLI_OPTIONS.card_duration.unwrap_or(3.0)
+    } else {
+        0.0
+    }
+}
+
+/// Filename to render the video into before title cards are added, or out_filename if none are.
+fn body_filename(out_filename: &str) -> String {
+    if title_cards_enabled() {
+        format!(
+            "{}-body.{}",
+            out_filename,
+            Codec::from_options().extension()
+        )
+    } else {
+        out_filename.to_string()
+    }
+}
+
+/// Add the title and end cards around body_filename into out_filename, if the title option is set.
+async fn add_cards(
+    image_dir: &Path,
+    n_points: usize,
+    size: (u32, u32),
+    body_filename: &str,
+    out_filename: &str,
+    metadata_result: &MetadataResult,
+) {
+    let title = match &CLI_OPTIONS.title {
+        Some(title) if !uses_native_encoder() => title,
+        Some(_) => {
+            warning("The native encoder can not render title cards, ignoring --title");
+            return;
+        }
+        None => return,
+    };
+    progress_stage("Adding title and end cards");
+    overlay::write_card_texts(
+        &image_dir,
+        title,
+        metadata_result.distance,
+        &metadata_result.gpsPoints,
+    );
+    add_title_cards(image_dir, n_points, size, body_filename, out_filename).await;
+}
+
+/// Write a subtitle track with the place and stats of each second of the video next to
+/// out_filename, and embed it into the video if the subtitle_track option is embed.
+async fn add_subtitle_track(
+    image_dir: &Path,
+    points: &[SerializablePointBearing],
+    places: &[geocode::Place],
+    out_filename: &str,
+) {
+    let embed = match CLI_OPTIONS.subtitle_track.as_deref() {
+        None => return,
+        Some("file") => false,
+        Some("embed") => true,
+        Some(mode) => fail(
+            ErrorCode::InputInvalid,
+            &format!("Unknown subtitle track {}, expected file or embed", mode),
+        ),
+    };
+    progress_stage("Writing subtitle track");
+    let offset = route_start_time();
+    let srt_filename = format!("{}.srt", out_filename);
+    overlay::write_srt(
+        &image_dir.join(&srt_filename),
+        &overlay::subtitle_track_cues(points, places, offset),
+    );
+    if embed && uses_native_encoder() {
+        warning("The native encoder can not embed subtitles, leaving them in a separate file");
+    } else if embed {
+        embed_subtitles(image_dir, out_filename, &srt_filename).await;
+    }
+}
+
+/// Write the location of every frame of out_filename to a telemetry subtitle file next to it
+/// and embed it as a subtitle track of the video, if the gps_subtitles option is set.
+async fn add_gps_subtitles(
+    image_dir: &Path,
+    points: &[SerializablePointBearing],
+    out_filename: &str,
+) {
+    if !CLI_OPTIONS.gps_subtitles {
+        return;
+    }
+    progress_stage("Writing GPS subtitle track");
+    let telemetry_filename = format!("{}-telemetry.srt", out_filename);
+    telemetry::write_telemetry(
+        &image_dir.join(&telemetry_filename),
+        points,
+        route_start_time(),
+    );
+    if uses_native_encoder() {
+        warning("The native encoder can not embed telemetry, leaving it in a separate file");
+        return;
+    }
+    let subtitle_streams = if CLI_OPTIONS.subtitle_track.as_deref() == Some("embed") {
+        1
+    } else {
+        0
+    };
+    embed_gps_subtitles(
+        image_dir,
+        out_filename,
+        &telemetry_filename,
+        subtitle_streams,
+    )
+    .await;
+}
+
+/// Geotag out_filename with the route's start or middle point, as given by the geotag option.
+async fn add_geotag(image_dir: &Path, points: &[SerializablePointBearing], out_filename: &str) {
+    let point = match CLI_OPTIONS.geotag.as_deref() {
+        None => return,
+        Some("start") => points.first(),
+        Some("middle") => points.get(points.len() / 2),
+        Some(mode) => fail(
+            ErrorCode::InputInvalid,
+            &format!("Unknown geotag {}, expected start or middle", mode),
+        ),
+    };
+    let point = match point {
+        Some(point) => point,
+        None => return,
+    };
+    if uses_native_encoder() {
+        warning("The native encoder can not geotag the video, ignoring geotag");
+        return;
+    }
+    let elevation = point
+        .ele
+        .map_or(String::new(), |ele| format!("{:+.3}", ele));
+    let location = format!("{:+08.4}{:+09.4}{}/", point.lat, point.lng, elevation);
+    progress(&format!("Geotagging video at {}", location));
+    add_location(image_dir, out_filename, &location).await;
+}
+
+/// Convert video_filename into the animated GIF out_filename if the format option is gif.
+async fn convert_to_gif(
+    image_dir: &Path,
+    n_points: usize,
+    video_filename: &str,
+    out_filename: &str,
+) {
+    if !gif_output() {
+        return;
+    }
+    if uses_native_encoder() {
+        warning(&format!(
+            "The native encoder can not create GIFs, leaving the video at {}",
+            video_filename
+        ));
+        return;
+    }
+    progress_stage("Converting video to GIF");
+    let duration = output_duration(n_points) + 2.0 * route_start_time();
+    create_gif(image_dir, video_filename, duration, out_filename).await;
+}
+
+/// Save a representative frame of video_filename next to out_filename (as .jpg) if the thumbnail
+/// option is set, choosing among the frames matching pattern. Return the thumbnail's filename.
+async fn add_thumbnail(
+    image_dir: &Path,
+    pattern: &str,
+    n_points: usize,
+    video_filename: &str,
+    out_filename: &str,
+) -> Option<String> {
+    if !CLI_OPTIONS.thumbnail {
+        return None;
+    }
+    progress_stage("Creating thumbnail");
+    let index = thumbnail::thumbnail_frame(image_dir, pattern, n_points);
+    let thumbnail_filename = Path::new(out_filename)
+        .with_extension("jpg")
+        .to_string_lossy()
+        .to_string();
+    if uses_native_encoder() {
+        // Without ffmpeg the frame can't be read back from the video, so use the source image.
+        let frame = image_dir.join(pattern.replace("%d", &index.to_string()));
+        fs::copy(&frame, image_dir.join(&thumbnail_filename)).unwrap_or_else(|e| {
+            fail(
+                ErrorCode::Io,
+                &format!("Could not copy {:?} to thumbnail: {:?}", frame, e),
+            )
+        });
+    } else {
+        let time = route_start_time() + index as f64 / input_framerate(n_points);
+        extract_frame(image_dir, video_filename, time, &thumbnail_filename).await;
+    }
+    Some(thumbnail_filename)
+}
+
+/// Add the audio option's file to video_filename as its soundtrack, if it is set.
+async fn add_music(image_dir: &Path, n_points: usize, video_filename: &str) {
+    let audio = match &CLI_OPTIONS.audio {
+        Some(audio) => audio,
+        None => return,
+    };
+    if uses_native_encoder() {
+        warning("The native encoder can not add audio, ignoring --audio");
+        return;
+    }
+    if gif_output() {
+        warning("GIFs have no audio, ignoring --audio");
+        return;
+    }
+    progress_stage("Adding audio track");
+    // ffmpeg runs in image_dir, so relative paths would not resolve.
+    let audio = fs::canonicalize(audio).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!("Could not find audio file {:?}: {:?}", audio, e),
+        )
+    });
+    let duration = output_duration(n_points) + 2.0 * route_start_time();
+    let fade = CLI_OPTIONS.audio_fade.unwrap_or(3.0).min(duration);
+    let start = beats::audio_start(route_start_time());
+    let loudness = if CLI_OPTIONS.loudnorm {
+        Some(CLI_OPTIONS.loudness_target.unwrap_or(-14.0))
+    } else {
+        None
+    };
+    add_audio(
+        image_dir,
+        video_filename,
+        &audio,
+        start,
+        duration,
+        fade,
+        loudness,
+    )
+    .await;
+}
+
+/// Add the tracks and metadata that go with the route into the video at video_filename, then
+/// convert it to out_filename if it is a different format.
+#[tracing::instrument(skip_all)]
+async fn finish_video(
+    image_dir: &Path,
+    metadata_result: &MetadataResult,
+    places: &[geocode::Place],
+    video_filename: &str,
+    out_filename: &str,
+) {
+    let points = &metadata_result.gpsPoints;
+    add_subtitle_track(image_dir, points, places, video_filename).await;
+    add_waypoint_chapters(image_dir, metadata_result, video_filename).await;
+    add_gps_subtitles(image_dir, points, video_filename).await;
+    add_geotag(image_dir, points, video_filename).await;
+    add_music(image_dir, points.len(), video_filename).await;
+    if OutputFormat::from_options() == OutputFormat::Webm && CLI_OPTIONS.audio.is_none() {
+        if uses_native_encoder() {
+            warning("The native encoder can not add a silent audio track to WebM output");
+        } else {
+            add_silent_audio(image_dir, video_filename).await;
+        }
+    }
+    convert_to_gif(image_dir, points.len(), video_filename, out_filename).await;
+}
+
+/// Add a chapter marker to out_filename at each waypoint of the input GPX that is on the route,
+/// if the chapters option is set.
+async fn add_waypoint_chapters(
+    image_dir: &Path,
+    metadata_result: &MetadataResult,
+    out_filename: &str,
+) {
+    if metadata_result.waypoints.is_empty() || !CLI_OPTIONS.chapters {
+        return;
+    }
+    if uses_native_encoder() {
+        warning("The native encoder can not add chapters, ignoring GPX waypoints");
+        return;
+    }
+    progress_stage("Adding chapters at waypoints");
+    let points = &metadata_result.gpsPoints;
+    let offset = route_start_time();
+    let metadata_filename = format!("{}-chapters.txt", out_filename);
+    let chapter_count = chapters::write_chapters(
+        &image_dir.join(&metadata_filename),
+        &metadata_result.waypoints,
+        points,
+        offset,
+        output_duration(points.len()) + 2.0 * offset,
+    );
+    if chapter_count == 0 {
+        progress("No waypoints are on the route, skipping chapters");
+        return;
+    }
+    add_chapters(image_dir, out_filename, &metadata_filename).await;
+}
+
+/// Smooth the timelapse at original_filename into out_filename according to the stabilize and
+/// minterp options. Both filenames are relative to image_dir.
+#[tracing::instrument(skip_all)]
+async fn apply_minterp(
+    image_dir: &Path,
+    n_points: usize,
+    original_filename: &str,
+    out_filename: &str,
+) {
+    let stabilized_filename = format!("{}-stabilized.mp4", out_filename);
+    let original_filename = if CLI_OPTIONS.stabilize && !uses_native_encoder() {
+        progress_stage("Stabilizing video");
+        stabilize_timelapse(image_dir, n_points, original_filename, &stabilized_filename).await;
+        &stabilized_filename
+    } else {
+        original_filename
+    };
+    match minterp_mode() {
+        "skip" if uses_target_bitrate() && !uses_native_encoder() => {
+            progress_stage("Encoding to target bitrate");
+            reencode_timelapse(image_dir, n_points, original_filename, out_filename).await
+        }
+        "skip" => {
+            let result = tokio::fs::rename(
+                image_dir.join(original_filename),
+                image_dir.join(out_filename),
+            )
+            .await;
+            result.expect("Could not rename video files");
+        }
+        "fast" => {
+            progress_stage("Blending frames to apply blur");
+            blend_timelapse(image_dir, n_points, original_filename, out_filename).await
+        }
+        "external" => {
+            progress_stage("Interpolating motion with the minterp command");
+            external_minterp_timelapse(image_dir, n_points, original_filename, out_filename).await
+        }
+        _ => {
+            progress_stage("Interpolating motion to apply blur");
+            minterp_timelapse(image_dir, n_points, original_filename, out_filename).await
+        }
+    };
+}
+
+/// Reverse geocode the points if an overlay needs their places, otherwise return no places.
+async fn geocode_for_overlays(points: &[SerializablePointBearing]) -> Vec<geocode::Place> {
+    if CLI_OPTIONS.street_names
+        || CLI_OPTIONS.entering_labels
+        || CLI_OPTIONS.subtitle_track.is_some()
+    {
+        progress_stage("Looking up street and town names");
+        geocode::geocode_points(points).await
+    } else {
+        vec![]
+    }
+}
+
+/// One part of a comparison video: frames of the same length as the others, rendered in
+/// the subdirectory name.
+struct Panel {
+    name: String,
+    points: Vec<SerializablePointBearing>,
+    places: Vec<geocode::Place>,
+}
+
+/// A panel per year in compare_years, with the panoramas of the route closest to that year.
+async fn year_panels(metadata_result: &MetadataResult) -> Vec<Panel> {
+    let places = geocode_for_overlays(&metadata_result.gpsPoints).await;
+    let mut panels = vec![];
+    for &year in &CLI_OPTIONS.compare_years {
+        progress_stage(&format!("Selecting panoramas closest to {}", year));
+        panels.push(Panel {
+            name: year.to_string(),
+            points: history::select_historical_panos(&metadata_result.gpsPoints, year).await,
+            places: places.clone(),
+        });
+    }
+    panels
+}
+
+/// Pick the n points closest to n positions evenly spaced by distance along the route of points,
+/// so routes of different lengths advance by the same fraction of their distance every frame.
+fn resample_by_distance(
+    points: &[SerializablePointBearing],
+    n: usize,
+) -> Vec<SerializablePointBearing> {
+    let distances = cumulative_distances(points);
+    let total = distances.last().copied().unwrap_or(0.0);
+    (0..n)
+        .map(|i| {
+            let target = total * i as f64 / (n - 1).max(1) as f64;
+            let index = match distances.binary_search_by(|d| d.partial_cmp(&target).unwrap()) {
+                Ok(index) => index,
+                Err(0) => 0,
+                Err(index) if index == points.len() => index - 1,
+                // Take whichever neighbor is closer to the target distance.
+                Err(index) if distances[index] - target < target - distances[index - 1] => index,
+                Err(index) => index - 1,
+            };
+            points[index]
+        })
+        .collect()
+}
+
+/// A panel for the main route and one for the compare_route, both advancing by the same
+/// fraction of their length every frame.
+async fn route_panels(metadata_result: &MetadataResult, other: &MetadataResult) -> Vec<Panel> {
+    let n_points = metadata_result.gpsPoints.len();
+    progress(&format!(
+        "Comparing {:.1} km route with {:.1} km route over {} frames",
+        metadata_result.distance / 1000.0,
+        other.distance / 1000.0,
+        n_points
+    ));
+    // The points of the main route are not evenly spaced either once grouped by panorama.
+    let points = resample_by_distance(&metadata_result.gpsPoints, n_points);
+    let other_points = resample_by_distance(&other.gpsPoints, n_points);
+    vec![
+        Panel {
+            name: "route-1".to_string(),
+            places: geocode_for_overlays(&points).await,
+            points,
+        },
+        Panel {
+            name: "route-2".to_string(),
+            places: geocode_for_overlays(&other_points).await,
+            points: other_points,
+        },
+    ]
+}
+
+/// Render each panel in its own subdirectory and stack the results into one video.
+/// Every panel uses the same frame count, so dedup and the optimizer are not applied in this mode.
+async fn create_comparison_video(
+    output_dir: PathBuf,
+    metadata_result: MetadataResult,
+    mut panels: Vec<Panel>,
+) {
+    // Frames without imagery in any panel, dropped from every panel by --on-missing skip so
+    // that the panels stay in step.
+    let mut placeholders = vec![];
+    for panel in &panels {
+        let panel_dir = output_dir.join(&panel.name);
+        fs::create_dir_all(&panel_dir).unwrap_or_else(|e| {
+            fail(
+                ErrorCode::Io,
+                &format!("Could not create {:?}: {:?}", panel_dir, e),
+            )
+        });
+        progress_stage(&format!(
+            "Fetching images from Streetview for {}",
+            panel.name
+        ));
+        get_images(&panel.points, &panel_dir).await;
+        placeholders.extend(validate::validate_images(&panel.points, &panel_dir).await);
+    }
+    placeholders.sort_unstable();
+    placeholders.dedup();
+    for panel in &mut panels {
+        let panel_dir = output_dir.join(&panel.name);
+        let n_points = panel.points.len();
+        panel.points = validate::drop_placeholders(
+            std::mem::take(&mut panel.points),
+            &panel_dir,
+            &placeholders,
+        );
+        if panel.points.len() < n_points && !panel.places.is_empty() {
+            panel.places = std::mem::take(&mut panel.places)
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| placeholders.binary_search(i).is_err())
+                .map(|(_, place)| place)
+                .collect();
+        }
+    }
+
+    let n_points = panels[0].points.len();
+    let mut panel_filenames = vec![];
+    for panel in &panels {
+        let panel_dir = output_dir.join(&panel.name);
+        let original_filename = format!("{}-original.mp4", panel.name);
+        let panel_filename = format!("{}.mp4", panel.name);
+        progress_stage(&format!(
+            "Joining {} images into video sequence for {}",
+            n_points, panel.name
+        ));
+        overlay::write_overlay_files(&panel_dir, &panel.points, &panel.places);
+        create_timelapse(&panel_dir, n_points, "%d.jpg", &original_filename).await;
+        apply_minterp(&panel_dir, n_points, &original_filename, &panel_filename).await;
+        panel_filenames.push(format!("{}/{}", panel.name, panel_filename));
+    }
+
+    let output_timelapse_name = &output_filename();
+    let vertical = matches!(
+        CLI_OPTIONS
+            .compare_layout
+            .clone()
+            .unwrap_or("side".to_string())
+            .as_str(),
+        "stack"
+    );
+    progress_stage("Combining panels into comparison video");
+    let video_name = video_filename(output_timelapse_name);
+    let body_name = body_filename(&video_name);
+    stack_timelapses(
+        &output_dir,
+        n_points,
+        &panel_filenames.iter().map(|f| f.as_str()).collect::<Vec<_>>(),
+        vertical,
+        &body_name,
+    )
+    .await;
+    let (width, height) = video_size();
+    let size = if vertical {
+        (width, height * 2)
+    } else {
+        (width * 2, height)
+    };
+    add_cards(
+        &output_dir,
+        n_points,
+        size,
+        &body_name,
+        &video_name,
+        &metadata_result,
+    )
+    .await;
+    finish_video(
+        &output_dir,
+        &metadata_result,
+        &panels[0].places,
+        &video_name,
+        output_timelapse_name,
+    )
+    .await;
+    let first_panel_pattern = format!("{}/%d.jpg", panels[0].name);
+    let thumbnail_name = add_thumbnail(
+        &output_dir,
+        &first_panel_pattern,
+        n_points,
+        &video_name,
+        output_timelapse_name,
+    )
+    .await;
+    let mut archive_files = panels
+        .iter()
+        .flat_map(|panel| (0..n_points).map(move |index| format!("{}/{}.jpg", panel.name, index)))
+        .collect::<Vec<_>>();
+    archive_files.push(output_timelapse_name.to_string());
+    archive_files.extend(thumbnail_name);
+    archive::write_archive(&output_dir, &archive_files, &metadata_result);
+    let dir_size = get_size(&output_dir).unwrap_or(0);
+    progress(&format!(
+        "Created video, total output size: {:.2} MB",
+        (dir_size as f64) / 1000000.0
+    ));
+}
+
+/// Evenly drop points so there is at most one per output frame of a video lasting duration seconds,
+/// leaving room for the frames held at waypoints.
+fn pace_to_duration(
+    points: Vec<SerializablePointBearing>,
+    waypoints: &[GPXWaypoint],
+    duration: f64,
+) -> Vec<SerializablePointBearing> {
+    let held_frames = pacing::dwell_frames(&points, waypoints);
+    let max_points = ((duration * fps() as f64).ceil() as usize)
+        .saturating_sub(held_frames)
+        .max(2);
+    if points.len() <= max_points {
+        return points;
+    }
+    progress(&format!(
+        "Keeping {} of {} frames to last {} seconds",
+        max_points,
+        points.len(),
+        duration
+    ));
+    let step = (points.len() - 1) as f64 / (max_points - 1) as f64;
+    (0..max_points)
+        .map(|i| points[(i as f64 * step).round() as usize])
+        .collect()
+}
+
+/// Default number of frames rendered by the compare_minterp option.
+const COMPARE_MINTERP_FRAMES: usize = 150;
+
+/// Render the points of metadata_result with every minterp mode and put the results side by
+/// side, so the modes can be compared on the same frames.
+async fn create_minterp_comparison(output_dir: PathBuf, metadata_result: MetadataResult) {
+    let points = &metadata_result.gpsPoints;
+    progress_stage("Fetching images from Streetview");
+    get_images(points, &output_dir).await;
+    let placeholders = validate::validate_images(points, &output_dir).await;
+    let points =
+        &validate::drop_placeholders(metadata_result.gpsPoints, &output_dir, &placeholders);
+    let n_points = points.len();
+    let places = geocode_for_overlays(points).await;
+    overlay::write_overlay_files(&output_dir, points, &places);
+
+    progress_stage(&format!("Joining {} images into video sequence", n_points));
+    let skip_filename = "minterp-skip.mp4";
+    let fast_filename = "minterp-fast.mp4";
+    let good_filename = "minterp-good.mp4";
+    create_timelapse(&output_dir, n_points, "%d.jpg", skip_filename).await;
+    progress_stage("Blending frames to apply blur");
+    blend_timelapse(&output_dir, n_points, skip_filename, fast_filename).await;
+    progress_stage("Interpolating motion to apply blur");
+    minterp_timelapse(&output_dir, n_points, skip_filename, good_filename).await;
+
+    progress_stage("Combining minterp modes into comparison video");
+    let output_timelapse_name = video_filename(&output_filename());
+    stack_labeled_timelapses(
+        &output_dir,
+        n_points,
+        &[
+            ("skip", skip_filename),
+            ("fast", fast_filename),
+            ("good", good_filename),
+        ],
+        &output_timelapse_name,
+    )
+    .await;
+    progress(&format!(
+        "Created minterp comparison of {} frames in {}",
+        n_points, output_timelapse_name
+    ));
+}
+
+/// File in the output directory of the fetch stage holding the points of the fetched images.
+const FETCHED_METADATA_FILE: &str = "streetwarp-fetched.json";
+
+/// Create the video of metadata_result in output_dir, side by side with compare_route if given,
+/// and return the metadata of the frames it shows. The fetch stage stops once the images are
+/// fetched.
+#[tracing::instrument(skip_all)]
+async fn create_video(
+    output_dir: PathBuf,
+    mut metadata_result: MetadataResult,
+    compare_route: Option<MetadataResult>,
+) -> MetadataResult {
+    let is_comparison = CLI_OPTIONS.compare_minterp
+        || !CLI_OPTIONS.compare_years.is_empty()
+        || compare_route.is_some();
+    if CLI_OPTIONS.stage() == Stage::Fetch && is_comparison {
+        fail(
+            ErrorCode::InputInvalid,
+            "fetch does not support comparison videos, use run instead",
+        );
+    }
     // Remove first offset frames from gps points
     metadata_result
         .gpsPoints
@@ -370,21 +1520,190 @@ async fn create_video(output_dir: PathBuf, mut metadata_result: MetadataResult)
     metadata_result
         .gpsPoints
         .truncate(CLI_OPTIONS.max_frames.unwrap_or(metadata_result.frames));
+    if CLI_OPTIONS.compare_minterp {
+        metadata_result
+            .gpsPoints
+            .truncate(CLI_OPTIONS.max_frames.unwrap_or(COMPARE_MINTERP_FRAMES));
+        create_minterp_comparison(output_dir, metadata_result.clone()).await;
+        return metadata_result;
+    }
+    if let Some(duration) = CLI_OPTIONS.target_duration {
+        metadata_result.gpsPoints = pace_to_duration(
+            metadata_result.gpsPoints,
+            &metadata_result.waypoints,
+            duration,
+        );
+    }
+    if CLI_OPTIONS.preview {
+        let step = CLI_OPTIONS.preview_step.unwrap_or(5).max(1);
+        progress(&format!(
+            "Preview: keeping every {} of {} frames",
+            step,
+            metadata_result.gpsPoints.len()
+        ));
+        metadata_result.gpsPoints = metadata_result
+            .gpsPoints
+            .into_iter()
+            .step_by(step)
+            .collect();
+    }
+    if let Some(frames_per_beat) = beats::frames_per_beat() {
+        progress_stage("Aligning scene changes to the beat");
+        metadata_result.gpsPoints =
+            beats::align_scene_changes(metadata_result.gpsPoints, frames_per_beat);
+    }
+    if is_comparison {
+        if OutputFormat::from_options() == OutputFormat::Frames {
+            fail(
+                ErrorCode::InputInvalid,
+                "--format frames can not be used with comparison videos",
+            );
+        }
+        if CLI_OPTIONS.contact_sheet.is_some() {
+            warning("--contact-sheet is not supported with comparison videos, ignoring it");
+        }
+        if CLI_OPTIONS.pace.is_some() || CLI_OPTIONS.waypoint_dwell.is_some() {
+            warning(
+                "--pace and --waypoint-dwell are not supported with comparison videos, ignoring them",
+            );
+        }
+        let panels = match compare_route {
+            Some(other) => {
+                let panels = route_panels(&metadata_result, &other).await;
+                // The video shows the resampled points of the main route.
+                metadata_result.gpsPoints = panels[0].points.clone();
+                panels
+            }
+            None => year_panels(&metadata_result).await,
+        };
+        create_comparison_video(output_dir, metadata_result.clone(), panels).await;
+        return metadata_result;
+    }
+    // Paced before fetching so that images of points no frame shows are not paid for.
+    metadata_result.gpsPoints =
+        pacing::pace_points(metadata_result.gpsPoints, &metadata_result.waypoints);
+    if pipeline::is_enabled() {
+        // The images are fetched while encoding the timelapse.
+        export::write_frame_sidecars(&metadata_result.gpsPoints, &output_dir);
+        return encode_video(output_dir, metadata_result, true).await;
+    }
+    start_phase(Phase::Download);
     progress_stage("Fetching images from Streetview");
     get_images(&metadata_result.gpsPoints, &output_dir).await;
-    let dir_size = get_size(&output_dir).unwrap_or(0);
-    let dir_files = get_dir_content(&output_dir)
-        .map(|d| d.files.len())
-        .unwrap_or(0);
-    progress(&format!(
-        "Fetched {} images, output size: {:.2} MB",
-        dir_files,
-        (dir_size as f64) / 1000000.0
-    ));
+    let placeholders = validate::validate_images(&metadata_result.gpsPoints, &output_dir).await;
+    metadata_result.gpsPoints =
+        validate::drop_placeholders(metadata_result.gpsPoints, &output_dir, &placeholders);
+    export::write_frame_sidecars(&metadata_result.gpsPoints, &output_dir);
+    // Written in every stage so that a cancelled run can resume from encoding.
+    write_fetched_metadata(&output_dir, &metadata_result);
+    if CLI_OPTIONS.stage() == Stage::Fetch {
+        progress(&format!(
+            "Fetched {} images, encode them with: streetwarp encode {}",
+            metadata_result.gpsPoints.len(),
+            output_dir.to_string_lossy()
+        ));
+        update_statistics(&mut metadata_result);
+        return metadata_result;
+    }
+    encode_video(output_dir, metadata_result, false).await
+}
 
-    let n_points = if CLI_OPTIONS.optimizer.is_some() {
+/// Write metadata_result next to its fetched images in output_dir, for the encode stage.
+fn write_fetched_metadata(output_dir: &Path, metadata_result: &MetadataResult) {
+    let path = output_dir.join(FETCHED_METADATA_FILE);
+    let file = File::create(&path).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Io,
+            &format!("Could not create {:?}: {:?}", path, e),
+        )
+    });
+    serde_json::to_writer(BufWriter::new(file), metadata_result)
+        .expect("Could not write fetched metadata");
+}
+
+/// Read the metadata written by write_fetched_metadata in image_dir.
+fn read_fetched_metadata(image_dir: &Path) -> MetadataResult {
+    let path = image_dir.join(FETCHED_METADATA_FILE);
+    let file = File::open(&path).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!(
+                "Could not open {:?}, was the directory written by streetwarp fetch? {:?}",
+                path, e
+            ),
+        )
+    });
+    schema::read_metadata_result(BufReader::new(file), "fetched metadata")
+}
+
+/// Whether a step after optimizing reads the frames as a numbered sequence of files, which
+/// needs the optimizer's kept frames renamed to {index}.opt.jpg.
+fn reads_frame_sequence() -> bool {
+    #[cfg(feature = "scripting")]
+    let scripted = CLI_OPTIONS.script.is_some();
+    #[cfg(not(feature = "scripting"))]
+    let scripted = false;
+    scripted
+        || CLI_OPTIONS.contact_sheet.is_some()
+        || CLI_OPTIONS.pace.is_some()
+        || CLI_OPTIONS.waypoint_dwell.is_some()
+        || CLI_OPTIONS.minimap
+        || CLI_OPTIONS.thumbnail
+        || CLI_OPTIONS.archive.is_some()
+        || OutputFormat::from_options() == OutputFormat::Frames
+        || uses_native_encoder()
+}
+
+/// Create the video from the images of metadata_result already fetched into output_dir, or if
+/// pipelined, fetch them while encoding the timelapse. Return the metadata of the frames the
+/// video shows, after removing duplicates, optimizing and pacing them.
+#[tracing::instrument(skip_all)]
+async fn encode_video(
+    output_dir: PathBuf,
+    mut metadata_result: MetadataResult,
+    pipelined: bool,
+) -> MetadataResult {
+    if CLI_OPTIONS.dedup {
+        progress_stage("Removing duplicate frames");
+        let kept_points = dedup::dedup_frames(&output_dir, metadata_result.gpsPoints.len());
+        metadata_result.gpsPoints = kept_points
+            .iter()
+            .map(|&i| metadata_result.gpsPoints[i])
+            .collect::<Vec<_>>();
+    }
+    if !pipelined {
+        let dir_size = get_size(&output_dir).unwrap_or(0);
+        let dir_files = get_dir_content(&output_dir)
+            .map(|d| d.files.len())
+            .unwrap_or(0);
+        progress(&format!(
+            "Fetched {} images, output size: {:.2} MB",
+            dir_files,
+            (dir_size as f64) / 1000000.0
+        ));
+    }
+    if CLI_OPTIONS.frame_hook.is_some() {
+        progress_stage("Running frame hook on each image");
+        store::detach_frames(&output_dir, metadata_result.gpsPoints.len());
+        hooks::run_frame_hooks(&output_dir, &metadata_result.gpsPoints).await;
+    }
+    if CLI_OPTIONS.color_match {
+        progress_stage("Matching colors between frames");
+        colormatch::match_colors(&output_dir, metadata_result.gpsPoints.len());
+    }
+
+    let image2pipe = CLI_OPTIONS.image2pipe && !uses_native_encoder();
+    // Original indices of the optimizer's kept frames when they are piped without renaming.
+    let mut piped_frames = None;
+    let n_points = if optim::is_enabled() {
+        start_phase(Phase::Optimize);
         progress_stage("Optimizing image sequence (removing inconsistencies)");
-        let kept_points = optim::optimize_sequence(&output_dir).await;
+        let rename = !image2pipe || reads_frame_sequence();
+        let kept_points =
+            optim::optimize_sequence(&output_dir, &metadata_result.gpsPoints, rename).await;
+        if !rename {
+            piped_frames = Some(kept_points.clone());
+        }
         metadata_result.gpsPoints = kept_points
             .iter()
             .map(|&i| metadata_result.gpsPoints[i])
@@ -413,159 +1732,451 @@ async fn create_video(output_dir: PathBuf, mut metadata_result: MetadataResult)
             .unwrap_or("streetwarp-lapse".to_string())
     );
 
-    progress_stage(&format!("Joining {} images into video sequence", n_points));
-    create_timelapse(&output_dir, n_points, &original_timelapse_name).await;
-    let output_timelapse_name = &CLI_OPTIONS
-        .output
-        .clone()
-        .unwrap_or("streetwarp-lapse.mp4".to_string());
-
-    match CLI_OPTIONS
-        .minterp
-        .clone()
-        .unwrap_or("good".to_string())
-        .as_str()
-    {
-        "skip" => {
-            let result = tokio::fs::rename(&original_timelapse_name, &output_timelapse_name).await;
-            result.expect("Could not rename video files");
-        }
-        "fast" => {
-            progress_stage("Blending frames to apply blur");
-            blend_timelapse(
-                &output_dir,
-                n_points,
-                &original_timelapse_name,
-                &output_timelapse_name,
-            )
-            .await
+    let pattern = if optim::is_enabled() {
+        "%d.opt.jpg"
+    } else {
+        "%d.jpg"
+    };
+    if let Some(grid) = &CLI_OPTIONS.contact_sheet {
+        progress_stage("Creating contact sheet");
+        let sheet_filename = format!(
+            "{}-contact-sheet.jpg",
+            Path::new(&output_filename())
+                .file_stem()
+                .map_or("streetwarp-lapse".into(), |stem| stem.to_string_lossy())
+        );
+        contactsheet::write_contact_sheet(
+            &output_dir,
+            pattern,
+            n_points,
+            grid,
+            &output_dir.join(sheet_filename),
+        );
+        if CLI_OPTIONS.contact_sheet_only {
+            update_statistics(&mut metadata_result);
+            return metadata_result;
         }
-        _ => {
-            progress_stage("Interpolating motion to apply blur");
-            minterp_timelapse(
-                &output_dir,
-                n_points,
-                &original_timelapse_name,
-                &output_timelapse_name,
-            )
-            .await
+    }
+    let paced = pacing::apply_pacing(
+        &output_dir,
+        pattern,
+        &metadata_result.gpsPoints,
+        &metadata_result.waypoints,
+    );
+    let (pattern, holds) = match paced {
+        Some(paced) => {
+            metadata_result.gpsPoints = paced.points;
+            (pacing::PACED_PATTERN, paced.holds)
         }
+        None => (pattern, vec![]),
     };
+    update_statistics(&mut metadata_result);
+    let n_points = metadata_result.gpsPoints.len();
+    if CLI_OPTIONS.ken_burns && !holds.is_empty() {
+        progress_stage("Zooming held frames");
+        kenburns::zoom_holds(&output_dir, pattern, &holds);
+    }
+    if CLI_OPTIONS.minimap {
+        progress_stage("Drawing minimap on each frame");
+        minimap::add_minimaps(&output_dir, pattern, &metadata_result.gpsPoints);
+    }
+
+    if OutputFormat::from_options() == OutputFormat::Frames {
+        progress_stage(&format!("Exporting {} frames", n_points));
+        let files = frames::export_frames(&output_dir, pattern, &metadata_result.gpsPoints).await;
+        archive::write_archive(&output_dir, &files, &metadata_result);
+        return metadata_result;
+    }
+
+    progress_stage(&format!("Joining {} images into video sequence", n_points));
+    let places = geocode_for_overlays(&metadata_result.gpsPoints).await;
+    overlay::write_overlay_files(&output_dir, &metadata_result.gpsPoints, &places);
+    overlay::write_dwell_labels(&output_dir, &holds, n_points);
+    if pipelined {
+        // Downloads and encoding overlap, so count both as downloading.
+        start_phase(Phase::Download);
+        progress_stage(&format!(
+            "Fetching and encoding {} images from Streetview",
+            n_points
+        ));
+        pipeline::fetch_and_encode(
+            &output_dir,
+            &metadata_result.gpsPoints,
+            &original_timelapse_name,
+        )
+        .await;
+        write_fetched_metadata(&output_dir, &metadata_result);
+    } else if image2pipe {
+        start_phase(Phase::Encode);
+        let frames = (0..n_points)
+            .map(|index| match &piped_frames {
+                Some(kept) => output_dir.join(format!("{}.jpg", kept[index])),
+                None => output_dir.join(pattern.replace("%d", &index.to_string())),
+            })
+            .collect::<Vec<_>>();
+        create_timelapse_piped(&output_dir, &frames, &original_timelapse_name).await;
+    } else {
+        start_phase(Phase::Encode);
+        create_timelapse(&output_dir, n_points, pattern, &original_timelapse_name).await;
+    }
+    let output_timelapse_name = &output_filename();
+    let video_name = video_filename(output_timelapse_name);
+    let body_name = body_filename(&video_name);
+    start_phase(Phase::Interpolate);
+    apply_minterp(&output_dir, n_points, &original_timelapse_name, &body_name).await;
+    add_cards(
+        &output_dir,
+        n_points,
+        video_size(),
+        &body_name,
+        &video_name,
+        &metadata_result,
+    )
+    .await;
+    finish_video(
+        &output_dir,
+        &metadata_result,
+        &places,
+        &video_name,
+        output_timelapse_name,
+    )
+    .await;
+    let thumbnail_name = add_thumbnail(
+        &output_dir,
+        pattern,
+        n_points,
+        &video_name,
+        output_timelapse_name,
+    )
+    .await;
+    let frame_names = (0..n_points)
+        .map(|index| pattern.replace("%d", &index.to_string()))
+        .collect::<Vec<_>>();
+    #[cfg(feature = "scripting")]
+    let frame_names = match scripting::Script::load() {
+        Some(script) => script.rename_frames(&output_dir, pattern, &metadata_result.gpsPoints),
+        None => frame_names,
+    };
+    let mut archive_files = frame_names;
+    archive_files.push(output_timelapse_name.to_string());
+    archive_files.extend(thumbnail_name);
+    archive::write_archive(&output_dir, &archive_files, &metadata_result);
     let dir_size = get_size(&output_dir).unwrap_or(0);
     progress(&format!(
         "Created video, total output size: {:.2} MB",
         (dir_size as f64) / 1000000.0
     ));
+    metadata_result
 }
 
-#[tokio::main]
-async fn main() {
-    lazy_static::initialize(&CLI_OPTIONS);
-
-    let file = File::open(&CLI_OPTIONS.input_path).unwrap();
-    let reader = BufReader::new(file);
-
-    let output_dir = CLI_OPTIONS
-        .output_dir
-        .as_ref()
-        .map(|o| PathBuf::from(o))
-        .unwrap_or_else(|| {
-            let start = SystemTime::now();
-            let now = start
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards");
-            env::temp_dir().join(format!("streetwarp-tmp-{}", now.as_secs()))
-        });
-    fs::create_dir_all(&output_dir).expect("Could not open output directory");
-    if !CLI_OPTIONS.json {
-        println!("output dir is {}", output_dir.to_string_lossy());
+/// Apply the user's choices of panorama date and script filters to the selected points.
+async fn adjust_points(mut points: Vec<SerializablePointBearing>) -> Vec<SerializablePointBearing> {
+    if let Some(target_year) = CLI_OPTIONS.pano_date_target {
+        progress_stage(&format!("Selecting panoramas closest to {}", target_year));
+        points = history::dedup_panos(history::select_historical_panos(&points, target_year).await);
     }
-
-    if CLI_OPTIONS.use_metadata {
-        progress_stage("Parsing metadata");
-        let metadata_result: MetadataResult =
-            serde_json::from_reader(reader).expect("Could not parse submitted metadata result");
-        create_video(output_dir, metadata_result).await;
-        return;
+    #[cfg(feature = "scripting")]
+    {
+        if let Some(script) = scripting::Script::load() {
+            progress_stage("Filtering points with script");
+            points = script.filter_points(points);
+        }
     }
+    points
+}
 
-    progress_stage("Parsing GPX data");
-    progress("Reading GPX file");
-    let read_result = read_gpx(reader);
+/// Sample the route of a GPX file into viewpoints and find their Streetview metadata.
+#[tracing::instrument(skip_all)]
+async fn route_metadata(read_result: ReadResult, low_memory: bool) -> MetadataResult {
     let original_points = read_result.points;
-    let all_points = original_points.clone();
 
     progress_stage(&format!(
         "Computing distance statistics ({} points)",
-        all_points.len()
+        original_points.len()
     ));
-    let distances = find_distances(&all_points);
+    let distances = find_distances(&original_points);
     let distance = distances.iter().sum::<f64>();
-    if !CLI_OPTIONS.json {
-        println!("distance is {} with {} points", distance, all_points.len());
-    }
+    info!(
+        "distance is {} with {} points",
+        distance,
+        original_points.len()
+    );
 
     // interpolate extra points to have more closely spaced pictures
     // from my observation it looks like Google can give back up to 300 points per mile
     let expected_frames =
         (CLI_OPTIONS.frames_per_mile.unwrap_or(100.0) * distance / 1600.0) as usize;
-    let all_points = interp_points(
-        all_points,
-        CLI_OPTIONS
-            .interp
-            .unwrap_or(expected_frames / &distances.len() + 1),
-    );
-    let distances = find_distances(&all_points);
+    let factor = CLI_OPTIONS
+        .interp
+        .unwrap_or(expected_frames / &distances.len() + 1);
+    drop(distances);
 
     progress_stage("Finding viewpoints");
-    let points = find_bearings(&sample_points_by_distance(
-        &all_points,
-        expected_frames,
-        &distances,
-    ));
+    // The interpolated distance and number of points, for the statistics.
+    let (sampled, interp_distance, interp_count) = if low_memory {
+        gpxstream::sample_interpolated(&original_points, factor, expected_frames)
+    } else {
+        let all_points = interp_points(original_points.clone(), factor);
+        let distances = find_distances(&all_points);
+        let sampled = sample_points_by_distance(&all_points, expected_frames, &distances);
+        (sampled, distances.iter().sum::<f64>(), all_points.len())
+    };
+    let points = shard::slice(find_bearings(&sampled));
+    start_phase(Phase::Metadata);
     progress_stage("Fetching Streetview metadata");
     let metadata = get_metadata(&points).await;
+    let (points, metadata): (Vec<_>, Vec<_>) =
+        shard::skip_previous_pano(points.into_iter().zip(metadata).collect(), |(_, meta)| {
+            Some(meta.pano_id.as_str()).filter(|_| meta.status == "OK")
+        })
+        .into_iter()
+        .unzip();
     progress_stage(&format!(
         "Found metadata for {} streetview points",
         metadata.len()
     ));
-    let (points, errs) = group_by_location(points, metadata);
+    let (points, metadata, errs) = group_by_location(points, metadata);
 
-    if !CLI_OPTIONS.json {
-        println!(
-            "distance is {} with {} points",
-            distances.iter().sum::<f64>(),
-            all_points.len()
-        );
-        println!("filtered to {} points", points.len());
-        println!(
-            "average error is {} meters",
-            errs.iter().sum::<f64>() / errs.len() as f64
-        );
-    }
+    info!(
+        "distance is {} with {} points",
+        interp_distance, interp_count
+    );
+    info!("filtered to {} points", points.len());
+    info!(
+        "average error is {} meters",
+        errs.iter().sum::<f64>() / errs.len() as f64
+    );
 
-    let metadata_result = MetadataResult {
-        distance: distances.iter().sum::<f64>(),
-        frames: points.len(),
-        averageError: errs.iter().sum::<f64>() / errs.len() as f64,
-        gpsPoints: points
-            .iter()
-            .map(|pb| SerializablePointBearing::from_geo(pb))
-            .collect::<Vec<_>>(),
+    let gps_points = points
+        .iter()
+        .zip(metadata.iter())
+        .zip(errs.iter())
+        .map(|((pb, meta), &err)| SerializablePointBearing::from_geo(pb, meta, err))
+        .collect::<Vec<_>>();
+    let gps_points = adjust_points(gps_points).await;
+    let segment_errors = segment_errors(&gps_points);
+
+    MetadataResult {
+        schemaVersion: capabilities::METADATA_SCHEMA_VERSION,
+        distance: interp_distance,
+        frames: gps_points.len(),
+        averageError: average_error(&gps_points),
+        gpsPoints: gps_points,
         originalPoints: original_points,
         name: read_result.name.unwrap_or("Unnamed GPX File".to_owned()),
         fileSizeBytes: read_result.size,
+        waypoints: read_result.waypoints,
+        segmentErrors: segment_errors,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = match errors::exit_on_panic(std::panic::catch_unwind(options::parse_cli)) {
+        options::Invocation::Run(cli) => *cli,
+        options::Invocation::Jobs {
+            jobs,
+            network_concurrency,
+            max_qps,
+        } => {
+            logging::init(0, None);
+            errors::install_panic_hook();
+            jobs::run_jobs(jobs, network_concurrency, max_qps).await;
+            return;
+        }
+        options::Invocation::Watch {
+            dir,
+            dest,
+            profile,
+            interval,
+            args,
+        } => {
+            logging::init(0, None);
+            watch::watch(&dir, &dest, profile.as_deref(), interval, &args);
+            return;
+        }
     };
-    if CLI_OPTIONS.dry_run {
-        if CLI_OPTIONS.json {
-            println!(
-                "{}",
-                serde_json::to_string(&metadata_result).expect("Serialization failed")
-            );
-        } else {
-            println!("{:?}", &metadata_result);
+    errors::exit_on_panic(std::panic::catch_unwind(|| {
+        logging::init(cli.verbose, cli.log_format.as_deref());
+    }));
+    context::init(cli);
+    errors::install_panic_hook();
+    errors::exit_on_panic(std::panic::catch_unwind(progress::open_sink));
+    let stage = CLI_OPTIONS.stage();
+    let (output_dir, is_temp) = output_dir(stage);
+    errors::exit_on_panic(std::panic::catch_unwind(|| {
+        prepare_output_dir(stage, &output_dir)
+    }));
+    cancel::handle_ctrl_c(
+        output_dir.clone(),
+        is_temp,
+        output_dir.join(FETCHED_METADATA_FILE),
+    );
+
+    // Catch failures so they can be reported to notify_url before exiting as usual.
+    let result = std::panic::AssertUnwindSafe(run_and_deliver(stage, output_dir.clone(), is_temp))
+        .catch_unwind()
+        .await;
+    notify::notify(&output_dir, run_outcome(&result)).await;
+    errors::exit_on_panic(result);
+}
+
+/// Directory the run writes its output into, and whether it is a temporary one.
+fn output_dir(stage: Stage) -> (PathBuf, bool) {
+    let output_dir = CLI_OPTIONS
+        .output_dir
+        .as_ref()
+        .map(|o| PathBuf::from(o))
+        .or_else(|| match stage {
+            Stage::Encode => Some(CLI_OPTIONS.input_path().to_path_buf()),
+            _ => None,
+        })
+        .unwrap_or_else(|| {
+            let start = SystemTime::now();
+            let now = start
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards");
+            env::temp_dir().join(deterministic::temp_dir_name(now.as_secs()))
+        });
+    let is_temp = CLI_OPTIONS.output_dir.is_none() && stage != Stage::Encode;
+    (output_dir, is_temp)
+}
+
+/// Create output_dir and check that ffmpeg can encode the video if stage needs it.
+fn prepare_output_dir(stage: Stage, output_dir: &Path) {
+    fs::create_dir_all(output_dir).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Io,
+            &format!("Could not open output directory {:?}: {:?}", output_dir, e),
+        )
+    });
+    if (stage == Stage::Run || stage == Stage::Encode) && !uses_native_encoder() {
+        check_ffmpeg();
+    }
+    info!("output dir is {}", output_dir.to_string_lossy());
+}
+
+/// The metadata a run returned, or the message of the panic it failed with.
+fn run_outcome(
+    result: &std::thread::Result<Option<MetadataResult>>,
+) -> Result<Option<&MetadataResult>, String> {
+    match result {
+        Ok(metadata_result) => Ok(metadata_result.as_ref()),
+        Err(panic) => Err(errors::panic_message(panic.as_ref())),
+    }
+}
+
+/// Run stage into output_dir, then write the exports and summary and deliver the output.
+async fn run_and_deliver(
+    stage: Stage,
+    output_dir: PathBuf,
+    is_temp: bool,
+) -> Option<MetadataResult> {
+    let metadata_result = run(stage, output_dir.clone()).await;
+    if let Some(metadata_result) = &metadata_result {
+        export::write_exports(metadata_result);
+        summary::report(metadata_result);
+    }
+    if stage == Stage::Run || stage == Stage::Encode {
+        upload::upload_output(&output_dir).await;
+        if let Some(metadata_result) = &metadata_result {
+            hooks::run_post_cmd(&output_dir, metadata_result).await;
+            shard::write_manifest(&output_dir, &output_filename(), metadata_result);
         }
-        return;
+        let output = output_dir.join(output_filename());
+        let n_points = metadata_result
+            .as_ref()
+            .map_or(0, |metadata_result| metadata_result.gpsPoints.len());
+        cleanup::clean_output_dir(&output_dir, is_temp, &output, n_points);
+        progress::result(&output);
+    }
+    metadata_result
+}
+
+/// Read the GPX file from reader and find its metadata, with the low memory path if enabled.
+async fn route_metadata_from(reader: BufReader<File>) -> MetadataResult {
+    let low_memory = gpxstream::is_enabled();
+    let read_result = if low_memory {
+        gpxstream::read_gpx_streaming(reader)
+    } else {
+        read_gpx(reader)
+    };
+    route_metadata(read_result, low_memory).await
+}
+
+/// Run the stage of the pipeline into output_dir, returning the metadata of the route.
+#[tracing::instrument(skip(output_dir))]
+async fn run(stage: Stage, output_dir: PathBuf) -> Option<MetadataResult> {
+    if stage == Stage::Encode {
+        progress_stage("Reading fetched metadata");
+        let metadata_result = read_fetched_metadata(CLI_OPTIONS.input_path());
+        return Some(encode_video(output_dir, metadata_result, false).await);
+    }
+    // A remote route has no input file, and is only downloaded once the API key is checked.
+    let reader = if sources::is_enabled() {
+        None
+    } else {
+        let file = File::open(CLI_OPTIONS.input_path()).unwrap_or_else(|e| {
+            fail(
+                ErrorCode::InputInvalid,
+                &format!("Could not open {:?}: {:?}", CLI_OPTIONS.input_path(), e),
+            )
+        });
+        Some(BufReader::new(file))
+    };
+    if !mock::is_enabled() {
+        check_api_key().await;
+    }
+
+    let metadata_result = match reader {
+        Some(reader) if CLI_OPTIONS.use_metadata => {
+            progress_stage("Parsing metadata");
+            let mut metadata_result =
+                schema::read_metadata_result(reader, "submitted metadata result");
+            metadata_result.gpsPoints =
+                shard::skip_previous_pano(shard::slice(metadata_result.gpsPoints), |point| {
+                    point.panoId.as_deref()
+                });
+            metadata_result.gpsPoints = adjust_points(metadata_result.gpsPoints).await;
+            update_statistics(&mut metadata_result);
+            return Some(create_video(output_dir, metadata_result, None).await);
+        }
+        Some(reader) if takeout::is_enabled() => {
+            progress_stage("Parsing location history");
+            route_metadata(takeout::read_takeout(reader), false).await
+        }
+        Some(reader) => {
+            progress_stage("Parsing GPX data");
+            progress("Reading GPX file");
+            route_metadata_from(reader).await
+        }
+        None => route_metadata(sources::read_route().await, false).await,
+    };
+    let compare_route = match &CLI_OPTIONS.compare_route {
+        Some(path) => {
+            progress_stage("Parsing second GPX route");
+            let file = File::open(path).unwrap_or_else(|e| {
+                fail(
+                    ErrorCode::InputInvalid,
+                    &format!("Could not open --compare-route file {:?}: {:?}", path, e),
+                )
+            });
+            Some(route_metadata_from(BufReader::new(file)).await)
+        }
+        None => None,
+    };
+    if stage == Stage::Metadata {
+        for result in std::iter::once(&metadata_result).chain(&compare_route) {
+            if CLI_OPTIONS.json {
+                println!(
+                    "{}",
+                    serde_json::to_string(result).expect("Serialization failed")
+                );
+            } else {
+                println!("{:?}", result);
+            }
+        }
+        return Some(metadata_result);
     }
-    create_video(output_dir, metadata_result).await;
+    Some(create_video(output_dir, metadata_result, compare_route).await)
 }
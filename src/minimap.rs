@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use image::codecs::jpeg::JpegEncoder;
+use image::{Rgb, RgbImage};
+use rayon::prelude::*;
+
+use crate::errors::{fail, ErrorCode};
+use crate::options::CLI_OPTIONS;
+use crate::progress::progress;
+use crate::SerializablePointBearing;
+
+/// Margin in pixels between the minimap and the frame edge, and between the route and map edge.
+const MARGIN: u32 = 10;
+const ROUTE_COLOR: [u8; 3] = [255, 255, 255];
+const MARKER_COLOR: [u8; 3] = [230, 40, 40];
+/// Opacity of the dark minimap background over the frame.
+const BACKGROUND_ALPHA: f32 = 0.55;
+
+/// Projects points into pixel coordinates of a square map of the given size.
+struct Projection {
+    min_x: f64,
+    max_y: f64,
+    scale: f64,
+    offset: (f64, f64),
+    cos_lat: f64,
+}
+
+impl Projection {
+    /// Fit the bounding box of the points into size x size pixels, keeping the route's proportions.
+    fn fit(points: &[SerializablePointBearing], size: u32) -> Projection {
+        let mean_lat = points.iter().map(|p| p.lat).sum::<f64>() / points.len().max(1) as f64;
+        let cos_lat = mean_lat.to_radians().cos();
+        let xs = points.iter().map(|p| p.lng * cos_lat);
+        let ys = points.iter().map(|p| p.lat);
+        let min_x = xs.clone().fold(f64::INFINITY, f64::min);
+        let max_x = xs.fold(f64::NEG_INFINITY, f64::max);
+        let min_y = ys.clone().fold(f64::INFINITY, f64::min);
+        let max_y = ys.fold(f64::NEG_INFINITY, f64::max);
+        let inner = (size - 2 * MARGIN) as f64;
+        let extent = (max_x - min_x).max(max_y - min_y).max(1e-9);
+        let scale = inner / extent;
+        // Center the route along its shorter dimension.
+        let offset = (
+            MARGIN as f64 + (inner - (max_x - min_x) * scale) / 2.0,
+            MARGIN as f64 + (inner - (max_y - min_y) * scale) / 2.0,
+        );
+        Projection {
+            min_x,
+            max_y,
+            scale,
+            offset,
+            cos_lat,
+        }
+    }
+
+    fn project(&self, point: &SerializablePointBearing) -> (f64, f64) {
+        (
+            self.offset.0 + (point.lng * self.cos_lat - self.min_x) * self.scale,
+            self.offset.1 + (self.max_y - point.lat) * self.scale,
+        )
+    }
+}
+
+/// Fill a disc of the given radius around (cx, cy), clipped to the image.
+fn fill_disc(img: &mut RgbImage, (cx, cy): (f64, f64), radius: f64, color: [u8; 3]) {
+    let r = radius.ceil() as i64;
+    for dy in -r..=r {
+        for dx in -r..=r {
+            let (x, y) = (cx.round() as i64 + dx, cy.round() as i64 + dy);
+            let inside = ((dx * dx + dy * dy) as f64) <= radius * radius;
+            if inside && x >= 0 && y >= 0 && (x as u32) < img.width() && (y as u32) < img.height() {
+                img.put_pixel(x as u32, y as u32, Rgb(color));
+            }
+        }
+    }
+}
+
+/// Draw the route as a polyline onto a mask of the minimap, returning which pixels are route.
+fn route_mask(points: &[SerializablePointBearing], projection: &Projection, size: u32) -> RgbImage {
+    let mut mask = RgbImage::new(size, size);
+    for pair in points.windows(2) {
+        let (from, to) = (projection.project(&pair[0]), projection.project(&pair[1]));
+        let steps = ((to.0 - from.0).abs().max((to.1 - from.1).abs()).ceil() as usize).max(1);
+        for step in 0..=steps {
+            let t = step as f64 / steps as f64;
+            let point = (from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t);
+            fill_disc(&mut mask, point, 1.0, ROUTE_COLOR);
+        }
+    }
+    mask
+}
+
+/// The route drawn once onto a map of one size, shared by every frame of that size.
+struct BaseMap {
+    projection: Projection,
+    mask: RgbImage,
+}
+
+impl BaseMap {
+    fn new(points: &[SerializablePointBearing], size: u32) -> BaseMap {
+        let projection = Projection::fit(points, size);
+        let mask = route_mask(points, &projection, size);
+        BaseMap { projection, mask }
+    }
+}
+
+/// Top-left position of a size x size minimap in the given corner of a width x height frame.
+fn corner_origin(corner: &str, width: u32, height: u32, size: u32) -> (u32, u32) {
+    let right = width.saturating_sub(size + MARGIN);
+    let bottom = height.saturating_sub(size + MARGIN);
+    match corner {
+        "top-left" => (MARGIN, MARGIN),
+        "top-right" => (right, MARGIN),
+        "bottom-left" => (MARGIN, bottom),
+        "bottom-right" => (right, bottom),
+        _ => fail(
+            ErrorCode::InputInvalid,
+            &format!(
+                "Unknown corner {}, expected top-left, top-right, bottom-left or bottom-right",
+                corner
+            ),
+        ),
+    }
+}
+
+/// Composite the minimap onto a frame: darken the map area, draw the route, then the marker.
+fn draw_minimap(
+    frame: &mut RgbImage,
+    origin: (u32, u32),
+    mask: &RgbImage,
+    marker: (f64, f64),
+    marker_radius: f64,
+) {
+    let (width, height) = frame.dimensions();
+    for (x, y, route) in mask.enumerate_pixels() {
+        let (fx, fy) = (origin.0 + x, origin.1 + y);
+        if fx >= width || fy >= height {
+            continue;
+        }
+        let pixel = frame.get_pixel_mut(fx, fy);
+        if route[0] > 0 {
+            *pixel = *route;
+        } else {
+            for channel in 0..3 {
+                pixel[channel] = (pixel[channel] as f32 * (1.0 - BACKGROUND_ALPHA)) as u8;
+            }
+        }
+    }
+    let marker = (origin.0 as f64 + marker.0, origin.1 as f64 + marker.1);
+    fill_disc(frame, marker, marker_radius, MARKER_COLOR);
+}
+
+/// Draw a minimap of the route into a corner (minimap_corner option) of every frame matching
+/// pattern (e.g. %d.jpg) in image_dir, with a marker at the frame's own point.
+pub fn add_minimaps<P: AsRef<Path>>(
+    image_dir: &P,
+    pattern: &str,
+    points: &[SerializablePointBearing],
+) {
+    let image_dir = image_dir.as_ref();
+    let corner = CLI_OPTIONS.minimap_corner.as_deref().unwrap_or("top-right");
+    let minimap_size = CLI_OPTIONS.minimap_size;
+    // Frames of different sizes each get a fitting map, but the route is only drawn once per size.
+    let base_maps: Mutex<HashMap<u32, Arc<BaseMap>>> = Mutex::new(HashMap::new());
+    let drawn = points
+        .par_iter()
+        .enumerate()
+        .filter(|(index, point)| {
+            let path = image_dir.join(pattern.replace("%d", &index.to_string()));
+            let mut frame = match image::open(&path) {
+                Ok(img) => img.to_rgb8(),
+                Err(_) => return false,
+            };
+            let (width, height) = frame.dimensions();
+            let size = minimap_size
+                .unwrap_or(width.min(height) / 4)
+                .max(2 * MARGIN + 1);
+            let base_map = base_maps
+                .lock()
+                .unwrap()
+                .entry(size)
+                .or_insert_with(|| Arc::new(BaseMap::new(points, size)))
+                .clone();
+            let origin = corner_origin(corner, width, height, size);
+            let marker_radius = (size as f64 / 30.0).max(3.0);
+            draw_minimap(
+                &mut frame,
+                origin,
+                &base_map.mask,
+                base_map.projection.project(point),
+                marker_radius,
+            );
+            // Paced frames may be hard links to the same file, so unlink before writing.
+            let _ = std::fs::remove_file(&path);
+            let file = match std::fs::File::create(&path) {
+                Ok(file) => file,
+                Err(_) => return false,
+            };
+            let mut writer = std::io::BufWriter::new(file);
+            JpegEncoder::new_with_quality(&mut writer, 95)
+                .encode_image(&frame)
+                .is_ok()
+        })
+        .count();
+    progress(&format!("Drew minimap on {} frames", drawn));
+}
@@ -0,0 +1,137 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::{Rgb, RgbImage};
+
+use crate::errors::{fail, ErrorCode};
+use crate::options::CLI_OPTIONS;
+use crate::{image_size, GSVMetadata, GSVPoint, PointBearing, SerializablePointBearing};
+
+/// Rows of the 3x5 pixel glyphs drawn onto mock frames, most significant bit on the left.
+const GLYPHS: &[(char, [u8; 5])] = &[
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b001, 0b001, 0b001]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    (',', [0b000, 0b000, 0b000, 0b010, 0b100]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('#', [0b101, 0b111, 0b101, 0b111, 0b101]),
+];
+/// Size in pixels of each glyph pixel.
+const GLYPH_SCALE: u32 = 4;
+const TEXT_COLOR: [u8; 3] = [255, 255, 255];
+const SHADOW_COLOR: [u8; 3] = [0, 0, 0];
+/// Distance in meters between the stripes of the mock scenery, so frames change along the route.
+const STRIPE_METERS: f64 = 20.0;
+
+/// Whether the provider option chose synthetic frames instead of Google's Street View API.
+pub fn is_enabled() -> bool {
+    match CLI_OPTIONS.provider.as_deref() {
+        None | Some("google") => false,
+        Some("mock") => true,
+        Some(other) => fail(
+            ErrorCode::InputInvalid,
+            &format!("Unknown provider {}, expected google or mock", other),
+        ),
+    }
+}
+
+/// Metadata of a panorama right at point_bearing, with an id of its own so no point is grouped
+/// with another.
+pub fn metadata(point_bearing: &PointBearing) -> GSVMetadata {
+    let point = &point_bearing.point;
+    GSVMetadata {
+        date: "2020-01".to_string(),
+        location: GSVPoint {
+            lat: point.lat,
+            lng: point.lng,
+        },
+        pano_id: format!("mock-{:.6},{:.6}", point.lat, point.lng),
+        copyright: "Mock imagery".to_string(),
+        status: "OK".to_string(),
+    }
+}
+
+/// Draw text at (x, y) with the pixel glyphs, over a drop shadow so it reads on any background.
+/// Characters without a glyph are left as spaces.
+fn draw_text(img: &mut RgbImage, text: &str, x: u32, y: u32) {
+    for (offset, color) in &[(1, SHADOW_COLOR), (0, TEXT_COLOR)] {
+        for (i, c) in text.chars().enumerate() {
+            let rows = match GLYPHS.iter().find(|(glyph, _)| *glyph == c) {
+                Some((_, rows)) => rows,
+                None => continue,
+            };
+            let left = x + i as u32 * 4 * GLYPH_SCALE + offset;
+            for (row, bits) in rows.iter().enumerate() {
+                for col in 0..3 {
+                    if bits & (0b100 >> col) == 0 {
+                        continue;
+                    }
+                    for dy in 0..GLYPH_SCALE {
+                        for dx in 0..GLYPH_SCALE {
+                            let px = left + col * GLYPH_SCALE + dx;
+                            let py = y + row as u32 * GLYPH_SCALE + dy + offset;
+                            if px < img.width() && py < img.height() {
+                                img.put_pixel(px, py, Rgb(*color));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// JPG of a synthetic frame for point_bearing: a sky and ground tinted by the bearing, with
+/// stripes that move along the route, and the frame index, coordinates and bearing drawn on.
+pub fn frame(index: usize, point_bearing: &SerializablePointBearing) -> Vec<u8> {
+    let (width, height) = image_size();
+    let hue = point_bearing.bearing.rem_euclid(360.0) / 360.0;
+    let tint = [
+        (127.0 + 100.0 * (hue * std::f64::consts::TAU).cos()) as u8,
+        (127.0 + 100.0 * ((hue + 1.0 / 3.0) * std::f64::consts::TAU).cos()) as u8,
+        (127.0 + 100.0 * ((hue + 2.0 / 3.0) * std::f64::consts::TAU).cos()) as u8,
+    ];
+    let travelled = (point_bearing.lat + point_bearing.lng) * 111_320.0 / STRIPE_METERS;
+    let stripe_offset = (travelled.rem_euclid(1.0) * width as f64) as u32;
+    let mut img = RgbImage::from_fn(width, height, |x, y| {
+        let depth = y as f32 / height as f32;
+        let shade = if y < height / 2 {
+            1.0 - depth * 0.6
+        } else {
+            0.2 + (depth - 0.5) * 0.8
+        };
+        let stripe =
+            y >= height / 2 && ((x + stripe_offset) / (width / 8).max(1)).is_multiple_of(2);
+        let shade = if stripe { shade * 0.6 } else { shade };
+        Rgb([
+            (tint[0] as f32 * shade) as u8,
+            (tint[1] as f32 * shade) as u8,
+            (tint[2] as f32 * shade) as u8,
+        ])
+    });
+    let line_height = 7 * GLYPH_SCALE;
+    let lines = [
+        format!("#{}", index),
+        format!("{:.5},{:.5}", point_bearing.lat, point_bearing.lng),
+        format!("{:.0}", point_bearing.bearing),
+    ];
+    for (i, line) in lines.iter().enumerate() {
+        draw_text(
+            &mut img,
+            line,
+            2 * GLYPH_SCALE,
+            (i as u32 + 1) * line_height,
+        );
+    }
+    let mut jpg = vec![];
+    JpegEncoder::new_with_quality(&mut jpg, 90)
+        .encode_image(&img)
+        .expect("Could not encode mock frame");
+    jpg
+}
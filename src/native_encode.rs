@@ -0,0 +1,142 @@
+use std::path::Path;
+
+use ffmpeg_next::software::scaling;
+use ffmpeg_next::{codec, encoder, format, frame, Dictionary, Packet, Rational};
+use image::imageops::{self, FilterType};
+use image::{GenericImageView, RgbImage};
+
+use crate::ffmpeg::{
+    crop_bottom, input_framerate, output_duration, pads_to_fit, target_bitrate_kbps, video_size,
+};
+use crate::options::CLI_OPTIONS;
+use crate::progress::{warning, ProgressReporter};
+
+/// Load the frame at path and fit it to width x height, cropping or padding to keep its aspect ratio.
+fn load_frame(path: &Path, width: u32, height: u32) -> RgbImage {
+    let img = image::open(path).expect(&format!("Could not open frame {:?}", path));
+    let crop = crop_bottom().min(img.height() - 1);
+    let img = img.crop_imm(0, 0, img.width(), img.height() - crop);
+    if !pads_to_fit() {
+        return img
+            .resize_to_fill(width, height, FilterType::Triangle)
+            .to_rgb8();
+    }
+    let img = img.resize(width, height, FilterType::Triangle).to_rgb8();
+    let mut padded = RgbImage::new(width, height);
+    imageops::overlay(
+        &mut padded,
+        &img,
+        (width - img.width()) / 2,
+        (height - img.height()) / 2,
+    );
+    padded
+}
+
+/// Write every packet the encoder has ready to the output stream.
+fn write_packets(
+    encoder: &mut encoder::Video,
+    octx: &mut format::context::Output,
+    time_base: Rational,
+) {
+    let stream_time_base = octx.stream(0).expect("Missing output stream").time_base();
+    let mut packet = Packet::empty();
+    while encoder.receive_packet(&mut packet).is_ok() {
+        packet.set_stream(0);
+        packet.rescale_ts(time_base, stream_time_base);
+        packet
+            .write_interleaved(octx)
+            .expect("Could not write video packet");
+    }
+}
+
+/// Encode frames matching pattern (e.g. %d.jpg) in image_dir into an h264 mp4 at out_filename
+/// (relative to image_dir) using the ffmpeg libraries in-process, with the same settings as
+/// create_timelapse. This does not need an ffmpeg executable, but has no filters for minterp.
+pub fn encode_timelapse(image_dir: &Path, num_images: usize, pattern: &str, out_filename: &str) {
+    ffmpeg_next::init().expect("Could not initialize ffmpeg libraries");
+    if CLI_OPTIONS.deflicker
+        || CLI_OPTIONS.attribution
+        || CLI_OPTIONS.hud
+        || CLI_OPTIONS.speed_overlay
+        || CLI_OPTIONS.data_overlay
+        || CLI_OPTIONS.street_names
+        || CLI_OPTIONS.entering_labels
+        || CLI_OPTIONS.waypoint_dwell_labels
+    {
+        warning("The native encoder has no filters, ignoring --deflicker and text overlays");
+    }
+    let mut octx =
+        format::output(&image_dir.join(out_filename)).expect("Could not create output video");
+    let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
+    let codec = encoder::find_by_name("libx264").expect("ffmpeg libraries have no libx264");
+    let mut ost = octx.add_stream(codec).expect("Could not add video stream");
+
+    let (width, height) = video_size();
+    // Each frame lasts one tick, in thousandths of a frame per second to allow target_duration.
+    let framerate = (input_framerate(num_images) * 1000.0).round() as i32;
+    let time_base = Rational(1000, framerate);
+    let mut encoder = codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()
+        .expect("Could not create video encoder");
+    encoder.set_width(width);
+    encoder.set_height(height);
+    encoder.set_format(format::Pixel::YUV420P);
+    encoder.set_time_base(time_base);
+    encoder.set_frame_rate(Some(Rational(framerate, 1000)));
+    if global_header {
+        encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+    }
+    let mut x264_opts = Dictionary::new();
+    // The in-process encoder makes a single pass, so a target bitrate is only approximate.
+    match target_bitrate_kbps(output_duration(num_images)) {
+        Some(kbps) => encoder.set_bit_rate(kbps as usize * 1000),
+        None => x264_opts.set("crf", &CLI_OPTIONS.crf.unwrap_or(22).to_string()),
+    }
+    x264_opts.set("preset", "faster");
+    let mut encoder = encoder
+        .open_with(x264_opts)
+        .expect("Could not open libx264 encoder");
+    ost.set_parameters(&encoder);
+
+    let mut mux_opts = Dictionary::new();
+    mux_opts.set("movflags", "faststart");
+    octx.write_header_with(mux_opts)
+        .expect("Could not write video header");
+
+    let mut scaler = scaling::Context::get(
+        format::Pixel::RGB24,
+        width,
+        height,
+        format::Pixel::YUV420P,
+        width,
+        height,
+        scaling::Flags::BILINEAR,
+    )
+    .expect("Could not create frame scaler");
+    let mut rgb_frame = frame::Video::new(format::Pixel::RGB24, width, height);
+    let mut yuv_frame = frame::Video::new(format::Pixel::YUV420P, width, height);
+    let mut reporter = ProgressReporter::frames();
+    for index in 0..num_images {
+        let path = image_dir.join(pattern.replace("%d", &index.to_string()));
+        let img = load_frame(&path, width, height);
+        let stride = rgb_frame.stride(0);
+        let row_bytes = (width * 3) as usize;
+        let data = rgb_frame.data_mut(0);
+        for (y, row) in img.as_raw().chunks(row_bytes).enumerate() {
+            data[y * stride..y * stride + row_bytes].copy_from_slice(row);
+        }
+        scaler
+            .run(&rgb_frame, &mut yuv_frame)
+            .expect("Could not convert frame to yuv");
+        yuv_frame.set_pts(Some(index as i64));
+        encoder
+            .send_frame(&yuv_frame)
+            .expect("Could not encode frame");
+        write_packets(&mut encoder, &mut octx, time_base);
+        reporter.set_percent(index + 1, 100.0 * (index + 1) as f64 / num_images as f64);
+    }
+    encoder.send_eof().expect("Could not flush encoder");
+    write_packets(&mut encoder, &mut octx, time_base);
+    octx.write_trailer().expect("Could not write video trailer");
+}
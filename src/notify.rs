@@ -0,0 +1,82 @@
+use std::path::Path;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::http;
+use crate::options::{Stage, CLI_OPTIONS};
+use crate::progress::warning;
+use crate::MetadataResult;
+
+/// Seconds to wait for the notify_url server before giving up.
+const NOTIFY_TIMEOUT_SECS: u64 = 30;
+
+/// The fields of metadata_result that describe the route, without its points.
+fn route_summary(metadata_result: &MetadataResult) -> Value {
+    json!({
+        "name": metadata_result.name,
+        "distance": metadata_result.distance,
+        "frames": metadata_result.frames,
+        "averageError": metadata_result.averageError,
+        "fileSizeBytes": metadata_result.fileSizeBytes,
+    })
+}
+
+/// The file the stage writes its result to in output_dir, or output_dir itself.
+fn output_path(output_dir: &Path) -> Option<std::path::PathBuf> {
+    match CLI_OPTIONS.stage() {
+        Stage::Metadata => None,
+        Stage::Fetch => Some(output_dir.to_path_buf()),
+        Stage::Encode | Stage::Run => Some(output_dir.join(crate::output_filename())),
+    }
+}
+
+/// POST the outcome of the run to the notify_url option as JSON: the job_id option, a status
+/// of success or failure, the output path and size, the route summary and any error message.
+/// result is the metadata the run returned or the message it failed with.
+/// A failed notification is only a warning, since the run itself is over.
+pub async fn notify(output_dir: &Path, result: Result<Option<&MetadataResult>, String>) {
+    let url = match &CLI_OPTIONS.notify_url {
+        Some(url) => url,
+        None => return,
+    };
+    let output = output_path(output_dir);
+    let output_size = output
+        .as_ref()
+        .and_then(|path| std::fs::metadata(path).ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len());
+    let mut payload = json!({
+        "jobId": CLI_OPTIONS.job_id,
+        "stage": format!("{:?}", CLI_OPTIONS.stage()).to_lowercase(),
+        "outputDir": output_dir,
+        "output": output,
+        "outputSizeBytes": output_size,
+    });
+    match result {
+        Ok(metadata_result) => {
+            payload["status"] = json!("success");
+            payload["metadata"] = json!(metadata_result.map(route_summary));
+        }
+        Err(message) => {
+            payload["status"] = json!("failure");
+            payload["error"] = json!(message);
+        }
+    }
+    let response = http::client()
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(payload.to_string())
+        .timeout(Duration::from_secs(NOTIFY_TIMEOUT_SECS))
+        .send()
+        .await;
+    match response {
+        Ok(response) if !response.status().is_success() => warning(&format!(
+            "Notification to {} failed with status {}",
+            url,
+            response.status()
+        )),
+        Err(e) => warning(&format!("Could not send notification to {}: {:?}", url, e)),
+        _ => (),
+    }
+}
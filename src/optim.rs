@@ -1,11 +1,37 @@
 use fs_extra::dir::get_dir_content;
+use image::imageops::FilterType;
+use image::{GenericImageView, GrayImage};
+use rayon::prelude::*;
+use serde_json::json;
 use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
 use tokio::process::Command;
+use tokio::time::timeout;
 
+use crate::errors::ErrorCode;
 use crate::options::CLI_OPTIONS;
+use crate::progress::{error, progress, warning};
+use crate::SerializablePointBearing;
 use futures::{stream, StreamExt};
 
-pub async fn optimize_sequence<P: AsRef<Path>>(image_dir: &P) -> Vec<usize> {
+/// Height in pixels of the 'google' text at the bottom of each frame, excluded from matching.
+const WATERMARK_HEIGHT: u32 = 19;
+/// Resolution at which frames are compared.
+const COMPARE_WIDTH: u32 = 128;
+const COMPARE_HEIGHT: u32 = 96;
+/// Maximum number of frames that may be skipped between two kept frames, plus one.
+const WINDOW_SIZE: usize = 4;
+
+/// Whether the image sequence should be optimized, natively or by an external executable.
+pub fn is_enabled() -> bool {
+    CLI_OPTIONS.optimize || CLI_OPTIONS.optimizer.is_some()
+}
+
+/// Run the external optimizer executable on image_dir.
+/// Return the kept indices printed by the optimizer, or None if it failed.
+async fn run_external_optimizer<P: AsRef<Path>>(image_dir: &P) -> Option<Vec<usize>> {
     let optimizer_cmd = CLI_OPTIONS.optimizer.clone().unwrap();
     let mut args = vec![image_dir
         .as_ref()
@@ -16,21 +42,283 @@ pub async fn optimize_sequence<P: AsRef<Path>>(image_dir: &P) -> Vec<usize> {
         args.push(arg)
     }
     let mut command = Command::new(optimizer_cmd);
-    let command = command.args(args);
+    let command = command.args(args).kill_on_drop(true);
     let output = (command.output().await).expect("Failed to get optimizer output");
     if !output.stderr.is_empty() {
-        eprintln!(
+        debug!(
             "optimizer stderr: {}",
             std::str::from_utf8(&output.stderr).unwrap()
         );
     }
     if !output.status.success() {
-        eprintln!("optimizer exit code {:?}", output.status.code());
-        return vec![];
+        error(
+            ErrorCode::EncodeFailed,
+            &format!("optimizer exit code {:?}", output.status.code()),
+            true,
+        );
+        return None;
     }
-    let kept_indices: Vec<usize> =
+    Some(
         serde_json::from_str(std::str::from_utf8(&output.stdout).expect("Output was not utf8"))
-            .unwrap();
+            .unwrap(),
+    )
+}
+
+/// A per-frame decision line printed by a v2 optimizer.
+#[derive(Deserialize, Debug)]
+struct OptimizerDecision {
+    index: usize,
+    keep: bool,
+}
+
+/// Run the external optimizer executable on image_dir using protocol v2:
+/// streetwarp writes one JSON object per line to the optimizer's stdin, first
+/// {"type": "START", "version": 2, "frames": n, "dir": ...}, then a
+/// {"type": "FRAME", "index", "path", "lat", "lng", "bearing", "panoId", "panoDate"} per frame,
+/// then {"type": "END"}, and closes stdin.
+/// The optimizer prints one JSON object per line to stdout, either a decision
+/// {"index": i, "keep": bool} or a progress update {"type": "PROGRESS", "message": ...}.
+/// Frames without a decision are kept. Return None if the optimizer failed.
+async fn run_streaming_optimizer<P: AsRef<Path>>(
+    image_dir: &P,
+    points: &[SerializablePointBearing],
+) -> Option<Vec<usize>> {
+    let optimizer_cmd = CLI_OPTIONS.optimizer.clone().unwrap();
+    let dir = image_dir
+        .as_ref()
+        .to_str()
+        .expect("Could not stringify image_dir")
+        .to_string();
+    let mut args = vec![dir.clone()];
+    if let Some(arg) = CLI_OPTIONS.optimizer_arg.clone() {
+        args.push(arg)
+    }
+    let mut command = Command::new(optimizer_cmd);
+    let command = command
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true);
+    let mut child = command.spawn().expect("optimizer spawn failure");
+    let _guard = crate::cancel::track_child(child.id());
+
+    let mut lines =
+        vec![json!({"type": "START", "version": 2, "frames": points.len(), "dir": &dir})];
+    lines.extend(points.iter().enumerate().map(|(index, point)| {
+        json!({
+            "type": "FRAME",
+            "index": index,
+            "path": image_dir.as_ref().join(format!("{}.jpg", index)),
+            "lat": point.lat,
+            "lng": point.lng,
+            "bearing": point.bearing,
+            "panoId": point.panoId,
+            "panoDate": point.panoDate,
+        })
+    }));
+    lines.push(json!({"type": "END"}));
+    let input = lines
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect::<String>();
+    let mut stdin = child.stdin.take().expect("optimizer stdin failure");
+    // Write from a separate task so a slow reader of stdin can't deadlock with our stdout reads.
+    let writer = tokio::spawn(async move {
+        if let Err(e) = stdin.write_all(input.as_bytes()).await {
+            warn!("Could not write to optimizer stdin: {:?}", e);
+        }
+    });
+
+    let stdout = child.stdout.take().expect("optimizer stdout failure");
+    let mut reader = tokio::io::BufReader::new(stdout).lines();
+    let mut keep = vec![true; points.len()];
+    loop {
+        let line = match reader.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            // Like the end of the output, so the frames not yet dropped are kept.
+            Err(e) => {
+                warning(&format!("Could not read the optimizer output: {:?}", e));
+                break;
+            }
+        };
+        let value: serde_json::Value = match serde_json::from_str(&line) {
+            Ok(value) => value,
+            Err(_) => {
+                debug!("optimizer output: {}", line);
+                continue;
+            }
+        };
+        if value["type"] == "PROGRESS" {
+            progress(&format!(
+                "Optimizer: {}",
+                value["message"].as_str().unwrap_or("")
+            ));
+        } else if let Ok(decision) = serde_json::from_value::<OptimizerDecision>(value) {
+            if decision.index < keep.len() {
+                keep[decision.index] = decision.keep;
+            }
+        }
+    }
+    writer.await.expect("Failed to join optimizer writer");
+    let status = child.await.expect("optimizer process encountered an error");
+    if !status.success() {
+        error(
+            ErrorCode::EncodeFailed,
+            &format!("optimizer exit code {:?}", status.code()),
+            true,
+        );
+        return None;
+    }
+    Some(
+        keep.into_iter()
+            .enumerate()
+            .filter(|&(_, keep)| keep)
+            .map(|(index, _)| index)
+            .collect(),
+    )
+}
+
+/// Load the frame at path as a small grayscale image with the watermark cropped out.
+fn load_compare_image(path: &Path) -> GrayImage {
+    let img = image::open(path).unwrap_or_else(|_| panic!("Could not open frame {:?}", path));
+    let (width, height) = (img.width(), img.height());
+    img.crop_imm(0, 0, width, height.saturating_sub(WATERMARK_HEIGHT))
+        .resize_exact(COMPARE_WIDTH, COMPARE_HEIGHT, FilterType::Triangle)
+        .to_luma8()
+}
+
+/// Mean structural similarity of two equally sized grayscale images over 8x8 blocks.
+fn ssim(img1: &GrayImage, img2: &GrayImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let mut total = 0.0;
+    let mut blocks = 0;
+    for by in (0..img1.height() / 8).map(|b| b * 8) {
+        for bx in (0..img1.width() / 8).map(|b| b * 8) {
+            let pixels = (0..64)
+                .map(|i| {
+                    let (x, y) = (bx + i % 8, by + i / 8);
+                    (
+                        img1.get_pixel(x, y)[0] as f64,
+                        img2.get_pixel(x, y)[0] as f64,
+                    )
+                })
+                .collect::<Vec<_>>();
+            let mean1 = pixels.iter().map(|p| p.0).sum::<f64>() / 64.0;
+            let mean2 = pixels.iter().map(|p| p.1).sum::<f64>() / 64.0;
+            let var1 = pixels.iter().map(|p| (p.0 - mean1).powi(2)).sum::<f64>() / 64.0;
+            let var2 = pixels.iter().map(|p| (p.1 - mean2).powi(2)).sum::<f64>() / 64.0;
+            let covar = pixels
+                .iter()
+                .map(|p| (p.0 - mean1) * (p.1 - mean2))
+                .sum::<f64>()
+                / 64.0;
+            total += ((2.0 * mean1 * mean2 + C1) * (2.0 * covar + C2))
+                / ((mean1 * mean1 + mean2 * mean2 + C1) * (var1 + var2 + C2));
+            blocks += 1;
+        }
+    }
+    total / blocks as f64
+}
+
+/// Choose the path through frames 0..n_frames of image_dir which minimizes the sum of
+/// visual inconsistency between consecutive kept frames and a penalty for skipping frames.
+/// This follows path_optimizer/main.py, using SSIM in place of feature matching.
+fn native_optimize(image_dir: &Path, n_frames: usize) -> Vec<usize> {
+    if n_frames <= 2 {
+        return (0..n_frames).collect();
+    }
+    let args: serde_json::Value = CLI_OPTIONS
+        .optimizer_arg
+        .as_ref()
+        .and_then(|arg| serde_json::from_str(arg).ok())
+        .unwrap_or_default();
+    let velocity_factor = args["velocity_factor"].as_f64().unwrap_or(100.0);
+    // Scale dissimilarity like the feature matching cost, which is capped at half the diagonal.
+    let max_match_cost = 0.5 * (640.0f64.powi(2) + 480.0f64.powi(2)).sqrt();
+
+    let frames = (0..n_frames)
+        .into_par_iter()
+        .map(|index| load_compare_image(&image_dir.join(format!("{}.jpg", index))))
+        .collect::<Vec<_>>();
+    let match_costs = (0..n_frames - 1)
+        .into_par_iter()
+        .map(|i| {
+            (i + 1..n_frames.min(i + WINDOW_SIZE + 1))
+                .map(|j| {
+                    let dissimilarity = (1.0 - ssim(&frames[i], &frames[j])).clamp(0.0, 1.0);
+                    dissimilarity * max_match_cost
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut cost = vec![f64::INFINITY; n_frames];
+    let mut prevs = vec![0; n_frames];
+    cost[0] = 0.0;
+    for i in 0..n_frames - 1 {
+        for (offset, match_cost) in match_costs[i].iter().enumerate() {
+            let j = i + 1 + offset;
+            let velocity_cost = velocity_factor * (offset as f64).powi(2);
+            let c = match_cost + velocity_cost + cost[i];
+            if c < cost[j] {
+                cost[j] = c;
+                prevs[j] = i;
+            }
+        }
+    }
+    let mut min_path = vec![n_frames - 1];
+    let mut pred = prevs[n_frames - 1];
+    while pred != 0 {
+        min_path.push(pred);
+        pred = prevs[pred];
+    }
+    min_path.push(0);
+    min_path.reverse();
+    min_path
+}
+
+/// Select a visually consistent subsequence of frames {index}.jpg in image_dir (one per point),
+/// using the optimizer executable if given and the native optimizer otherwise.
+/// If rename, kept frames are renamed to {index}.opt.jpg. Their original indices are returned.
+#[tracing::instrument(skip_all)]
+pub async fn optimize_sequence<P: AsRef<Path>>(
+    image_dir: &P,
+    points: &[SerializablePointBearing],
+    rename: bool,
+) -> Vec<usize> {
+    let result = if CLI_OPTIONS.optimizer.is_none() {
+        Some(native_optimize(image_dir.as_ref(), points.len()))
+    } else {
+        let external = async {
+            match CLI_OPTIONS.optimizer_protocol {
+                Some(2) => run_streaming_optimizer(image_dir, points).await,
+                _ => run_external_optimizer(image_dir).await,
+            }
+        };
+        match CLI_OPTIONS.optimizer_timeout {
+            // Dropping the timed out future kills the optimizer process.
+            Some(seconds) => match timeout(Duration::from_secs_f64(seconds), external).await {
+                Ok(result) => result,
+                Err(_) => {
+                    warning(&format!(
+                        "Optimizer timed out after {} seconds, continuing without optimization",
+                        seconds
+                    ));
+                    Some((0..points.len()).collect())
+                }
+            },
+            None => external.await,
+        }
+    };
+    let kept_indices = match result {
+        Some(kept_indices) => kept_indices,
+        None => return vec![],
+    };
+    if !rename {
+        return kept_indices;
+    }
 
     stream::iter(kept_indices.iter().enumerate())
         .for_each(|(to, from)| async move {
@@ -44,7 +332,7 @@ pub async fn optimize_sequence<P: AsRef<Path>>(image_dir: &P) -> Vec<usize> {
                         image_dir.as_ref()
                     ))
                     .files;
-                eprintln!(
+                error!(
                     "file operation error detected, current folder contents are {:?}",
                     &dir_files
                 );
@@ -1,21 +1,312 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use structopt::clap::{ArgGroup, ArgMatches, Shell};
 use structopt::StructOpt;
 
+use crate::errors::{fail, ErrorCode};
+
+/// Stage of the pipeline to run, chosen by the subcommand.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Stage {
+    /// Read the route and print its metadata result.
+    Metadata,
+    /// Fetch the images of the route without encoding them.
+    Fetch,
+    /// Encode a video from images written by Fetch.
+    Encode,
+    /// Fetch the images and encode the video.
+    #[default]
+    Run,
+}
+
 #[derive(StructOpt)]
+#[structopt(name = "streetwarp")]
+enum Command {
+    /// Read the route and print its metadata result without fetching images (same as --dry-run)
+    Metadata(Cli),
+    /// Fetch the images of the route into --output-dir, along with the metadata encode needs
+    Fetch(Cli),
+    /// Encode a video from the directory written by fetch, given as the input path
+    Encode(Cli),
+    /// Fetch the images of the route and encode the video (same as no subcommand)
+    Run(Cli),
+    /// Read jobs from stdin, one JSON object per line like {"id": 1, "args": ["route.gpx", ...]},
+    /// run them in this process and print their progress and results to stdout as JSON lines
+    /// tagged with the job id
+    Jobs {
+        /// Number of jobs to run at once. Default: 1
+        #[structopt(long)]
+        jobs: Option<usize>,
+
+        /// Number of API requests to allow at once across all jobs. Default: 40
+        #[structopt(long)]
+        network_concurrency: Option<usize>,
+
+        /// Maximum number of API requests per second across all jobs, which share one token
+        /// bucket. Default: the --max-qps of each job
+        #[structopt(long)]
+        max_qps: Option<f64>,
+    },
+    /// Render every .gpx or .json route dropped into a directory, moving outputs and a status
+    /// JSON file for each route into a destination directory
+    Watch {
+        /// Directory to watch for routes
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+
+        /// Directory to move outputs and status files into. Default: DIR/rendered
+        #[structopt(long, parse(from_os_str))]
+        dest: Option<PathBuf>,
+
+        /// Profile of options to render with, see --profile of the run subcommand
+        #[structopt(long)]
+        profile: Option<String>,
+
+        /// Seconds between checks for new routes. Default: 5
+        #[structopt(long)]
+        interval: Option<u64>,
+
+        /// Options passed on to each render, after --
+        #[structopt(last = true)]
+        args: Vec<String>,
+    },
+    /// Concatenate the videos and metadata results of the shards rendered with --shard
+    Merge {
+        /// Output directories of the shards, in any order
+        #[structopt(parse(from_os_str), required = true)]
+        shard_dirs: Vec<PathBuf>,
+
+        /// Path of the merged video. Default: streetwarp-lapse with the extension of the shards
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+
+        /// Path of the merged metadata result. Default: the output path with extension .json
+        #[structopt(long, parse(from_os_str))]
+        metadata: Option<PathBuf>,
+
+        /// The path to the ffmpeg executable. Default: ffmpeg on PATH
+        #[structopt(long)]
+        ffmpeg_path: Option<String>,
+    },
+    /// Print a completion script for the shell (bash, zsh, fish, powershell or elvish)
+    Completions {
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+}
+
+/// Arguments that select a subcommand rather than the input path of the flat command line.
+const SUBCOMMANDS: &[&str] = &[
+    "metadata",
+    "fetch",
+    "encode",
+    "run",
+    "jobs",
+    "watch",
+    "merge",
+    "completions",
+    "help",
+];
+
+/// Options that download the route instead of reading the input path, of which only one can be
+/// given.
+const REMOTE_ROUTES: &[&str] = &[
+    "strava-activity",
+    "komoot-tour",
+    "ridewithgps",
+    "garmin-activity",
+];
+
+/// Values of the provider option.
+const PROVIDERS: &[&str] = &["google", "mock"];
+/// Values of the on_missing option.
+const MISSING_POLICIES: &[&str] = &["skip", "placeholder", "fail"];
+/// Values of the on_error option.
+const ERROR_POLICIES: &[&str] = &["retry", "skip", "fail"];
+/// Values of the minterp option.
+pub const MINTERP_MODES: &[&str] = &["skip", "fast", "good", "external"];
+/// Values of the codec option.
+pub const CODECS: &[&str] = &["h264", "hevc", "vp9", "av1"];
+/// Values of the thumbnail_frame option.
+const THUMBNAIL_FRAMES: &[&str] = &["first", "middle", "scenic"];
+/// Values of the ken_burns_direction option.
+const KEN_BURNS_DIRECTIONS: &[&str] = &["in", "out"];
+/// Values of the format option.
+pub const FORMATS: &[&str] = &["video", "gif", "webm", "frames"];
+/// Values of the frame_format option.
+const FRAME_FORMATS: &[&str] = &["jpg", "png", "webp"];
+/// Values of the aspect_fit option.
+const ASPECT_FITS: &[&str] = &["crop", "pad"];
+/// Values of the log_format option.
+const LOG_FORMATS: &[&str] = &["text", "json"];
+/// Values of the attribution_corner and minimap_corner options.
+const CORNERS: &[&str] = &["top-left", "top-right", "bottom-left", "bottom-right"];
+/// Values of the hud_units option.
+const HUD_UNITS: &[&str] = &["km", "mi"];
+/// Values of the speed_units option.
+const SPEED_UNITS: &[&str] = &["kmh", "mph"];
+/// Values of the geotag option.
+const GEOTAGS: &[&str] = &["start", "middle"];
+/// Values of the subtitle_track option.
+const SUBTITLE_TRACKS: &[&str] = &["file", "embed"];
+/// Values of the geocoder option.
+pub const GEOCODERS: &[&str] = &["google", "nominatim"];
+/// Values of the pace option, besides real:SPEEDUP.
+pub const PACES: &[&str] = &["distance", "real", "speed", "gradient"];
+/// Values of the compare_layout option.
+const COMPARE_LAYOUTS: &[&str] = &["side", "stack"];
+
+/// Fail unless value, given for option, is one of choices. Options from the config file skip
+/// the possible_values check of the command line, so every choice is checked again here.
+fn check_choice(option: &str, value: Option<&str>, choices: &[&str]) {
+    if let Some(value) = value {
+        if !choices.contains(&value) {
+            fail(
+                ErrorCode::InputInvalid,
+                &format!(
+                    "Invalid {} {}, expected one of: {}",
+                    option,
+                    value,
+                    choices.join(", ")
+                ),
+            );
+        }
+    }
+}
+
+#[derive(StructOpt, Serialize, Deserialize)]
+#[structopt(group = ArgGroup::with_name("remote-route"))]
 pub struct Cli {
-    /// The path to the file to read, accepts .gpx and .json (format: metadata result) files
-    #[structopt(parse(from_os_str))]
-    pub input_path: PathBuf,
+    /// The path to the file to read, accepts .gpx and .json (format: metadata result) files,
+    /// or for encode the directory written by fetch. Not needed with --strava-activity,
+    /// --komoot-tour, --ridewithgps or --garmin-activity
+    #[structopt(parse(from_os_str), required_unless_one = REMOTE_ROUTES)]
+    input_path: Option<PathBuf>,
+
+    /// Id of a Strava activity to download the route of, instead of reading the input path.
+    /// Requires --strava-token
+    #[structopt(long, group = "remote-route", conflicts_with_all = &["input-path", "use-metadata"])]
+    pub strava_activity: Option<String>,
+
+    /// Strava API access token with the activity:read scope (activity:read_all for private
+    /// activities)
+    #[structopt(long)]
+    pub strava_token: Option<String>,
+
+    /// Id or URL of a Komoot tour to download the route of, instead of reading the input path.
+    /// The share token of a private tour's share link is read from its URL or --komoot-token
+    #[structopt(long, group = "remote-route", conflicts_with_all = &["input-path", "use-metadata"])]
+    pub komoot_tour: Option<String>,
+
+    /// Share token of a private Komoot tour, from the share_token parameter of its share link
+    #[structopt(long)]
+    pub komoot_token: Option<String>,
+
+    /// URL of a RideWithGPS route or trip to download the route of, instead of reading the
+    /// input path, like https://ridewithgps.com/routes/123456
+    #[structopt(long, group = "remote-route", conflicts_with_all = &["input-path", "use-metadata"])]
+    pub ridewithgps: Option<String>,
+
+    /// RideWithGPS API key, needed with --ridewithgps-token for private routes and trips
+    #[structopt(long)]
+    pub ridewithgps_api_key: Option<String>,
+
+    /// RideWithGPS auth token of the user who can see a private route or trip
+    #[structopt(long)]
+    pub ridewithgps_token: Option<String>,
+
+    /// Id of a Garmin Connect activity to download the GPX (or with --garmin-fit, the FIT file)
+    /// of, instead of reading the input path. Requires --garmin-token
+    #[structopt(long, group = "remote-route", conflicts_with_all = &["input-path", "use-metadata"])]
+    pub garmin_activity: Option<String>,
+
+    /// Garmin Connect OAuth 2 access token of the activity's owner, e.g. from the garth library
+    #[structopt(long)]
+    pub garmin_token: Option<String>,
 
-    /// Key for google streetview static API
+    /// Download the original FIT file of the --garmin-activity instead of its GPX export, e.g.
+    /// for activities recorded with more points than the export keeps
+    #[structopt(long, requires = "garmin-activity")]
+    pub garmin_fit: bool,
+
+    /// Read the input path as Google Takeout location history (Records.json, a monthly semantic
+    /// location history file or the on-device timeline export) instead of GPX
+    #[structopt(long, conflicts_with = "use-metadata")]
+    pub takeout: bool,
+
+    /// Start of the location history to use with --takeout, as YYYY-MM-DD (UTC) or an RFC 3339
+    /// time. Default: the first record
+    #[structopt(long, requires = "takeout")]
+    pub takeout_from: Option<String>,
+
+    /// End of the location history to use with --takeout, as YYYY-MM-DD (UTC, including that
+    /// day) or an RFC 3339 time. Default: the last record
+    #[structopt(long, requires = "takeout")]
+    pub takeout_to: Option<String>,
+
+    #[structopt(skip)]
+    #[serde(skip)]
+    stage: Stage,
+
+    /// Key for google streetview static API, required unless set in the config file
+    #[structopt(long)]
+    pub api_key: Option<String>,
+
+    /// TOML file of default options, with named profiles in a [profiles.NAME] table for each.
+    /// Options given on the command line take precedence. Default: $XDG_CONFIG_HOME/streetwarp/config.toml
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Upload the output video to object storage at s3://bucket/key or gs://bucket/key (a key
+    /// ending in / gets the output filename). Credentials are read from AWS_ACCESS_KEY_ID and
+    /// AWS_SECRET_ACCESS_KEY (with AWS_REGION, AWS_SESSION_TOKEN and AWS_ENDPOINT_URL if set),
+    /// or GCS_HMAC_ACCESS_ID and GCS_HMAC_SECRET for gs. Default: none
     #[structopt(long)]
-    pub api_key: String,
+    pub upload: Option<String>,
+
+    /// After a successful encode, delete the fetched frames and intermediate files, or the whole
+    /// output directory if it is a temporary one and --output points elsewhere. Default: off
+    #[structopt(long, conflicts_with = "keep-frames")]
+    pub cleanup: bool,
+
+    /// Keep the fetched frames and intermediate files without warning about their size.
+    /// Default: off (kept, with a warning for temporary directories)
+    #[structopt(long)]
+    pub keep_frames: bool,
+
+    /// On Ctrl-C, delete the output directory if it is a temporary one created for this run,
+    /// instead of leaving it with a checkpoint to resume from. Default: off
+    #[structopt(long)]
+    pub cancel_cleanup: bool,
+
+    /// Shell command to run after a successful encode, with the output path and the path of a
+    /// JSON file of the route metadata as arguments, e.g. "rclone copyto". Default: none
+    #[structopt(long)]
+    pub post_cmd: Option<String>,
+
+    /// URL to POST a JSON notification to when the run finishes or fails, with its status,
+    /// output path and size, and route summary. Default: none
+    #[structopt(long)]
+    pub notify_url: Option<String>,
+
+    /// Identifier of the run included in --notify-url notifications. Default: none
+    #[structopt(long)]
+    pub job_id: Option<String>,
+
+    /// Print a JSON description of the supported input formats, providers, minterp modes, codecs
+    /// and schema versions, then exit. No input path is needed.
+    #[structopt(long)]
+    pub capabilities: bool,
+
+    /// Profile of options to apply from the config file, or built in: preview (fast low quality
+    /// render), archival (dense frames, hevc, high quality), social (9:16, 30 seconds, thumbnail).
+    #[structopt(long)]
+    pub profile: Option<String>,
 
     /// Output location for individual frames. Default: tmp folder
     #[structopt(long)]
     pub output_dir: Option<String>,
 
-    /// Output filename for timelapse. Default: streetwarp-lapse.mp4
+    /// Output filename for timelapse. Default: streetwarp-lapse.mp4 (.webm for vp9)
     #[structopt(short, long)]
     pub output: Option<String>,
 
@@ -23,19 +314,122 @@ pub struct Cli {
     #[structopt(long)]
     pub network_concurrency: Option<usize>,
 
+    /// Seconds to wait for a network request, including reading its response, before retrying
+    /// it. Default: no timeout
+    #[structopt(long)]
+    pub request_timeout: Option<u64>,
+
+    /// Proxy for every network request, e.g. http://proxy:8080 or socks5://proxy:1080.
+    /// Default: HTTP_PROXY/HTTPS_PROXY, or ALL_PROXY if neither is set
+    #[structopt(long)]
+    pub proxy: Option<String>,
+
+    /// Encode frames while the rest are still being fetched, overlapping downloads with encoding.
+    /// Not available with options that need every frame first, like --optimize. Default: off
+    #[structopt(long)]
+    pub pipeline: bool,
+
+    /// Pipe the frames to ffmpeg over stdin instead of having it read a numbered file sequence.
+    /// Optimized frames are then piped under their original names rather than renamed, unless
+    /// a later step reads them in sequence. Default: off
+    #[structopt(long)]
+    pub image2pipe: bool,
+
+    /// Size in meters of the grid cells whose points share one metadata request, since points
+    /// only meters apart get the same panorama. 0 requests metadata for every point. Default: 10
+    #[structopt(long)]
+    pub metadata_cell_meters: Option<f64>,
+
+    /// Read the GPX file as a stream and interpolate its points lazily, so memory stays bounded
+    /// for tracks with millions of points. Default: off
+    #[structopt(long)]
+    pub low_memory: bool,
+
+    /// Maximum number of API requests to send per second across metadata, image and geocoding
+    /// requests, e.g. 5 to stay under a quota of 300 per minute. Default: unlimited
+    #[structopt(long)]
+    pub max_qps: Option<f64>,
+
+    /// Make runs reproducible: handle concurrent results in input order, report every progress
+    /// message without debouncing or ETAs, and name the temporary directory after the command
+    /// line, so the same input and cache give bit-identical metadata. Default: off
+    #[structopt(long)]
+    pub deterministic: bool,
+
+    /// Render only shard I of N equal slices of the route's frames, given as I/N counting from 1,
+    /// into --output-dir, for streetwarp merge to join with the other shards. Every shard reads
+    /// the whole route, so with the same input and options the slices never overlap. Options that
+    /// apply to the whole video, like --title, --audio and --chapters, cannot be used with it.
+    #[structopt(long)]
+    pub shard: Option<String>,
+
+    /// Source of the streetview imagery: google, or mock for synthetic frames numbered and
+    /// labeled with their coordinates, to try the whole pipeline without an API key or cost.
+    /// Default: google
+    #[structopt(long, possible_values = PROVIDERS)]
+    pub provider: Option<String>,
+
+    /// Save every API response into this directory, to replay the run later with --replay.
+    /// The API key is left out of the saved requests. Default: off
+    #[structopt(long, conflicts_with = "replay")]
+    pub record: Option<String>,
+
+    /// Answer API requests with the responses saved by --record into this directory instead of
+    /// calling the API, failing on any request that was not recorded. No API key is needed.
+    /// Default: off
+    #[structopt(long)]
+    pub replay: Option<String>,
+
+    /// Number of times to refetch missing or corrupt images before giving up, waiting longer
+    /// before each attempt, default: 3.
+    #[structopt(long)]
+    pub fetch_retries: Option<usize>,
+
+    /// Directory of frames shared between runs, keyed by panorama, heading and size, so routes
+    /// that overlap earlier ones reuse their frames instead of downloading them again.
+    /// Default: no frame store
+    #[structopt(long, parse(from_os_str))]
+    pub frame_store: Option<PathBuf>,
+
+    /// Largest size of the frame store in megabytes. After fetching, the least recently used
+    /// frames are removed until it fits. Default: no limit
+    #[structopt(long, requires = "frame-store")]
+    pub frame_store_max_mb: Option<f64>,
+
+    /// What to do with points where Street View has no imagery. Available: skip (drop them),
+    /// placeholder (keep them with Google's "no imagery" frame), fail. Default: skip
+    #[structopt(long, possible_values = MISSING_POLICIES)]
+    pub on_missing: Option<String>,
+
+    /// What to do when a metadata request fails. Available: retry (up to --fetch-retries times,
+    /// then fail), skip (drop the point), fail. Default: fail
+    #[structopt(long, possible_values = ERROR_POLICIES)]
+    pub on_error: Option<String>,
+
     /// Number of frames to search for per mile, default: 100.
     #[structopt(short, long)]
     pub frames_per_mile: Option<f64>,
 
+    /// Drop frames whose panorama is more than this many meters from the route, e.g. on a
+    /// parallel highway. Default: no limit
+    #[structopt(long)]
+    pub max_error_meters: Option<f64>,
+
     /// Maximum number of frames, default: unlimited (set to 0)
     #[structopt(long)]
     pub max_frames: Option<usize>,
 
+    /// Length of the output video in seconds. Frames are dropped evenly if the route has more than
+    /// one per output frame, otherwise each frame is shown longer. Default: one frame per 1/fps seconds
+    #[structopt(long)]
+    pub target_duration: Option<f64>,
+
     /// Skip first offset_frames in creating the video, default: 0
     #[structopt(long)]
     pub offset_frames: Option<usize>,
 
-    /// Don't fetch images or create video, just show metadata and expected error.
+    /// Don't fetch images or create video, just show metadata and expected error (same as the
+    /// metadata subcommand).
     #[structopt(short, long)]
     pub dry_run: bool,
 
@@ -51,10 +445,256 @@ pub struct Cli {
     #[structopt(long)]
     pub interp: Option<usize>,
 
-    /// Use motion interpolation to smooth output video. Available: skip, fast, good. Default: good
-    #[structopt(long)]
+    /// Use motion interpolation to smooth output video. Available: skip, fast, good, external
+    /// (interpolate with --minterp-cmd). Default: good
+    #[structopt(long, possible_values = MINTERP_MODES)]
     pub minterp: Option<String>,
 
+    /// Command for --minterp external, like a wrapper around RIFE, run through the shell with the
+    /// path of the video to interpolate, the path of the video to write and the frame rate to
+    /// interpolate to as arguments. Both paths are relative to --output-dir, where it runs
+    #[structopt(long)]
+    pub minterp_cmd: Option<String>,
+
+    /// Render a short segment (max_frames, default: 150 frames) with each minterp mode side by
+    /// side in one video to help choose a mode, instead of the full route.
+    #[structopt(long)]
+    pub compare_minterp: bool,
+
+    /// Use the historical panorama captured closest to this year (format: YYYY). Default: latest.
+    /// Requires --photo-service
+    #[structopt(long)]
+    pub pano_date_target: Option<i32>,
+
+    /// Render the route for two years (format: YYYY,YYYY) and combine them into one comparison video.
+    /// Requires --photo-service
+    #[structopt(long, use_delimiter = true)]
+    pub compare_years: Vec<i32>,
+
+    /// Allow finding older panoramas through the photo service of the Google Maps javascript
+    /// client, which is not a documented API and may change or stop working at any time.
+    /// Needed by --pano-date-target and --compare-years. Default: off
+    #[structopt(long)]
+    pub photo_service: bool,
+
+    /// Render a second GPX route next to the main one in a comparison video. Both routes advance
+    /// by the same fraction of their distance every frame.
+    #[structopt(long, parse(from_os_str), conflicts_with = "compare-years")]
+    pub compare_route: Option<PathBuf>,
+
+    /// Layout of the comparison video. Available: side (side-by-side), stack (top/bottom). Default: side
+    #[structopt(long, possible_values = COMPARE_LAYOUTS)]
+    pub compare_layout: Option<String>,
+
+    /// Video codec of the output. Available: h264, hevc, vp9, av1. Default: h264
+    #[structopt(long, possible_values = CODECS)]
+    pub codec: Option<String>,
+
+    /// Package the kept frames, the metadata as JSON and the final video into this archive
+    /// (.zip or .tar). Default: none
+    #[structopt(long, parse(from_os_str))]
+    pub archive: Option<PathBuf>,
+
+    /// Write the original track, the panorama of each frame (with its bearing, pano id, date and
+    /// distance from the route) and the gaps in coverage to this GeoJSON file. Default: off
+    #[structopt(long, parse(from_os_str))]
+    pub export_geojson: Option<PathBuf>,
+
+    /// Write the route as rendered, one track point at the panorama of each frame with its
+    /// bearing as the course, to this GPX file, to load into mapping tools or render again.
+    /// Default: off
+    #[structopt(long, parse(from_os_str))]
+    pub export_gpx: Option<PathBuf>,
+
+    /// Write a row per frame of the video, after --dedup, --optimize and pacing, with its index,
+    /// location, bearing, pano id, pano date, distance from the route and distance along the
+    /// route to this CSV file. Default: off
+    #[structopt(long, parse(from_os_str))]
+    pub export_csv: Option<PathBuf>,
+
+    /// Write a map of the track, the panorama of each frame colored by its distance from the
+    /// route and the gaps in coverage to this HTML file. With --dry-run, check the coverage
+    /// before downloading any images. Default: off
+    #[structopt(long, parse(from_os_str))]
+    pub export_html: Option<PathBuf>,
+
+    /// Write {index}.json next to each fetched {index}.jpg with the pano id, capture date,
+    /// copyright, location and heading of the frame.
+    #[structopt(long)]
+    pub frame_sidecars: bool,
+
+    /// Render a quick draft to check the route: only every preview_step-th frame, at half the
+    /// video size and with --minterp skip, so it costs a fraction of the time and API requests.
+    #[structopt(long)]
+    pub preview: bool,
+
+    /// Keep every Nth frame in --preview mode. Default: 5
+    #[structopt(long)]
+    pub preview_step: Option<usize>,
+
+    /// Tile evenly spaced frames into a grid image (format: NxM for N columns and M rows) before
+    /// encoding, to check coverage and imagery quality. Default: none
+    #[structopt(long)]
+    pub contact_sheet: Option<String>,
+
+    /// Stop after writing the contact sheet, without encoding the video.
+    #[structopt(long, requires = "contact-sheet")]
+    pub contact_sheet_only: bool,
+
+    /// Save a representative frame of the video next to it as a .jpg thumbnail.
+    #[structopt(long)]
+    pub thumbnail: bool,
+
+    /// Which frame to use as the thumbnail: first, middle or scenic (the most colorful).
+    /// Default: middle
+    #[structopt(long, possible_values = THUMBNAIL_FRAMES)]
+    pub thumbnail_frame: Option<String>,
+
+    /// Music to add to the video as its soundtrack, looped or trimmed to the video length.
+    #[structopt(long, parse(from_os_str))]
+    pub audio: Option<PathBuf>,
+
+    /// Seconds to fade out the --audio track at the end of the video. Default: 3
+    #[structopt(long)]
+    pub audio_fade: Option<f64>,
+
+    /// Normalize the loudness of the --audio track with ffmpeg's loudnorm filter.
+    #[structopt(long, requires = "audio")]
+    pub loudnorm: bool,
+
+    /// Integrated loudness target in LUFS for --loudnorm. Default: -14 (streaming services)
+    #[structopt(long, allow_hyphen_values = true)]
+    pub loudness_target: Option<f64>,
+
+    /// How long each frame is shown: distance (one frame per sampled point) or real[:SPEEDUP]
+    /// (by the time between GPX track points, sped up SPEEDUP times, by default to keep the
+    /// video length), repeating frames where the route was slow and skipping where fast,
+    /// speed (skip more frames where the recorded speed was low, see --speed-curve) or gradient
+    /// (slow down on climbs and speed up on descents, see --gradient-doubling). Default: distance
+    #[structopt(long)]
+    pub pace: Option<String>,
+
+    /// Exponent of recorded speed for --pace speed: positive drops more frames where slow,
+    /// negative drops more where fast, and 0 keeps every frame. Default: 1
+    #[structopt(long, allow_hyphen_values = true)]
+    pub speed_curve: Option<f64>,
+
+    /// Grade in percent that doubles the time a frame is shown on climbs (and halves it on
+    /// descents) for --pace gradient. Default: 8
+    #[structopt(long)]
+    pub gradient_doubling: Option<f64>,
+
+    /// Seconds to hold the frame closest to each GPX waypoint near the route. Default: 0
+    #[structopt(long)]
+    pub waypoint_dwell: Option<f64>,
+
+    /// Show the waypoint name while holding its frame for --waypoint-dwell.
+    #[structopt(long, requires = "waypoint-dwell")]
+    pub waypoint_dwell_labels: bool,
+
+    /// Slowly zoom into frames that are held (by --waypoint-dwell or slow parts of --pace)
+    /// instead of freezing them.
+    #[structopt(long)]
+    pub ken_burns: bool,
+
+    /// Zoom factor reached at the end of each held frame for --ken-burns. Default: 1.1
+    #[structopt(long)]
+    pub ken_burns_zoom: Option<f64>,
+
+    /// Direction of the --ken-burns zoom: in or out. Default: in
+    #[structopt(long, possible_values = KEN_BURNS_DIRECTIONS)]
+    pub ken_burns_direction: Option<String>,
+
+    /// Pace frames to the beat of the --audio track (or --bpm): use a frame rate with a whole
+    /// number of frames per beat, and drop frames so sharp turns land on a beat.
+    #[structopt(long, conflicts_with = "target-duration")]
+    pub beat_sync: bool,
+
+    /// Tempo in beats per minute for --beat-sync, instead of detecting it from --audio.
+    #[structopt(long)]
+    pub bpm: Option<f64>,
+
+    /// Output format: video (encoded with the codec option), gif (an animated GIF converted from
+    /// the video, for chats and READMEs), webm (vp9 or av1 with a silent opus audio track) or
+    /// frames (skip encoding and export the final frames with a manifest.json into frames/).
+    /// Default: video
+    #[structopt(long, possible_values = FORMATS)]
+    pub format: Option<String>,
+
+    /// Image format of frames exported by --format frames: jpg, png or webp (both lossless).
+    /// Default: jpg
+    #[structopt(long, possible_values = FRAME_FORMATS)]
+    pub frame_format: Option<String>,
+
+    /// Frames per second of GIF output. Default: 10
+    #[structopt(long)]
+    pub gif_fps: Option<u32>,
+
+    /// Width in pixels of GIF output, keeping the aspect ratio. Default: 480
+    #[structopt(long)]
+    pub gif_width: Option<u32>,
+
+    /// Frame rate of the timelapse before motion interpolation, default: 24.
+    #[structopt(long)]
+    pub fps: Option<u32>,
+
+    /// Size of the output video (format: WxH), default: 640x480.
+    /// Images are requested at the largest size within 640x640 with the same aspect ratio.
+    #[structopt(long)]
+    pub video_size: Option<String>,
+
+    /// Aspect ratio of the output video (format: W:H, e.g. 9:16, 1:1, 16:9), used instead of video_size.
+    /// The shorter side of the video is 480 pixels.
+    #[structopt(long, conflicts_with = "video-size")]
+    pub aspect: Option<String>,
+
+    /// How frames with a different aspect ratio than the video are fit into it: crop|pad, default: crop
+    #[structopt(long, possible_values = ASPECT_FITS)]
+    pub aspect_fit: Option<String>,
+
+    /// Pixels to crop from the bottom of every frame before fitting it to the video size,
+    /// or "auto" to crop just the Google logo and copyright line. Default: 0
+    #[structopt(long)]
+    pub crop_bottom: Option<String>,
+
+    /// Constant rate factor of the encoder, lower is higher quality.
+    /// Default: 22 (h264), 26 (hevc), 32 (vp9), 30 (av1)
+    #[structopt(long, alias = "quality")]
+    pub crf: Option<u32>,
+
+    /// Encode the final video in two passes at this video bitrate in kbps instead of using crf.
+    #[structopt(long, conflicts_with = "target-size-mb")]
+    pub bitrate: Option<u64>,
+
+    /// Encode the final video in two passes at the bitrate that makes it about this many megabytes.
+    /// Useful for uploading to sites with file size limits.
+    #[structopt(long)]
+    pub target_size_mb: Option<f64>,
+
+    /// Encode the timelapse frames in chunks of about this many frames in parallel ffmpeg
+    /// processes, up to one per core, then join the chunks without reencoding. Speeds up long
+    /// videos on machines with many cores. Default: off (one encode).
+    #[structopt(long)]
+    pub encode_chunk_frames: Option<usize>,
+
+    /// Extra whitespace-separated arguments for ffmpeg, inserted before the first input of every command.
+    #[structopt(long, allow_hyphen_values = true)]
+    pub ffmpeg_input_args: Option<String>,
+
+    /// Extra whitespace-separated arguments for ffmpeg, inserted before the output of every command.
+    #[structopt(long, allow_hyphen_values = true)]
+    pub ffmpeg_output_args: Option<String>,
+
+    /// The path to the ffmpeg executable. Default: ffmpeg on PATH
+    #[structopt(long)]
+    pub ffmpeg_path: Option<String>,
+
+    /// Encode the timelapse in-process with the ffmpeg libraries instead of the ffmpeg executable.
+    /// Motion interpolation is not available in this mode. Default: off.
+    #[cfg(feature = "native-encode")]
+    #[structopt(long)]
+    pub native_encode: bool,
+
     /// Output in JSON format. Default: off.
     #[structopt(long)]
     pub json: bool,
@@ -63,15 +703,537 @@ pub struct Cli {
     #[structopt(long)]
     pub progress: bool,
 
-    /// The path to the image optimization executable file.
+    /// Write progress messages (in JSON) to a file path, unix:/path/to/socket or tcp:host:port
+    /// instead of stdout, keeping stdout for the metadata output. Implies --progress.
+    /// Default: stdout
+    #[structopt(long)]
+    pub progress_sink: Option<String>,
+
+    /// Log more details to stderr: -v for debug messages, -vv for trace messages.
+    /// Default: info messages
+    #[structopt(short, long, parse(from_occurrences))]
+    pub verbose: u8,
+
+    /// Format of the log messages on stderr, text or json (one object per line, with the spans
+    /// of the stage it was logged in). Default: text
+    #[structopt(long, possible_values = LOG_FORMATS)]
+    pub log_format: Option<String>,
+
+    /// Remove near-identical consecutive frames by perceptual hash before encoding. Default: off.
+    #[structopt(long)]
+    pub dedup: bool,
+
+    /// Maximum number of differing hash bits (out of 64) for frames to count as duplicates, default: 4.
+    #[structopt(long)]
+    pub dedup_threshold: Option<u32>,
+
+    /// Histogram-match the colors of each frame to the frames before it to reduce color
+    /// and exposure jumps between panoramas. Default: off.
+    #[structopt(long)]
+    pub color_match: bool,
+
+    /// Number of previous frames whose colors each frame is matched to, default: 5.
+    #[structopt(long)]
+    pub color_match_window: Option<usize>,
+
+    /// How far (0 to 1) each frame's colors move toward the previous frames, default: 0.7.
+    #[structopt(long)]
+    pub color_match_strength: Option<f64>,
+
+    /// Burn an attribution ("© Google Street View, imagery dates ...") into a corner of the video.
+    /// Default: off.
+    #[structopt(long)]
+    pub attribution: bool,
+
+    /// Attribution text to burn in instead, where {dates} is replaced by the range of imagery dates.
+    #[structopt(long)]
+    pub attribution_text: Option<String>,
+
+    /// Corner of the attribution: top-left|top-right|bottom-left|bottom-right, default: bottom-right
+    #[structopt(long, possible_values = CORNERS)]
+    pub attribution_corner: Option<String>,
+
+    /// Add a title card with this title, the route distance and imagery dates to the start
+    /// of the video, and an end card to the end.
+    #[structopt(long)]
+    pub title: Option<String>,
+
+    /// Seconds to show each of the title and end cards, default: 3.
+    #[structopt(long)]
+    pub card_duration: Option<f64>,
+
+    /// Burn a HUD with the distance travelled and percent complete into each frame. Default: off.
+    #[structopt(long)]
+    pub hud: bool,
+
+    /// Units of the HUD distance: km|mi, default: km
+    #[structopt(long, possible_values = HUD_UNITS)]
+    pub hud_units: Option<String>,
+
+    /// Show the speed at each frame, computed from the times of the GPX track points. Default: off.
+    #[structopt(long)]
+    pub speed_overlay: bool,
+
+    /// Units of the speed overlay: kmh|mph, default: kmh
+    #[structopt(long, possible_values = SPEED_UNITS)]
+    pub speed_units: Option<String>,
+
+    /// Show heart rate, power and cadence at each frame, read from the sensor file. Default: off.
+    #[structopt(long)]
+    pub data_overlay: bool,
+
+    /// FIT or GPX file with heart rate, power and cadence (e.g. Garmin TrackPointExtension),
+    /// matched to frames by distance. Default: the input GPX file
+    #[structopt(long, parse(from_os_str))]
+    pub sensor_file: Option<PathBuf>,
+
+    /// Add a subtitle track named GPS to the video giving the location of every frame, in the
+    /// style of drone SRT logs, and write it next to the video as an .srt file for telemetry
+    /// overlay tools. This is a plain subtitle track, not a GPMF or camm metadata track, so
+    /// photo managers will not georeference the video from it (see --geotag). Default: off.
+    #[structopt(long)]
+    pub gps_subtitles: bool,
+
+    /// Geotag the video with the location of the route's start or middle point (start|middle),
+    /// so phones and photo managers can show it on a map. Default: none
+    #[structopt(long, possible_values = GEOTAGS)]
+    pub geotag: Option<String>,
+
+    /// Add chapter markers at the waypoints of the input GPX. Default: off.
+    #[structopt(long)]
+    pub chapters: bool,
+
+    /// Write a subtitle track with the place, distance and imagery date of each second of the video:
+    /// file (an .srt next to the video) or embed (also muxed into the video). Default: none
+    #[structopt(long, possible_values = SUBTITLE_TRACKS)]
+    pub subtitle_track: Option<String>,
+
+    /// Show the name of the current street at the bottom of the video, found by reverse geocoding.
+    /// Default: off.
+    #[structopt(long)]
+    pub street_names: bool,
+
+    /// Briefly show "Entering <town>" at the top of the video when the route crosses into
+    /// a new town, found by reverse geocoding. Default: off.
+    #[structopt(long)]
+    pub entering_labels: bool,
+
+    /// Seconds to show each entering label, default: 2.5.
+    #[structopt(long)]
+    pub entering_label_duration: Option<f64>,
+
+    /// ASS style of the entering labels (e.g. "Alignment=8,FontSize=28,PrimaryColour=&H00FFFF&").
+    /// Default: large bold outlined text at the top center.
+    #[structopt(long)]
+    pub entering_label_style: Option<String>,
+
+    /// Reverse geocoding service: google|nominatim, default: google (needs the Geocoding API enabled
+    /// for api_key). Nominatim is limited to one request per second.
+    #[structopt(long, possible_values = GEOCODERS)]
+    pub geocoder: Option<String>,
+
+    /// Distance in meters between reverse geocoded points along the route, default: 250.
+    #[structopt(long)]
+    pub geocode_interval: Option<f64>,
+
+    /// Draw a minimap of the route with a marker at the current position into a corner of each frame.
+    /// Default: off.
+    #[structopt(long)]
+    pub minimap: bool,
+
+    /// Corner of the minimap: top-left|top-right|bottom-left|bottom-right, default: top-right
+    #[structopt(long, possible_values = CORNERS)]
+    pub minimap_corner: Option<String>,
+
+    /// Width and height of the minimap in pixels, default: a quarter of the frame's shorter side.
+    #[structopt(long)]
+    pub minimap_size: Option<u32>,
+
+    /// Even out brightness between frames with ffmpeg's deflicker filter before minterp. Default: off.
+    #[structopt(long)]
+    pub deflicker: bool,
+
+    /// Number of frames the deflicker filter averages brightness over, default: 5.
+    #[structopt(long)]
+    pub deflicker_size: Option<u32>,
+
+    /// Reduce jitter with ffmpeg's two-pass vid.stab stabilization before minterp. Default: off.
+    #[structopt(long)]
+    pub stabilize: bool,
+
+    /// Number of frames on each side used to smooth camera motion when stabilizing, default: 10.
+    #[structopt(long)]
+    pub stabilize_smoothing: Option<u32>,
+
+    /// The path to an executable to run on every fetched frame, with arguments: frame path, frame metadata JSON.
+    /// It may modify the frame in place.
+    #[structopt(long, parse(from_os_str))]
+    pub frame_hook: Option<PathBuf>,
+
+    /// Number of frame hooks to run at once, default: number of cpus.
+    #[structopt(long)]
+    pub frame_hook_concurrency: Option<usize>,
+
+    /// The path to a rhai script defining filter_point(point) and/or frame_name(index, point)
+    /// to drop or adjust points and rename frames.
+    #[cfg(feature = "scripting")]
+    #[structopt(long, parse(from_os_str))]
+    pub script: Option<PathBuf>,
+
+    /// Optimize the image sequence to remove visually inconsistent frames. Default: off.
+    #[structopt(long)]
+    pub optimize: bool,
+
+    /// The path to an image optimization executable file, used instead of the built-in optimizer.
     #[structopt(long, parse(from_os_str))]
     pub optimizer: Option<PathBuf>,
 
-    /// Additional argument to pass to optimization executable (after output folder)
+    /// Additional argument to pass to optimization executable (after output folder).
+    /// The built-in optimizer reads velocity_factor from this JSON object.
     #[structopt(long)]
     pub optimizer_arg: Option<String>,
+
+    /// Protocol version of the optimization executable. 1: directory in argv, JSON indices on stdout.
+    /// 2: frame metadata streamed to stdin and per-frame decisions read from stdout. Default: 1
+    #[structopt(long)]
+    pub optimizer_protocol: Option<u32>,
+
+    /// Seconds to wait for the optimization executable before killing it and continuing
+    /// without optimization. Default: no timeout
+    #[structopt(long)]
+    pub optimizer_timeout: Option<f64>,
 }
 
-lazy_static! {
-    pub static ref CLI_OPTIONS: Cli = Cli::from_args();
+impl Cli {
+    /// The api_key option, which every stage that requests from Google needs.
+    pub fn api_key(&self) -> &str {
+        // Recorded requests are found without their key, so any will do.
+        if self.replay.is_some() && self.api_key.is_none() {
+            return "replay";
+        }
+        if self.provider.as_deref() == Some("mock") && self.api_key.is_none() {
+            return "mock";
+        }
+        self.api_key.as_deref().unwrap_or_else(|| {
+            fail(
+                ErrorCode::ApiAuth,
+                "--api-key is required (or api_key in the config file)",
+            )
+        })
+    }
+
+    /// The input_path argument, which every source but a remote route needs.
+    pub fn input_path(&self) -> &Path {
+        self.input_path.as_deref().unwrap_or_else(|| {
+            fail(
+                ErrorCode::InputInvalid,
+                "This needs an input path, which a remote route does not provide",
+            )
+        })
+    }
+
+    /// Check the options that can be wrong together, before anything is requested.
+    fn validate(&self) {
+        if (self.pano_date_target.is_some() || !self.compare_years.is_empty())
+            && !self.photo_service
+        {
+            fail(
+                ErrorCode::InputInvalid,
+                "--pano-date-target and --compare-years find older panoramas through an undocumented photo service, pass --photo-service to allow it",
+            );
+        }
+        if !self.compare_years.is_empty() && self.compare_years.len() != 2 {
+            fail(
+                ErrorCode::InputInvalid,
+                "--compare-years expects exactly two years, e.g. 2010,2020",
+            );
+        }
+        check_choice(
+            "--compare-layout",
+            self.compare_layout.as_deref(),
+            COMPARE_LAYOUTS,
+        );
+        check_choice("--provider", self.provider.as_deref(), PROVIDERS);
+        check_choice("--on-missing", self.on_missing.as_deref(), MISSING_POLICIES);
+        check_choice("--on-error", self.on_error.as_deref(), ERROR_POLICIES);
+        check_choice("--minterp", self.minterp.as_deref(), MINTERP_MODES);
+        if self.minterp.as_deref() == Some("external") && self.minterp_cmd.is_none() {
+            fail(
+                ErrorCode::InputInvalid,
+                "--minterp external needs the interpolation command in --minterp-cmd",
+            );
+        }
+        check_choice("--codec", self.codec.as_deref(), CODECS);
+        check_choice(
+            "--thumbnail-frame",
+            self.thumbnail_frame.as_deref(),
+            THUMBNAIL_FRAMES,
+        );
+        check_choice(
+            "--ken-burns-direction",
+            self.ken_burns_direction.as_deref(),
+            KEN_BURNS_DIRECTIONS,
+        );
+        check_choice("--format", self.format.as_deref(), FORMATS);
+        check_choice(
+            "--frame-format",
+            self.frame_format.as_deref(),
+            FRAME_FORMATS,
+        );
+        check_choice("--aspect-fit", self.aspect_fit.as_deref(), ASPECT_FITS);
+        check_choice("--log-format", self.log_format.as_deref(), LOG_FORMATS);
+        check_choice(
+            "--attribution-corner",
+            self.attribution_corner.as_deref(),
+            CORNERS,
+        );
+        check_choice("--minimap-corner", self.minimap_corner.as_deref(), CORNERS);
+        check_choice("--hud-units", self.hud_units.as_deref(), HUD_UNITS);
+        check_choice("--speed-units", self.speed_units.as_deref(), SPEED_UNITS);
+        check_choice("--geotag", self.geotag.as_deref(), GEOTAGS);
+        check_choice(
+            "--subtitle-track",
+            self.subtitle_track.as_deref(),
+            SUBTITLE_TRACKS,
+        );
+        check_choice("--geocoder", self.geocoder.as_deref(), GEOCODERS);
+        if let Some(pace) = self.pace.as_deref() {
+            let valid = match pace.strip_prefix("real:") {
+                Some(speedup) => speedup.parse::<f64>().is_ok_and(|s| s > 0.0),
+                None => PACES.contains(&pace),
+            };
+            if !valid {
+                fail(
+                    ErrorCode::InputInvalid,
+                    &format!(
+                        "Invalid --pace {}, expected distance, real[:SPEEDUP], speed or gradient",
+                        pace
+                    ),
+                );
+            }
+        }
+        if let Some(pixels) = self.crop_bottom.as_deref() {
+            if pixels != "auto" && pixels.parse::<u32>().is_err() {
+                fail(
+                    ErrorCode::InputInvalid,
+                    &format!("Invalid --crop-bottom {}, expected pixels or auto", pixels),
+                );
+            }
+        }
+        if self.beat_sync && self.audio.is_none() && self.bpm.is_none() {
+            fail(
+                ErrorCode::InputInvalid,
+                "--beat-sync needs the tempo from --bpm or the beat of --audio",
+            );
+        }
+        if let Some(bpm) = self.bpm {
+            if !bpm.is_finite() || bpm <= 0.0 {
+                fail(
+                    ErrorCode::InputInvalid,
+                    &format!("Invalid --bpm {}, expected beats per minute > 0", bpm),
+                );
+            }
+        }
+        if self.shard.is_some() {
+            // Each shard only renders its part of the video, and merge only concatenates them.
+            let whole_video = [
+                ("--target-duration", self.target_duration.is_some()),
+                ("--title", self.title.is_some()),
+                ("--card-duration", self.card_duration.is_some()),
+                ("--audio", self.audio.is_some()),
+                ("--audio-fade", self.audio_fade.is_some()),
+                ("--chapters", self.chapters),
+            ];
+            let set = whole_video
+                .iter()
+                .filter(|(_, set)| *set)
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>();
+            if !set.is_empty() {
+                fail(
+                    ErrorCode::InputInvalid,
+                    &format!(
+                        "{} cannot be used with --shard, since each shard renders only part of the video",
+                        set.join(", ")
+                    ),
+                );
+            }
+        }
+        if let Some(seconds) = self.optimizer_timeout {
+            // Checked here since Duration::from_secs_f64 panics on these.
+            if !seconds.is_finite() || seconds < 0.0 {
+                fail(
+                    ErrorCode::InputInvalid,
+                    &format!(
+                        "Invalid --optimizer-timeout {}, expected a number of seconds >= 0",
+                        seconds
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Stage of the pipeline to run, from the subcommand or the dry_run option.
+    pub fn stage(&self) -> Stage {
+        if self.dry_run {
+            Stage::Metadata
+        } else {
+            self.stage
+        }
+    }
+}
+
+/// What the command line asks for.
+pub enum Invocation {
+    /// One run of the pipeline with these options.
+    Run(Box<Cli>),
+    /// Run the jobs read from stdin, jobs at once, sharing at most network_concurrency requests
+    /// at once and max_qps requests per second.
+    Jobs {
+        jobs: usize,
+        network_concurrency: usize,
+        max_qps: Option<f64>,
+    },
+    /// Watch dir for routes, rendering them with profile and args into dest every interval
+    /// seconds.
+    Watch {
+        dir: PathBuf,
+        dest: PathBuf,
+        profile: Option<String>,
+        interval: u64,
+        args: Vec<String>,
+    },
+}
+
+/// Parse the command line as a subcommand, or as the flat command line that runs everything.
+pub fn parse_cli() -> Invocation {
+    let args = std::env::args_os().collect::<Vec<_>>();
+    // Checked before parsing since it is the only option that needs no input path.
+    if args.iter().any(|arg| arg == "--capabilities") {
+        println!("{}", crate::capabilities::capabilities());
+        std::process::exit(0);
+    }
+    let has_subcommand = args
+        .get(1)
+        .and_then(|arg| arg.to_str())
+        .is_some_and(|arg| SUBCOMMANDS.contains(&arg));
+    let matches = if has_subcommand {
+        Command::clap().get_matches_from(args)
+    } else {
+        Cli::clap().get_matches_from(args)
+    };
+    let (stage, cli) = if !has_subcommand {
+        (Stage::Run, Cli::from_clap(&matches))
+    } else {
+        match Command::from_clap(&matches) {
+            Command::Metadata(cli) => (Stage::Metadata, cli),
+            Command::Fetch(cli) => (Stage::Fetch, cli),
+            Command::Encode(cli) => (Stage::Encode, cli),
+            Command::Run(cli) => (Stage::Run, cli),
+            Command::Jobs {
+                jobs,
+                network_concurrency,
+                max_qps,
+            } => {
+                return Invocation::Jobs {
+                    jobs: jobs.unwrap_or(1).max(1),
+                    network_concurrency: network_concurrency.unwrap_or(40).max(1),
+                    max_qps,
+                };
+            }
+            Command::Watch {
+                dir,
+                dest,
+                profile,
+                interval,
+                args,
+            } => {
+                return Invocation::Watch {
+                    dest: dest.unwrap_or_else(|| dir.join("rendered")),
+                    dir,
+                    profile,
+                    interval: interval.unwrap_or(5),
+                    args,
+                };
+            }
+            Command::Merge {
+                shard_dirs,
+                output,
+                metadata,
+                ffmpeg_path,
+            } => {
+                crate::shard::merge(
+                    &shard_dirs,
+                    output.as_deref(),
+                    metadata.as_deref(),
+                    ffmpeg_path.as_deref().unwrap_or("ffmpeg"),
+                );
+                std::process::exit(0);
+            }
+            Command::Completions { shell } => {
+                Command::clap().gen_completions_to("streetwarp", shell, &mut std::io::stdout());
+                std::process::exit(0);
+            }
+        }
+    };
+    Invocation::Run(Box::new(finish_parsing(stage, cli, &matches)))
+}
+
+/// Parse the command line of a job of the jobs subcommand, without the program name, as a
+/// stage subcommand or the flat command line. Unlike the process's own command line, errors
+/// fail the job instead of exiting, and progress events are always on.
+pub fn parse_job(args: &[String]) -> Cli {
+    let has_subcommand = args
+        .first()
+        .is_some_and(|arg| SUBCOMMANDS.contains(&arg.as_str()));
+    let args = std::iter::once("streetwarp").chain(args.iter().map(|arg| arg.as_str()));
+    let parsed = if has_subcommand {
+        Command::clap().get_matches_from_safe(args)
+    } else {
+        Cli::clap().get_matches_from_safe(args)
+    };
+    let matches = parsed.unwrap_or_else(|e| fail(ErrorCode::InputInvalid, &e.message));
+    let (stage, cli) = if !has_subcommand {
+        (Stage::Run, Cli::from_clap(&matches))
+    } else {
+        match Command::from_clap(&matches) {
+            Command::Metadata(cli) => (Stage::Metadata, cli),
+            Command::Fetch(cli) => (Stage::Fetch, cli),
+            Command::Encode(cli) => (Stage::Encode, cli),
+            Command::Run(cli) => (Stage::Run, cli),
+            _ => fail(
+                ErrorCode::InputInvalid,
+                "Jobs can only run the metadata, fetch, encode or run subcommands",
+            ),
+        }
+    };
+    let mut cli = finish_parsing(stage, cli, &matches);
+    cli.progress = true;
+    cli
+}
+
+/// Apply the config file to the cli parsed from matches and validate it for stage.
+fn finish_parsing(stage: Stage, cli: Cli, matches: &ArgMatches) -> Cli {
+    // The options of the stage subcommands are matched by the subcommand.
+    let matches = match matches.subcommand() {
+        (_, Some(matches)) => matches,
+        _ => matches,
+    };
+    let mut cli = crate::config::apply_config(cli, matches);
+    cli.stage = stage;
+    cli.validate();
+    cli
+}
+
+/// The options of the current run, which are those of the job in the jobs subcommand.
+pub struct CurrentOptions;
+
+impl std::ops::Deref for CurrentOptions {
+    type Target = Cli;
+
+    fn deref(&self) -> &Cli {
+        &crate::context::current().options
+    }
 }
+
+pub static CLI_OPTIONS: CurrentOptions = CurrentOptions;
@@ -0,0 +1,579 @@
+use std::path::Path;
+
+use crate::errors::{fail, ErrorCode};
+use crate::ffmpeg::{input_framerate, output_duration};
+use crate::geocode::Place;
+use crate::options::CLI_OPTIONS;
+use crate::pacing::Hold;
+use crate::progress::warning;
+use crate::sensors::{read_sensor_file, sensors_at_points, SensorRecord};
+use crate::SerializablePointBearing;
+
+/// File in the image directory holding the attribution text, read by the drawtext filter.
+/// Reading it from a file avoids escaping the text inside the filter graph, and expansion=none
+/// keeps drawtext from expanding % sequences in it.
+const ATTRIBUTION_FILE: &str = "attribution.txt";
+
+/// File in the image directory with the sendcmd commands that update the HUD text per frame.
+const HUD_COMMANDS_FILE: &str = "hud.cmd";
+/// File in the image directory with the sendcmd commands that update the speed per frame.
+const SPEED_COMMANDS_FILE: &str = "speed.cmd";
+/// File in the image directory with the sendcmd commands that update the sensor readings.
+const SENSOR_COMMANDS_FILE: &str = "sensors.cmd";
+
+/// Subtitle file in the image directory with the street name of each stretch of the route.
+const STREET_NAMES_FILE: &str = "streets.srt";
+
+/// Subtitle file in the image directory with a label each time the route enters a new locality.
+const ENTERING_LABELS_FILE: &str = "entering.srt";
+const DWELL_LABELS_FILE: &str = "dwell.srt";
+
+/// Files in the image directory holding the text of the title and end cards.
+pub const TITLE_CARD_FILE: &str = "title-card.txt";
+pub const END_CARD_FILE: &str = "end-card.txt";
+
+/// Every file written into the image directory for the overlays, which the output doesn't need.
+pub const OVERLAY_FILES: &[&str] = &[
+    ATTRIBUTION_FILE,
+    HUD_COMMANDS_FILE,
+    SPEED_COMMANDS_FILE,
+    SENSOR_COMMANDS_FILE,
+    STREET_NAMES_FILE,
+    ENTERING_LABELS_FILE,
+    DWELL_LABELS_FILE,
+    TITLE_CARD_FILE,
+    END_CARD_FILE,
+];
+
+/// Range of capture dates (YYYY-MM) of the points, e.g. "2014-06–2021-08", if any are known.
+fn imagery_dates(points: &[SerializablePointBearing]) -> Option<String> {
+    let first = points.iter().filter_map(|p| p.panoDate.as_ref()).min()?;
+    let last = points.iter().filter_map(|p| p.panoDate.as_ref()).max()?;
+    if first == last {
+        Some(first.to_string())
+    } else {
+        Some(format!("{}–{}", first, last))
+    }
+}
+
+/// The attribution_text option with {dates} replaced by the imagery dates of the points.
+fn attribution_text(points: &[SerializablePointBearing]) -> String {
+    let dates = imagery_dates(points);
+    match &CLI_OPTIONS.attribution_text {
+        Some(text) => text.replace("{dates}", dates.as_deref().unwrap_or("unknown")),
+        None => match dates {
+            Some(dates) => format!("© Google Street View, imagery dates {}", dates),
+            None => "© Google Street View".to_string(),
+        },
+    }
+}
+
+/// Write the attribution text for the points into image_dir if the attribution option is set.
+fn write_attribution<P: AsRef<Path>>(image_dir: &P, points: &[SerializablePointBearing]) {
+    if CLI_OPTIONS.attribution {
+        let path = image_dir.as_ref().join(ATTRIBUTION_FILE);
+        std::fs::write(&path, attribution_text(points)).unwrap_or_else(|e| {
+            fail(
+                ErrorCode::Io,
+                &format!("Could not write {:?}: {:?}", path, e),
+            )
+        });
+    }
+}
+
+/// drawtext x and y expressions placing text a small margin from the given corner.
+fn corner_position(corner: &str) -> (&'static str, &'static str) {
+    match corner {
+        "top-left" => ("10", "10"),
+        "top-right" => ("w-tw-10", "10"),
+        "bottom-left" => ("10", "h-th-10"),
+        "bottom-right" => ("w-tw-10", "h-th-10"),
+        _ => fail(
+            ErrorCode::InputInvalid,
+            &format!(
+                "Unknown corner {}, expected top-left, top-right, bottom-left or bottom-right",
+                corner
+            ),
+        ),
+    }
+}
+
+/// drawtext filter burning the attribution text into a corner of the video, if enabled.
+pub fn attribution_filter() -> Option<String> {
+    if !CLI_OPTIONS.attribution {
+        return None;
+    }
+    let corner = CLI_OPTIONS
+        .attribution_corner
+        .as_deref()
+        .unwrap_or("bottom-right");
+    let (x, y) = corner_position(corner);
+    Some(format!(
+        "drawtext=textfile={}:expansion=none:x={}:y={}:fontsize=h/32:fontcolor=white:box=1:boxcolor=black@0.4:boxborderw=4",
+        ATTRIBUTION_FILE, x, y
+    ))
+}
+
+/// HUD text for a frame at distance meters along a route of total meters.
+fn hud_text(distance: f64, total: f64) -> String {
+    let percent = 100.0 * distance / total.max(1.0);
+    match CLI_OPTIONS.hud_units.as_deref() {
+        None | Some("km") => format!("{:.1} km  {:.0}%", distance / 1000.0, percent),
+        Some("mi") => format!("{:.1} mi  {:.0}%", distance / 1609.34, percent),
+        Some(units) => fail(
+            ErrorCode::InputInvalid,
+            &format!("Unknown HUD units {}, expected km or mi", units),
+        ),
+    }
+}
+
+/// Write the sendcmd file at path that sets the text of the drawtext filter named target to
+/// texts[index] at the time frame index appears in the raw timelapse.
+/// The texts must not contain quotes, colons or semicolons.
+fn write_frame_text_commands(path: &Path, target: &str, texts: &[String]) {
+    let framerate = input_framerate(texts.len());
+    let commands = texts
+        .iter()
+        .enumerate()
+        .map(|(index, text)| {
+            // Start the command half a frame early so rounding can't delay it by a frame.
+            let time = (index as f64 - 0.5).max(0.0) / framerate;
+            format!("{:.4} drawtext@{} reinit 'text={}';", time, target, text)
+        })
+        .collect::<Vec<_>>();
+    std::fs::write(path, commands.join("\n")).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Io,
+            &format!("Could not write {:?}: {:?}", path, e),
+        )
+    });
+}
+
+/// sendcmd and drawtext filters drawing text updated by write_frame_text_commands into a corner,
+/// moved line text lines away from the corner so several fields can share it.
+fn frame_text_filter(
+    commands_file: &str,
+    target: &str,
+    initial_text: &str,
+    corner: &str,
+    line: u32,
+) -> String {
+    let (x, y) = corner_position(corner);
+    let y = match (line, corner.starts_with("top")) {
+        (0, _) => y.to_string(),
+        (line, true) => format!("{}+{}*(th+20)", y, line),
+        (line, false) => format!("{}-{}*(th+20)", y, line),
+    };
+    format!(
+        "sendcmd=f={},drawtext@{}=text='{}':expansion=none:x={}:y={}:fontsize=h/24:fontcolor=white:box=1:boxcolor=black@0.4:boxborderw=6",
+        commands_file, target, initial_text, x, y
+    )
+}
+
+/// Write the commands that set the HUD text to each point's distance along the route and
+/// percent complete, if the hud option is set.
+fn write_hud_commands<P: AsRef<Path>>(image_dir: &P, points: &[SerializablePointBearing]) {
+    if !CLI_OPTIONS.hud {
+        return;
+    }
+    let distances = crate::cumulative_distances(points);
+    let total = distances.last().copied().unwrap_or(0.0);
+    let texts = distances
+        .iter()
+        .map(|&distance| hud_text(distance, total))
+        .collect::<Vec<_>>();
+    write_frame_text_commands(&image_dir.as_ref().join(HUD_COMMANDS_FILE), "hud", &texts);
+}
+
+/// Filters drawing the HUD into the top left corner, if enabled.
+pub fn hud_filter() -> Option<String> {
+    if !CLI_OPTIONS.hud {
+        return None;
+    }
+    Some(frame_text_filter(
+        HUD_COMMANDS_FILE,
+        "hud",
+        &hud_text(0.0, 1.0),
+        "top-left",
+        0,
+    ))
+}
+
+/// Speed in km/h (or mph with the speed_units option) at each point, averaged over the two
+/// points on each side to smooth out GPS noise. None if the points have no times.
+pub fn point_speeds(points: &[SerializablePointBearing]) -> Vec<Option<f64>> {
+    let distances = crate::cumulative_distances(points);
+    let to_units = match CLI_OPTIONS.speed_units.as_deref() {
+        None | Some("kmh") => 3.6,
+        Some("mph") => 2.23694,
+        Some(units) => fail(
+            ErrorCode::InputInvalid,
+            &format!("Unknown speed units {}, expected kmh or mph", units),
+        ),
+    };
+    (0..points.len())
+        .map(|index| {
+            let from = index.saturating_sub(2);
+            let to = (index + 2).min(points.len() - 1);
+            let elapsed = points[to].time? - points[from].time?;
+            if elapsed <= 0.0 {
+                return None;
+            }
+            Some((distances[to] - distances[from]) / elapsed * to_units)
+        })
+        .collect()
+}
+
+fn speed_units() -> &'static str {
+    if CLI_OPTIONS.speed_units.as_deref() == Some("mph") {
+        "mph"
+    } else {
+        "km/h"
+    }
+}
+
+/// Write the commands that set the speed text of each frame, if the speed_overlay option is set.
+fn write_speed_commands<P: AsRef<Path>>(image_dir: &P, points: &[SerializablePointBearing]) {
+    if !CLI_OPTIONS.speed_overlay {
+        return;
+    }
+    if points.iter().all(|p| p.time.is_none()) {
+        warning("The route has no timestamps, so the speed overlay will be empty");
+    }
+    let texts = point_speeds(points)
+        .into_iter()
+        .map(|speed| match speed {
+            Some(speed) => format!("{:.1} {}", speed, speed_units()),
+            None => format!("-- {}", speed_units()),
+        })
+        .collect::<Vec<_>>();
+    write_frame_text_commands(
+        &image_dir.as_ref().join(SPEED_COMMANDS_FILE),
+        "speed",
+        &texts,
+    );
+}
+
+/// Filters drawing the speed into the bottom left corner, if enabled.
+pub fn speed_filter() -> Option<String> {
+    if !CLI_OPTIONS.speed_overlay {
+        return None;
+    }
+    Some(frame_text_filter(
+        SPEED_COMMANDS_FILE,
+        "speed",
+        &format!("-- {}", speed_units()),
+        "bottom-left",
+        0,
+    ))
+}
+
+/// Heart rate, power and cadence text of a frame, leaving out the readings that are missing.
+fn sensor_text(record: &SensorRecord) -> String {
+    let fields = [
+        record.heart_rate.map(|hr| format!("{:.0} bpm", hr)),
+        record.power.map(|power| format!("{:.0} W", power)),
+        record.cadence.map(|cadence| format!("{:.0} rpm", cadence)),
+    ];
+    let text = fields
+        .iter()
+        .flatten()
+        .cloned()
+        .collect::<Vec<_>>()
+        .join("  ");
+    if text.is_empty() {
+        "--".to_string()
+    } else {
+        text
+    }
+}
+
+/// Write the commands that set the heart rate, power and cadence text of each frame from the
+/// sensor file (default: the input GPX), if the data_overlay option is set.
+fn write_sensor_commands<P: AsRef<Path>>(image_dir: &P, points: &[SerializablePointBearing]) {
+    if !CLI_OPTIONS.data_overlay {
+        return;
+    }
+    let path = CLI_OPTIONS
+        .sensor_file
+        .as_deref()
+        .unwrap_or_else(|| CLI_OPTIONS.input_path());
+    let records = read_sensor_file(path);
+    let texts = sensors_at_points(&records, points)
+        .iter()
+        .map(sensor_text)
+        .collect::<Vec<_>>();
+    write_frame_text_commands(
+        &image_dir.as_ref().join(SENSOR_COMMANDS_FILE),
+        "sensors",
+        &texts,
+    );
+}
+
+/// Filters drawing the sensor readings into the bottom left corner (above the speed), if enabled.
+pub fn sensor_filter() -> Option<String> {
+    if !CLI_OPTIONS.data_overlay {
+        return None;
+    }
+    let line = if CLI_OPTIONS.speed_overlay { 1 } else { 0 };
+    Some(frame_text_filter(
+        SENSOR_COMMANDS_FILE,
+        "sensors",
+        "--",
+        "bottom-left",
+        line,
+    ))
+}
+
+/// A subtitle shown from start to end seconds into the video.
+pub struct Cue {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+/// Format seconds as an SRT timestamp (HH:MM:SS,mmm).
+fn srt_timestamp(seconds: f64) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Write the cues as a SubRip (.srt) subtitle file.
+pub fn write_srt(path: &Path, cues: &[Cue]) {
+    let contents = cues
+        .iter()
+        .enumerate()
+        .map(|(i, cue)| {
+            format!(
+                "{}\n{} --> {}\n{}\n",
+                i + 1,
+                srt_timestamp(cue.start),
+                srt_timestamp(cue.end),
+                cue.text
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    std::fs::write(path, contents).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Io,
+            &format!("Could not write {:?}: {:?}", path, e),
+        )
+    });
+}
+
+/// Merge runs of frames with the same text into cues, timed by when the frames appear
+/// in a timelapse showing framerate frames per second. Frames without text get no cue.
+pub fn frame_cues(texts: &[Option<String>], framerate: f64) -> Vec<Cue> {
+    let mut cues = vec![];
+    let mut start = 0;
+    for end in 1..=texts.len() {
+        if end == texts.len() || texts[end] != texts[start] {
+            if let Some(text) = &texts[start] {
+                cues.push(Cue {
+                    start: start as f64 / framerate,
+                    end: end as f64 / framerate,
+                    text: text.clone(),
+                });
+            }
+            start = end;
+        }
+    }
+    cues
+}
+
+/// Write the subtitles with the street name of each frame, if the street_names option is set.
+pub fn write_street_names<P: AsRef<Path>>(image_dir: &P, places: &[Place]) {
+    if !CLI_OPTIONS.street_names {
+        return;
+    }
+    let streets = places.iter().map(|p| p.street.clone()).collect::<Vec<_>>();
+    let cues = frame_cues(&streets, input_framerate(places.len()));
+    write_srt(&image_dir.as_ref().join(STREET_NAMES_FILE), &cues);
+}
+
+/// subtitles filter burning the street names into the lower third of the video, if enabled.
+pub fn street_names_filter() -> Option<String> {
+    if !CLI_OPTIONS.street_names {
+        return None;
+    }
+    // Alignment=2 is bottom center; MarginV lifts it above the attribution line.
+    Some(format!(
+        "subtitles={}:force_style='Alignment=2,MarginV=30,FontSize=20,BorderStyle=3,Outline=1,Shadow=0'",
+        STREET_NAMES_FILE
+    ))
+}
+
+/// Write the subtitles with an "Entering <town>" label wherever the locality changes between
+/// frames, shown for entering_label_duration seconds (default: 2.5), if entering_labels is set.
+fn write_entering_labels<P: AsRef<Path>>(image_dir: &P, places: &[Place]) {
+    if !CLI_OPTIONS.entering_labels {
+        return;
+    }
+    let framerate = input_framerate(places.len());
+    let duration = CLI_OPTIONS.entering_label_duration.unwrap_or(2.5);
+    let mut cues: Vec<Cue> = vec![];
+    let mut current = places.first().and_then(|p| p.locality.as_ref());
+    for (index, place) in places.iter().enumerate() {
+        let locality = match &place.locality {
+            Some(locality) => locality,
+            // Unknown stretches (e.g. failed lookups) don't count as leaving the locality.
+            None => continue,
+        };
+        if current != Some(locality) {
+            let start = index as f64 / framerate;
+            // Cut the previous label short if towns follow each other closely.
+            if let Some(previous) = cues.last_mut() {
+                previous.end = previous.end.min(start);
+            }
+            cues.push(Cue {
+                start,
+                end: start + duration,
+                text: format!("Entering {}", locality),
+            });
+            current = Some(locality);
+        }
+    }
+    write_srt(&image_dir.as_ref().join(ENTERING_LABELS_FILE), &cues);
+}
+
+/// subtitles filter showing the entering labels at the top of the video, if enabled.
+/// The entering_label_style option overrides the ASS style.
+pub fn entering_labels_filter() -> Option<String> {
+    if !CLI_OPTIONS.entering_labels {
+        return None;
+    }
+    let style = CLI_OPTIONS
+        .entering_label_style
+        .as_deref()
+        .unwrap_or("Alignment=8,MarginV=40,FontSize=28,Bold=1,Outline=2,Shadow=1");
+    Some(format!(
+        "subtitles={}:force_style='{}'",
+        ENTERING_LABELS_FILE, style
+    ))
+}
+
+/// Write the subtitles naming the waypoint of each hold that dwells at one, if the
+/// waypoint_dwell_labels option is set. There are n_frames frames in all.
+pub fn write_dwell_labels<P: AsRef<Path>>(image_dir: &P, holds: &[Hold], n_frames: usize) {
+    if !CLI_OPTIONS.waypoint_dwell_labels {
+        return;
+    }
+    let framerate = input_framerate(n_frames);
+    let cues = holds
+        .iter()
+        .filter_map(|hold| {
+            Some(Cue {
+                start: hold.start as f64 / framerate,
+                end: (hold.start + hold.frames) as f64 / framerate,
+                text: hold.label.clone()?,
+            })
+        })
+        .collect::<Vec<_>>();
+    write_srt(&image_dir.as_ref().join(DWELL_LABELS_FILE), &cues);
+}
+
+/// subtitles filter showing the names of waypoints while dwelling at them, if enabled.
+pub fn dwell_labels_filter() -> Option<String> {
+    if !CLI_OPTIONS.waypoint_dwell_labels {
+        return None;
+    }
+    Some(format!(
+        "subtitles={}:force_style='Alignment=2,MarginV=60,FontSize=26,Bold=1,Outline=2,Shadow=1'",
+        DWELL_LABELS_FILE
+    ))
+}
+
+/// One subtitle per second of video with the place, distance along the route and imagery date
+/// of the frame shown at the start of that second. The route starts offset seconds into the video.
+pub fn subtitle_track_cues(
+    points: &[SerializablePointBearing],
+    places: &[Place],
+    offset: f64,
+) -> Vec<Cue> {
+    let distances = crate::cumulative_distances(points);
+    let total = distances.last().copied().unwrap_or(0.0);
+    let framerate = input_framerate(points.len());
+    let duration = output_duration(points.len());
+    (0..duration.ceil() as usize)
+        .map(|second| {
+            let index = ((second as f64 * framerate) as usize).min(points.len() - 1);
+            let mut lines = vec![];
+            if let Some(place) = places.get(index) {
+                let names = [place.street.clone(), place.locality.clone()];
+                let location = names.iter().flatten().cloned().collect::<Vec<_>>();
+                if !location.is_empty() {
+                    lines.push(location.join(", "));
+                }
+            }
+            let mut stats = hud_text(distances[index], total);
+            if let Some(date) = &points[index].panoDate {
+                stats += &format!("  imagery {}", date);
+            }
+            lines.push(stats);
+            Cue {
+                start: offset + second as f64,
+                end: offset + (second as f64 + 1.0).min(duration),
+                text: lines.join("\n"),
+            }
+        })
+        .collect()
+}
+
+/// Write the files read by the enabled overlay filters for the frames of points into image_dir.
+/// places has the geocoded place of each point if any overlay needs it, otherwise it is empty.
+pub fn write_overlay_files<P: AsRef<Path>>(
+    image_dir: &P,
+    points: &[SerializablePointBearing],
+    places: &[Place],
+) {
+    write_attribution(image_dir, points);
+    write_hud_commands(image_dir, points);
+    write_speed_commands(image_dir, points);
+    write_sensor_commands(image_dir, points);
+    write_street_names(image_dir, places);
+    write_entering_labels(image_dir, places);
+}
+
+/// Write the text of the title card (title, distance, imagery dates) and end card into image_dir.
+/// distance is the route length in meters.
+pub fn write_card_texts<P: AsRef<Path>>(
+    image_dir: &P,
+    title: &str,
+    distance: f64,
+    points: &[SerializablePointBearing],
+) {
+    let mut title_lines = vec![
+        title.to_string(),
+        format!("{:.1} km ({:.1} mi)", distance / 1000.0, distance / 1609.34),
+    ];
+    title_lines.extend(imagery_dates(points).map(|dates| format!("Imagery {}", dates)));
+    let end_lines = ["Made with streetwarp", "Imagery © Google Street View"];
+    for (file, text) in &[
+        (TITLE_CARD_FILE, title_lines.join("\n")),
+        (END_CARD_FILE, end_lines.join("\n")),
+    ] {
+        let path = image_dir.as_ref().join(file);
+        std::fs::write(&path, text).unwrap_or_else(|e| {
+            fail(
+                ErrorCode::Io,
+                &format!("Could not write {:?}: {:?}", path, e),
+            )
+        });
+    }
+}
+
+/// drawtext filter centering the text of text_file on a card, without expanding % sequences in
+/// titles such as "100% gravel".
+pub fn card_filter(text_file: &str) -> String {
+    format!(
+        "drawtext=textfile={}:expansion=none:x=(w-tw)/2:y=(h-th)/2:fontsize=h/16:fontcolor=white:line_spacing=16",
+        text_file
+    )
+}
@@ -0,0 +1,356 @@
+use std::path::Path;
+
+use crate::chapters::nearest_points;
+use crate::errors::{fail, ErrorCode};
+use crate::ffmpeg::fps;
+use crate::options::CLI_OPTIONS;
+use crate::progress::{progress, warning};
+use crate::{GPXWaypoint, SerializablePointBearing};
+
+/// Pattern of the frame files written by apply_pacing.
+pub const PACED_PATTERN: &str = "%d.paced.jpg";
+/// Meters of route that the grade at each point is measured over.
+const GRADE_DISTANCE: f64 = 100.0;
+/// Most times slower or faster that --pace gradient shows a point.
+const MAX_GRADIENT_RAMP: f64 = 8.0;
+
+/// Waypoints with the index of the point each dwells at.
+type Dwells<'a> = Vec<(usize, &'a GPXWaypoint)>;
+
+/// Seconds between each point and the next by their GPX times. The last point gets the median
+/// interval. Return None if any point has no time.
+fn time_intervals(points: &[SerializablePointBearing]) -> Option<Vec<f64>> {
+    let times = points.iter().map(|p| p.time).collect::<Option<Vec<_>>>()?;
+    let mut intervals = times
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).max(0.0))
+        .collect::<Vec<_>>();
+    intervals.push(percentile(&intervals, 0.5));
+    Some(intervals)
+}
+
+/// Output frames per point to show the route in real time divided by a speedup factor, from
+/// the pace option (real or real:SPEEDUP). Without a speedup, the video keeps its length.
+fn real_time_weights(points: &[SerializablePointBearing], pace: &str) -> Vec<f64> {
+    let intervals = time_intervals(points).unwrap_or_else(|| {
+        fail(
+            ErrorCode::InputInvalid,
+            "--pace real needs a GPX file with a time for every track point",
+        )
+    });
+    let total_seconds = intervals.iter().sum::<f64>().max(1e-9);
+    let speedup = match pace.strip_prefix("real:") {
+        Some(speedup) => speedup
+            .parse::<f64>()
+            .ok()
+            .filter(|&s| s > 0.0)
+            .unwrap_or_else(|| {
+                fail(
+                    ErrorCode::InputInvalid,
+                    &format!("Invalid speedup in --pace {}", pace),
+                )
+            }),
+        None => total_seconds * fps() as f64 / points.len() as f64,
+    };
+    progress(&format!(
+        "Pacing the route in real time sped up {:.1}x",
+        speedup
+    ));
+    intervals
+        .iter()
+        .map(|seconds| seconds / speedup * fps() as f64)
+        .collect()
+}
+
+/// Value at the given fraction (0 to 1) of the sorted values.
+fn percentile(values: &[f64], fraction: f64) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let index = ((sorted.len() as f64 - 1.0) * fraction).round() as usize;
+    sorted.get(index).copied().unwrap_or(0.0)
+}
+
+/// Output frames per point by recorded speed, relative to the fast end of the route with a
+/// positive speed_curve (default: 1) so slow parts lose more frames, or to the slow end with a
+/// negative one so fast parts do. Larger curves make the difference more pronounced.
+fn speed_weights(points: &[SerializablePointBearing]) -> Vec<f64> {
+    let speeds = crate::overlay::point_speeds(points);
+    let known = speeds.iter().flatten().copied().collect::<Vec<_>>();
+    if known.is_empty() {
+        fail(
+            ErrorCode::InputInvalid,
+            "--pace speed needs a GPX file with track point times",
+        );
+    }
+    let median = percentile(&known, 0.5);
+    let curve = CLI_OPTIONS.speed_curve.unwrap_or(1.0);
+    // Percentiles instead of the extremes keep GPS spikes from flattening the curve.
+    let reference = percentile(&known, if curve >= 0.0 { 0.95 } else { 0.05 }).max(1e-3);
+    speeds
+        .iter()
+        .map(|speed| {
+            let speed = speed.unwrap_or(median).max(1e-3);
+            (speed / reference).powf(curve).min(1.0)
+        })
+        .collect()
+}
+
+/// Grade in percent at each point, measured over about GRADE_DISTANCE meters around it to
+/// smooth out elevation noise. Points without elevation get 0.
+fn point_grades(points: &[SerializablePointBearing]) -> Vec<f64> {
+    let distances = crate::cumulative_distances(points);
+    (0..points.len())
+        .map(|index| {
+            let center = distances[index];
+            let from = distances[..index]
+                .iter()
+                .rposition(|&d| center - d >= GRADE_DISTANCE / 2.0)
+                .unwrap_or(0);
+            let to = distances[index..]
+                .iter()
+                .position(|&d| d - center >= GRADE_DISTANCE / 2.0)
+                .map_or(points.len() - 1, |offset| index + offset);
+            match (points[from].ele, points[to].ele) {
+                (Some(start), Some(end)) if distances[to] > distances[from] => {
+                    100.0 * (end - start) / (distances[to] - distances[from])
+                }
+                _ => 0.0,
+            }
+        })
+        .collect()
+}
+
+/// Output frames per point by grade: every gradient_doubling percent (default: 8) of climb
+/// doubles the time a point is shown, and every gradient_doubling percent of descent halves it,
+/// within MAX_GRADIENT_RAMP times either way.
+fn gradient_weights(points: &[SerializablePointBearing]) -> Vec<f64> {
+    if points.iter().all(|p| p.ele.is_none()) {
+        fail(
+            ErrorCode::InputInvalid,
+            "--pace gradient needs elevation data in the GPX file",
+        );
+    }
+    let doubling = CLI_OPTIONS.gradient_doubling.unwrap_or(8.0);
+    point_grades(points)
+        .iter()
+        .map(|grade| {
+            2f64.powf(grade / doubling)
+                .clamp(1.0 / MAX_GRADIENT_RAMP, MAX_GRADIENT_RAMP)
+        })
+        .collect()
+}
+
+/// A run of frames repeating the same point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hold {
+    /// Index of the first frame of the run.
+    pub start: usize,
+    pub frames: usize,
+    /// Name of the waypoint the frames dwell at, if any.
+    pub label: Option<String>,
+}
+
+/// Frames and their points after pacing.
+pub struct Paced {
+    pub points: Vec<SerializablePointBearing>,
+    pub holds: Vec<Hold>,
+}
+
+/// Output frames to show each point for by the pace option, or None to keep one frame per point.
+fn pace_weights(points: &[SerializablePointBearing]) -> Option<Vec<f64>> {
+    match CLI_OPTIONS.pace.as_deref() {
+        None | Some("distance") => None,
+        Some(pace) if pace == "real" || pace.starts_with("real:") => {
+            Some(real_time_weights(points, pace))
+        }
+        Some("speed") => Some(speed_weights(points)),
+        Some("gradient") => Some(gradient_weights(points)),
+        Some(pace) => fail(
+            ErrorCode::InputInvalid,
+            &format!(
+                "Unknown pace {}, expected distance, real[:SPEEDUP], speed or gradient",
+                pace
+            ),
+        ),
+    }
+}
+
+/// Seconds to dwell at each waypoint by the waypoint_dwell option, if set.
+fn dwell_seconds() -> Option<f64> {
+    CLI_OPTIONS.waypoint_dwell.filter(|&seconds| seconds > 0.0)
+}
+
+/// Frames the waypoint_dwell option adds to the video by holding points at the waypoints.
+pub fn dwell_frames(points: &[SerializablePointBearing], waypoints: &[GPXWaypoint]) -> usize {
+    match dwell_seconds() {
+        Some(seconds) => {
+            let dwells = nearest_points(waypoints, points).len();
+            (dwells as f64 * seconds * fps() as f64).round() as usize
+        }
+        None => 0,
+    }
+}
+
+/// Output frames to show each point for by the pace and waypoint_dwell options, and the points
+/// that waypoints dwell at. None if every point keeps one frame.
+fn frame_weights<'a>(
+    points: &[SerializablePointBearing],
+    waypoints: &'a [GPXWaypoint],
+) -> Option<(Vec<f64>, Dwells<'a>)> {
+    let weights = pace_weights(points);
+    let dwell_seconds = match dwell_seconds() {
+        Some(seconds) if !waypoints.is_empty() => seconds,
+        _ => return weights.map(|weights| (weights, vec![])),
+    };
+    let mut weights = weights.unwrap_or_else(|| vec![1.0; points.len()]);
+    let dwells = nearest_points(waypoints, points);
+    for (index, _) in &dwells {
+        weights[*index] += dwell_seconds * fps() as f64;
+    }
+    progress(&format!(
+        "Dwelling {} seconds at {} waypoints",
+        dwell_seconds,
+        dwells.len()
+    ));
+    Some((weights, dwells))
+}
+
+/// Resample the points into output frames where point i spans weights[i] frames:
+/// return the index of the point shown in each frame.
+fn resample(weights: &[f64]) -> Vec<usize> {
+    let mut indices = vec![];
+    let mut cumulative = 0.0;
+    for (index, weight) in weights.iter().enumerate() {
+        cumulative += weight;
+        // Frame j shows the point whose span contains j + 0.5.
+        while (indices.len() as f64) + 0.5 < cumulative {
+            indices.push(index);
+        }
+    }
+    indices
+}
+
+/// Runs of repeated points in the frames given by indices, labeled with the names of the
+/// waypoints dwelt at.
+fn find_holds(indices: &[usize], dwells: &[(usize, &GPXWaypoint)]) -> Vec<Hold> {
+    let mut holds = vec![];
+    let mut start = 0;
+    for end in 1..=indices.len() {
+        if end < indices.len() && indices[end] == indices[start] {
+            continue;
+        }
+        if end - start > 1 {
+            let label = dwells
+                .iter()
+                .find(|(index, _)| *index == indices[start])
+                .map(|(_, waypoint)| waypoint.name.clone());
+            holds.push(Hold {
+                start,
+                frames: end - start,
+                label,
+            });
+        }
+        start = end;
+    }
+    holds
+}
+
+/// Choose how long each point is shown by the pace and waypoint_dwell options before its image
+/// is fetched: drop the points no frame shows and set the frameCount of the others, for
+/// apply_pacing to repeat their frames once fetched. Return the points unchanged if pacing is off.
+pub fn pace_points(
+    points: Vec<SerializablePointBearing>,
+    waypoints: &[GPXWaypoint],
+) -> Vec<SerializablePointBearing> {
+    let (weights, _) = match frame_weights(&points, waypoints) {
+        Some(weights) => weights,
+        None => return points,
+    };
+    let indices = resample(&weights);
+    if indices.is_empty() {
+        warning("Pacing left no frames, keeping uniform pacing");
+        return points;
+    }
+    let mut counts = vec![0; points.len()];
+    for &index in &indices {
+        counts[index] += 1;
+    }
+    let paced = points
+        .into_iter()
+        .zip(counts)
+        .filter(|&(_, count)| count > 0)
+        .map(|(mut point, count)| {
+            point.frameCount = Some(count);
+            point
+        })
+        .collect::<Vec<_>>();
+    progress(&format!(
+        "Paced the route into {} frames, showing {} points",
+        indices.len(),
+        paced.len()
+    ));
+    paced
+}
+
+/// Change how long each point is shown by the pace and waypoint_dwell options: write the frames
+/// matching pattern in image_dir as PACED_PATTERN, repeating each frame frameCount times as
+/// chosen by pace_points. Points fetched without pace_points, e.g. by an older version, are
+/// paced here instead, skipping frames of points shown shorter. Return the new frames, or None
+/// if pacing is off.
+pub fn apply_pacing(
+    image_dir: &Path,
+    pattern: &str,
+    points: &[SerializablePointBearing],
+    waypoints: &[GPXWaypoint],
+) -> Option<Paced> {
+    let (indices, dwells) = if points.iter().any(|point| point.frameCount.is_some()) {
+        let indices = points
+            .iter()
+            .enumerate()
+            .flat_map(|(index, point)| {
+                std::iter::repeat_n(index, point.frameCount.unwrap_or(1) as usize)
+            })
+            .collect::<Vec<_>>();
+        let dwells = match dwell_seconds() {
+            Some(_) => nearest_points(waypoints, points),
+            None => vec![],
+        };
+        (indices, dwells)
+    } else {
+        let (weights, dwells) = frame_weights(points, waypoints)?;
+        (resample(&weights), dwells)
+    };
+    if indices.is_empty() {
+        warning("Pacing left no frames, keeping uniform pacing");
+        return None;
+    }
+    for (frame, &index) in indices.iter().enumerate() {
+        let source = image_dir.join(pattern.replace("%d", &index.to_string()));
+        let target = image_dir.join(PACED_PATTERN.replace("%d", &frame.to_string()));
+        let _ = std::fs::remove_file(&target);
+        // Frames that failed to download stay missing; repeats share storage where possible.
+        if source.exists() && std::fs::hard_link(&source, &target).is_err() {
+            std::fs::copy(&source, &target).unwrap_or_else(|e| {
+                fail(
+                    ErrorCode::Io,
+                    &format!("Could not copy frame {:?}: {:?}", source, e),
+                )
+            });
+        }
+    }
+    progress(&format!(
+        "Paced {} points into {} frames",
+        points.len(),
+        indices.len()
+    ));
+    Some(Paced {
+        points: indices
+            .iter()
+            .map(|&i| SerializablePointBearing {
+                frameCount: None,
+                ..points[i]
+            })
+            .collect(),
+        holds: find_holds(&indices, &dwells),
+    })
+}
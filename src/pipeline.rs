@@ -0,0 +1,142 @@
+use std::path::Path;
+
+use futures::{stream, StreamExt};
+use reqwest::Client;
+
+use crate::errors::{fail, ErrorCode};
+use crate::ffmpeg::{create_timelapse_from_stream, uses_native_encoder, OutputFormat};
+use crate::options::{Stage, CLI_OPTIONS};
+use crate::progress::{error, warning};
+use crate::validate::{expects_placeholder, validate_frame_bytes, FrameStatus};
+use crate::{http, image_url, mock, optim, store, throttle, SerializablePointBearing};
+
+/// Option of a step that needs every frame before encoding starts, if any is set.
+fn whole_sequence_option() -> Option<&'static str> {
+    let options = [
+        (CLI_OPTIONS.dedup, "--dedup"),
+        (CLI_OPTIONS.frame_hook.is_some(), "--frame-hook"),
+        (CLI_OPTIONS.color_match, "--color-match"),
+        (optim::is_enabled(), "--optimize"),
+        (CLI_OPTIONS.contact_sheet.is_some(), "--contact-sheet"),
+        (CLI_OPTIONS.pace.is_some(), "--pace"),
+        (CLI_OPTIONS.waypoint_dwell.is_some(), "--waypoint-dwell"),
+        (CLI_OPTIONS.minimap, "--minimap"),
+        (
+            OutputFormat::from_options() == OutputFormat::Frames,
+            "--format frames",
+        ),
+        (uses_native_encoder(), "--native-encode"),
+    ];
+    options
+        .iter()
+        .find(|(enabled, _)| *enabled)
+        .map(|(_, option)| *option)
+}
+
+/// Whether the pipeline option is set and the run can encode frames as they are fetched.
+/// Warns and returns false if an option needs every frame first.
+pub fn is_enabled() -> bool {
+    if !CLI_OPTIONS.pipeline || CLI_OPTIONS.stage() != Stage::Run {
+        return false;
+    }
+    match whole_sequence_option() {
+        Some(option) => {
+            warning(&format!(
+                "--pipeline can not be used with {}, fetching every image before encoding",
+                option
+            ));
+            false
+        }
+        None => true,
+    }
+}
+
+/// Fetch the image of point, refetching it up to fetch_retries times (default: 3) if it is
+/// missing, corrupt or a placeholder although it has a panorama. Panics if it is still missing
+/// or corrupt afterwards.
+async fn fetch_frame(client: &Client, index: usize, point: &SerializablePointBearing) -> Vec<u8> {
+    if mock::is_enabled() {
+        return mock::frame(index, point);
+    }
+    let url = image_url(point);
+    let retries = CLI_OPTIONS.fetch_retries.unwrap_or(3);
+    let mut placeholder = None;
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            throttle::retry_backoff(attempt).await;
+        }
+        let bytes = match throttle::send(|| client.get(&url)).await {
+            Ok((_, bytes)) => bytes,
+            Err(e) => {
+                error(
+                    ErrorCode::Network,
+                    &format!("Error in streetview image response {}: {:?}", index, e),
+                    true,
+                );
+                continue;
+            }
+        };
+        match validate_frame_bytes(&bytes) {
+            (FrameStatus::Ok, _) => return bytes,
+            (FrameStatus::Converted, Some(jpg)) => return jpg,
+            (FrameStatus::Placeholder, _) if expects_placeholder(point) => return bytes,
+            (FrameStatus::Placeholder, _) => placeholder = Some(bytes),
+            _ => (),
+        }
+    }
+    match placeholder {
+        Some(bytes) => {
+            error(
+                ErrorCode::FramesMissing,
+                &format!("Frame without imagery after refetch: {}", index),
+                true,
+            );
+            bytes
+        }
+        None => fail(
+            ErrorCode::FramesMissing,
+            &format!(
+                "Frame missing or corrupt after {} retries: {}",
+                retries, index
+            ),
+        ),
+    }
+}
+
+/// Fetch the images of points and encode them into out_filename in output_dir as they arrive,
+/// so downloading overlaps with encoding. Images are fetched network_concurrency at a time but
+/// encoded in order, and each is also saved as {index}.jpg for the steps after encoding.
+#[tracing::instrument(skip_all)]
+pub async fn fetch_and_encode(
+    output_dir: &Path,
+    points: &[SerializablePointBearing],
+    out_filename: &str,
+) {
+    let client = http::client();
+    let frames = stream::iter(points.iter().cloned().enumerate())
+        .map(|(index, point)| {
+            let client = &client;
+            async move {
+                let filename = output_dir.join(format!("{}.jpg", index));
+                if store::link(&point, &filename) {
+                    if let Ok(bytes) = tokio::fs::read(&filename).await {
+                        return bytes;
+                    }
+                }
+                let bytes = fetch_frame(client, index, &point).await;
+                tokio::fs::write(&filename, &bytes)
+                    .await
+                    .unwrap_or_else(|e| {
+                        fail(
+                            ErrorCode::Io,
+                            &format!("Could not write {:?}: {:?}", filename, e),
+                        )
+                    });
+                store::save(&point, &filename);
+                bytes
+            }
+        })
+        .buffered(CLI_OPTIONS.network_concurrency.unwrap_or(40));
+    create_timelapse_from_stream(output_dir, points.len(), frames, out_filename).await;
+    store::prune();
+}
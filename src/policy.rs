@@ -0,0 +1,64 @@
+use crate::errors::{fail, ErrorCode};
+use crate::options::CLI_OPTIONS;
+
+/// Status given to the metadata of points whose request failed and was skipped, so they are
+/// dropped like points without imagery.
+pub const REQUEST_FAILED_STATUS: &str = "REQUEST_FAILED";
+
+/// What to do with points whose metadata says there is no imagery, chosen by the on_missing
+/// option.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MissingPolicy {
+    /// Drop the point from the route.
+    Skip,
+    /// Keep the point, whose frame is Google's "no imagery" placeholder.
+    Placeholder,
+    /// End the run.
+    Fail,
+}
+
+impl MissingPolicy {
+    pub fn from_options() -> MissingPolicy {
+        match CLI_OPTIONS.on_missing.as_deref().unwrap_or("skip") {
+            "skip" => MissingPolicy::Skip,
+            "placeholder" => MissingPolicy::Placeholder,
+            "fail" => MissingPolicy::Fail,
+            other => fail(
+                ErrorCode::InputInvalid,
+                &format!(
+                    "Unknown --on-missing policy {}, available: skip, placeholder, fail",
+                    other
+                ),
+            ),
+        }
+    }
+}
+
+/// What to do when a metadata request fails or the API answers with an HTTP error, chosen by
+/// the on_error option.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorPolicy {
+    /// Send the request again, up to fetch_retries times, then end the run.
+    Retry,
+    /// Drop the point from the route.
+    Skip,
+    /// End the run.
+    Fail,
+}
+
+impl ErrorPolicy {
+    pub fn from_options() -> ErrorPolicy {
+        match CLI_OPTIONS.on_error.as_deref().unwrap_or("fail") {
+            "retry" => ErrorPolicy::Retry,
+            "skip" => ErrorPolicy::Skip,
+            "fail" => ErrorPolicy::Fail,
+            other => fail(
+                ErrorCode::InputInvalid,
+                &format!(
+                    "Unknown --on-error policy {}, available: retry, skip, fail",
+                    other
+                ),
+            ),
+        }
+    }
+}
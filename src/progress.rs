@@ -1,22 +1,306 @@
 use serde_json::json;
+use std::io::Write;
 use std::sync::Mutex;
+use std::time::Instant;
 
-use crate::options::CLI_OPTIONS;
+use crate::capabilities::PROGRESS_SCHEMA_VERSION;
+use crate::context;
+use crate::deterministic;
+use crate::errors::{fail, ErrorCode};
+use crate::options::{Stage, CLI_OPTIONS};
 
 const PROGRESS_DEBOUNCE_MS: u128 = 200;
+/// Overall percentage after which an ETA is estimated, since earlier ones are mostly noise.
+const ETA_MIN_PERCENT: f64 = 1.0;
 
-lazy_static! {
-    static ref LAST_PROGRESS_TIME: Mutex<u128> = Mutex::new(0);
+/// The parts of the pipeline that overall progress is split between, in the order they run.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Phase {
+    Metadata,
+    Download,
+    Optimize,
+    Encode,
+    Interpolate,
 }
 
+impl Phase {
+    /// Typical share of the run time of the phase, relative to the others.
+    fn weight(self) -> f64 {
+        match self {
+            Phase::Metadata => 10.0,
+            Phase::Download => 30.0,
+            Phase::Optimize => 10.0,
+            Phase::Encode => 25.0,
+            Phase::Interpolate => 25.0,
+        }
+    }
+}
+
+/// Where the run is in its phases, for overall progress.
+#[derive(Default)]
+struct Overall {
+    phase: Option<Phase>,
+    /// Progress of the current phase in percent.
+    phase_percent: f64,
+    started: Option<Instant>,
+    /// Last overall percentage reported, which never goes backwards.
+    percent: f64,
+}
+
+/// Progress of a run, kept in its context.
+#[derive(Default)]
+pub struct State {
+    last_progress_time: Mutex<u128>,
+    current_stage: Mutex<Option<String>>,
+    overall: Mutex<Overall>,
+    /// Where progress events are written, stdout unless the progress_sink option is set.
+    /// Opened by open_sink at the start of the run.
+    sink: Mutex<Option<Box<dyn Write + Send>>>,
+}
+
+fn state() -> &'static State {
+    &context::current().progress
+}
+
+/// Connect to or create sink, the progress_sink option: a file path, unix:/path/to/socket or
+/// tcp:host:port.
+fn connect_sink(sink: &str) -> std::io::Result<Box<dyn Write + Send>> {
+    if let Some(address) = sink.strip_prefix("tcp:") {
+        return Ok(Box::new(std::net::TcpStream::connect(address)?));
+    }
+    if let Some(path) = sink.strip_prefix("unix:") {
+        #[cfg(unix)]
+        return Ok(Box::new(std::os::unix::net::UnixStream::connect(path)?));
+        #[cfg(not(unix))]
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("unix sockets are not supported on this platform: {}", path),
+        ));
+    }
+    Ok(Box::new(std::fs::File::create(sink)?))
+}
+
+/// Open where the progress events of the run are written, before anything is reported. If the
+/// progress_sink option cannot be opened, fail with its error reported on stdout instead.
+pub fn open_sink() {
+    let opened = CLI_OPTIONS.progress_sink.as_deref().map(connect_sink);
+    let (sink, error): (Box<dyn Write + Send>, _) = match opened {
+        Some(Ok(sink)) => (sink, None),
+        Some(Err(e)) => (Box::new(std::io::stdout()), Some(e)),
+        None => (Box::new(std::io::stdout()), None),
+    };
+    *state().sink.lock().unwrap() = Some(sink);
+    if let Some(e) = error {
+        fail(
+            ErrorCode::Io,
+            &format!(
+                "Could not open progress sink {}: {:?}",
+                CLI_OPTIONS.progress_sink.as_deref().unwrap_or_default(),
+                e
+            ),
+        );
+    }
+}
+
+/// Whether progress events are reported, to stdout or the progress sink. Outside of a run, as
+/// in the watch subcommand, messages are only logged.
+fn enabled() -> bool {
+    context::try_current()
+        .is_some_and(|context| context.options.progress || context.options.progress_sink.is_some())
+}
+
+/// The phases this run goes through with its stage and options.
+fn enabled_phases() -> Vec<Phase> {
+    let stage = CLI_OPTIONS.stage();
+    let mut phases = vec![];
+    if stage != Stage::Encode && !CLI_OPTIONS.use_metadata {
+        phases.push(Phase::Metadata);
+    }
+    if stage == Stage::Fetch || stage == Stage::Run {
+        phases.push(Phase::Download);
+    }
+    if stage == Stage::Encode || stage == Stage::Run {
+        if crate::optim::is_enabled() {
+            phases.push(Phase::Optimize);
+        }
+        phases.push(Phase::Encode);
+        if crate::ffmpeg::minterp_mode() != "skip" {
+            phases.push(Phase::Interpolate);
+        }
+    }
+    phases
+}
+
+/// Start counting the progress of phase towards overall progress.
+pub fn start_phase(phase: Phase) {
+    let mut overall = state().overall.lock().unwrap();
+    overall.started.get_or_insert_with(Instant::now);
+    overall.phase = Some(phase);
+    overall.phase_percent = 0.0;
+}
+
+/// Update the overall percentage from the current phase being percent done, and return it with
+/// the estimated seconds left, if the run is far enough along to tell.
+fn update_overall(percent: Option<f64>) -> (f64, Option<f64>) {
+    let mut overall = state().overall.lock().unwrap();
+    if let Some(percent) = percent {
+        overall.phase_percent = percent.clamp(0.0, 100.0);
+    }
+    if let Some(phase) = overall.phase {
+        let phases = enabled_phases();
+        let total = phases.iter().map(|p| p.weight()).sum::<f64>();
+        let done = phases
+            .iter()
+            .filter(|&&p| p < phase)
+            .map(|p| p.weight())
+            .sum::<f64>();
+        let current = if phases.contains(&phase) {
+            phase.weight() * overall.phase_percent / 100.0
+        } else {
+            0.0
+        };
+        if total > 0.0 {
+            overall.percent = overall.percent.max(100.0 * (done + current) / total);
+        }
+    }
+    // Estimates depend on timing, so deterministic runs leave them out.
+    let eta = match overall.started {
+        Some(_) if deterministic::is_enabled() => None,
+        Some(started) if overall.percent >= ETA_MIN_PERCENT => {
+            let elapsed = started.elapsed().as_secs_f64();
+            Some(elapsed * (100.0 - overall.percent) / overall.percent)
+        }
+        _ => None,
+    };
+    (overall.percent, eta)
+}
+
+/// Fields of a PROGRESS event besides its type and schema version.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProgressEvent<'a> {
+    message: &'a str,
+    stage: Option<String>,
+    /// Position of the current phase among the stage_count phases of the run, from 0.
+    stage_index: Option<usize>,
+    stage_count: usize,
+    /// Progress of the current phase, if it is counted.
+    percent: Option<f64>,
+    done: Option<usize>,
+    total: Option<usize>,
+    unit: Option<&'static str>,
+    overall_percent: f64,
+    eta_seconds: Option<f64>,
+}
+
+/// What a ProgressReporter counts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Requests,
+    Frames,
+    Megabytes,
+}
+
+impl Unit {
+    fn name(self) -> &'static str {
+        match self {
+            Unit::Requests => "requests",
+            Unit::Frames => "frames",
+            Unit::Megabytes => "megabytes",
+        }
+    }
+}
+
+/// Reports the progress of a counted step of the current phase as typed PROGRESS events.
+pub struct ProgressReporter {
+    unit: Unit,
+    /// Number of units in the step, unless only the caller can tell the percentage.
+    total: Option<usize>,
+    done: usize,
+}
+
+impl ProgressReporter {
+    pub fn new(unit: Unit, total: usize) -> ProgressReporter {
+        ProgressReporter {
+            unit,
+            total: Some(total),
+            done: 0,
+        }
+    }
+
+    /// A reporter of rendered frames, whose percentage is given by the encoder.
+    pub fn frames() -> ProgressReporter {
+        ProgressReporter {
+            unit: Unit::Frames,
+            total: None,
+            done: 0,
+        }
+    }
+
+    /// Count one more unit done and report it.
+    pub fn advance(&mut self) {
+        self.set(self.done + 1);
+    }
+
+    /// Report done units out of the total.
+    pub fn set(&mut self, done: usize) {
+        let total = self.total.unwrap_or(done).max(1);
+        self.set_percent(done, 100.0 * done as f64 / total as f64);
+    }
+
+    /// Report done units with the percentage computed by the caller.
+    pub fn set_percent(&mut self, done: usize, percent: f64) {
+        self.done = done;
+        let message = match (self.unit, self.total) {
+            (Unit::Frames, None) => format!("{:.1}% rendered", percent),
+            (Unit::Megabytes, Some(total)) => {
+                format!("Progress: {:.1}% ({}/{} MB)", percent, done, total)
+            }
+            (_, Some(total)) => format!("Progress: {:.1}% ({}/{})", percent, done, total),
+            (_, None) => format!("Progress: {:.1}%", percent),
+        };
+        report_progress(&message, Some((percent, self)));
+    }
+}
+
+/// Print a JSON event of the progress protocol, tagged with its type and schema version, and
+/// with the job id in the jobs subcommand.
+fn emit(event_type: &str, fields: serde_json::Value) {
+    let context = context::current();
+    let mut event = json!({
+        "type": event_type,
+        "schemaVersion": PROGRESS_SCHEMA_VERSION,
+    });
+    if let (Some(event), serde_json::Value::Object(fields)) = (event.as_object_mut(), fields) {
+        event.extend(fields);
+        if let Some(id) = &context.job_id {
+            event.insert("id".to_string(), id.clone());
+        }
+    }
+    let line = serde_json::to_string(&event).expect("Could not print progress message");
+    let mut sink = context.progress.sink.lock().unwrap();
+    let sink = sink.get_or_insert_with(|| Box::new(std::io::stdout()));
+    // Flush each event so readers of a file or socket see it right away.
+    if let Err(e) = writeln!(sink, "{}", line).and_then(|_| sink.flush()) {
+        error!("Could not write progress event: {:?}", e);
+    }
+}
+
+/// Report a free-form message about the current stage.
 pub fn progress(msg: &str) {
-    if !CLI_OPTIONS.progress {
+    report_progress(msg, None);
+}
+
+fn report_progress(msg: &str, counted: Option<(f64, &ProgressReporter)>) {
+    if !enabled() {
+        debug!("{}", msg);
         return;
     }
+    let (overall_percent, eta) = update_overall(counted.map(|(percent, _)| percent));
     // If last progress time + debounce < current time, then skip
-    {
+    if !deterministic::is_enabled() {
         // Start new context so we can drop the lock before printing
-        let mut last_progress_time = LAST_PROGRESS_TIME.lock().unwrap();
+        let mut last_progress_time = state().last_progress_time.lock().unwrap();
         let current_time = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
@@ -26,31 +310,146 @@ pub fn progress(msg: &str) {
         }
         *last_progress_time = current_time;
     }
-    println!(
-        "{}",
-        serde_json::to_string(&json!({
-            "type": "PROGRESS",
-            "message": msg,
-        }))
-        .expect("Could not print progress message")
+    let phases = enabled_phases();
+    let phase = state().overall.lock().unwrap().phase;
+    let event = ProgressEvent {
+        message: msg,
+        stage: current_stage(),
+        stage_index: phase.and_then(|phase| phases.iter().position(|&p| p == phase)),
+        stage_count: phases.len(),
+        percent: counted.map(|(percent, _)| (percent * 10.0).round() / 10.0),
+        done: counted.map(|(_, reporter)| reporter.done),
+        total: counted.and_then(|(_, reporter)| reporter.total),
+        unit: counted.map(|(_, reporter)| reporter.unit.name()),
+        overall_percent: (overall_percent * 10.0).round() / 10.0,
+        eta_seconds: eta.map(|eta| eta.round()),
+    };
+    emit(
+        "PROGRESS",
+        serde_json::to_value(&event).expect("Could not print progress message"),
     );
 }
 
 pub fn progress_stage(stage: &str) {
-    if !CLI_OPTIONS.progress {
+    if let Some(context) = context::try_current() {
+        *context.progress.current_stage.lock().unwrap() = Some(stage.to_string());
+    }
+    if !enabled() {
+        info!("{}", stage);
         return;
     }
     {
         // Reset the last progress time to 0
-        let mut last_progress_time = LAST_PROGRESS_TIME.lock().unwrap();
+        let mut last_progress_time = state().last_progress_time.lock().unwrap();
         *last_progress_time = 0;
     }
-    println!(
-        "{}",
-        serde_json::to_string(&json!({
-            "type": "PROGRESS_STAGE",
+    emit(
+        "PROGRESS_STAGE",
+        json!({
             "stage": stage,
-        }))
-        .expect("Could not print progress message")
+        }),
+    );
+}
+
+/// Report a problem that the pipeline recovered from.
+/// Printed as a JSON message if progress is enabled, otherwise logged.
+pub fn warning(msg: &str) {
+    if !enabled() {
+        warn!("{}", msg);
+        return;
+    }
+    emit(
+        "WARNING",
+        json!({
+            "message": msg,
+        }),
+    );
+}
+
+/// Report a failure with its code and the current stage. Recoverable failures are skipped or
+/// retried and the run goes on; the others end the run.
+/// Printed as a JSON message if progress is enabled, otherwise recoverable failures are logged
+/// and the others are left to the panic message.
+pub fn error(code: ErrorCode, msg: &str, recoverable: bool) {
+    if !enabled() {
+        if recoverable {
+            error!(code = code.name(), "{}", msg);
+        }
+        return;
+    }
+    emit(
+        "ERROR",
+        json!({
+            "stage": current_stage(),
+            "code": code.name(),
+            "message": msg,
+            "recoverable": recoverable,
+        }),
+    );
+}
+
+/// Report that the API throttled requests for reason, pausing them for wait_secs.
+/// Printed as a JSON message if progress is enabled, otherwise logged.
+pub fn throttled(reason: &str, wait_secs: u64) {
+    if !enabled() {
+        warn!(
+            "Throttled by the API ({}), pausing for {}s",
+            reason, wait_secs
+        );
+        return;
+    }
+    emit(
+        "THROTTLED",
+        json!({
+            "reason": reason,
+            "waitSeconds": wait_secs,
+        }),
+    );
+}
+
+/// Report the path of the file the run produced, if progress is enabled.
+pub fn result(output: &std::path::Path) {
+    if !enabled() {
+        return;
+    }
+    emit(
+        "RESULT",
+        json!({
+            "output": output,
+        }),
+    );
+}
+
+/// Report the statistics of the finished run. Printed as a JSON message if progress is enabled,
+/// otherwise the lines describing it are logged.
+pub fn summary(summary: serde_json::Value, lines: &[String]) {
+    if !enabled() {
+        for line in lines {
+            info!("{}", line);
+        }
+        return;
+    }
+    emit("SUMMARY", summary);
+}
+
+/// The stage most recently reported by progress_stage.
+pub fn current_stage() -> Option<String> {
+    context::try_current()
+        .and_then(|context| context.progress.current_stage.lock().unwrap().clone())
+}
+
+/// Report that the run was cancelled, with the checkpoint written to resume it, if any.
+/// Printed as a JSON message if progress is enabled, otherwise logged.
+pub fn cancelled(checkpoint: Option<&std::path::Path>) {
+    if !enabled() {
+        warn!("Cancelled during: {}", current_stage().unwrap_or_default());
+        return;
+    }
+    emit(
+        "CANCELLED",
+        json!({
+            "stage": current_stage(),
+            "checkpoint": checkpoint,
+        }),
     );
 }
@@ -0,0 +1,57 @@
+use std::io::Read;
+
+use serde_json::{json, Value};
+
+use crate::capabilities::METADATA_SCHEMA_VERSION;
+use crate::errors::{fail, ErrorCode};
+use crate::MetadataResult;
+
+/// The version of files written before the schemaVersion field existed.
+const UNVERSIONED_SCHEMA_VERSION: u32 = 1;
+
+/// Upgrade a version 1 metadata result, which only lacks the schemaVersion field, to version 2.
+fn migrate_v1(mut metadata: Value) -> Value {
+    metadata["schemaVersion"] = json!(2);
+    metadata
+}
+
+/// Upgrade metadata from version to the next one. Each new schema version adds its migration.
+fn migrate(version: u32, metadata: Value) -> Value {
+    match version {
+        1 => migrate_v1(metadata),
+        _ => unreachable!("No migration from metadata schema version {}", version),
+    }
+}
+
+/// Read a metadata result of any supported schema version from reader, upgrading older
+/// versions to the current one. source names the input in errors.
+pub fn read_metadata_result<R: Read>(reader: R, source: &str) -> MetadataResult {
+    let mut metadata: Value = serde_json::from_reader(reader).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!("Could not parse {}: {}", source, e),
+        )
+    });
+    let mut version = metadata["schemaVersion"]
+        .as_u64()
+        .map_or(UNVERSIONED_SCHEMA_VERSION, |version| version as u32);
+    if version > METADATA_SCHEMA_VERSION {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!(
+                "{} has metadata schema version {}, but this streetwarp only reads up to version {}; upgrade streetwarp to read it",
+                source, version, METADATA_SCHEMA_VERSION
+            ),
+        );
+    }
+    while version < METADATA_SCHEMA_VERSION {
+        metadata = migrate(version, metadata);
+        version += 1;
+    }
+    serde_json::from_value(metadata).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!("Could not parse {}: {}", source, e),
+        )
+    })
+}
@@ -0,0 +1,112 @@
+use std::path::Path;
+
+use rhai::serde::{from_dynamic, to_dynamic};
+use rhai::{Dynamic, Engine, Scope, AST};
+
+use crate::errors::{fail, ErrorCode};
+use crate::options::CLI_OPTIONS;
+use crate::SerializablePointBearing;
+
+/// A user script loaded from the script option. It may define:
+///   fn filter_point(point) - called per sampled point (a map with index, lat, lng, bearing, ele,
+///     panoId, panoDate). Return false to drop the point, a modified map to adjust it,
+///     or anything else to keep it unchanged.
+///   fn frame_name(index, point) - called per frame after the video (or frame export) is created.
+///     Return a string to rename the frame file, or anything else to leave it.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    /// Load the script given by the script option, if any.
+    pub fn load() -> Option<Script> {
+        let path = CLI_OPTIONS.script.as_ref()?;
+        let engine = Engine::new();
+        let ast = engine.compile_file(path.clone()).unwrap_or_else(|e| {
+            fail(
+                ErrorCode::InputInvalid,
+                &format!("Could not compile script {:?}: {}", path, e),
+            )
+        });
+        Some(Script { engine, ast })
+    }
+
+    fn has_fn(&self, name: &str) -> bool {
+        self.ast.iter_functions().any(|f| f.name == name)
+    }
+
+    fn call(&self, name: &str, args: impl rhai::FuncArgs) -> Dynamic {
+        self.engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, name, args)
+            .unwrap_or_else(|e| {
+                fail(
+                    ErrorCode::InputInvalid,
+                    &format!("Error in script function {}: {}", name, e),
+                )
+            })
+    }
+
+    /// Pass every point through filter_point and return the kept (possibly adjusted) points.
+    pub fn filter_points(
+        &self,
+        points: Vec<SerializablePointBearing>,
+    ) -> Vec<SerializablePointBearing> {
+        if !self.has_fn("filter_point") {
+            return points;
+        }
+        points
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, point)| {
+                let mut arg = to_dynamic(&point).expect("Could not convert point for script");
+                if let Some(mut map) = arg.write_lock::<rhai::Map>() {
+                    map.insert("index".into(), Dynamic::from(index as i64));
+                }
+                let result = self.call("filter_point", (arg,));
+                if result.is::<bool>() {
+                    Some(point).filter(|_| result.as_bool().unwrap_or(true))
+                } else if result.is::<rhai::Map>() {
+                    Some(from_dynamic(&result).expect("filter_point returned an invalid point"))
+                } else {
+                    Some(point)
+                }
+            })
+            .collect()
+    }
+
+    /// Rename each frame in image_dir matching pattern (e.g. %d.jpg) by calling frame_name.
+    /// Return the resulting filename of every frame.
+    pub fn rename_frames(
+        &self,
+        image_dir: &Path,
+        pattern: &str,
+        points: &[SerializablePointBearing],
+    ) -> Vec<String> {
+        let original_name = |index: usize| pattern.replace("%d", &index.to_string());
+        if !self.has_fn("frame_name") {
+            return (0..points.len()).map(original_name).collect();
+        }
+        points
+            .iter()
+            .enumerate()
+            .map(|(index, point)| {
+                let arg = to_dynamic(point).expect("Could not convert point for script");
+                let result = self.call("frame_name", (index as i64, arg));
+                match result.into_string() {
+                    Ok(name) => {
+                        let from = image_dir.join(original_name(index));
+                        std::fs::rename(&from, image_dir.join(&name)).unwrap_or_else(|e| {
+                            fail(
+                                ErrorCode::Io,
+                                &format!("Could not rename {:?} to {}: {:?}", &from, name, e),
+                            )
+                        });
+                        name
+                    }
+                    Err(_) => original_name(index),
+                }
+            })
+            .collect()
+    }
+}
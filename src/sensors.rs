@@ -0,0 +1,144 @@
+use std::path::Path;
+
+use geo::{prelude::*, Point};
+
+use crate::errors::{fail, ErrorCode};
+use crate::fit::{self, FitRecord};
+use crate::progress::warning;
+use crate::SerializablePointBearing;
+
+/// Sensor readings recorded at some distance (meters) along the activity.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SensorRecord {
+    pub distance: f64,
+    pub heart_rate: Option<f64>,
+    pub power: Option<f64>,
+    pub cadence: Option<f64>,
+}
+
+/// Value of the attribute name="..." within an XML start tag.
+fn xml_attribute(tag: &str, name: &str) -> Option<f64> {
+    let start = tag.find(&format!("{}=", name))? + name.len() + 2;
+    let end = start + tag[start..].find(['"', '\''])?;
+    tag[start..end].parse().ok()
+}
+
+/// Text of the first element with the given local name (ignoring any namespace prefix) in xml.
+fn xml_element_value(xml: &str, local_name: &str) -> Option<f64> {
+    let mut rest = xml;
+    while let Some(open) = rest.find('<') {
+        let after = &rest[open + 1..];
+        let tag_end = after.find('>')?;
+        let tag = &after[..tag_end];
+        let name = tag.rsplit(':').next().unwrap_or(tag);
+        if name == local_name {
+            let text = &after[tag_end + 1..];
+            return text[..text.find('<')?].trim().parse().ok();
+        }
+        rest = &after[tag_end..];
+    }
+    None
+}
+
+/// Read heart rate, power and cadence from the track point extensions of a GPX file,
+/// as written by Garmin (gpxtpx:TrackPointExtension) and most other devices.
+fn read_gpx_sensors(contents: &str) -> Vec<SensorRecord> {
+    let mut records = vec![];
+    let mut distance = 0.0;
+    let mut last_point: Option<Point<f64>> = None;
+    for chunk in contents.split("<trkpt").skip(1) {
+        let tag = &chunk[..chunk.find('>').unwrap_or(chunk.len())];
+        let (lat, lon) = match (xml_attribute(tag, "lat"), xml_attribute(tag, "lon")) {
+            (Some(lat), Some(lon)) => (lat, lon),
+            _ => continue,
+        };
+        let body = &chunk[..chunk.find("</trkpt>").unwrap_or(chunk.len())];
+        let point = Point::new(lon, lat);
+        if let Some(last) = last_point {
+            distance += last.geodesic_distance(&point);
+        }
+        last_point = Some(point);
+        records.push(SensorRecord {
+            distance,
+            heart_rate: xml_element_value(body, "hr"),
+            power: xml_element_value(body, "power")
+                .or_else(|| xml_element_value(body, "PowerInWatts")),
+            cadence: xml_element_value(body, "cad"),
+        });
+    }
+    records
+}
+
+/// Read the distance, heart rate, power and cadence of the record messages of a FIT file.
+fn read_fit_sensors(data: &[u8]) -> Vec<SensorRecord> {
+    let records = match fit::read_records(data) {
+        Some(records) => records,
+        None => {
+            warning("Sensor file is not a FIT file");
+            return vec![];
+        }
+    };
+    let value = |record: &FitRecord, number| record.uint(number).map(|v| v as f64);
+    records
+        .iter()
+        .filter_map(|record| {
+            Some(SensorRecord {
+                // Distance is stored in centimeters.
+                distance: value(record, 5)? / 100.0,
+                heart_rate: value(record, 3),
+                power: value(record, 7),
+                cadence: value(record, 4),
+            })
+        })
+        .collect()
+}
+
+/// Read sensor records from a FIT file or GPX file (detected by extension), sorted by distance.
+pub fn read_sensor_file(path: &Path) -> Vec<SensorRecord> {
+    let is_fit = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("fit"));
+    let bytes = std::fs::read(path).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!("Could not read sensor file {:?}: {:?}", path, e),
+        )
+    });
+    let mut records = if is_fit {
+        read_fit_sensors(&bytes)
+    } else {
+        read_gpx_sensors(&String::from_utf8_lossy(&bytes))
+    };
+    records.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap());
+    if records
+        .iter()
+        .all(|r| r.heart_rate.is_none() && r.power.is_none() && r.cadence.is_none())
+    {
+        warning(&format!(
+            "No heart rate, power or cadence found in {:?}",
+            path
+        ));
+    }
+    records
+}
+
+/// Find the sensor readings at each point by distance along the route. The distances of the
+/// points are scaled to the length of the recording, since they only approximate the route.
+pub fn sensors_at_points(
+    records: &[SensorRecord],
+    points: &[SerializablePointBearing],
+) -> Vec<SensorRecord> {
+    let distances = crate::cumulative_distances(points);
+    let route_length = distances.last().copied().unwrap_or(0.0).max(1.0);
+    let recorded_length = records.last().map_or(0.0, |r| r.distance);
+    distances
+        .iter()
+        .map(|&distance| {
+            let target = distance / route_length * recorded_length;
+            let index = records
+                .binary_search_by(|r| r.distance.partial_cmp(&target).unwrap())
+                .unwrap_or_else(|index| index.min(records.len().saturating_sub(1)));
+            records.get(index).copied().unwrap_or_default()
+        })
+        .collect()
+}
@@ -0,0 +1,302 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::errors::{fail, ErrorCode};
+use crate::options::{Stage, CLI_OPTIONS};
+use crate::progress::progress;
+use crate::{update_statistics, MetadataResult};
+
+/// File in the output directory of a shard naming its place among the shards and its video.
+const SHARD_FILE: &str = "streetwarp-shard.json";
+
+/// Written by each shard for merge to put the shards back together.
+#[derive(Serialize, Deserialize)]
+struct ShardManifest {
+    /// Number of the shard, counting from 1.
+    shard: usize,
+    /// Number of shards the route is split into.
+    shards: usize,
+    /// Name of the video of the shard in its output directory.
+    video: String,
+    metadata: MetadataResult,
+}
+
+/// The shard option as the number of the shard, counting from 1, and the number of shards.
+fn shard() -> Option<(usize, usize)> {
+    let shard = CLI_OPTIONS.shard.as_ref()?;
+    let invalid = || {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!("Invalid --shard {}, expected I/N with 1 <= I <= N", shard),
+        )
+    };
+    let mut parts = shard
+        .splitn(2, '/')
+        .map(|part| part.trim().parse::<usize>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(index)), Some(Ok(count))) if index >= 1 && index <= count => Some((index, count)),
+        _ => invalid(),
+    }
+}
+
+/// Keep only the items of the shard chosen by the shard option, all of them without it.
+/// Shard I of N gets the I-th of N runs of consecutive items as even as possible. Every shard
+/// after the first also keeps the last item of the shard before it, first, for
+/// skip_previous_pano to find the frames that shard already shows.
+pub fn slice<T>(items: Vec<T>) -> Vec<T> {
+    let (index, count) = match shard() {
+        Some(shard) => shard,
+        None => return items,
+    };
+    if CLI_OPTIONS.stage() != Stage::Metadata && CLI_OPTIONS.output_dir.is_none() {
+        fail(
+            ErrorCode::InputInvalid,
+            "--shard needs --output-dir, for streetwarp merge to find the output of the shard",
+        );
+    }
+    let len = items.len();
+    let start = (index - 1) * len / count;
+    let end = index * len / count;
+    if start == end {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!(
+                "Shard {}/{} has no frames of the {} in the route, use fewer shards",
+                index, count, len
+            ),
+        );
+    }
+    progress(&format!(
+        "Shard {}/{}: keeping frames {} to {} of {}",
+        index,
+        count,
+        start,
+        end - 1,
+        len
+    ));
+    let previous = usize::from(start > 0);
+    items
+        .into_iter()
+        .skip(start - previous)
+        .take(end - start + previous)
+        .collect()
+}
+
+/// Drop the item that slice kept from the shard before this one, along with the items at the
+/// start of this shard that have the same panorama as it, by pano_id. Otherwise the merged
+/// video would show the panorama at the boundary twice, once at the end of each shard.
+pub fn skip_previous_pano<T>(items: Vec<T>, pano_id: impl Fn(&T) -> Option<&str>) -> Vec<T> {
+    match shard() {
+        Some((index, _)) if index > 1 => (),
+        _ => return items,
+    }
+    let mut items = items.into_iter().peekable();
+    let previous = items.next().expect("No item of the previous shard");
+    let previous_pano = pano_id(&previous).filter(|pano| !pano.is_empty());
+    let mut skipped = 0;
+    while items
+        .peek()
+        .is_some_and(|item| previous_pano.is_some() && pano_id(item) == previous_pano)
+    {
+        items.next();
+        skipped += 1;
+    }
+    if skipped > 0 {
+        progress(&format!(
+            "Skipped {} frames of the panorama the previous shard ends with",
+            skipped
+        ));
+    }
+    let items = items.collect::<Vec<_>>();
+    if items.is_empty() {
+        fail(
+            ErrorCode::InputInvalid,
+            "The shard only shows the panorama the previous shard ends with, use fewer shards",
+        );
+    }
+    items
+}
+
+/// Write the manifest of the shard into output_dir, where it rendered video_filename from
+/// metadata_result, if the shard option is set.
+pub fn write_manifest(output_dir: &Path, video_filename: &str, metadata_result: &MetadataResult) {
+    let (shard, shards) = match shard() {
+        Some(shard) => shard,
+        None => return,
+    };
+    let manifest = ShardManifest {
+        shard,
+        shards,
+        video: video_filename.to_string(),
+        metadata: metadata_result.clone(),
+    };
+    let path = output_dir.join(SHARD_FILE);
+    let file = File::create(&path).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Io,
+            &format!("Could not create {:?}: {:?}", path, e),
+        )
+    });
+    serde_json::to_writer(BufWriter::new(file), &manifest).expect("Could not write shard manifest");
+}
+
+fn read_manifest(dir: &Path) -> ShardManifest {
+    let path = dir.join(SHARD_FILE);
+    let file = File::open(&path).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!(
+                "Could not open {:?}, was the directory rendered with --shard? {:?}",
+                path, e
+            ),
+        )
+    });
+    serde_json::from_reader(BufReader::new(file)).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!("Could not parse {:?}: {}", path, e),
+        )
+    })
+}
+
+/// One metadata result for the whole route from the results of its shards, in order.
+fn merge_metadata(results: Vec<MetadataResult>) -> MetadataResult {
+    let frames = results.iter().map(|result| result.frames).sum::<usize>();
+    let total_error = results
+        .iter()
+        .map(|result| result.averageError * result.frames as f64)
+        .sum::<f64>();
+    let mut results = results.into_iter();
+    // Every shard read the whole route, so all but the frames are the same in each.
+    let mut merged = results.next().expect("No shards to merge");
+    for result in results {
+        merged.gpsPoints.extend(result.gpsPoints);
+    }
+    // Kept for metadata written before points recorded their error.
+    merged.averageError = total_error / frames.max(1) as f64;
+    update_statistics(&mut merged);
+    merged
+}
+
+/// Concatenate the videos of the shards in shard_dirs in order into output, without reencoding,
+/// and write their merged metadata result to metadata. Runs before the options are parsed, so
+/// it reports to stdout and runs the ffmpeg executable at ffmpeg_path directly.
+pub fn merge(
+    shard_dirs: &[PathBuf],
+    output: Option<&Path>,
+    metadata: Option<&Path>,
+    ffmpeg_path: &str,
+) {
+    let mut manifests = shard_dirs
+        .iter()
+        .map(|dir| (dir, read_manifest(dir)))
+        .collect::<Vec<_>>();
+    manifests.sort_by_key(|(_, manifest)| manifest.shard);
+    let shards = manifests[0].1.shards;
+    if manifests
+        .iter()
+        .any(|(_, manifest)| manifest.shards != shards)
+    {
+        fail(
+            ErrorCode::InputInvalid,
+            "The shards were split into different numbers of shards",
+        );
+    }
+    let missing = (1..=shards)
+        .filter(|shard| {
+            !manifests
+                .iter()
+                .any(|(_, manifest)| manifest.shard == *shard)
+        })
+        .map(|shard| shard.to_string())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() || manifests.len() != shards {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!(
+                "Expected each of the {} shards once, missing: [{}]",
+                shards,
+                missing.join(", ")
+            ),
+        );
+    }
+
+    let extension = Path::new(&manifests[0].1.video)
+        .extension()
+        .map_or("mp4".to_string(), |ext| ext.to_string_lossy().to_string());
+    let output = output.map_or_else(
+        || PathBuf::from(format!("streetwarp-lapse.{}", extension)),
+        |output| output.to_path_buf(),
+    );
+    let list_path = PathBuf::from(format!("{}-shards.txt", output.to_string_lossy()));
+    let list = manifests
+        .iter()
+        .map(|(dir, manifest)| {
+            let video = dir.join(&manifest.video);
+            let video = video.canonicalize().unwrap_or_else(|e| {
+                fail(
+                    ErrorCode::InputInvalid,
+                    &format!("Could not find the shard video {:?}: {:?}", video, e),
+                )
+            });
+            format!(
+                "file '{}'\n",
+                video.to_string_lossy().replace('\'', "'\\''")
+            )
+        })
+        .collect::<String>();
+    std::fs::write(&list_path, list).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Io,
+            &format!("Could not write shard list {:?}: {:?}", list_path, e),
+        )
+    });
+    let mut command = Command::new(ffmpeg_path);
+    command.args(["-loglevel", "error", "-f", "concat", "-safe", "0", "-i"]);
+    command.arg(&list_path).args(["-c", "copy"]);
+    if extension == "mp4" || extension == "mov" {
+        command.args(["-movflags", "faststart"]);
+    }
+    let status = command
+        .arg("-y")
+        .arg(&output)
+        .stdin(Stdio::null())
+        .status()
+        .unwrap_or_else(|e| {
+            fail(
+                ErrorCode::FfmpegMissing,
+                &format!("Could not run {}: {:?}", ffmpeg_path, e),
+            )
+        });
+    let _ = std::fs::remove_file(&list_path);
+    if !status.success() {
+        fail(
+            ErrorCode::EncodeFailed,
+            &format!("ffmpeg failed with exit code {:?}", status.code()),
+        );
+    }
+
+    let metadata = metadata.map_or_else(|| output.with_extension("json"), Path::to_path_buf);
+    let merged = merge_metadata(
+        manifests
+            .into_iter()
+            .map(|(_, manifest)| manifest.metadata)
+            .collect(),
+    );
+    let file = File::create(&metadata).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Io,
+            &format!("Could not create {:?}: {:?}", metadata, e),
+        )
+    });
+    serde_json::to_writer(BufWriter::new(file), &merged).expect("Could not write merged metadata");
+    println!(
+        "Merged {} shards of {} frames into {} and {}",
+        shards,
+        merged.frames,
+        output.to_string_lossy(),
+        metadata.to_string_lossy()
+    );
+}
@@ -0,0 +1,388 @@
+use std::io::Read;
+
+use reqwest::{RequestBuilder, StatusCode};
+use serde_json::Value;
+
+use crate::errors::{fail, ErrorCode};
+use crate::options::CLI_OPTIONS;
+use crate::progress::{progress, progress_stage};
+use crate::{fit, http, read_gpx, throttle, GPXPoint, GPXWaypoint, ReadResult};
+
+const STRAVA_API_URL: &str = "https://www.strava.com/api/v3";
+const KOMOOT_API_URL: &str = "https://www.komoot.com/api/v007";
+const RIDEWITHGPS_URL: &str = "https://ridewithgps.com";
+const GARMIN_API_URL: &str = "https://connectapi.garmin.com";
+
+/// Send the request built by request to the API of service and return its body, failing if it is
+/// not a success. what describes the requested resource in errors.
+async fn get(service: &str, what: &str, request: impl Fn() -> RequestBuilder) -> Vec<u8> {
+    let (status, body) = throttle::send(request).await.unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Network,
+            &format!("Could not reach {} for {}: {:?}", service, what, e),
+        )
+    });
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => fail(
+            ErrorCode::ApiAuth,
+            &format!("{} refused access to {} ({})", service, what, status),
+        ),
+        StatusCode::NOT_FOUND => fail(
+            ErrorCode::InputInvalid,
+            &format!("{} has no {}", service, what),
+        ),
+        status if !status.is_success() => fail(
+            ErrorCode::Network,
+            &format!("Error code from {} for {}: {}", service, what, status),
+        ),
+        _ => (),
+    }
+    body
+}
+
+/// Send the request built by request like get and parse its JSON response, with its size.
+async fn get_json(service: &str, what: &str, request: impl Fn() -> RequestBuilder) -> (Value, u64) {
+    let body = get(service, what, request).await;
+    let json = serde_json::from_slice(&body).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Network,
+            &format!("Invalid response from {} for {}: {}", service, what, e),
+        )
+    });
+    (json, body.len() as u64)
+}
+
+/// Route of the Strava activity with id, from its latlng, altitude and time streams.
+async fn strava_route(id: &str) -> ReadResult {
+    let token = CLI_OPTIONS.strava_token.as_deref().unwrap_or_else(|| {
+        fail(
+            ErrorCode::ApiAuth,
+            "--strava-token is required to read a Strava activity",
+        )
+    });
+    let client = http::client();
+    let what = format!("activity {}", id);
+    let activity_url = format!("{}/activities/{}", STRAVA_API_URL, id);
+    let streams_url = format!(
+        "{}/streams?keys=latlng,altitude,time&key_by_type=true",
+        activity_url
+    );
+    let (activity, activity_size) = get_json("Strava", &what, || {
+        client.get(&activity_url).bearer_auth(token)
+    })
+    .await;
+    let (streams, streams_size) = get_json("Strava", &what, || {
+        client.get(&streams_url).bearer_auth(token)
+    })
+    .await;
+    let start = activity["start_date"]
+        .as_str()
+        .and_then(|date| chrono::DateTime::parse_from_rfc3339(date).ok())
+        .map(|date| date.timestamp() as f64);
+    let data = |key: &str| streams[key]["data"].as_array().cloned().unwrap_or_default();
+    let (altitudes, times) = (data("altitude"), data("time"));
+    let points = data("latlng")
+        .iter()
+        .enumerate()
+        .filter_map(|(i, latlng)| {
+            Some(GPXPoint {
+                lat: latlng[0].as_f64()?,
+                lng: latlng[1].as_f64()?,
+                ele: altitudes.get(i).and_then(Value::as_f64),
+                time: start
+                    .zip(times.get(i).and_then(Value::as_f64))
+                    .map(|(start, offset)| start + offset),
+            })
+        })
+        .collect::<Vec<_>>();
+    if points.is_empty() {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!("Strava {} has no GPS points", what),
+        );
+    }
+    ReadResult {
+        points,
+        waypoints: vec![],
+        name: activity["name"].as_str().map(str::to_string),
+        size: activity_size + streams_size,
+    }
+}
+
+/// Id and share token of a Komoot tour given as its id or the URL of its page or share link,
+/// like https://www.komoot.com/tour/123456?share_token=abc.
+fn parse_komoot_tour(tour: &str) -> (String, Option<String>) {
+    let url = match reqwest::Url::parse(tour) {
+        Ok(url) => url,
+        Err(_) => return (tour.to_string(), None),
+    };
+    let mut segments = url.path_segments().into_iter().flatten();
+    let id = segments
+        .find(|segment| *segment == "tour")
+        .and_then(|_| segments.next())
+        .filter(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()))
+        .unwrap_or_else(|| {
+            fail(
+                ErrorCode::InputInvalid,
+                &format!("{} is not the URL of a Komoot tour", tour),
+            )
+        })
+        .to_string();
+    let share_token = url
+        .query_pairs()
+        .find(|(name, _)| name == "share_token")
+        .map(|(_, token)| token.into_owned());
+    (id, share_token)
+}
+
+/// Route of the Komoot tour given by the komoot_tour option, from its coordinates.
+/// Private tours need the share token of their share link.
+async fn komoot_route(tour: &str) -> ReadResult {
+    let (id, url_token) = parse_komoot_tour(tour);
+    let share_token = CLI_OPTIONS.komoot_token.clone().or(url_token);
+    let client = http::client();
+    let what = format!("tour {}", id);
+    let request = |url: &str| {
+        let request = client.get(url);
+        match &share_token {
+            Some(token) => request.query(&[("share_token", token)]),
+            None => request,
+        }
+    };
+    let tour_url = format!("{}/tours/{}", KOMOOT_API_URL, id);
+    let coordinates_url = format!("{}/coordinates", tour_url);
+    let (tour, tour_size) = get_json("Komoot", &what, || request(&tour_url)).await;
+    let (coordinates, coordinates_size) =
+        get_json("Komoot", &what, || request(&coordinates_url)).await;
+    let start = tour["date"]
+        .as_str()
+        .and_then(|date| chrono::DateTime::parse_from_rfc3339(date).ok())
+        .map(|date| date.timestamp() as f64);
+    let points = coordinates["items"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|item| {
+            Some(GPXPoint {
+                lat: item["lat"].as_f64()?,
+                lng: item["lng"].as_f64()?,
+                ele: item["alt"].as_f64(),
+                // t is in milliseconds since the start of the tour.
+                time: start
+                    .zip(item["t"].as_f64())
+                    .map(|(start, offset)| start + offset / 1000.0),
+            })
+        })
+        .collect::<Vec<_>>();
+    if points.is_empty() {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!("Komoot {} has no coordinates", what),
+        );
+    }
+    ReadResult {
+        points,
+        waypoints: vec![],
+        name: tour["name"].as_str().map(str::to_string),
+        size: tour_size + coordinates_size,
+    }
+}
+
+/// Kind (routes or trips) and id of a RideWithGPS route or trip given as the URL of its page,
+/// like https://ridewithgps.com/routes/123456.
+fn parse_ridewithgps_url(url: &str) -> (String, String) {
+    let parsed = reqwest::Url::parse(url).ok();
+    let segments = parsed
+        .as_ref()
+        .and_then(|url| url.path_segments())
+        .map(|segments| segments.collect::<Vec<_>>())
+        .unwrap_or_default();
+    match segments.as_slice() {
+        [kind, id, ..]
+            if (*kind == "routes" || *kind == "trips")
+                && !id.is_empty()
+                && id.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            (kind.to_string(), id.to_string())
+        }
+        _ => fail(
+            ErrorCode::InputInvalid,
+            &format!("{} is not the URL of a RideWithGPS route or trip", url),
+        ),
+    }
+}
+
+/// Route of the RideWithGPS route or trip at url, from the track points of its JSON export.
+/// Its points of interest become waypoints. Private ones need an API key and auth token.
+async fn ridewithgps_route(url: &str) -> ReadResult {
+    let (kind, id) = parse_ridewithgps_url(url);
+    let client = http::client();
+    let what = format!("{} {}", kind.trim_end_matches('s'), id);
+    let export_url = format!("{}/{}/{}.json", RIDEWITHGPS_URL, kind, id);
+    let auth = [
+        ("apikey", CLI_OPTIONS.ridewithgps_api_key.as_ref()),
+        ("auth_token", CLI_OPTIONS.ridewithgps_token.as_ref()),
+    ];
+    let auth = auth
+        .iter()
+        .filter_map(|(name, value)| Some((*name, (*value)?)))
+        .collect::<Vec<_>>();
+    let (export, size) = get_json("RideWithGPS", &what, || {
+        client.get(&export_url).query(&auth)
+    })
+    .await;
+    // Newer exports nest the route or trip under its kind.
+    let track = ["route", "trip"]
+        .iter()
+        .map(|key| &export[key])
+        .find(|track| track.is_object())
+        .unwrap_or(&export);
+    let points = track["track_points"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|point| {
+            Some(GPXPoint {
+                lat: point["y"].as_f64()?,
+                lng: point["x"].as_f64()?,
+                ele: point["e"].as_f64(),
+                time: point["t"].as_f64(),
+            })
+        })
+        .collect::<Vec<_>>();
+    if points.is_empty() {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!("RideWithGPS {} has no track points", what),
+        );
+    }
+    let waypoints = track["points_of_interest"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|poi| {
+            Some(GPXWaypoint {
+                name: poi["name"].as_str()?.to_string(),
+                lat: poi["lat"].as_f64()?,
+                lng: poi["lng"].as_f64()?,
+            })
+        })
+        .collect();
+    ReadResult {
+        points,
+        waypoints,
+        name: track["name"].as_str().map(str::to_string),
+        size,
+    }
+}
+
+/// The FIT file in the zip archive of the original files of a Garmin Connect activity.
+fn garmin_fit_file(archive: &[u8], what: &str) -> Vec<u8> {
+    let invalid = |reason: &str| -> ! {
+        fail(
+            ErrorCode::Network,
+            &format!(
+                "Invalid FIT archive from Garmin Connect for {}: {}",
+                what, reason
+            ),
+        )
+    };
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(archive))
+        .unwrap_or_else(|e| invalid(&e.to_string()));
+    for index in 0..archive.len() {
+        let mut file = archive
+            .by_index(index)
+            .unwrap_or_else(|e| invalid(&e.to_string()));
+        if file.name().to_lowercase().ends_with(".fit") {
+            let mut contents = vec![];
+            file.read_to_end(&mut contents)
+                .unwrap_or_else(|e| invalid(&e.to_string()));
+            return contents;
+        }
+    }
+    invalid("no .fit file")
+}
+
+/// Route of the Garmin Connect activity with id, from its GPX export, or from its original FIT
+/// file with the garmin_fit option.
+async fn garmin_route(id: &str) -> ReadResult {
+    let token = CLI_OPTIONS.garmin_token.as_deref().unwrap_or_else(|| {
+        fail(
+            ErrorCode::ApiAuth,
+            "--garmin-token is required to read a Garmin Connect activity",
+        )
+    });
+    let client = http::client();
+    let what = format!("activity {}", id);
+    if CLI_OPTIONS.garmin_fit {
+        let url = format!("{}/download-service/files/activity/{}", GARMIN_API_URL, id);
+        let archive = get("Garmin Connect", &what, || {
+            client.get(&url).bearer_auth(token)
+        })
+        .await;
+        let fit = garmin_fit_file(&archive, &what);
+        let points = fit::read_track(&fit)
+            .filter(|points| !points.is_empty())
+            .unwrap_or_else(|| {
+                fail(
+                    ErrorCode::InputInvalid,
+                    &format!("The FIT file of Garmin Connect {} has no positions", what),
+                )
+            });
+        return ReadResult {
+            points,
+            waypoints: vec![],
+            name: None,
+            size: archive.len() as u64,
+        };
+    }
+    let url = format!(
+        "{}/download-service/export/gpx/activity/{}",
+        GARMIN_API_URL, id
+    );
+    let gpx = get("Garmin Connect", &what, || {
+        client.get(&url).bearer_auth(token)
+    })
+    .await;
+    let size = gpx.len() as u64;
+    ReadResult {
+        size,
+        ..read_gpx(gpx.as_slice())
+    }
+}
+
+/// Whether the options chose a remote route instead of the input path.
+pub fn is_enabled() -> bool {
+    CLI_OPTIONS.strava_activity.is_some()
+        || CLI_OPTIONS.komoot_tour.is_some()
+        || CLI_OPTIONS.ridewithgps.is_some()
+        || CLI_OPTIONS.garmin_activity.is_some()
+}
+
+/// Download the route chosen by the options, if is_enabled.
+pub async fn read_route() -> ReadResult {
+    progress_stage("Downloading route");
+    let (route, source) = if let Some(id) = &CLI_OPTIONS.strava_activity {
+        (strava_route(id).await, format!("Strava activity {}", id))
+    } else if let Some(tour) = &CLI_OPTIONS.komoot_tour {
+        (komoot_route(tour).await, format!("Komoot tour {}", tour))
+    } else if let Some(url) = &CLI_OPTIONS.ridewithgps {
+        (ridewithgps_route(url).await, url.clone())
+    } else if let Some(id) = &CLI_OPTIONS.garmin_activity {
+        (
+            garmin_route(id).await,
+            format!("Garmin Connect activity {}", id),
+        )
+    } else {
+        panic!("No remote route to read");
+    };
+    progress(&format!(
+        "Downloaded {} points of {}",
+        route.points.len(),
+        source
+    ));
+    route
+}
@@ -0,0 +1,128 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+use crate::options::CLI_OPTIONS;
+use crate::progress::progress;
+use crate::validate::{validate_frame, FrameStatus};
+use crate::{image_size, mock, SerializablePointBearing};
+
+/// Width of the heading buckets in degrees. Frames of the same panorama whose headings fall in
+/// one bucket look the same, so they share a stored frame.
+const HEADING_BUCKET_DEGREES: f64 = 2.0;
+
+/// Directory of the frame store: the frame_store option. None if it is not set or frames are
+/// mocked.
+fn store_dir() -> Option<PathBuf> {
+    if mock::is_enabled() {
+        return None;
+    }
+    CLI_OPTIONS.frame_store.clone()
+}
+
+/// Path in the store of the frame of point, keyed by its panorama, heading bucket and image size.
+/// None for points without a known panorama, whose frames may change between runs.
+fn stored_path(point: &SerializablePointBearing) -> Option<PathBuf> {
+    let pano_id = point.panoId.as_ref()?;
+    let dir = store_dir()?;
+    let buckets = (360.0 / HEADING_BUCKET_DEGREES).round() as i64;
+    let bucket =
+        (point.bearing.rem_euclid(360.0) / HEADING_BUCKET_DEGREES).round() as i64 % buckets;
+    let (width, height) = image_size();
+    let key = format!("{}|{}|{}x{}", pano_id, bucket, width, height);
+    let hash = hex::encode(Sha256::digest(key.as_bytes()));
+    Some(dir.join(&hash[..2]).join(format!("{}.jpg", hash)))
+}
+
+/// Hard link from to to, or copy it if they are on different file systems.
+fn link_or_copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::hard_link(from, to).or_else(|_| std::fs::copy(from, to).map(|_| ()))
+}
+
+/// Put the stored frame of point at path, if the store has it. Return whether it did.
+pub fn link(point: &SerializablePointBearing, path: &Path) -> bool {
+    let stored = match stored_path(point) {
+        Some(stored) if stored.is_file() => stored,
+        _ => return false,
+    };
+    let _ = std::fs::remove_file(path);
+    // Mark the frame as used, so prune keeps it over frames no run has needed for longer.
+    let _ = std::fs::File::options()
+        .write(true)
+        .open(&stored)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    link_or_copy(&stored, path).is_ok()
+}
+
+/// Add the frame of point at path to the store, unless it is already there or is not a valid
+/// frame. Failures only lose the chance to reuse the frame, so they are ignored.
+pub fn save(point: &SerializablePointBearing, path: &Path) {
+    let stored = match stored_path(point) {
+        Some(stored) if !stored.exists() => stored,
+        _ => return,
+    };
+    if validate_frame(path) != FrameStatus::Ok {
+        return;
+    }
+    if let Some(dir) = stored.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let _ = link_or_copy(path, &stored);
+}
+
+/// Replace each frame {index}.jpg in image_dir by a copy of itself, so that changing it in place
+/// does not change the stored frame it may be a hard link to.
+pub fn detach_frames(image_dir: &Path, n_frames: usize) {
+    if store_dir().is_none() {
+        return;
+    }
+    for index in 0..n_frames {
+        let path = image_dir.join(format!("{}.jpg", index));
+        if let Ok(bytes) = std::fs::read(&path) {
+            let _ = std::fs::remove_file(&path);
+            let _ = std::fs::write(&path, bytes);
+        }
+    }
+}
+
+/// Remove the least recently used frames of the store until it is at most frame_store_max_mb
+/// megabytes, if that option is set.
+pub fn prune() {
+    let (dir, max_mb) = match (store_dir(), CLI_OPTIONS.frame_store_max_mb) {
+        (Some(dir), Some(max_mb)) => (dir, max_mb),
+        _ => return,
+    };
+    let mut frames = std::fs::read_dir(&dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|bucket| std::fs::read_dir(bucket.path()).ok())
+        .flatten()
+        .flatten()
+        .filter_map(|frame| {
+            let metadata = frame.metadata().ok()?;
+            Some((metadata.modified().ok()?, metadata.len(), frame.path()))
+        })
+        .collect::<Vec<_>>();
+    let mut size = frames.iter().map(|(_, len, _)| len).sum::<u64>();
+    let max_size = (max_mb * 1e6) as u64;
+    if size <= max_size {
+        return;
+    }
+    frames.sort();
+    let mut removed = 0;
+    for (_, len, path) in frames {
+        if size <= max_size {
+            break;
+        }
+        if std::fs::remove_file(&path).is_ok() {
+            size -= len;
+            removed += 1;
+        }
+    }
+    progress(&format!(
+        "Removed {} frames from the frame store to keep it under {} MB",
+        removed, max_mb
+    ));
+}
@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+
+use serde_json::{json, Value};
+
+use crate::export::find_gaps;
+use crate::throttle::network_totals;
+use crate::{cumulative_distances, MetadataResult};
+
+/// Fraction of the route distance that has frames, without the stretches of the gaps between
+/// frames that find_gaps reports.
+fn coverage(metadata_result: &MetadataResult) -> f64 {
+    let points = &metadata_result.gpsPoints;
+    if metadata_result.distance <= 0.0 || points.is_empty() {
+        return 0.0;
+    }
+    let distances = cumulative_distances(points);
+    let gap_meters = find_gaps(points)
+        .into_iter()
+        .map(|(index, _)| distances[index + 1] - distances[index])
+        .sum::<f64>();
+    let covered = distances[distances.len() - 1] - gap_meters;
+    (covered / metadata_result.distance).clamp(0.0, 1.0)
+}
+
+/// The value at fraction of the way through sorted values, which must not be empty.
+fn percentile(sorted: &[f64], fraction: f64) -> f64 {
+    let index = ((sorted.len() - 1) as f64 * fraction).round() as usize;
+    sorted[index]
+}
+
+/// Statistics of the run of metadata_result: the coverage of the route, the panoramas by
+/// capture year, the distance of panoramas from the route and the API requests sent.
+fn summary(metadata_result: &MetadataResult) -> Value {
+    let points = &metadata_result.gpsPoints;
+    let mut errors = points
+        .iter()
+        .filter_map(|point| point.error)
+        .collect::<Vec<_>>();
+    errors.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let (average_error, p95_error) = if errors.is_empty() {
+        (None, None)
+    } else {
+        (
+            Some(errors.iter().sum::<f64>() / errors.len() as f64),
+            Some(percentile(&errors, 0.95)),
+        )
+    };
+    let mut years = BTreeMap::new();
+    for point in points {
+        let year = point
+            .panoDate
+            .as_deref()
+            .and_then(|date| date.split('-').next())
+            .unwrap_or("unknown");
+        *years.entry(year.to_string()).or_insert(0) += 1;
+    }
+    let (requests, bytes) = network_totals();
+    json!({
+        "coveragePercent": (coverage(metadata_result) * 1000.0).round() / 10.0,
+        "panos": points.len(),
+        "panoYears": years,
+        "averageError": average_error,
+        "p95Error": p95_error,
+        "apiRequests": requests,
+        "bytesDownloaded": bytes,
+    })
+}
+
+/// Lines of summary for people to read.
+fn describe(summary: &Value) -> Vec<String> {
+    let meters = |value: &Value| {
+        value
+            .as_f64()
+            .map_or("unknown".to_string(), |meters| format!("{:.1} m", meters))
+    };
+    let years = summary["panoYears"]
+        .as_object()
+        .map(|years| {
+            years
+                .iter()
+                .map(|(year, count)| format!("{}: {}", year, count))
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default();
+    vec![
+        format!(
+            "Coverage: {}% of the route, {} panoramas",
+            summary["coveragePercent"], summary["panos"]
+        ),
+        format!("Panoramas by year: {}", years),
+        format!(
+            "Distance from the route: {} on average, {} at the 95th percentile",
+            meters(&summary["averageError"]),
+            meters(&summary["p95Error"])
+        ),
+        format!(
+            "API requests: {}, {:.2} MB downloaded",
+            summary["apiRequests"],
+            summary["bytesDownloaded"].as_f64().unwrap_or(0.0) / 1000000.0
+        ),
+    ]
+}
+
+/// Report the summary of the run of metadata_result.
+pub fn report(metadata_result: &MetadataResult) {
+    let summary = summary(metadata_result);
+    let lines = describe(&summary);
+    crate::progress::summary(summary, &lines);
+}
@@ -0,0 +1,409 @@
+use std::fmt;
+use std::io::{BufReader, Read};
+
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use serde::de::{Deserializer, SeqAccess, Visitor};
+
+use crate::errors::{fail, ErrorCode};
+use crate::options::CLI_OPTIONS;
+use crate::progress::{progress, warning};
+use crate::{GPXPoint, GPXWaypoint, ReadResult};
+
+/// Whether the takeout option asked to read the input path as Google location history.
+pub fn is_enabled() -> bool {
+    CLI_OPTIONS.takeout
+}
+
+/// Seconds since the unix epoch of the start of date, given as YYYY-MM-DD (in UTC) or RFC 3339.
+/// A date without a time is extended to its whole day when end is set.
+fn parse_bound(option: &str, date: &str, end: bool) -> f64 {
+    if let Ok(time) = DateTime::parse_from_rfc3339(date) {
+        return time.timestamp() as f64;
+    }
+    match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        Ok(day) => {
+            let midnight = day.and_hms_opt(0, 0, 0).unwrap();
+            let start = Utc.from_utc_datetime(&midnight).timestamp() as f64;
+            if end {
+                start + 86400.0
+            } else {
+                start
+            }
+        }
+        Err(_) => fail(
+            ErrorCode::InputInvalid,
+            &format!(
+                "Invalid {} {}, expected YYYY-MM-DD or an RFC 3339 time",
+                option, date
+            ),
+        ),
+    }
+}
+
+/// The takeout_from and takeout_to options as a range of seconds since the unix epoch, unbounded
+/// where not set.
+fn time_range() -> (f64, f64) {
+    let from = CLI_OPTIONS
+        .takeout_from
+        .as_deref()
+        .map_or(f64::NEG_INFINITY, |date| {
+            parse_bound("--takeout-from", date, false)
+        });
+    let to = CLI_OPTIONS
+        .takeout_to
+        .as_deref()
+        .map_or(f64::INFINITY, |date| {
+            parse_bound("--takeout-to", date, true)
+        });
+    (from, to)
+}
+
+/// Whether time is in the range of time_range. Points and places without a time are not.
+fn in_range((from, to): (f64, f64), time: Option<f64>) -> bool {
+    time.is_some_and(|time| time >= from && time < to)
+}
+
+/// Seconds since the unix epoch of a timestamp of location history: an RFC 3339 string or a
+/// string of milliseconds.
+fn parse_timestamp(timestamp: Option<&String>) -> Option<f64> {
+    let timestamp = timestamp?;
+    match DateTime::parse_from_rfc3339(timestamp) {
+        Ok(time) => Some(time.timestamp_millis() as f64 / 1000.0),
+        Err(_) => timestamp.parse::<f64>().ok().map(|millis| millis / 1000.0),
+    }
+}
+
+/// Coordinates of the "48.8566°, 2.3522°" strings of the on-device timeline export.
+fn degrees_point(value: Option<&String>) -> Option<(f64, f64)> {
+    let mut parts = value?
+        .split(',')
+        .map(|part| part.trim().trim_end_matches('°').parse::<f64>());
+    Some((parts.next()?.ok()?, parts.next()?.ok()?))
+}
+
+fn point((lat, lng): (f64, f64), time: Option<f64>, ele: Option<f64>) -> GPXPoint {
+    GPXPoint {
+        lat,
+        lng,
+        ele,
+        time,
+    }
+}
+
+/// A location of Records.json, in degrees times 10^7.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Location {
+    latitude_e7: Option<f64>,
+    longitude_e7: Option<f64>,
+    timestamp: Option<String>,
+    timestamp_ms: Option<String>,
+    altitude: Option<f64>,
+}
+
+impl Location {
+    fn point(&self) -> Option<GPXPoint> {
+        let coordinates = (self.latitude_e7? / 1e7, self.longitude_e7? / 1e7);
+        let time = parse_timestamp(self.timestamp.as_ref())
+            .or_else(|| parse_timestamp(self.timestamp_ms.as_ref()));
+        Some(point(coordinates, time, self.altitude))
+    }
+}
+
+/// The locations of Records.json in the time_range, read one at a time so that the years of
+/// history outside of it are never held in memory.
+fn locations_in_range<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<GPXPoint>, D::Error> {
+    struct Locations((f64, f64));
+
+    impl<'de> Visitor<'de> for Locations {
+        type Value = Vec<GPXPoint>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a list of locations")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<GPXPoint>, A::Error> {
+            let mut points = vec![];
+            while let Some(location) = seq.next_element::<Location>()? {
+                points.extend(
+                    location
+                        .point()
+                        .filter(|point| in_range(self.0, point.time)),
+                );
+            }
+            Ok(points)
+        }
+    }
+
+    deserializer.deserialize_seq(Locations(time_range()))
+}
+
+/// A point of a path of the semantic location history, in degrees times 10^7 under either name.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PathPoint {
+    lat_e7: Option<f64>,
+    lng_e7: Option<f64>,
+    latitude_e7: Option<f64>,
+    longitude_e7: Option<f64>,
+    timestamp: Option<String>,
+    timestamp_ms: Option<String>,
+}
+
+impl PathPoint {
+    fn coordinates(&self) -> Option<(f64, f64)> {
+        let lat = self.lat_e7.or(self.latitude_e7)?;
+        let lng = self.lng_e7.or(self.longitude_e7)?;
+        Some((lat / 1e7, lng / 1e7))
+    }
+
+    fn time(&self) -> Option<f64> {
+        parse_timestamp(self.timestamp.as_ref())
+            .or_else(|| parse_timestamp(self.timestamp_ms.as_ref()))
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct Path {
+    /// Points of the raw path, or the waypoints of the waypoint path.
+    #[serde(default, alias = "waypoints")]
+    points: Vec<PathPoint>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Duration {
+    start_timestamp: Option<String>,
+    start_timestamp_ms: Option<String>,
+    end_timestamp: Option<String>,
+    end_timestamp_ms: Option<String>,
+}
+
+impl Duration {
+    fn start(&self) -> Option<f64> {
+        parse_timestamp(self.start_timestamp.as_ref())
+            .or_else(|| parse_timestamp(self.start_timestamp_ms.as_ref()))
+    }
+
+    fn end(&self) -> Option<f64> {
+        parse_timestamp(self.end_timestamp.as_ref())
+            .or_else(|| parse_timestamp(self.end_timestamp_ms.as_ref()))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ActivitySegment {
+    #[serde(default)]
+    simplified_raw_path: Path,
+    #[serde(default)]
+    waypoint_path: Path,
+    duration: Option<Duration>,
+}
+
+impl ActivitySegment {
+    /// Points of the raw path of the segment, or without one, of its waypoint path. Waypoints
+    /// have no time of their own, so they are spread evenly over the duration of the segment,
+    /// or left without a time if it has none.
+    fn points(&self) -> Vec<GPXPoint> {
+        if !self.simplified_raw_path.points.is_empty() {
+            return self
+                .simplified_raw_path
+                .points
+                .iter()
+                .filter_map(|path_point| {
+                    Some(point(path_point.coordinates()?, path_point.time(), None))
+                })
+                .collect();
+        }
+        let waypoints = &self.waypoint_path.points;
+        let duration = self.duration.as_ref();
+        let span = duration.and_then(|duration| Some((duration.start()?, duration.end()?)));
+        waypoints
+            .iter()
+            .enumerate()
+            .filter_map(|(index, waypoint)| {
+                let time = waypoint.time().or_else(|| {
+                    let (start, end) = span?;
+                    let fraction = index as f64 / (waypoints.len() - 1).max(1) as f64;
+                    Some(start + (end - start) * fraction)
+                });
+                Some(point(waypoint.coordinates()?, time, None))
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaceLocation {
+    latitude_e7: Option<f64>,
+    longitude_e7: Option<f64>,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PlaceVisit {
+    location: PlaceLocation,
+    duration: Option<Duration>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TimelineObject {
+    activity_segment: Option<ActivitySegment>,
+    place_visit: Option<PlaceVisit>,
+}
+
+#[derive(Deserialize)]
+struct TimelinePoint {
+    point: Option<String>,
+    time: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LatLng {
+    lat_lng: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Candidate {
+    semantic_type: Option<String>,
+    place_location: Option<LatLng>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Visit {
+    top_candidate: Option<Candidate>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SemanticSegment {
+    start_time: Option<String>,
+    #[serde(default)]
+    timeline_path: Vec<TimelinePoint>,
+    visit: Option<Visit>,
+}
+
+/// Any of the location history formats: Records.json, the monthly semantic location history
+/// files with timelineObjects, and the on-device timeline export with semanticSegments.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct History {
+    #[serde(default, deserialize_with = "locations_in_range")]
+    locations: Vec<GPXPoint>,
+    #[serde(default)]
+    timeline_objects: Vec<TimelineObject>,
+    #[serde(default)]
+    semantic_segments: Vec<SemanticSegment>,
+}
+
+/// Points and visited places of history, with the time of each place.
+fn read_history(history: History) -> (Vec<GPXPoint>, Vec<(GPXWaypoint, Option<f64>)>) {
+    let mut points = history.locations;
+    let mut places = vec![];
+    for object in history.timeline_objects {
+        if let Some(segment) = object.activity_segment {
+            points.extend(segment.points());
+        }
+        if let Some(visit) = object.place_visit {
+            let location = visit.location;
+            if let (Some(lat), Some(lng), Some(name)) =
+                (location.latitude_e7, location.longitude_e7, location.name)
+            {
+                let (lat, lng) = (lat / 1e7, lng / 1e7);
+                let time = visit.duration.and_then(|duration| duration.start());
+                places.push((GPXWaypoint { name, lat, lng }, time));
+            }
+        }
+    }
+    for segment in history.semantic_segments {
+        for path_point in &segment.timeline_path {
+            if let Some(coordinates) = degrees_point(path_point.point.as_ref()) {
+                points.push(point(
+                    coordinates,
+                    parse_timestamp(path_point.time.as_ref()),
+                    None,
+                ));
+            }
+        }
+        let candidate = segment.visit.and_then(|visit| visit.top_candidate);
+        if let Some(candidate) = candidate {
+            let lat_lng = candidate
+                .place_location
+                .and_then(|location| location.lat_lng);
+            if let Some((lat, lng)) = degrees_point(lat_lng.as_ref()) {
+                let name = candidate
+                    .semantic_type
+                    .unwrap_or_else(|| "Visit".to_string());
+                places.push((
+                    GPXWaypoint { name, lat, lng },
+                    parse_timestamp(segment.start_time.as_ref()),
+                ));
+            }
+        }
+    }
+    (points, places)
+}
+
+/// Read Google Takeout location history from reader, keeping the points and visited places
+/// between the takeout_from and takeout_to options, in order of time.
+pub fn read_takeout<R: Read>(reader: R) -> ReadResult {
+    let range = time_range();
+    if range.0.is_infinite() && range.1.is_infinite() {
+        warning(
+            "Using all of the location history, set --takeout-from and --takeout-to to pick a range",
+        );
+    }
+    let history: History = serde_json::from_reader(BufReader::new(reader)).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!("Could not parse location history: {}", e),
+        )
+    });
+    let (points, places) = read_history(history);
+    let untimed = points.iter().filter(|point| point.time.is_none()).count();
+    if untimed > 0 {
+        warning(&format!(
+            "Skipped {} points of location history without a time",
+            untimed
+        ));
+    }
+    let mut points = points
+        .into_iter()
+        .filter(|point| in_range(range, point.time))
+        .collect::<Vec<_>>();
+    points.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+    points.dedup_by(|a, b| a.lat == b.lat && a.lng == b.lng);
+    if points.is_empty() {
+        fail(
+            ErrorCode::InputInvalid,
+            "The location history has no points in the chosen time range",
+        );
+    }
+    let waypoints = places
+        .into_iter()
+        .filter(|(_, time)| in_range(range, *time))
+        .map(|(place, _)| place)
+        .collect();
+    progress(&format!("Read {} points of location history", points.len()));
+    let range = [&CLI_OPTIONS.takeout_from, &CLI_OPTIONS.takeout_to]
+        .iter()
+        .filter_map(|date| date.as_deref())
+        .collect::<Vec<_>>()
+        .join(" to ");
+    // Estimate each point is about 32 bytes, as for GPX files.
+    let size = (points.len() * 32) as u64;
+    ReadResult {
+        points,
+        waypoints,
+        name: Some(format!("Location history {}", range).trim_end().to_string()),
+        size,
+    }
+}
@@ -0,0 +1,38 @@
+use std::path::Path;
+
+use crate::ffmpeg::input_framerate;
+use crate::overlay::{write_srt, Cue};
+use crate::SerializablePointBearing;
+
+/// Telemetry text of one frame, in the bracketed key: value style of drone SRT logs,
+/// which telemetry overlay tools already know how to read.
+fn frame_telemetry(index: usize, point: &SerializablePointBearing) -> String {
+    let mut fields = vec![
+        format!("[latitude: {:.6}]", point.lat),
+        format!("[longitude: {:.6}]", point.lng),
+        format!("[heading: {:.1}]", point.bearing),
+    ];
+    if let Some(ele) = point.ele {
+        fields.push(format!("[abs_alt: {:.1}]", ele));
+    }
+    if let Some(time) = point.time {
+        fields.push(format!("[time: {:.3}]", time));
+    }
+    format!("FrameCnt: {}\n{}", index + 1, fields.join(" "))
+}
+
+/// Write a subtitle file with a cue for every frame giving the location it was taken at.
+/// The route starts offset seconds into the video.
+pub fn write_telemetry(path: &Path, points: &[SerializablePointBearing], offset: f64) {
+    let framerate = input_framerate(points.len());
+    let cues = points
+        .iter()
+        .enumerate()
+        .map(|(index, point)| Cue {
+            start: offset + index as f64 / framerate,
+            end: offset + (index + 1) as f64 / framerate,
+            text: frame_telemetry(index, point),
+        })
+        .collect::<Vec<_>>();
+    write_srt(path, &cues);
+}
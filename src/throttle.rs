@@ -0,0 +1,276 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use reqwest::{RequestBuilder, StatusCode};
+use tokio::sync::Semaphore;
+
+use crate::context;
+use crate::fixtures;
+use crate::options::CLI_OPTIONS;
+use crate::progress::{progress, throttled, warning};
+
+/// Pause after the first throttled response without a Retry-After header, doubled for each
+/// further one in a row up to MAX_BACKOFF_SECS.
+const INITIAL_BACKOFF_SECS: u64 = 5;
+const MAX_BACKOFF_SECS: u64 = 300;
+/// Times a request is retried after being throttled before its response is returned as is.
+const MAX_RETRIES: usize = 10;
+/// Times a request is retried after timing out before the timeout is returned.
+const MAX_TIMEOUT_RETRIES: usize = 5;
+/// Wait before the first retry of a failed fetch, doubled for each further attempt up to
+/// MAX_RETRY_BACKOFF_MS.
+const INITIAL_RETRY_BACKOFF_MS: u64 = 500;
+const MAX_RETRY_BACKOFF_MS: u64 = 30_000;
+
+/// Throttling state of the requests of a run, or of every job under a shared budget.
+pub struct Limits {
+    /// When every request may resume after the API throttled one of them, shared so the whole
+    /// request stream pauses instead of each request hammering the API on its own.
+    paused_until: Mutex<Option<Instant>>,
+    /// Number of throttled responses since the last successful one.
+    throttled_in_a_row: Mutex<u32>,
+    /// Tokens left in the max_qps bucket, which starts full, and when it was last refilled.
+    rate_bucket: Mutex<(f64, Instant)>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            paused_until: Mutex::new(None),
+            throttled_in_a_row: Mutex::new(0),
+            rate_bucket: Mutex::new((f64::INFINITY, Instant::now())),
+        }
+    }
+}
+
+/// Number of requests a run sent to the API, including retries, and bytes of their responses.
+#[derive(Default)]
+pub struct Totals {
+    requests_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+/// Limits of the jobs subcommand on the requests of all of its jobs together.
+struct SharedBudget {
+    /// One permit per request that may be in flight at once.
+    requests: Semaphore,
+    max_qps: Option<f64>,
+    limits: Limits,
+}
+
+static SHARED_BUDGET: OnceLock<SharedBudget> = OnceLock::new();
+
+/// Hold the requests of every run in this process to network_concurrency at once and, if set,
+/// max_qps per second, in place of the max_qps option of each run.
+pub fn share_budget(network_concurrency: usize, max_qps: Option<f64>) {
+    let budget = SharedBudget {
+        requests: Semaphore::new(network_concurrency),
+        max_qps,
+        limits: Limits::default(),
+    };
+    if SHARED_BUDGET.set(budget).is_err() {
+        panic!("The request budget was already shared");
+    }
+}
+
+/// Number of requests sent through send so far and the bytes of the responses to them.
+/// Replayed responses are not counted.
+pub fn network_totals() -> (u64, u64) {
+    let totals = &context::current().network;
+    (
+        totals.requests_sent.load(Ordering::Relaxed),
+        totals.bytes_received.load(Ordering::Relaxed),
+    )
+}
+
+/// Throttling state of the requests of the current run: that of the budget shared by jobs if
+/// any, so they pause together, or else the run's own.
+fn limits() -> &'static Limits {
+    match SHARED_BUDGET.get() {
+        Some(budget) => &budget.limits,
+        None => &context::current().limits,
+    }
+}
+
+/// Wait for a token from a bucket refilled at the max_qps option's rate and holding up to one
+/// second of requests, so bursts stay within the per-second rate. The max_qps of a budget shared
+/// by jobs fills one bucket for all of them, while that of each job fills its own.
+async fn wait_for_rate_limit() {
+    let (max_qps, bucket) = match SHARED_BUDGET.get() {
+        Some(SharedBudget {
+            max_qps: Some(max_qps),
+            limits,
+            ..
+        }) => (Some(*max_qps), &limits.rate_bucket),
+        _ => (CLI_OPTIONS.max_qps, &context::current().limits.rate_bucket),
+    };
+    let max_qps = match max_qps {
+        Some(max_qps) if max_qps > 0.0 => max_qps,
+        _ => return,
+    };
+    let wait = {
+        let mut bucket = bucket.lock().unwrap();
+        let (tokens, refilled_at) = *bucket;
+        let now = Instant::now();
+        let tokens = (tokens + max_qps * (now - refilled_at).as_secs_f64()).min(max_qps.max(1.0));
+        // Take the token now even if it is not there yet, so requests waiting at the same time
+        // queue up behind each other instead of all waking together.
+        *bucket = (tokens - 1.0, now);
+        if tokens >= 1.0 {
+            return;
+        }
+        Duration::from_secs_f64((1.0 - tokens) / max_qps)
+    };
+    tokio::time::delay_for(wait).await;
+}
+
+/// Random number in [0, 1), from the random keys std gives each hasher so no extra crate is needed.
+fn jitter() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(network_totals().0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Wait before retry number attempt, counting from 1, of a failed fetch: an exponential backoff
+/// with random jitter, so that failed requests are not all sent again at the same moment.
+pub async fn retry_backoff(attempt: usize) {
+    let max_ms = (INITIAL_RETRY_BACKOFF_MS << attempt.saturating_sub(1).min(16))
+        .min(MAX_RETRY_BACKOFF_MS) as f64;
+    let wait = Duration::from_millis((max_ms * (0.5 + jitter() / 2.0)) as u64);
+    tokio::time::delay_for(wait).await;
+}
+
+/// Wait until the pause set by the last throttled response is over.
+async fn wait_for_resume() {
+    loop {
+        let paused_until = *limits().paused_until.lock().unwrap();
+        match paused_until {
+            Some(until) if until > Instant::now() => {
+                tokio::time::delay_for(until - Instant::now()).await
+            }
+            _ => return,
+        }
+    }
+}
+
+/// Pause all requests for retry_after, or an exponential backoff if the API didn't say.
+fn pause(retry_after: Option<Duration>, reason: &str) {
+    let in_a_row = {
+        let mut in_a_row = limits().throttled_in_a_row.lock().unwrap();
+        *in_a_row += 1;
+        *in_a_row
+    };
+    let wait = retry_after.unwrap_or_else(|| {
+        Duration::from_secs((INITIAL_BACKOFF_SECS << (in_a_row - 1).min(16)).min(MAX_BACKOFF_SECS))
+    });
+    let now = Instant::now();
+    let mut paused_until = limits().paused_until.lock().unwrap();
+    // Other requests in flight are likely throttled too; only report the pause once.
+    if paused_until.is_none_or(|until| until <= now) {
+        throttled(reason, wait.as_secs());
+    }
+    if paused_until.is_none_or(|until| until < now + wait) {
+        *paused_until = Some(now + wait);
+    }
+}
+
+/// Mark a response that was not throttled, resetting the backoff.
+fn resumed() {
+    let mut in_a_row = limits().throttled_in_a_row.lock().unwrap();
+    if *in_a_row > 0 {
+        *in_a_row = 0;
+        progress("Resumed after throttling");
+    }
+}
+
+/// Seconds in the Retry-After header of a response.
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get("Retry-After")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Whether body is a JSON response of a Google API with status OVER_QUERY_LIMIT.
+fn is_over_query_limit(body: &[u8]) -> bool {
+    body.first() == Some(&b'{')
+        && serde_json::from_slice::<serde_json::Value>(body)
+            .is_ok_and(|value| value["status"] == "OVER_QUERY_LIMIT")
+}
+
+/// Send request and read its whole response, returning its status, Retry-After and body.
+async fn receive(
+    request: RequestBuilder,
+) -> reqwest::Result<(StatusCode, Option<Duration>, Vec<u8>)> {
+    let response = request.send().await?;
+    let status = response.status();
+    let wait = retry_after(&response);
+    Ok((status, wait, response.bytes().await?.to_vec()))
+}
+
+/// Send the request built by request, returning its status and body. When the API answers
+/// with HTTP 429 or OVER_QUERY_LIMIT, pause every request sent through this function, honoring
+/// Retry-After, and retry up to MAX_RETRIES times. Requests that time out, while sending or
+/// while reading the response, are retried up to MAX_TIMEOUT_RETRIES times after
+/// retry_backoff. Requests are also held to the max_qps option and the budget shared by jobs.
+///
+/// With the replay option the response comes from the fixture directory instead, and with the
+/// record option it is saved there.
+pub async fn send(request: impl Fn() -> RequestBuilder) -> reqwest::Result<(StatusCode, Vec<u8>)> {
+    if let Some(response) = fixtures::replay(request()) {
+        return Ok(response);
+    }
+    let mut retries = 0;
+    let mut timeouts = 0;
+    loop {
+        wait_for_resume().await;
+        wait_for_rate_limit().await;
+        let permit = match SHARED_BUDGET.get() {
+            Some(budget) => Some(budget.requests.acquire().await),
+            None => None,
+        };
+        let (status, wait, body) = match receive(request()).await {
+            Ok(response) => response,
+            Err(e) if e.is_timeout() && timeouts < MAX_TIMEOUT_RETRIES => {
+                warning(&format!(
+                    "Request timed out, retrying: {}",
+                    e.url().map_or("", |url| url.path())
+                ));
+                timeouts += 1;
+                // Free the shared budget for the requests of other jobs while this one waits.
+                drop(permit);
+                retry_backoff(timeouts).await;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let totals = &context::current().network;
+        totals.requests_sent.fetch_add(1, Ordering::Relaxed);
+        totals
+            .bytes_received
+            .fetch_add(body.len() as u64, Ordering::Relaxed);
+        if retries < MAX_RETRIES {
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                pause(wait, "HTTP 429 Too Many Requests");
+                retries += 1;
+                continue;
+            }
+            if is_over_query_limit(&body) {
+                pause(wait, "OVER_QUERY_LIMIT");
+                retries += 1;
+                continue;
+            }
+        }
+        resumed();
+        fixtures::record(request(), status, &body);
+        return Ok((status, body));
+    }
+}
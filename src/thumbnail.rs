@@ -0,0 +1,60 @@
+use std::path::Path;
+
+use rayon::prelude::*;
+
+use crate::errors::{fail, ErrorCode};
+use crate::options::CLI_OPTIONS;
+use crate::progress::progress;
+
+/// Colorfulness of an image by the metric of Hasler and Suesstrunk: the spread and mean of the
+/// red-green and yellow-blue opponent channels. Gray, washed out or dark frames score low.
+fn colorfulness(img: &image::RgbImage) -> f64 {
+    let count = (img.width() * img.height()).max(1) as f64;
+    let (mut sum_rg, mut sum_yb, mut sum_rg2, mut sum_yb2) = (0.0, 0.0, 0.0, 0.0);
+    for pixel in img.pixels() {
+        let (r, g, b) = (pixel[0] as f64, pixel[1] as f64, pixel[2] as f64);
+        let rg = r - g;
+        let yb = 0.5 * (r + g) - b;
+        sum_rg += rg;
+        sum_yb += yb;
+        sum_rg2 += rg * rg;
+        sum_yb2 += yb * yb;
+    }
+    let (mean_rg, mean_yb) = (sum_rg / count, sum_yb / count);
+    let var_rg = sum_rg2 / count - mean_rg * mean_rg;
+    let var_yb = sum_yb2 / count - mean_yb * mean_yb;
+    (var_rg + var_yb).max(0.0).sqrt() + 0.3 * (mean_rg * mean_rg + mean_yb * mean_yb).sqrt()
+}
+
+/// Index of the most colorful of the frames matching pattern (e.g. %d.jpg) in image_dir.
+fn most_scenic_frame(image_dir: &Path, pattern: &str, n_frames: usize) -> usize {
+    (0..n_frames)
+        .into_par_iter()
+        .filter_map(|index| {
+            let path = image_dir.join(pattern.replace("%d", &index.to_string()));
+            // Scoring a small version is much faster and ranks frames the same way.
+            let img = image::open(path).ok()?.thumbnail(160, 160).to_rgb8();
+            Some((index, colorfulness(&img)))
+        })
+        .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+        .map_or(0, |(index, _)| index)
+}
+
+/// Choose the frame for the thumbnail by the thumbnail_frame option (first|middle|scenic,
+/// default: middle) among the n_frames frames matching pattern in image_dir.
+pub fn thumbnail_frame(image_dir: &Path, pattern: &str, n_frames: usize) -> usize {
+    let index = match CLI_OPTIONS.thumbnail_frame.as_deref().unwrap_or("middle") {
+        "first" => 0,
+        "middle" => n_frames / 2,
+        "scenic" => most_scenic_frame(image_dir, pattern, n_frames),
+        other => fail(
+            ErrorCode::InputInvalid,
+            &format!(
+                "Unknown thumbnail frame {}, expected first, middle or scenic",
+                other
+            ),
+        ),
+    };
+    progress(&format!("Using frame {} as the thumbnail", index));
+    index
+}
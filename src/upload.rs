@@ -0,0 +1,444 @@
+use std::io::Read;
+use std::path::Path;
+
+use hmac::{Hmac, Mac};
+use reqwest::{Body, Client, Method};
+use sha2::{Digest, Sha256};
+
+use crate::errors::{fail, ErrorCode};
+use crate::http;
+use crate::options::CLI_OPTIONS;
+use crate::progress::{progress, progress_stage, warning, ProgressReporter, Unit};
+
+/// Size of each part of a multipart upload. S3 requires at least 5 MiB for all but the last.
+const PART_SIZE: usize = 16 * 1024 * 1024;
+/// Size of the chunks a file is streamed from disk in.
+const CHUNK_SIZE: usize = 256 * 1024;
+/// Seconds to wait for an upload request, which is much larger than an API request and so
+/// does not use the request_timeout option.
+const UPLOAD_TIMEOUT_SECS: u64 = 600;
+
+/// An S3 compatible object store and the credentials to sign its requests with.
+struct Store {
+    /// Base URL of the store, e.g. https://s3.us-east-1.amazonaws.com.
+    endpoint: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+/// Environment variable that must be set for an upload.
+fn required_env(name: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| {
+        fail(
+            ErrorCode::InputInvalid,
+            &format!("--upload needs the {} environment variable", name),
+        )
+    })
+}
+
+impl Store {
+    /// Store of the scheme of the upload URL: s3 with AWS_* credentials (and AWS_ENDPOINT_URL for
+    /// other S3 compatible stores), or gs with GCS HMAC keys through its S3 compatible XML API.
+    fn from_scheme(scheme: &str) -> Store {
+        match scheme {
+            "s3" => {
+                let region = std::env::var("AWS_REGION")
+                    .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+                    .unwrap_or_else(|_| "us-east-1".to_string());
+                Store {
+                    endpoint: std::env::var("AWS_ENDPOINT_URL")
+                        .unwrap_or_else(|_| format!("https://s3.{}.amazonaws.com", region)),
+                    region,
+                    access_key: required_env("AWS_ACCESS_KEY_ID"),
+                    secret_key: required_env("AWS_SECRET_ACCESS_KEY"),
+                    session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+                }
+            }
+            "gs" => Store {
+                endpoint: "https://storage.googleapis.com".to_string(),
+                region: "auto".to_string(),
+                access_key: required_env("GCS_HMAC_ACCESS_ID"),
+                secret_key: required_env("GCS_HMAC_SECRET"),
+                session_token: None,
+            },
+            other => fail(
+                ErrorCode::InputInvalid,
+                &format!("Unknown upload scheme {}, expected s3:// or gs://", other),
+            ),
+        }
+    }
+}
+
+/// Percent-encode value as SigV4 requires, keeping slashes if keep_slash.
+fn uri_encode(value: &str, keep_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            b'/' if keep_slash => "/".to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Body of an upload request.
+enum Payload {
+    /// Bytes in memory, signed with their hash.
+    Bytes(Vec<u8>),
+    /// An open file of the given size, streamed from disk as an unsigned payload so that it is
+    /// never read into memory whole.
+    File(std::fs::File, u64),
+}
+
+/// Stream of the chunks of file, read as the request sends them.
+fn file_chunks(
+    mut file: std::fs::File,
+) -> impl futures::Stream<Item = std::io::Result<Vec<u8>>> + Send + Sync {
+    let chunks = std::iter::from_fn(move || {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        match file.read(&mut chunk) {
+            Ok(0) => None,
+            Ok(len) => {
+                chunk.truncate(len);
+                Some(Ok(chunk))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    });
+    futures::stream::iter(chunks)
+}
+
+/// Send a request for the object at path (/bucket/key) signed with AWS signature version 4,
+/// and return its response or the message of why it failed.
+async fn send_signed(
+    client: &Client,
+    store: &Store,
+    method: Method,
+    path: &str,
+    query: &[(&str, String)],
+    payload: Payload,
+) -> Result<reqwest::Response, String> {
+    let now = chrono::Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let host = store
+        .endpoint
+        .split("://")
+        .nth(1)
+        .unwrap_or(&store.endpoint)
+        .trim_end_matches('/');
+    let (payload_hash, body, content_length) = match payload {
+        Payload::Bytes(bytes) => (hex::encode(Sha256::digest(&bytes)), Body::from(bytes), None),
+        Payload::File(file, size) => (
+            "UNSIGNED-PAYLOAD".to_string(),
+            Body::wrap_stream(file_chunks(file)),
+            Some(size),
+        ),
+    };
+    let canonical_uri = uri_encode(path, true);
+    let mut query = query
+        .iter()
+        .map(|(key, value)| (uri_encode(key, false), uri_encode(value, false)))
+        .collect::<Vec<_>>();
+    query.sort();
+    let canonical_query = query
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let mut headers = vec![
+        ("host", host.to_string()),
+        ("x-amz-content-sha256", payload_hash.clone()),
+        ("x-amz-date", amz_date.clone()),
+    ];
+    if let Some(token) = &store.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+    let canonical_headers = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect::<String>();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method, canonical_uri, canonical_query, canonical_headers, signed_headers, payload_hash
+    );
+    let scope = format!("{}/{}/s3/aws4_request", date, store.region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let signing_key = ["s3", "aws4_request"].iter().fold(
+        hmac_sha256(
+            &hmac_sha256(format!("AWS4{}", store.secret_key).as_bytes(), &date),
+            &store.region,
+        ),
+        |key, part| hmac_sha256(&key, part),
+    );
+    let signature = hex::encode(hmac_sha256(&signing_key, &string_to_sign));
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        store.access_key, scope, signed_headers, signature
+    );
+
+    let url = if canonical_query.is_empty() {
+        format!("{}{}", store.endpoint.trim_end_matches('/'), canonical_uri)
+    } else {
+        format!(
+            "{}{}?{}",
+            store.endpoint.trim_end_matches('/'),
+            canonical_uri,
+            canonical_query
+        )
+    };
+    let mut request = client
+        .request(method.clone(), &url)
+        .header("Authorization", authorization)
+        .timeout(std::time::Duration::from_secs(UPLOAD_TIMEOUT_SECS))
+        .body(body);
+    for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+        request = request.header(*name, value.as_str());
+    }
+    // Stores reject streamed bodies sent in chunks, so give them the length up front.
+    if let Some(content_length) = content_length {
+        request = request.header("Content-Length", content_length);
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|e| format!("Upload request {} {} failed: {:?}", method, path, e))?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        return Err(format!(
+            "Upload request {} {} failed with status {}: {}",
+            method, path, status, text
+        ));
+    }
+    Ok(response)
+}
+
+/// Text between the first <tag> and </tag> in xml.
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    Some(&xml[start..end])
+}
+
+/// Upload the file at file_path to path in parts, reporting progress after each part.
+/// If a part fails, the upload is aborted so the store doesn't keep (and bill) its parts.
+async fn upload_multipart(client: &Client, store: &Store, path: &str, file_path: &Path) {
+    let total_size = std::fs::metadata(file_path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    let created = send_signed(
+        client,
+        store,
+        Method::POST,
+        path,
+        &[("uploads", String::new())],
+        Payload::Bytes(vec![]),
+    )
+    .await
+    .unwrap_or_else(|e| fail(ErrorCode::Network, &e));
+    let text = created.text().await.unwrap_or_default();
+    let upload_id = xml_value(&text, "UploadId")
+        .unwrap_or_else(|| {
+            fail(
+                ErrorCode::Network,
+                &format!("No UploadId in multipart upload response: {}", text),
+            )
+        })
+        .to_string();
+
+    let uploaded = upload_parts(client, store, path, file_path, &upload_id, total_size).await;
+    if let Err((code, message)) = uploaded {
+        let aborted = send_signed(
+            client,
+            store,
+            Method::DELETE,
+            path,
+            &[("uploadId", upload_id)],
+            Payload::Bytes(vec![]),
+        )
+        .await;
+        if let Err(e) = aborted {
+            warning(&format!("Could not abort the multipart upload: {}", e));
+        }
+        fail(code, &message);
+    }
+}
+
+/// Upload the file at file_path as the parts of the multipart upload with upload_id, then
+/// complete it. Return the code and message of the first failure, if any.
+async fn upload_parts(
+    client: &Client,
+    store: &Store,
+    path: &str,
+    file_path: &Path,
+    upload_id: &str,
+    total_size: u64,
+) -> Result<(), (ErrorCode, String)> {
+    let mut file = std::fs::File::open(file_path).map_err(|e| {
+        (
+            ErrorCode::Io,
+            format!("Could not open {:?} for upload: {:?}", file_path, e),
+        )
+    })?;
+    let mut etags = vec![];
+    let mut uploaded = 0;
+    let mut reporter = ProgressReporter::new(Unit::Megabytes, (total_size / 1000000) as usize);
+    loop {
+        let mut part = Vec::with_capacity(PART_SIZE);
+        (&mut file)
+            .take(PART_SIZE as u64)
+            .read_to_end(&mut part)
+            .map_err(|e| {
+                (
+                    ErrorCode::Io,
+                    format!("Could not read {:?} for upload: {:?}", file_path, e),
+                )
+            })?;
+        if part.is_empty() {
+            break;
+        }
+        let part_number = etags.len() + 1;
+        let part_len = part.len();
+        let response = send_signed(
+            client,
+            store,
+            Method::PUT,
+            path,
+            &[
+                ("partNumber", part_number.to_string()),
+                ("uploadId", upload_id.to_string()),
+            ],
+            Payload::Bytes(part),
+        )
+        .await
+        .map_err(|e| (ErrorCode::Network, e))?;
+        let etag = response
+            .headers()
+            .get("ETag")
+            .and_then(|etag| etag.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        etags.push(etag);
+        uploaded += part_len as u64;
+        reporter.set_percent(
+            (uploaded / 1000000) as usize,
+            100.0 * uploaded as f64 / total_size.max(1) as f64,
+        );
+    }
+
+    let parts = etags
+        .iter()
+        .enumerate()
+        .map(|(index, etag)| {
+            format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                index + 1,
+                etag
+            )
+        })
+        .collect::<String>();
+    let body = format!(
+        "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+        parts
+    );
+    send_signed(
+        client,
+        store,
+        Method::POST,
+        path,
+        &[("uploadId", upload_id.to_string())],
+        Payload::Bytes(body.into_bytes()),
+    )
+    .await
+    .map_err(|e| (ErrorCode::Network, e))?;
+    Ok(())
+}
+
+/// Upload the output video in output_dir to the upload option's URL (s3://bucket/key or
+/// gs://bucket/key, where a key ending in / gets the output filename appended).
+/// Files larger than PART_SIZE are sent with a multipart upload.
+#[tracing::instrument(skip_all)]
+pub async fn upload_output(output_dir: &Path) {
+    let url = match &CLI_OPTIONS.upload {
+        Some(url) => url,
+        None => return,
+    };
+    let filename = crate::output_filename();
+    let file_path = output_dir.join(&filename);
+    if !file_path.is_file() {
+        warning(&format!(
+            "No output file {:?} to upload, skipping --upload",
+            file_path
+        ));
+        return;
+    }
+    let (scheme, location) = match url.split_once("://") {
+        Some(parts) => parts,
+        None => fail(
+            ErrorCode::InputInvalid,
+            &format!("Invalid upload URL {}, expected s3://bucket/key", url),
+        ),
+    };
+    let (bucket, key) = location.split_once('/').unwrap_or((location, ""));
+    let key = if key.is_empty() || key.ends_with('/') {
+        let name = Path::new(&filename)
+            .file_name()
+            .map_or(filename.clone(), |name| name.to_string_lossy().to_string());
+        format!("{}{}", key, name)
+    } else {
+        key.to_string()
+    };
+    let store = Store::from_scheme(scheme);
+    let path = format!("/{}/{}", bucket, key);
+    let size = std::fs::metadata(&file_path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    progress_stage(&format!(
+        "Uploading output to {}://{}/{}",
+        scheme, bucket, key
+    ));
+    let client = http::client();
+    if size as usize <= PART_SIZE {
+        let file = std::fs::File::open(&file_path).unwrap_or_else(|e| {
+            fail(
+                ErrorCode::Io,
+                &format!("Could not open {:?} for upload: {:?}", file_path, e),
+            )
+        });
+        let payload = Payload::File(file, size);
+        send_signed(&client, &store, Method::PUT, &path, &[], payload)
+            .await
+            .unwrap_or_else(|e| fail(ErrorCode::Network, &e));
+    } else {
+        upload_multipart(&client, &store, &path, &file_path).await;
+    }
+    progress(&format!(
+        "Uploaded {:.2} MB to {}://{}/{}",
+        size as f64 / 1000000.0,
+        scheme,
+        bucket,
+        key
+    ));
+}
@@ -0,0 +1,208 @@
+use std::path::Path;
+
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use rayon::prelude::*;
+
+use crate::dedup::keep_frames;
+use crate::errors::{fail, ErrorCode};
+use crate::options::CLI_OPTIONS;
+use crate::policy::MissingPolicy;
+use crate::progress::{error, progress, progress_stage};
+use crate::throttle::retry_backoff;
+use crate::{get_images_by_index, SerializablePointBearing};
+
+/// Share of pixels that must be close to the median brightness to call a frame a placeholder.
+const PLACEHOLDER_UNIFORM_RATIO: f64 = 0.95;
+/// Brightness distance from the median that still counts as "close".
+const PLACEHOLDER_LUMA_TOLERANCE: i32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameStatus {
+    Ok,
+    /// The frame was a valid PNG and has been rewritten as a JPG.
+    Converted,
+    /// The frame decodes but looks like the gray "no imagery" placeholder.
+    Placeholder,
+    /// The frame is missing or could not be decoded (e.g. an error message body).
+    Invalid,
+}
+
+/// Whether almost every pixel of the image has the same brightness,
+/// which is how the "Sorry, we have no imagery here" frames look.
+fn is_placeholder(img: &DynamicImage) -> bool {
+    let luma = img.thumbnail(64, 64).to_luma8();
+    let mut values = luma.pixels().map(|p| p[0] as i32).collect::<Vec<_>>();
+    if values.is_empty() {
+        return true;
+    }
+    values.sort_unstable();
+    let median = values[values.len() / 2];
+    let uniform = values
+        .iter()
+        .filter(|&&v| (v - median).abs() <= PLACEHOLDER_LUMA_TOLERANCE)
+        .count();
+    uniform as f64 / values.len() as f64 > PLACEHOLDER_UNIFORM_RATIO
+}
+
+/// Check the magic bytes of a downloaded frame and decode it.
+/// Return its status and, for PNG frames, the frame converted to JPG so the jpg sequence stays
+/// intact.
+pub fn validate_frame_bytes(bytes: &[u8]) -> (FrameStatus, Option<Vec<u8>>) {
+    let format = match image::guess_format(bytes) {
+        Ok(format @ ImageFormat::Jpeg) | Ok(format @ ImageFormat::Png) => format,
+        _ => return (FrameStatus::Invalid, None),
+    };
+    let img = match image::load_from_memory_with_format(bytes, format) {
+        Ok(img) => img,
+        Err(_) => return (FrameStatus::Invalid, None),
+    };
+    if is_placeholder(&img) {
+        return (FrameStatus::Placeholder, None);
+    }
+    if format == ImageFormat::Png {
+        let mut jpg = vec![];
+        return match img.write_to(&mut jpg, ImageOutputFormat::Jpeg(75)) {
+            Ok(_) => (FrameStatus::Converted, Some(jpg)),
+            Err(_) => (FrameStatus::Invalid, None),
+        };
+    }
+    (FrameStatus::Ok, None)
+}
+
+/// Check the magic bytes of the frame at path and decode it.
+/// PNG frames are converted to JPG in place so the jpg sequence stays intact.
+pub fn validate_frame(path: &Path) -> FrameStatus {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(_) => return FrameStatus::Invalid,
+    };
+    match validate_frame_bytes(&bytes) {
+        (FrameStatus::Converted, Some(jpg)) => match std::fs::write(path, jpg) {
+            Ok(_) => FrameStatus::Converted,
+            Err(_) => FrameStatus::Invalid,
+        },
+        (status, _) => status,
+    }
+}
+
+/// Whether the frame of point can only be Google's "no imagery" placeholder: the point has no
+/// panorama and was kept by --on-missing placeholder, so refetching its frame can't help.
+pub fn expects_placeholder(point: &SerializablePointBearing) -> bool {
+    point.panoId.is_none() && MissingPolicy::from_options() == MissingPolicy::Placeholder
+}
+
+fn is_bad(status: FrameStatus) -> bool {
+    status == FrameStatus::Invalid || status == FrameStatus::Placeholder
+}
+
+/// Validate the frames {index}.jpg in image_dir at the given indices.
+/// Return the index and status of every bad frame.
+fn find_bad_frames(image_dir: &Path, indices: &[usize]) -> Vec<(usize, FrameStatus)> {
+    let statuses = indices
+        .par_iter()
+        .map(|&index| {
+            (
+                index,
+                validate_frame(&image_dir.join(format!("{}.jpg", index))),
+            )
+        })
+        .collect::<Vec<_>>();
+    let converted = statuses
+        .iter()
+        .filter(|(_, s)| *s == FrameStatus::Converted)
+        .count();
+    if converted > 0 {
+        progress(&format!("Converted {} png frames to jpg", converted));
+    }
+    statuses.into_iter().filter(|(_, s)| is_bad(*s)).collect()
+}
+
+/// Validate the downloaded frames for point_bearings and refetch any bad frames,
+/// retrying up to fetch_retries times (default: 3). Downloaded frames of points that
+/// expects_placeholder are kept as they are.
+/// Panics if a frame is still missing or corrupt afterwards, since the video would be broken.
+/// Return the indices of frames which are still placeholders.
+#[tracing::instrument(skip_all)]
+pub async fn validate_images<P: AsRef<Path>>(
+    point_bearings: &[SerializablePointBearing],
+    image_dir: &P,
+) -> Vec<usize> {
+    progress_stage("Validating fetched images");
+    let all_indices = (0..point_bearings.len())
+        .filter(|&index| {
+            !expects_placeholder(&point_bearings[index])
+                || !image_dir.as_ref().join(format!("{}.jpg", index)).is_file()
+        })
+        .collect::<Vec<_>>();
+    let mut bad_frames = find_bad_frames(image_dir.as_ref(), &all_indices);
+    let retries = CLI_OPTIONS.fetch_retries.unwrap_or(3);
+    for attempt in 1..=retries {
+        if bad_frames.is_empty() {
+            break;
+        }
+        progress_stage(&format!(
+            "Refetching {} invalid images (attempt {}/{})",
+            bad_frames.len(),
+            attempt,
+            retries
+        ));
+        retry_backoff(attempt).await;
+        let indices = bad_frames.iter().map(|&(i, _)| i).collect::<Vec<_>>();
+        get_images_by_index(point_bearings, &indices, image_dir).await;
+        bad_frames = find_bad_frames(image_dir.as_ref(), &indices);
+    }
+    let invalid = bad_frames
+        .iter()
+        .filter(|(_, s)| *s == FrameStatus::Invalid)
+        .map(|&(i, _)| i)
+        .collect::<Vec<_>>();
+    if !invalid.is_empty() {
+        fail(
+            ErrorCode::FramesMissing,
+            &format!(
+                "Frames missing or corrupt after {} retries: {:?}",
+                retries, &invalid
+            ),
+        );
+    }
+    let placeholders = bad_frames.into_iter().map(|(i, _)| i).collect::<Vec<_>>();
+    if !placeholders.is_empty() {
+        error(
+            ErrorCode::FramesMissing,
+            &format!("Frames without imagery after refetch: {:?}", &placeholders),
+            true,
+        );
+    }
+    placeholders
+}
+
+/// Apply the on_missing policy to the frames of point_bearings in image_dir which validate_images
+/// found to still be placeholders: drop them and renumber the rest with skip, or end the run with
+/// fail. Return the points of the kept frames.
+pub fn drop_placeholders<P: AsRef<Path>>(
+    point_bearings: Vec<SerializablePointBearing>,
+    image_dir: &P,
+    placeholders: &[usize],
+) -> Vec<SerializablePointBearing> {
+    if placeholders.is_empty() {
+        return point_bearings;
+    }
+    match MissingPolicy::from_options() {
+        MissingPolicy::Placeholder => point_bearings,
+        MissingPolicy::Fail => fail(
+            ErrorCode::FramesMissing,
+            &format!("Frames without imagery: {:?}", placeholders),
+        ),
+        MissingPolicy::Skip => {
+            let kept = (0..point_bearings.len())
+                .filter(|i| placeholders.binary_search(i).is_err())
+                .collect::<Vec<_>>();
+            keep_frames(image_dir.as_ref(), point_bearings.len(), &kept);
+            progress(&format!(
+                "Removed {} frames without imagery",
+                placeholders.len()
+            ));
+            kept.iter().map(|&i| point_bearings[i]).collect()
+        }
+    }
+}
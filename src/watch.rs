@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::errors::{fail, ErrorCode};
+use crate::progress::{progress_stage, warning};
+
+/// Seconds since the epoch, for the times in status files.
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// Whether path is a route the watch subcommand renders, rather than a status file.
+fn is_route(path: &Path) -> bool {
+    if path.to_string_lossy().ends_with(".status.json") {
+        return false;
+    }
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("gpx") || ext.eq_ignore_ascii_case("json"),
+        None => false,
+    }
+}
+
+/// Path of the status file of input in dest.
+fn status_path(dest: &Path, input: &Path) -> PathBuf {
+    let name = input.file_name().unwrap_or_default().to_string_lossy();
+    dest.join(format!("{}.status.json", name))
+}
+
+fn write_status(dest: &Path, input: &Path, status: Value) {
+    let path = status_path(dest, input);
+    std::fs::write(&path, status.to_string()).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Io,
+            &format!("Could not write status file {:?}: {:?}", path, e),
+        )
+    });
+}
+
+/// Move from to to, copying if they are on different file systems.
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to)?;
+    std::fs::remove_file(from)
+}
+
+/// Render input with the streetwarp executable into work_dir, passing it profile and args, and
+/// return the path of its output.
+fn render(
+    input: &Path,
+    work_dir: &Path,
+    profile: Option<&str>,
+    args: &[String],
+) -> Result<PathBuf, String> {
+    let exe = std::env::current_exe().expect("Could not find the streetwarp executable");
+    let mut command = Command::new(exe);
+    command
+        .arg(input)
+        .arg("--output-dir")
+        .arg(work_dir)
+        .arg("--progress")
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(profile) = profile {
+        command.arg("--profile").arg(profile);
+    }
+    if input.extension().and_then(|ext| ext.to_str()) == Some("json") {
+        command.arg("--use-metadata");
+    }
+    let output = command
+        .output()
+        .map_err(|e| format!("Could not start streetwarp: {:?}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    // The output path comes from the RESULT progress event.
+    BufReader::new(output.stdout.as_slice())
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(&line.ok()?).ok())
+        .filter(|event| event["type"] == "RESULT")
+        .find_map(|event| event["output"].as_str().map(PathBuf::from))
+        .filter(|path| path.is_file())
+        .ok_or_else(|| "The render finished without an output file".to_string())
+}
+
+/// Render input in a temporary directory and move its output to dest, named after input,
+/// recording progress in its status file. The temporary directory is removed afterwards.
+fn process(input: &Path, dest: &Path, profile: Option<&str>, args: &[String]) {
+    let started_at = now_secs();
+    let status = |state: &str| {
+        json!({
+            "input": input,
+            "status": state,
+            "startedAt": started_at,
+        })
+    };
+    write_status(dest, input, status("rendering"));
+    progress_stage(&format!("Rendering {}", input.to_string_lossy()));
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    let work_dir = std::env::temp_dir().join(format!("streetwarp-watch-{}-{}", stem, started_at));
+    let moved = render(input, &work_dir, profile, args).and_then(|output| {
+        let mut target = dest.join(input.file_stem().unwrap_or_default());
+        if let Some(ext) = output.extension() {
+            target.set_extension(ext);
+        }
+        move_file(&output, &target)
+            .map_err(|e| format!("Could not move {:?} to {:?}: {:?}", output, target, e))?;
+        Ok(target)
+    });
+    if work_dir.exists() {
+        if let Err(e) = std::fs::remove_dir_all(&work_dir) {
+            warning(&format!("Could not remove {:?}: {:?}", work_dir, e));
+        }
+    }
+    let mut final_status = status("success");
+    final_status["finishedAt"] = json!(now_secs());
+    match moved {
+        Ok(target) => {
+            progress_stage(&format!(
+                "Rendered {} to {}",
+                input.to_string_lossy(),
+                target.to_string_lossy()
+            ));
+            final_status["output"] = json!(target);
+        }
+        Err(error) => {
+            warning(&format!(
+                "Could not render {}: {}",
+                input.to_string_lossy(),
+                error
+            ));
+            final_status["status"] = json!("failure");
+            final_status["error"] = json!(error);
+        }
+    }
+    write_status(dest, input, final_status);
+}
+
+/// Poll dir every interval_secs for new .gpx and .json routes and render each once its size
+/// stops changing, moving outputs and status files into dest. Routes with a status file in dest
+/// are skipped, so removing the status file renders a route again. Runs until interrupted.
+pub fn watch(dir: &Path, dest: &Path, profile: Option<&str>, interval_secs: u64, args: &[String]) {
+    std::fs::create_dir_all(dest).unwrap_or_else(|e| {
+        fail(
+            ErrorCode::Io,
+            &format!("Could not create destination {:?}: {:?}", dest, e),
+        )
+    });
+    progress_stage(&format!(
+        "Watching {} for routes, rendering into {}",
+        dir.to_string_lossy(),
+        dest.to_string_lossy()
+    ));
+    // Sizes seen on the previous poll, to wait for files still being written.
+    let mut last_sizes: HashMap<PathBuf, u64> = HashMap::new();
+    loop {
+        let entries = std::fs::read_dir(dir).unwrap_or_else(|e| {
+            fail(
+                ErrorCode::Io,
+                &format!("Could not read watched directory {:?}: {:?}", dir, e),
+            )
+        });
+        let mut sizes = HashMap::new();
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if !path.is_file() || !is_route(&path) || status_path(dest, &path).exists() {
+                continue;
+            }
+            let size = std::fs::metadata(&path).map_or(0, |metadata| metadata.len());
+            if size > 0 && last_sizes.get(&path) == Some(&size) {
+                process(&path, dest, profile, args);
+            } else {
+                sizes.insert(path, size);
+            }
+        }
+        last_sizes = sizes;
+        std::thread::sleep(Duration::from_secs(interval_secs));
+    }
+}
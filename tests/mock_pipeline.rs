@@ -0,0 +1,87 @@
+//! Runs the metadata, fetch and encode stages end to end with the mock provider, which draws
+//! frames locally so no API key or network is needed.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde_json::Value;
+
+const ROUTE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/res/straight_test.gpx");
+const FETCHED_METADATA_FILE: &str = "streetwarp-fetched.json";
+const MAX_FRAMES: usize = 10;
+
+/// Run streetwarp with args and the mock provider, returning its stdout. Panics with its stderr
+/// if it fails.
+fn streetwarp(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_streetwarp"))
+        .args(args)
+        .args(["--provider", "mock"])
+        .output()
+        .expect("Could not run streetwarp");
+    assert!(
+        output.status.success(),
+        "streetwarp {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).expect("Output is not UTF-8")
+}
+
+fn has_ffmpeg() -> bool {
+    Command::new("ffmpeg")
+        .arg("-version")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// A new empty directory for the frames of this test.
+fn work_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("streetwarp-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn read_json(path: &Path) -> Value {
+    let file = std::fs::File::open(path).unwrap_or_else(|e| panic!("No {:?}: {:?}", path, e));
+    serde_json::from_reader(file).expect("Invalid JSON")
+}
+
+#[test]
+fn fetch_encode_metadata() {
+    let metadata: Value =
+        serde_json::from_str(&streetwarp(&["metadata", ROUTE, "--json"])).expect("Invalid JSON");
+    let frames = metadata["frames"].as_u64().expect("No frame count") as usize;
+    assert!(frames > 0);
+    assert_eq!(metadata["gpsPoints"].as_array().unwrap().len(), frames);
+    assert!(metadata["distance"].as_f64().unwrap() > 0.0);
+
+    let dir = work_dir("mock");
+    let dir_arg = dir.to_str().unwrap();
+    let max_frames = MAX_FRAMES.to_string();
+    streetwarp(&[
+        "fetch",
+        ROUTE,
+        "--output-dir",
+        dir_arg,
+        "--max-frames",
+        &max_frames,
+    ]);
+    let fetched = read_json(&dir.join(FETCHED_METADATA_FILE));
+    let points = fetched["gpsPoints"].as_array().expect("No points fetched");
+    assert_eq!(points.len(), frames.min(MAX_FRAMES));
+    for (index, point) in points.iter().enumerate() {
+        assert!(point["panoId"].as_str().unwrap().starts_with("mock-"));
+        assert!(dir.join(format!("{}.jpg", index)).is_file());
+    }
+
+    if has_ffmpeg() {
+        streetwarp(&["encode", dir_arg, "--output", "mock.mp4"]);
+        let size = std::fs::metadata(dir.join("mock.mp4"))
+            .expect("No video encoded")
+            .len();
+        assert!(size > 0);
+    } else {
+        eprintln!("ffmpeg not found, skipping encode");
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}